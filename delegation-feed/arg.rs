@@ -2,9 +2,14 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use crate::rirstat::rirbase::CountrySpec;
+use crate::rirstat::rirbase::{CountrySpec, RirName, Status};
+use crate::rirstat::Source;
 use clap::Parser;
-use std::net::{IpAddr, Ipv4Addr};
+use pabgp::capability::RfcRole;
+use pabgp::path::Communities;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[allow(clippy::struct_excessive_bools)]
@@ -23,12 +28,71 @@ pub struct DelegationFeed {
     /// Defaults to the local ID if not specified.
     #[arg(short = 'n', long)]
     pub next_hop: Option<IpAddr>,
-    /// BGP session listen address
-    #[arg(short = 'l', long, default_value = "::")]
-    pub listen_addr: IpAddr,
-    /// BGP session listen port
-    #[arg(short = 'p', long, default_value = "179")]
-    pub listen_port: u16,
+    /// Address and port to accept BGP sessions on
+    ///
+    /// May be given multiple times, e.g. `--listen 0.0.0.0:179 --listen
+    /// [::1]:1179`. Note that on Linux, `[::]` also accepts IPv4 connections
+    /// unless `net.ipv6.bindv6only` is set, so it cannot be combined with
+    /// `0.0.0.0` on the same port.
+    #[arg(short = 'l', long = "listen", default_value = "[::]:179")]
+    pub listen: Vec<SocketAddr>,
+    /// Actively connect to this peer on startup
+    ///
+    /// We send OPEN first in this session instead of waiting for the peer.
+    /// Incoming connections are still accepted.
+    #[arg(short = 'c', long)]
+    pub connect: Option<SocketAddr>,
+    /// Only accept sessions from this address
+    ///
+    /// May be given multiple times. Connections from any address are
+    /// accepted if none is given.
+    #[arg(long = "allow-peer")]
+    pub allowed_peers: Vec<IpAddr>,
+    /// Sign the TCP segments of sessions with this peer (RFC 2385), e.g.
+    /// `192.0.2.2=secret`
+    ///
+    /// May be given multiple times. Only supported on Linux when built with
+    /// the `tcp-md5` feature.
+    #[arg(long = "md5-password", value_parser = parse_md5_password)]
+    pub md5_passwords: Vec<(IpAddr, String)>,
+    /// Only peer with this AS number
+    ///
+    /// May be given multiple times. Peers with a different AS number are
+    /// sent a Bad Peer AS notification. Any AS is accepted if none is given.
+    #[arg(long = "allow-asn")]
+    pub allowed_asns: Vec<u32>,
+    /// Shortest IPv4 prefix to advertise
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(u8).range(0..=32))]
+    pub min_prefix_len4: u8,
+    /// Longest IPv4 prefix to advertise
+    #[arg(long, default_value = "32", value_parser = clap::value_parser!(u8).range(0..=32))]
+    pub max_prefix_len4: u8,
+    /// Shortest IPv6 prefix to advertise
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub min_prefix_len6: u8,
+    /// Longest IPv6 prefix to advertise
+    #[arg(long, default_value = "128", value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub max_prefix_len6: u8,
+    /// Close the session if the peer advertises more than this many prefixes
+    #[arg(long)]
+    pub max_prefixes: Option<usize>,
+    /// Flush and pause briefly after sending this many messages, to avoid
+    /// overwhelming slow peers during a large table push
+    ///
+    /// By default, all messages are sent at once.
+    #[arg(long)]
+    pub updates_per_burst: Option<NonZeroUsize>,
+    /// LOCAL_PREF attached to routes advertised to internal peers
+    ///
+    /// It is never sent to external peers (RFC 4271 Section 5.1.5).
+    #[arg(long)]
+    pub local_pref: Option<u32>,
+    /// MULTI_EXIT_DISC attached to advertised routes
+    #[arg(long)]
+    pub med: Option<u32>,
+    /// Our BGP role (RFC 9234): provider, rs, rs-client, customer, or peer
+    #[arg(long, value_parser = parse_role)]
+    pub role: Option<RfcRole>,
     /// Whether to parse and advertise IPv4 prefixes
     #[arg(short = '4', long)]
     pub enable_ipv4: bool,
@@ -38,12 +102,129 @@ pub struct DelegationFeed {
     /// Interval in minutes to update the database
     #[arg(short = 'u', long, default_value = "60")]
     pub update_interval: u64,
+    /// File to cache the database in between runs
+    ///
+    /// It is loaded on startup if present and saved after each update.
+    #[arg(long)]
+    pub cache_file: Option<PathBuf>,
+    /// Append each route announced or withdrawn to this file as a
+    /// tab-separated line: timestamp, action, prefix, next hop, and reason
+    #[arg(long)]
+    pub change_log: Option<PathBuf>,
+    /// Read the statistics file of a RIR from a local path or URL instead
+    /// of the default mirror, e.g. `arin=/path/to/file`
+    #[arg(long = "source", value_parser = parse_source)]
+    pub sources: Vec<(RirName, Source)>,
+    /// Refuse database updates that withdraw more than this fraction of a
+    /// country's prefixes, e.g. `0.5`
+    ///
+    /// A broken RIR file would otherwise withdraw most of the feed. The old
+    /// data is kept and the update is retried at the next interval.
+    #[arg(long, value_parser = parse_fraction)]
+    pub max_withdraw_fraction: Option<f64>,
+    /// Withdraw all prefixes of a RIR whose statistics file can no longer be
+    /// parsed, e.g. after a format change, instead of keeping the old data
+    ///
+    /// Download failures always keep the old data.
+    #[arg(long)]
+    pub withdraw_on_failure: bool,
+    /// Number of times to retry a failed download, with exponential backoff
+    #[arg(long, default_value_t = crate::rirstat::DEFAULT_HTTP_RETRIES)]
+    pub http_retries: u32,
+    /// Statuses of records to import from the statistics files, separated
+    /// by commas: allocated, assigned, available, or reserved
+    #[arg(
+        long = "include-status",
+        value_delimiter = ',',
+        default_value = "allocated,assigned"
+    )]
+    pub include_statuses: Vec<Status>,
+    /// Tag the routes of a country with a community (RFC 1997), e.g.
+    /// `arin:US=65000:1`
+    ///
+    /// May be given multiple times. Routes of countries without a community
+    /// are advertised untagged.
+    #[arg(long = "country-community", value_parser = parse_country_community)]
+    pub country_communities: Vec<(CountrySpec, u32)>,
     /// Countries of which prefixes are advertised
     pub countries: Vec<CountrySpec>,
     /// Verbose mode
     #[arg(short = 'v', long)]
     pub verbose: bool,
-    /// Dry-run mode: download, parse, and print the routes, then exit
+    /// Dry-run mode: download and parse the routes, print the UPDATE messages
+    /// a session would send (route counts, path attributes, and hex
+    /// encoding), then exit
     #[arg(short = 'i', long)]
     pub dry_run: bool,
 }
+
+/// Parse a `RIR=SOURCE` pair
+fn parse_source(s: &str) -> Result<(RirName, Source), String> {
+    let (rir, source) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected RIR=SOURCE, got `{s}`"))?;
+    let rir = rir.parse().map_err(|e| format!("{e}"))?;
+    let Ok(source) = source.parse();
+    Ok((rir, source))
+}
+
+/// Parse a `COUNTRY=ASN:VALUE` pair
+fn parse_country_community(s: &str) -> Result<(CountrySpec, u32), String> {
+    let (country, community) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected COUNTRY=ASN:VALUE, got `{s}`"))?;
+    let country = country.parse().map_err(|e| format!("{e}"))?;
+    let (asn, value) = community
+        .split_once(':')
+        .ok_or_else(|| format!("expected ASN:VALUE, got `{community}`"))?;
+    let asn = asn
+        .parse()
+        .map_err(|e| format!("invalid ASN `{asn}`: {e}"))?;
+    let value = value
+        .parse()
+        .map_err(|e| format!("invalid community value `{value}`: {e}"))?;
+    Ok((country, Communities::community(asn, value)))
+}
+
+/// Parse a fraction between 0 and 1
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    let fraction: f64 = s
+        .parse()
+        .map_err(|e| format!("invalid fraction `{s}`: {e}"))?;
+    if (0.0..=1.0).contains(&fraction) {
+        Ok(fraction)
+    } else {
+        Err(format!("expected a fraction between 0 and 1, got `{s}`"))
+    }
+}
+
+/// Parse a `PEER=SECRET` pair
+fn parse_md5_password(s: &str) -> Result<(IpAddr, String), String> {
+    let (peer, secret) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected PEER=SECRET, got `{s}`"))?;
+    let peer = peer
+        .parse()
+        .map_err(|e| format!("invalid peer address `{peer}`: {e}"))?;
+    if secret.is_empty() || secret.len() > crate::session::TCP_MD5_MAX_KEY_LEN {
+        return Err(format!(
+            "the secret must be 1 to {} bytes long",
+            crate::session::TCP_MD5_MAX_KEY_LEN
+        ));
+    }
+    Ok((peer, secret.to_string()))
+}
+
+/// Parse a BGP role name as used in RFC 9234
+fn parse_role(s: &str) -> Result<RfcRole, String> {
+    match s {
+        "provider" => Ok(RfcRole::Provider),
+        "rs" => Ok(RfcRole::RouteServer),
+        "rs-client" => Ok(RfcRole::RouteServerClient),
+        "customer" => Ok(RfcRole::Customer),
+        "peer" => Ok(RfcRole::Peer),
+        _ => Err(format!(
+            "expected provider, rs, rs-client, customer, or peer, got `{s}`"
+        )),
+    }
+}
@@ -3,9 +3,28 @@ mod rirstat;
 mod session;
 
 use clap::Parser;
-use rirstat::{Database, DatabaseDiff};
+use pabgp::capability::{Capabilities, CapabilitiesBuilder, RfcRole};
+use pabgp::path;
+use pabgp::{Codec, Message};
+use rirstat::rirbase::CountrySpec;
+use rirstat::{Database, DatabaseDiff, RirFetcher, UreqFetcher};
 use session::Feeder;
-use tokio::sync::broadcast;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio_util::codec::Encoder;
+
+/// Shutdown communication sent to peers when we exit
+const SHUTDOWN_COMMUNICATION: &str = "delegation-feed is shutting down";
+
+/// How long to wait for sessions to close after a shutdown is requested
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 
 fn setup_logger(level: log::LevelFilter) {
     let config = simplelog::ConfigBuilder::new()
@@ -20,67 +39,450 @@ fn setup_logger(level: log::LevelFilter) {
     .expect("Failed to initialize logger");
 }
 
-async fn handle_session(
-    init_db: Database,
-    recv_updates: broadcast::Receiver<DatabaseDiff>,
-    socket: tokio::net::TcpStream,
+/// Per-session settings shared by all sessions
+#[derive(Clone, Debug)]
+struct SessionConfig {
     local_as: u32,
     local_id: std::net::Ipv4Addr,
     next_hop: std::net::IpAddr,
+    prefix_len_v4: (u8, u8),
+    prefix_len_v6: (u8, u8),
+    max_prefixes: Option<usize>,
+    updates_per_burst: Option<NonZeroUsize>,
+    role: Option<RfcRole>,
+    local_pref: Option<u32>,
+    med: Option<u32>,
+    capabilities: Capabilities,
+    country_communities: HashMap<CountrySpec, u32>,
+    allowed_asns: Vec<u32>,
+    md5_passwords: HashMap<IpAddr, String>,
+    change_log: Option<PathBuf>,
+}
+
+impl SessionConfig {
+    /// Apply the settings that are not passed to the `Feeder` constructors
+    fn configure(&self, session: &mut Feeder) {
+        let (min_v4, max_v4) = self.prefix_len_v4;
+        let (min_v6, max_v6) = self.prefix_len_v6;
+        session.set_prefix_len_filter(min_v4, max_v4, min_v6, max_v6);
+        session.set_max_prefixes(self.max_prefixes);
+        session.set_updates_per_burst(self.updates_per_burst);
+        session.set_role(self.role);
+        session.set_local_pref(self.local_pref);
+        session.set_med(self.med);
+        session.set_capabilities(self.capabilities.clone());
+        session.set_country_communities(self.country_communities.clone());
+        session.set_allowed_asns(self.allowed_asns.clone());
+        if let Some(path) = &self.change_log {
+            match std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+            {
+                Ok(file) => session.set_change_log(Some(Box::new(std::io::LineWriter::new(file)))),
+                Err(e) => log::warn!("Failed to open change log {}: {e}", path.display()),
+            }
+        }
+    }
+}
+
+/// Capabilities to advertise for the enabled address families
+fn local_capabilities(args: &arg::DelegationFeed) -> Capabilities {
+    let mut builder = CapabilitiesBuilder::new();
+    if args.enable_ipv4 {
+        // IPv4 routes may have an IPv6 next hop
+        builder = builder.mp_ipv4_unicast().enh_ipv4_over_ipv6();
+    }
+    if args.enable_ipv6 {
+        builder = builder.mp_ipv6_unicast();
+    }
+    builder
+        .route_refresh()
+        .extended_message()
+        .four_octet_as_number_if_needed(args.local_as)
+        .build()
+}
+
+/// Log what was negotiated with the peer and the session counters
+fn log_session_summary(session: &Feeder) {
+    if let Some(negotiated) = session.negotiated() {
+        log::info!(
+            "Session with AS{}: hold time {}s, peer capabilities {:?}, negotiated {:?}",
+            negotiated.peer_asn(),
+            negotiated.hold_time(),
+            negotiated.peer_capabilities(),
+            negotiated.capabilities()
+        );
+    }
+    log::info!(
+        "Session statistics: {:?}, {} routes received",
+        session.stats(),
+        session.received_routes().len()
+    );
+}
+
+/// A session registered with the `CollisionResolver`
+#[derive(Debug)]
+struct RegisteredSession {
+    /// Identifies the session when it is unregistered
+    token: u64,
+    /// Whether we opened the connection
+    locally_initiated: bool,
+    /// Tells the session that it lost, fails if it is already established
+    lose: oneshot::Sender<()>,
+}
+
+/// Resolves connection collisions between sessions with the same peer,
+/// keyed by the peer's BGP Identifier (RFC 4271 Section 6.8)
+#[derive(Debug)]
+struct CollisionResolver {
+    local_id: Ipv4Addr,
+    sessions: Mutex<HashMap<Ipv4Addr, RegisteredSession>>,
+    next_token: AtomicU64,
+}
+
+impl CollisionResolver {
+    fn new(local_id: Ipv4Addr) -> Self {
+        Self {
+            local_id,
+            sessions: Mutex::new(HashMap::new()),
+            next_token: AtomicU64::new(0),
+        }
+    }
+
+    /// Create the collision check of a new session
+    ///
+    /// # Returns
+    /// The token to unregister the session with and the check to give to the session.
+    fn check(self: &Arc<Self>, locally_initiated: bool) -> (u64, session::CollisionCheck) {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let this = Arc::clone(self);
+        let check = Box::new(move |peer_id| this.register(peer_id, token, locally_initiated));
+        (token, check)
+    }
+
+    /// Register a session that entered OpenConfirm
+    ///
+    /// The connection opened by the side with the higher BGP Identifier is
+    /// kept, unless the other one is already established.
+    ///
+    /// # Returns
+    /// - None if the new session loses.
+    /// - Some(_) with a receiver that fires if the new session loses later.
+    fn register(
+        &self,
+        peer_id: Ipv4Addr,
+        token: u64,
+        locally_initiated: bool,
+    ) -> Option<oneshot::Receiver<()>> {
+        let mut sessions = self.sessions.lock().expect("collision resolver poisoned");
+        if let Some(existing) = sessions.get(&peer_id) {
+            // Keep the connection initiated by the side with the higher BGP Identifier
+            let keep_local = self.local_id > peer_id;
+            let new_wins =
+                locally_initiated == keep_local && existing.locally_initiated != keep_local;
+            if !new_wins {
+                log::warn!("Connection collision with peer {peer_id}, closing the new session");
+                return None;
+            }
+            let existing = sessions.remove(&peer_id).expect("entry exists");
+            if existing.lose.send(()).is_err() {
+                // The existing session is already established and has to be kept.
+                // Its receiver is gone, so a fresh sender behaves the same way.
+                log::warn!("Connection collision with peer {peer_id}, closing the new session");
+                let (lose, _) = oneshot::channel();
+                sessions.insert(peer_id, RegisteredSession { lose, ..existing });
+                return None;
+            }
+            log::warn!("Connection collision with peer {peer_id}, closing the existing session");
+        }
+        let (lose, lost) = oneshot::channel();
+        sessions.insert(
+            peer_id,
+            RegisteredSession {
+                token,
+                locally_initiated,
+                lose,
+            },
+        );
+        Some(lost)
+    }
+
+    /// Forget a session that has ended
+    fn unregister(&self, peer_id: Ipv4Addr, token: u64) {
+        let mut sessions = self.sessions.lock().expect("collision resolver poisoned");
+        if sessions.get(&peer_id).is_some_and(|s| s.token == token) {
+            sessions.remove(&peer_id);
+        }
+    }
+}
+
+/// Log why a session ended
+fn log_session_end(result: Result<(), session::Error>) {
+    match result {
+        Ok(()) => {}
+        Err(session::Error::ConnectionClosed) => log::info!("Peer closed the connection"),
+        Err(e) => log::error!("Session error: {:?}", e),
+    }
+}
+
+async fn handle_session(
+    database: watch::Receiver<Database>,
+    recv_updates: broadcast::Receiver<DatabaseDiff>,
+    socket: TcpStream,
+    config: SessionConfig,
+    shutdown_signal: broadcast::Receiver<String>,
+    resolver: Arc<CollisionResolver>,
 ) {
-    let (ipv4_routes, ipv6_routes) = init_db.into_prefixes();
-    let init_ipv4_routes = Some(ipv4_routes.into_values().flatten().into());
-    let init_ipv6_routes = Some(ipv6_routes.into_values().flatten().into());
     let mut session = Feeder::new(
-        init_ipv4_routes,
-        init_ipv6_routes,
+        database,
         recv_updates,
         socket,
-        local_as,
-        local_id,
-        next_hop,
+        config.local_as,
+        config.local_id,
+        config.next_hop,
     );
-    if let Err(e) = session.idle().await {
-        log::error!("Session error: {:?}", e);
+    config.configure(&mut session);
+    session.set_shutdown_signal(shutdown_signal);
+    let (token, check) = resolver.check(false);
+    session.set_collision_check(check);
+    log_session_end(session.idle().await);
+    if let Some(peer_id) = session.peer_id() {
+        resolver.unregister(peer_id, token);
     }
+    log_session_summary(&session);
+}
+
+async fn handle_active_session(
+    database: watch::Receiver<Database>,
+    recv_updates: broadcast::Receiver<DatabaseDiff>,
+    peer_addr: SocketAddr,
+    config: SessionConfig,
+    shutdown_signal: broadcast::Receiver<String>,
+    resolver: Arc<CollisionResolver>,
+) {
+    let md5_key = config
+        .md5_passwords
+        .get(&peer_addr.ip().to_canonical())
+        .map(String::as_bytes);
+    let session = Feeder::connect_to(
+        peer_addr,
+        md5_key,
+        database,
+        recv_updates,
+        config.local_as,
+        config.local_id,
+        config.next_hop,
+    )
+    .await;
+    let mut session = match session {
+        Ok(session) => session,
+        Err(e) => {
+            log::error!("Failed to connect to peer: {:?}", e);
+            return;
+        }
+    };
+    config.configure(&mut session);
+    session.set_shutdown_signal(shutdown_signal);
+    let (token, check) = resolver.check(true);
+    session.set_collision_check(check);
+    log_session_end(session.active().await);
+    if let Some(peer_id) = session.peer_id() {
+        resolver.unregister(peer_id, token);
+    }
+    log_session_summary(&session);
+}
+
+/// Require TCP MD5 signatures (RFC 2385) from the peers with a password
+fn sign_listener(
+    listener: &TcpListener,
+    passwords: &HashMap<IpAddr, String>,
+) -> std::io::Result<()> {
+    let is_ipv6 = listener.local_addr()?.is_ipv6();
+    for (peer, password) in passwords {
+        let peer = match (peer, is_ipv6) {
+            // IPv6 sockets see IPv4 peers as IPv4-mapped addresses
+            (IpAddr::V4(v4), true) => IpAddr::V6(v4.to_ipv6_mapped()),
+            (IpAddr::V6(_), false) => continue,
+            (peer, _) => *peer,
+        };
+        session::set_tcp_md5sig(listener, peer, password.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Accept a connection on whichever listener gets one first
+async fn accept_any(listeners: &[TcpListener]) -> std::io::Result<(TcpStream, SocketAddr)> {
+    // `accept` is cancel safe, so the other listeners lose nothing
+    let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+    futures_util::future::select_all(accepts).await.0
 }
 
-fn updater(
-    mut init_db: Database,
-    send_updates: &broadcast::Sender<DatabaseDiff>,
+/// Check if a peer's address is in the allow list, which allows any address
+/// if empty
+fn is_peer_allowed(allowed: &[IpAddr], addr: IpAddr) -> bool {
+    // IPv4 peers connecting to an IPv6 listener appear as mapped addresses
+    allowed.is_empty() || allowed.contains(&addr.to_canonical())
+}
+
+/// Load the database cache, falling back to `db` if it is missing or unusable
+fn load_cache(path: &Path, db: Database) -> Database {
+    match Database::load_from(path) {
+        Ok(cached) if cached.has_same_config(&db) => {
+            log::info!("Loaded database cache from {}", path.display());
+            cached
+        }
+        Ok(_) => {
+            log::warn!(
+                "Ignoring database cache with different countries, address families, or statuses"
+            );
+            db
+        }
+        Err(rirstat::Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("No database cache found at {}", path.display());
+            db
+        }
+        Err(e) => {
+            log::warn!("Failed to load database cache: {:?}", e);
+            db
+        }
+    }
+}
+
+/// Update the database every `update_interval` and send the diffs to the
+/// sessions until `shutdown` is received
+///
+/// The updated database is published to `send_database` before its diff,
+/// so that a session that missed some diffs can resynchronize with it.
+///
+/// Downloading and parsing run on the blocking thread pool, so no runtime
+/// thread is held while waiting for the next update.
+async fn updater(
+    mut db: Database,
+    fetcher: Arc<impl RirFetcher + Send + 'static>,
+    send_database: watch::Sender<Database>,
+    send_updates: broadcast::Sender<DatabaseDiff>,
     update_interval: std::time::Duration,
+    cache_file: Option<PathBuf>,
+    mut shutdown: broadcast::Receiver<String>,
 ) {
+    let mut interval = tokio::time::interval(update_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
     loop {
-        let diff = init_db.update_with_diff().unwrap_or_else(|e| {
-            log::error!("Database update failed: {:?}", e);
-            DatabaseDiff::default()
+        tokio::select! {
+            // Do not start another update once shutdown has been requested
+            biased;
+            _ = shutdown.recv() => break,
+            _ = interval.tick() => {}
+        }
+        let fetcher = Arc::clone(&fetcher);
+        let cache_file = cache_file.clone();
+        let update = tokio::task::spawn_blocking(move || {
+            let result = db.update_with_diff(&*fetcher);
+            if let (Ok(_), Some(path)) = (&result, cache_file) {
+                if let Err(e) = db.save_to(&path) {
+                    log::error!("Failed to save database cache: {:?}", e);
+                }
+            }
+            (db, result)
         });
+        let result;
+        (db, result) = update.await.expect("Database update panicked");
+        let diff = match result {
+            Ok(diff) => {
+                log::debug!("Database diff: {}", diff.summary());
+                send_database.send_replace(db.clone());
+                diff
+            }
+            Err(e) => {
+                log::error!("Database update failed: {:?}", e);
+                DatabaseDiff::default()
+            }
+        };
         if send_updates.send(diff).is_err() {
             log::error!("Failed to send update to session");
             // `tokio` says the only way to fail is if all receivers are dropped,
             // which implies that the main loop has exited. We should exit too.
             break;
         }
-        std::thread::sleep(update_interval);
     }
 }
 
-fn dry_run_and_exit(mut db: Database) -> ! {
-    db.update_all().expect("Failed to update database");
+/// Build the UPDATE messages a session would advertise for a database,
+/// assuming an external peer that supports MP-BGP but not Extended Messages
+fn dry_run_updates(db: Database, config: &SessionConfig) -> Vec<pabgp::Update> {
     let (ipv4_prefixes, ipv6_prefixes) = db.into_prefixes();
-    println!("IPv4 prefixes:");
-    for (country, prefixes) in &ipv4_prefixes {
-        println!("  {country}:");
-        for prefix in prefixes {
-            println!("    {prefix}");
-        }
+    let (min_v4, max_v4) = config.prefix_len_v4;
+    let (min_v6, max_v6) = config.prefix_len_v6;
+    let base = session::base_update_builder(
+        true,
+        None,
+        config.local_as,
+        config.next_hop,
+        // Only sent to internal peers
+        None,
+        config.med,
+    );
+    session::country_update_builders(
+        &base,
+        &ipv4_prefixes,
+        &ipv6_prefixes,
+        &config.country_communities,
+    )
+    .into_iter()
+    .flat_map(|builder| {
+        builder
+            .filter_prefix_len(min_v4, max_v4, min_v6, max_v6)
+            .build()
+            .expect("Failed to build UPDATE messages")
+    })
+    .collect()
+}
+
+/// Describe an UPDATE message with its route counts, path attributes, and encoding
+fn describe_update(index: usize, update: &pabgp::Update) -> String {
+    let mut encoded = bytes::BytesMut::new();
+    Codec::default()
+        .encode(Message::Update(update.clone()), &mut encoded)
+        .expect("Failed to encode UPDATE message");
+    let (advertised, withdrawn) = session::route_counts(update);
+    let mut description = format!(
+        "UPDATE #{index}: {} bytes, {advertised} advertised, {withdrawn} withdrawn\n",
+        encoded.len()
+    );
+    for attr in update.path_attributes.iter() {
+        // Summarize the routes instead of dumping them
+        let _ = match &attr.data {
+            path::Data::MpReachNlri(reach) => writeln!(
+                description,
+                "  MpReachNlri: {:?} {:?} via {:?}, {} routes",
+                reach.afi,
+                reach.safi,
+                reach.next_hop,
+                reach.nlri.len()
+            ),
+            path::Data::MpUnreachNlri(unreach) => writeln!(
+                description,
+                "  MpUnreachNlri: {:?} {:?}, {} routes",
+                unreach.afi,
+                unreach.safi,
+                unreach.withdrawn_routes.len()
+            ),
+            data => writeln!(description, "  {data:?}"),
+        };
     }
-    println!("IPv6 prefixes:");
-    for (country, prefixes) in &ipv6_prefixes {
-        println!("  {country}:");
-        for prefix in prefixes {
-            println!("    {prefix}");
-        }
+    description.push_str("  ");
+    for byte in &encoded {
+        let _ = write!(description, "{byte:02x}");
+    }
+    description
+}
+
+/// Print the UPDATE messages we would advertise, then exit
+fn dry_run_and_exit(mut db: Database, fetcher: &impl RirFetcher, config: &SessionConfig) -> ! {
+    db.update_all(fetcher).expect("Failed to update database");
+    for (index, update) in dry_run_updates(db, config).iter().enumerate() {
+        println!("{}", describe_update(index + 1, update));
     }
     std::process::exit(0);
 }
@@ -94,32 +496,305 @@ async fn main() {
         log::LevelFilter::Info
     });
     let mut db = Database::new(args.countries.clone(), args.enable_ipv4, args.enable_ipv6);
+    db.set_statuses(args.include_statuses.clone());
+    if let Some(path) = &args.cache_file {
+        db = load_cache(path, db);
+    }
+    for (rir, source) in &args.sources {
+        db.set_source(*rir, source.clone());
+    }
+    let fetcher = UreqFetcher::new(args.http_retries);
+    if let Some(fraction) = args.max_withdraw_fraction {
+        db.set_max_withdraw_fraction(fraction);
+    }
+    db.set_withdraw_on_failure(args.withdraw_on_failure);
+    let config = SessionConfig {
+        local_as: args.local_as,
+        local_id: args.local_id,
+        next_hop: args.next_hop.unwrap_or_else(|| args.local_id.into()),
+        prefix_len_v4: (args.min_prefix_len4, args.max_prefix_len4),
+        prefix_len_v6: (args.min_prefix_len6, args.max_prefix_len6),
+        max_prefixes: args.max_prefixes,
+        updates_per_burst: args.updates_per_burst,
+        role: args.role,
+        local_pref: args.local_pref,
+        med: args.med,
+        capabilities: local_capabilities(&args),
+        country_communities: args.country_communities.iter().copied().collect(),
+        allowed_asns: args.allowed_asns.clone(),
+        md5_passwords: args
+            .md5_passwords
+            .iter()
+            .map(|(peer, password)| (peer.to_canonical(), password.clone()))
+            .collect(),
+        change_log: args.change_log.clone(),
+    };
     if args.dry_run {
-        dry_run_and_exit(db);
+        dry_run_and_exit(db, &fetcher, &config);
     }
-    let local_as = args.local_as;
-    let local_id = args.local_id;
-    let next_hop = args.next_hop.unwrap_or_else(|| local_id.into());
     let update_interval = std::time::Duration::from_secs(args.update_interval * 60);
-    let socket = tokio::net::TcpListener::bind((args.listen_addr, args.listen_port))
-        .await
-        .expect("Failed to bind to listen address");
-    let (send_updates, mut recv_updates) = broadcast::channel(16);
-    let updater_copy = db.clone();
-    tokio::task::spawn_blocking(move || {
-        updater(updater_copy, &send_updates, update_interval);
-    });
+    let mut listeners = Vec::with_capacity(args.listen.len());
+    for addr in &args.listen {
+        let listener = TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to bind to {addr}: {e}"));
+        sign_listener(&listener, &config.md5_passwords)
+            .unwrap_or_else(|e| panic!("Failed to set TCP MD5 passwords on {addr}: {e}"));
+        log::info!("Listening on {addr}");
+        listeners.push(listener);
+    }
+    let (send_database, recv_database) = watch::channel(db.clone());
+    let (send_updates, recv_updates) = broadcast::channel(16);
+    let (send_shutdown, _) = broadcast::channel(1);
+    tokio::spawn(updater(
+        db,
+        Arc::new(fetcher),
+        send_database,
+        send_updates,
+        update_interval,
+        args.cache_file.clone(),
+        send_shutdown.subscribe(),
+    ));
+    let mut sessions = tokio::task::JoinSet::new();
+    let resolver = Arc::new(CollisionResolver::new(config.local_id));
+    if let Some(peer_addr) = args.connect {
+        sessions.spawn(handle_active_session(
+            recv_database.clone(),
+            recv_updates.resubscribe(),
+            peer_addr,
+            config.clone(),
+            send_shutdown.subscribe(),
+            Arc::clone(&resolver),
+        ));
+    }
     loop {
         let sub_recv_updates = recv_updates.resubscribe();
         tokio::select! {
-            Ok((socket, _)) = socket.accept() => {
-                tokio::spawn(handle_session(db.clone(), sub_recv_updates, socket, local_as, local_id, next_hop));
-            }
-            diff = recv_updates.recv() => {
-                if let Ok(diff) = diff {
-                    diff.apply_to(&mut db);
+            Ok((socket, addr)) = accept_any(&listeners) => {
+                if !is_peer_allowed(&args.allowed_peers, addr.ip()) {
+                    log::warn!("Rejecting connection from {addr}, which is not allowed");
+                    continue;
                 }
+                sessions.spawn(handle_session(
+                    recv_database.clone(),
+                    sub_recv_updates,
+                    socket,
+                    config.clone(),
+                    send_shutdown.subscribe(),
+                    Arc::clone(&resolver),
+                ));
+            }
+            Some(_) = sessions.join_next() => {}
+            _ = tokio::signal::ctrl_c() => {
+                log::info!("Received interrupt, shutting down");
+                break;
             }
         }
     }
+    // Sending fails only if there are no sessions to notify
+    let _ = send_shutdown.send(SHUTDOWN_COMMUNICATION.to_string());
+    let wait_sessions = async { while sessions.join_next().await.is_some() {} };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, wait_sessions)
+        .await
+        .is_err()
+    {
+        log::warn!("Timed out waiting for sessions to close");
+    }
+    // Do not wait for an update in progress on the blocking thread pool
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rirstat::rirbase::RirName;
+    use rirstat::Source;
+
+    #[test]
+    fn test_dry_run_med() {
+        let path = std::env::temp_dir().join(format!(
+            "delegation-feed-dry-run-{}.txt",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "2|apnic|20240101|1|19830613|20231231|+1000\n\
+             apnic|JP|ipv4|43.252.240.0|1024|20140417|allocated\n",
+        )
+        .unwrap();
+        let mut db = Database::new(vec!["apnic:JP".parse().unwrap()], true, false);
+        db.set_source(RirName::Apnic, Source::File(path.clone()));
+        db.update_all(&UreqFetcher::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let config = SessionConfig {
+            local_as: 65000,
+            local_id: "192.0.2.1".parse().unwrap(),
+            next_hop: "192.0.2.1".parse().unwrap(),
+            prefix_len_v4: (0, 32),
+            prefix_len_v6: (0, 128),
+            max_prefixes: None,
+            updates_per_burst: None,
+            role: None,
+            local_pref: Some(200),
+            med: Some(50),
+            capabilities: Capabilities::default(),
+            country_communities: HashMap::new(),
+            allowed_asns: Vec::new(),
+            md5_passwords: HashMap::new(),
+            change_log: None,
+        };
+        let updates = dry_run_updates(db, &config);
+        let update = updates
+            .iter()
+            .find(|update| {
+                update.path_attributes.iter().any(|attr| {
+                    matches!(&attr.data, path::Data::MpReachNlri(reach) if !reach.nlri.is_empty())
+                })
+            })
+            .expect("no UPDATE advertises the route");
+        let attrs: Vec<_> = update
+            .path_attributes
+            .iter()
+            .map(|attr| &attr.data)
+            .collect();
+        // LOCAL_PREF is only sent to internal peers
+        assert!(!attrs.contains(&&path::Data::LocalPref(200)));
+        assert!(attrs.contains(&&path::Data::MultiExitDisc(50)));
+        let description = describe_update(1, update);
+        let mut lines = description.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .ends_with("bytes, 1 advertised, 0 withdrawn"));
+        assert!(description.contains("  MultiExitDisc(50)\n"));
+        assert!(description.contains("MpReachNlri: Ipv4 Unicast via"));
+        // The encoding starts with the BGP marker
+        assert!(lines
+            .last()
+            .unwrap()
+            .starts_with(&format!("  {}", "ff".repeat(16))));
+    }
+
+    #[tokio::test]
+    async fn test_updater_shutdown() {
+        let path = std::env::temp_dir().join(format!(
+            "delegation-feed-updater-{}.txt",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "2|apnic|20240101|1|19830613|20231231|+1000\n\
+             apnic|JP|ipv4|43.252.240.0|1024|20140417|allocated\n",
+        )
+        .unwrap();
+        let mut db = Database::new(vec!["apnic:JP".parse().unwrap()], true, false);
+        db.set_source(RirName::Apnic, Source::File(path.clone()));
+        let (send_database, recv_database) = watch::channel(db.clone());
+        let (send_updates, mut recv_updates) = broadcast::channel(16);
+        let (send_shutdown, _) = broadcast::channel(1);
+        let task = tokio::spawn(updater(
+            db,
+            Arc::new(UreqFetcher::default()),
+            send_database,
+            send_updates,
+            std::time::Duration::from_secs(3600),
+            None,
+            send_shutdown.subscribe(),
+        ));
+        // The first update runs immediately
+        let diff = recv_updates.recv().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(diff.summary().total.new_ipv4, 1);
+        // The updated database is published before the diff
+        let (ipv4_prefixes, _) = recv_database.borrow().clone().into_prefixes();
+        assert_eq!(ipv4_prefixes, diff.new_ipv4);
+        // Shutting down does not wait for the next update
+        send_shutdown.send(String::new()).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(1), task)
+            .await
+            .expect("updater did not stop")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_updater_shutdown_before_update() {
+        let db = Database::new(vec!["apnic:JP".parse().unwrap()], true, false);
+        let (send_updates, mut recv_updates) = broadcast::channel(16);
+        let (send_shutdown, _) = broadcast::channel(1);
+        let shutdown = send_shutdown.subscribe();
+        send_shutdown.send(String::new()).unwrap();
+        // The first tick is also ready, but no update is started
+        updater(
+            db.clone(),
+            Arc::new(UreqFetcher::default()),
+            watch::channel(db).0,
+            send_updates,
+            std::time::Duration::from_secs(3600),
+            None,
+            shutdown,
+        )
+        .await;
+        assert!(matches!(
+            recv_updates.recv().await,
+            Err(broadcast::error::RecvError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_accept_any() {
+        let listeners = vec![
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        for listener in &listeners {
+            let addr = listener.local_addr().unwrap();
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (socket, _) = accept_any(&listeners).await.unwrap();
+            assert_eq!(socket.local_addr().unwrap(), addr);
+            assert_eq!(socket.peer_addr().unwrap(), client.local_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_is_peer_allowed() {
+        let allowed: Vec<IpAddr> =
+            vec!["192.0.2.2".parse().unwrap(), "2001:db8::2".parse().unwrap()];
+        assert!(is_peer_allowed(&[], "198.51.100.1".parse().unwrap()));
+        assert!(is_peer_allowed(&allowed, "192.0.2.2".parse().unwrap()));
+        assert!(is_peer_allowed(
+            &allowed,
+            "::ffff:192.0.2.2".parse().unwrap()
+        ));
+        assert!(is_peer_allowed(&allowed, "2001:db8::2".parse().unwrap()));
+        assert!(!is_peer_allowed(&allowed, "192.0.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_collision_resolver() {
+        let peer_id: Ipv4Addr = "192.0.2.2".parse().unwrap();
+        // Our identifier is lower, so the peer's connection is preferred
+        let resolver = CollisionResolver::new("192.0.2.1".parse().unwrap());
+        let mut local = resolver.register(peer_id, 0, true).unwrap();
+        let _remote = resolver.register(peer_id, 1, false).unwrap();
+        assert!(local.try_recv().is_ok());
+        // Another locally initiated connection loses
+        assert!(resolver.register(peer_id, 2, true).is_none());
+        // The token of a closed session does not remove the winner
+        resolver.unregister(peer_id, 0);
+        assert!(resolver.register(peer_id, 3, false).is_none());
+        resolver.unregister(peer_id, 1);
+        assert!(resolver.register(peer_id, 4, true).is_some());
+    }
+
+    #[test]
+    fn test_collision_resolver_established() {
+        let peer_id: Ipv4Addr = "192.0.2.1".parse().unwrap();
+        // Our identifier is higher, so our connection is preferred
+        let resolver = CollisionResolver::new("192.0.2.2".parse().unwrap());
+        let remote = resolver.register(peer_id, 0, false).unwrap();
+        // The existing session is established
+        drop(remote);
+        assert!(resolver.register(peer_id, 1, true).is_none());
+        assert!(resolver.register(peer_id, 2, true).is_none());
+    }
 }
@@ -5,14 +5,16 @@
 
 pub mod rirbase;
 
-use http::{StatusCode, Response};
+use http::StatusCode;
 use lazy_static::lazy_static;
 use pabgp::cidr::{Cidr, Cidr4, Cidr6};
-use rirbase::{CountrySpec, RirName};
+use rirbase::{CountrySpec, RirName, Status};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::hash::Hash;
 use std::io::BufRead;
+use std::path::{Path, PathBuf};
 
 pub const ARIN_URL: &str = "https://ftp.arin.net/pub/stats/arin/delegated-arin-extended-latest";
 pub const RIPE_URL: &str = "https://ftp.ripe.net/ripe/stats/delegated-ripencc-latest";
@@ -20,6 +22,17 @@ pub const APNIC_URL: &str = "https://ftp.apnic.net/apnic/stats/apnic/delegated-a
 pub const LACNIC_URL: &str = "https://ftp.lacnic.net/pub/stats/lacnic/delegated-lacnic-latest";
 pub const AFRINIC_URL: &str = "https://ftp.afrinic.net/pub/stats/afrinic/delegated-afrinic-latest";
 pub const SUPPORTED_VERSIONS: [&str; 2] = ["2", "2.3"];
+/// Default number of retries for failed downloads
+pub const DEFAULT_HTTP_RETRIES: u32 = 3;
+/// Delay before the first retry, doubled for each subsequent retry
+const RETRY_BASE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+/// Number of retries after which the delay stops doubling
+const RETRY_MAX_DOUBLINGS: u32 = 10;
+
+/// Delay before retrying a download that has already been retried `retries` times
+fn retry_delay(retries: u32) -> std::time::Duration {
+    RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(retries.min(RETRY_MAX_DOUBLINGS)))
+}
 
 lazy_static! {
     static ref RIR_INFO: HashMap<RirName, &'static str> = [
@@ -49,6 +62,165 @@ pub enum Error {
     UnexpectedRir(RirName, RirName),
     #[error("Invalid header line: {0}")]
     InvalidHeader(String),
+    #[error("{0} statistics contain {2} {1} records, but the summary lists {3}")]
+    RecordCountMismatch(RirName, String, u64, u64),
+    #[error("Refusing to shrink {0} from {1} to {2} prefixes")]
+    TooManyWithdrawals(CountrySpec, usize, usize),
+    #[error(transparent)]
+    Cache(#[from] bincode::Error),
+}
+
+impl Error {
+    /// Whether the statistics file can no longer be parsed, e.g. because its
+    /// format changed, rather than failing to be fetched
+    pub const fn invalidates_data(&self) -> bool {
+        matches!(
+            self,
+            Self::UnsupportedVersion(..) | Self::UnexpectedRir(..) | Self::InvalidHeader(_)
+        )
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(e: ureq::Error) -> Self {
+        Self::Ureq(Box::new(e))
+    }
+}
+
+/// Fetches RIR statistics files from URLs
+pub trait RirFetcher: Sync {
+    /// Start fetching `url` and return a reader of its content
+    fn fetch(&self, url: &str) -> Result<Box<dyn BufRead>, Error>;
+}
+
+/// Fetcher that downloads files over HTTP(S) with `ureq`
+#[derive(Clone, Copy, Debug)]
+pub struct UreqFetcher {
+    /// Number of retries for failed downloads
+    retries: u32,
+}
+
+impl UreqFetcher {
+    /// Create a fetcher that retries a failed download `retries` times
+    pub const fn new(retries: u32) -> Self {
+        Self { retries }
+    }
+
+    /// Download a file, retrying with exponential backoff on failure
+    fn download(&self, url: &str) -> Result<ureq::Body, Error> {
+        let mut retries = 0;
+        loop {
+            let error = match ureq::get(url).call() {
+                Ok(response) if response.status() == StatusCode::OK => {
+                    return Ok(response.into_body());
+                }
+                Ok(response) => Error::HttpStatus(response.status()),
+                Err(ureq::Error::StatusCode(code)) => StatusCode::from_u16(code)
+                    .map_or_else(|_| ureq::Error::StatusCode(code).into(), Error::HttpStatus),
+                Err(e) => e.into(),
+            };
+            if retries >= self.retries {
+                return Err(error);
+            }
+            let delay = retry_delay(retries);
+            retries += 1;
+            log::warn!(
+                "Failed to download {url} ({error}), retry {retries}/{} in {delay:?}",
+                self.retries
+            );
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+impl Default for UreqFetcher {
+    fn default() -> Self {
+        Self::new(DEFAULT_HTTP_RETRIES)
+    }
+}
+
+impl RirFetcher for UreqFetcher {
+    fn fetch(&self, url: &str) -> Result<Box<dyn BufRead>, Error> {
+        let reader = self.download(url)?.into_reader();
+        Ok(Box::new(std::io::BufReader::new(reader)))
+    }
+}
+
+/// Where to fetch a RIR statistics file from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// Download the file over HTTP(S)
+    Url(String),
+    /// Read the file from the local filesystem
+    File(PathBuf),
+}
+
+impl std::str::FromStr for Source {
+    type Err = std::convert::Infallible;
+
+    /// Parse anything with a URL scheme as a URL and everything else as a path
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(Self::Url(s.to_string()))
+        } else {
+            Ok(Self::File(s.into()))
+        }
+    }
+}
+
+/// Numbers of prefixes added and removed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrefixCounts {
+    pub new_ipv4: usize,
+    pub new_ipv6: usize,
+    pub withdrawn_ipv4: usize,
+    pub withdrawn_ipv6: usize,
+}
+
+impl std::fmt::Display for PrefixCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} new IPv4, {} new IPv6, {} withdrawn IPv4, {} withdrawn IPv6",
+            self.new_ipv4, self.new_ipv6, self.withdrawn_ipv4, self.withdrawn_ipv6
+        )
+    }
+}
+
+/// Summary of a database diff
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiffSummary {
+    /// Counts over all countries
+    pub total: PrefixCounts,
+    /// Counts of each country in the diff
+    pub countries: HashMap<CountrySpec, PrefixCounts>,
+}
+
+impl DiffSummary {
+    /// Add the prefix counts of each country to the field selected by `field`
+    fn add<T>(
+        &mut self,
+        prefixes: &HashMap<CountrySpec, Vec<T>>,
+        field: fn(&mut PrefixCounts) -> &mut usize,
+    ) {
+        for (country, prefixes) in prefixes {
+            *field(self.countries.entry(*country).or_default()) += prefixes.len();
+            *field(&mut self.total) += prefixes.len();
+        }
+    }
+}
+
+impl std::fmt::Display for DiffSummary {
+    /// Write the total counts, followed by one line per country
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.total)?;
+        let mut countries: Vec<_> = self.countries.iter().collect();
+        countries.sort_by_cached_key(|(country, _)| country.to_string());
+        for (country, counts) in countries {
+            write!(f, "\n  {country}: {counts}")?;
+        }
+        Ok(())
+    }
 }
 
 /// Database diff
@@ -62,10 +234,24 @@ pub struct DatabaseDiff {
     pub new_ipv6: HashMap<CountrySpec, Vec<Cidr6>>,
     /// IPv6 prefixes that were removed
     pub withdrawn_ipv6: HashMap<CountrySpec, Vec<Cidr6>>,
+    /// Countries that lost more than the allowed fraction of their prefixes,
+    /// with the old and new prefix counts
+    pub shrunk_countries: Vec<(CountrySpec, usize, usize)>,
 }
 
 impl DatabaseDiff {
+    /// Count the added and removed prefixes, in total and per country
+    pub fn summary(&self) -> DiffSummary {
+        let mut summary = DiffSummary::default();
+        summary.add(&self.new_ipv4, |counts| &mut counts.new_ipv4);
+        summary.add(&self.new_ipv6, |counts| &mut counts.new_ipv6);
+        summary.add(&self.withdrawn_ipv4, |counts| &mut counts.withdrawn_ipv4);
+        summary.add(&self.withdrawn_ipv6, |counts| &mut counts.withdrawn_ipv6);
+        summary
+    }
+
     /// Apply the diff to a database
+    #[cfg(test)]
     pub fn apply_to(self, db: &mut Database) {
         for (country, prefixes) in self.new_ipv4 {
             db.ipv4_prefixes
@@ -74,6 +260,7 @@ impl DatabaseDiff {
                 .extend(prefixes);
         }
         for (country, prefixes) in self.withdrawn_ipv4 {
+            let prefixes: HashSet<Cidr4> = prefixes.into_iter().collect();
             let db_prefixes = db.ipv4_prefixes.entry(country).or_default();
             db_prefixes.retain(|prefix| !prefixes.contains(prefix));
         }
@@ -84,33 +271,43 @@ impl DatabaseDiff {
                 .extend(prefixes);
         }
         for (country, prefixes) in self.withdrawn_ipv6 {
+            let prefixes: HashSet<Cidr6> = prefixes.into_iter().collect();
             let db_prefixes = db.ipv6_prefixes.entry(country).or_default();
             db_prefixes.retain(|prefix| !prefixes.contains(prefix));
         }
     }
 
     /// Compute the diff between two databases
-    pub fn compute_diff(old: &Database, new: &Database, updated_rirs: &HashSet<RirName>) -> Self {
+    ///
+    /// If `max_withdraw_fraction` is given, countries whose prefix count
+    /// dropped by more than this fraction are listed in `shrunk_countries`.
+    pub fn compute_diff(
+        old: &Database,
+        new: &Database,
+        updated_rirs: &HashSet<RirName>,
+        max_withdraw_fraction: Option<f64>,
+    ) -> Self {
         let mut diff = Self::default();
+        if let Some(fraction) = max_withdraw_fraction {
+            for country in &new.country_specs {
+                if !updated_rirs.contains(&country.rir()) {
+                    continue;
+                }
+                let old_count = old.prefix_count(country);
+                let new_count = new.prefix_count(country);
+                #[allow(clippy::cast_precision_loss)]
+                if (new_count as f64) < old_count as f64 * (1.0 - fraction) {
+                    diff.shrunk_countries.push((*country, old_count, new_count));
+                }
+            }
+        }
         for (country, prefixes) in &new.ipv4_prefixes {
             if !updated_rirs.contains(&country.rir()) {
                 // This country was not updated
                 continue;
             }
-            let old_prefixes = old.ipv4_prefixes.get(country);
-            let new_prefixes: Vec<Cidr4> = prefixes
-                .iter()
-                // Keep those that are not in the old prefixes
-                .filter(|prefix| old_prefixes.map_or(true, |p| !p.contains(prefix)))
-                .copied()
-                .collect();
-            let withdrawn_prefixes: Vec<Cidr4> = old_prefixes.map_or(vec![], |p| {
-                p.iter()
-                    // Keep those that are not in the new prefixes
-                    .filter(|prefix| !prefixes.contains(prefix))
-                    .copied()
-                    .collect()
-            });
+            let (new_prefixes, withdrawn_prefixes) =
+                diff_prefixes(old.ipv4_prefixes.get(country), prefixes);
             if !new_prefixes.is_empty() {
                 diff.new_ipv4.insert(*country, new_prefixes);
             }
@@ -123,20 +320,8 @@ impl DatabaseDiff {
                 // This country was not updated
                 continue;
             }
-            let old_prefixes = old.ipv6_prefixes.get(country);
-            let new_prefixes: Vec<Cidr6> = prefixes
-                .iter()
-                // Keep those that are not in the old prefixes
-                .filter(|prefix| old_prefixes.map_or(true, |p| !p.contains(prefix)))
-                .copied()
-                .collect();
-            let withdrawn_prefixes: Vec<Cidr6> = old_prefixes.map_or(vec![], |p| {
-                p.iter()
-                    // Keep those that are not in the new prefixes
-                    .filter(|prefix| !prefixes.contains(prefix))
-                    .copied()
-                    .collect()
-            });
+            let (new_prefixes, withdrawn_prefixes) =
+                diff_prefixes(old.ipv6_prefixes.get(country), prefixes);
             if !new_prefixes.is_empty() {
                 diff.new_ipv6.insert(*country, new_prefixes);
             }
@@ -146,6 +331,68 @@ impl DatabaseDiff {
         }
         diff
     }
+
+    /// Compute the diff from the prefixes currently advertised to those in `db`
+    ///
+    /// Unlike `compute_diff`, all countries are compared, including those
+    /// no longer present in `db`.
+    pub fn from_advertised(
+        ipv4_prefixes: &HashMap<CountrySpec, Vec<Cidr4>>,
+        ipv6_prefixes: &HashMap<CountrySpec, Vec<Cidr6>>,
+        db: &Database,
+    ) -> Self {
+        let mut diff = Self::default();
+        let countries: HashSet<&CountrySpec> = ipv4_prefixes
+            .keys()
+            .chain(db.ipv4_prefixes.keys())
+            .collect();
+        for country in countries {
+            let new = db.ipv4_prefixes.get(country).map_or(&[][..], Vec::as_slice);
+            let (new_prefixes, withdrawn_prefixes) = diff_prefixes(ipv4_prefixes.get(country), new);
+            if !new_prefixes.is_empty() {
+                diff.new_ipv4.insert(*country, new_prefixes);
+            }
+            if !withdrawn_prefixes.is_empty() {
+                diff.withdrawn_ipv4.insert(*country, withdrawn_prefixes);
+            }
+        }
+        let countries: HashSet<&CountrySpec> = ipv6_prefixes
+            .keys()
+            .chain(db.ipv6_prefixes.keys())
+            .collect();
+        for country in countries {
+            let new = db.ipv6_prefixes.get(country).map_or(&[][..], Vec::as_slice);
+            let (new_prefixes, withdrawn_prefixes) = diff_prefixes(ipv6_prefixes.get(country), new);
+            if !new_prefixes.is_empty() {
+                diff.new_ipv6.insert(*country, new_prefixes);
+            }
+            if !withdrawn_prefixes.is_empty() {
+                diff.withdrawn_ipv6.insert(*country, withdrawn_prefixes);
+            }
+        }
+        diff
+    }
+}
+
+/// Compare the old and new prefixes of a country
+///
+/// # Returns
+/// The prefixes only in `new` and the prefixes only in `old`.
+fn diff_prefixes<T: Copy + Eq + Hash>(old: Option<&Vec<T>>, new: &[T]) -> (Vec<T>, Vec<T>) {
+    let old = old.map_or(&[][..], Vec::as_slice);
+    let old_set: HashSet<&T> = old.iter().collect();
+    let new_set: HashSet<&T> = new.iter().collect();
+    let added = new
+        .iter()
+        .filter(|prefix| !old_set.contains(prefix))
+        .copied()
+        .collect();
+    let withdrawn = old
+        .iter()
+        .filter(|prefix| !new_set.contains(prefix))
+        .copied()
+        .collect();
+    (added, withdrawn)
 }
 
 /// Main database of RIR statistics (country to IP prefix)
@@ -163,6 +410,21 @@ pub struct Database {
     enable_ipv6: bool,
     /// Mapping from countries to delegated IPv6 prefixes
     ipv6_prefixes: HashMap<CountrySpec, Vec<Cidr6>>,
+    /// Mapping from countries to delegated ASN ranges (start ASN, count)
+    asn_delegations: HashMap<CountrySpec, Vec<(u32, u32)>>,
+    /// Statuses of records to import
+    statuses: Vec<Status>,
+    /// Overridden sources of RIR statistics files, not saved with the database
+    #[serde(skip)]
+    sources: HashMap<RirName, Source>,
+    /// Largest fraction of a country's prefixes an update may withdraw, not
+    /// saved with the database
+    #[serde(skip)]
+    max_withdraw_fraction: Option<f64>,
+    /// Whether to withdraw the prefixes of a RIR whose statistics can no
+    /// longer be parsed, not saved with the database
+    #[serde(skip)]
+    withdraw_on_failure: bool,
 }
 
 impl Database {
@@ -175,41 +437,226 @@ impl Database {
             ipv4_prefixes: HashMap::new(),
             enable_ipv6,
             ipv6_prefixes: HashMap::new(),
+            asn_delegations: HashMap::new(),
+            statuses: Status::DELEGATED.to_vec(),
+            sources: HashMap::new(),
+            max_withdraw_fraction: None,
+            withdraw_on_failure: false,
         }
     }
 
+    /// Refuse updates that withdraw more than `fraction` of a country's
+    /// prefixes, which usually means that the RIR file was broken
+    pub fn set_max_withdraw_fraction(&mut self, fraction: f64) {
+        self.max_withdraw_fraction = Some(fraction);
+    }
+
+    /// Withdraw all prefixes of a RIR instead of keeping the old data if its
+    /// statistics file can no longer be parsed, see [`Error::invalidates_data`]
+    pub fn set_withdraw_on_failure(&mut self, withdraw: bool) {
+        self.withdraw_on_failure = withdraw;
+    }
+
+    /// Only import records with one of these statuses, `allocated` and
+    /// `assigned` by default
+    pub fn set_statuses(&mut self, statuses: Vec<Status>) {
+        self.statuses = statuses;
+    }
+
+    /// Fetch the statistics file of `rir` from `source` instead of the default URL
+    pub fn set_source(&mut self, rir: RirName, source: Source) {
+        self.sources.insert(rir, source);
+    }
+
+    /// Get where to fetch the statistics file of `rir` from
+    fn source(&self, rir: RirName) -> Source {
+        self.sources
+            .get(&rir)
+            .cloned()
+            .unwrap_or_else(|| Source::Url(RIR_INFO[&rir].to_string()))
+    }
+
+    /// Load a database previously saved with `save_to`
+    pub fn load_from(path: &Path) -> Result<Self, Error> {
+        let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        Ok(bincode::deserialize_from(reader)?)
+    }
+
+    /// Save the database to a file, replacing it atomically
+    pub fn save_to(&self, path: &Path) -> Result<(), Error> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let file = std::fs::File::create(&tmp_path)?;
+        let mut writer = std::io::BufWriter::new(file);
+        bincode::serialize_into(&mut writer, self)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Check if another database was created with the same countries,
+    /// address families, and statuses
+    pub fn has_same_config(&self, other: &Self) -> bool {
+        self.country_specs == other.country_specs
+            && self.enable_ipv4 == other.enable_ipv4
+            && self.enable_ipv6 == other.enable_ipv6
+            && self.statuses == other.statuses
+    }
+
     /// Update the database with a new country's statistics.
-    pub fn update_all(&mut self) -> Result<HashSet<RirName>, Error> {
+    ///
+    /// RIRs are fetched in parallel. If some of them fail, the others are
+    /// still updated and the failures are only logged; an error is returned
+    /// only if all of them fail.
+    pub fn update_all(&mut self, fetcher: &impl RirFetcher) -> Result<HashSet<RirName>, Error> {
+        Self::updated_rirs(self.update_each(fetcher))
+    }
+
+    /// Fetch all needed RIRs in parallel and merge the successful ones
+    ///
+    /// # Returns
+    /// For each RIR, whether it was updated or the error it failed with.
+    fn update_each(&mut self, fetcher: &impl RirFetcher) -> Vec<(RirName, Result<bool, Error>)> {
         let needed_rirs = self.needed_rirs();
-        let mut updated = HashSet::new();
         log::info!("Updating from RIRs: {:?}", needed_rirs);
-        for rir in needed_rirs {
-            let url = RIR_INFO[&rir];
-            let response = ureq::get(url).call().map_err(Box::new)?;
-            match response.status() {
-                StatusCode::OK => {
-                    if self.update_from_response(response, rir)? {
-                        log::info!("Updated database with {rir}");
-                        updated.insert(rir);
-                    }
+        let this = &*self;
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = needed_rirs
+                .iter()
+                .map(|&rir| (rir, scope.spawn(move || this.fetch_rir(rir, fetcher))))
+                .collect();
+            handles
+                .into_iter()
+                .map(|(rir, handle)| (rir, handle.join().expect("RIR fetch thread panicked")))
+                .collect()
+        });
+        results
+            .into_iter()
+            .map(|(rir, result)| match result {
+                Ok(Some(partial)) => {
+                    log::info!("Updated database with {rir}");
+                    self.merge(rir, partial);
+                    (rir, Ok(true))
+                }
+                Ok(None) => (rir, Ok(false)),
+                Err(e) => {
+                    log::error!("Failed to update from {rir}: {e}");
+                    (rir, Err(e))
+                }
+            })
+            .collect()
+    }
+
+    /// Collect the updated RIRs from the results of `update_each`, failing
+    /// only if all RIRs failed
+    fn updated_rirs(
+        results: Vec<(RirName, Result<bool, Error>)>,
+    ) -> Result<HashSet<RirName>, Error> {
+        let mut updated = HashSet::new();
+        let mut first_error = None;
+        let n_results = results.len();
+        let mut n_errors = 0;
+        for (rir, result) in results {
+            match result {
+                Ok(true) => {
+                    updated.insert(rir);
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    n_errors += 1;
+                    first_error.get_or_insert(e);
                 }
-                status => return Err(Error::HttpStatus(status)),
             }
         }
-        Ok(updated)
+        match first_error {
+            Some(e) if n_errors == n_results => Err(e),
+            _ => Ok(updated),
+        }
+    }
+
+    /// Fetch and parse the statistics file of a single RIR
+    ///
+    /// # Returns
+    /// - Ok(Some(_)) with a database containing only this RIR's prefixes.
+    /// - Ok(None) if the database was already up-to-date.
+    /// - Err(_) if the file could not be fetched or was invalid.
+    fn fetch_rir(&self, rir: RirName, fetcher: &impl RirFetcher) -> Result<Option<Self>, Error> {
+        let mut partial = Self::new(
+            self.country_specs.clone(),
+            self.enable_ipv4,
+            self.enable_ipv6,
+        );
+        partial.statuses.clone_from(&self.statuses);
+        if let Some(&serial) = self.serial_numbers.get(&rir) {
+            partial.serial_numbers.insert(rir, serial);
+        }
+        let is_updated = match self.source(rir) {
+            Source::Url(url) => partial.update_from_reader(fetcher.fetch(&url)?, rir)?,
+            Source::File(path) => {
+                let file = std::fs::File::open(path)?;
+                partial.update_from_reader(std::io::BufReader::new(file), rir)?
+            }
+        };
+        Ok(is_updated.then_some(partial))
+    }
+
+    /// Merge the serial numbers and prefixes of a database fetched from `rir`
+    /// into this one, replacing what was known about the countries of `rir`
+    fn merge(&mut self, rir: RirName, other: Self) {
+        self.serial_numbers.extend(other.serial_numbers);
+        self.ipv4_prefixes.retain(|country, _| country.rir() != rir);
+        self.ipv4_prefixes.extend(other.ipv4_prefixes);
+        self.ipv6_prefixes.retain(|country, _| country.rir() != rir);
+        self.ipv6_prefixes.extend(other.ipv6_prefixes);
+        self.asn_delegations
+            .retain(|country, _| country.rir() != rir);
+        self.asn_delegations.extend(other.asn_delegations);
     }
 
     /// Update the database with a new country's statistics.
-    pub fn update_with_diff(&mut self) -> Result<DatabaseDiff, Error> {
+    pub fn update_with_diff(&mut self, fetcher: &impl RirFetcher) -> Result<DatabaseDiff, Error> {
         let mut new_db = Self::new(
             self.country_specs.clone(),
             self.enable_ipv4,
             self.enable_ipv6,
         );
-        // Copy the serial numbers from the old database
+        // Copy the serial numbers, statuses, and sources from the old database
         new_db.serial_numbers.clone_from(&self.serial_numbers);
-        let updated_rirs = new_db.update_all()?;
-        let diff = DatabaseDiff::compute_diff(self, &new_db, &updated_rirs);
+        new_db.statuses.clone_from(&self.statuses);
+        new_db.sources.clone_from(&self.sources);
+        new_db.max_withdraw_fraction = self.max_withdraw_fraction;
+        new_db.withdraw_on_failure = self.withdraw_on_failure;
+        let mut results = new_db.update_each(fetcher);
+        // RIRs whose data is withdrawn instead of kept
+        let invalidated: HashSet<RirName> = if self.withdraw_on_failure {
+            results
+                .iter()
+                .filter(|(_, result)| result.as_ref().is_err_and(Error::invalidates_data))
+                .map(|(rir, _)| *rir)
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        results.retain(|(rir, _)| !invalidated.contains(rir));
+        let updated_rirs = match Self::updated_rirs(results) {
+            Ok(updated_rirs) => updated_rirs,
+            Err(e) if invalidated.is_empty() => return Err(e),
+            Err(_) => HashSet::new(),
+        };
+        let mut diff =
+            DatabaseDiff::compute_diff(self, &new_db, &updated_rirs, self.max_withdraw_fraction);
+        if let Some(&(country, old_count, new_count)) = diff.shrunk_countries.first() {
+            for (country, old_count, new_count) in &diff.shrunk_countries {
+                log::error!(
+                    "{country} would shrink from {old_count} to {new_count} prefixes, \
+                     keeping the old data"
+                );
+            }
+            return Err(Error::TooManyWithdrawals(country, old_count, new_count));
+        }
         let old_db = std::mem::replace(self, new_db);
         // Insert unaffected countries back into the new database
         for (country, prefixes) in old_db.ipv4_prefixes {
@@ -222,22 +669,64 @@ impl Database {
                 self.ipv6_prefixes.insert(country, prefixes);
             }
         }
+        for (country, asns) in old_db.asn_delegations {
+            if !updated_rirs.contains(&country.rir()) {
+                self.asn_delegations.insert(country, asns);
+            }
+        }
+        for rir in invalidated {
+            log::warn!("Withdrawing all prefixes of {rir}");
+            let withdrawal = self.withdraw_all_for_rir(rir);
+            diff.withdrawn_ipv4.extend(withdrawal.withdrawn_ipv4);
+            diff.withdrawn_ipv6.extend(withdrawal.withdrawn_ipv6);
+        }
+        for country in &self.country_specs {
+            log::debug!("{country} has {} ASN ranges", self.asns_for(country).len());
+        }
         Ok(diff)
     }
 
-    /// Parse the response from a ureq request
+    /// Remove all prefixes of the countries of `rir` from the database
+    ///
+    /// The serial number of `rir` is forgotten as well, so that the next
+    /// successful fetch is imported even if the file has not changed.
+    ///
+    /// # Returns
+    /// A diff withdrawing the removed prefixes.
+    pub fn withdraw_all_for_rir(&mut self, rir: RirName) -> DatabaseDiff {
+        let mut diff = DatabaseDiff::default();
+        self.serial_numbers.remove(&rir);
+        for country in &self.country_specs {
+            if country.rir() != rir {
+                continue;
+            }
+            if let Some(prefixes) = self.ipv4_prefixes.remove(country) {
+                if !prefixes.is_empty() {
+                    diff.withdrawn_ipv4.insert(*country, prefixes);
+                }
+            }
+            if let Some(prefixes) = self.ipv6_prefixes.remove(country) {
+                if !prefixes.is_empty() {
+                    diff.withdrawn_ipv6.insert(*country, prefixes);
+                }
+            }
+            self.asn_delegations.remove(country);
+        }
+        diff
+    }
+
+    /// Parse a RIR statistics file
     ///
     /// # Returns
     /// - Ok(true) if the database was updated.
     /// - Ok(false) if the database was already up-to-date.
-    /// - Err(_) if the response was invalid.
-    fn update_from_response(
+    /// - Err(_) if the file was invalid or its records do not match the
+    ///   summary lines, which usually means that the download was truncated.
+    fn update_from_reader(
         &mut self,
-        response: Response<ureq::Body>,
+        reader: impl BufRead,
         expected_rir: RirName,
     ) -> Result<bool, Error> {
-        let body = response.into_body();
-        let reader = std::io::BufReader::new(body.into_reader());
         let mut lines = reader.lines().enumerate();
         // Find the header line
         for (_, line) in &mut lines {
@@ -254,15 +743,66 @@ impl Database {
                 break;
             }
         }
+        // Record counts by type as listed in the summary lines and as seen
+        let mut expected_counts: HashMap<String, u64> = HashMap::new();
+        let mut actual_counts: HashMap<String, u64> = HashMap::new();
         for (n, line) in lines {
-            self.update_from_line(&line?);
+            let line = line?;
+            if let Some((type_, count)) = Self::parse_summary_line(&line) {
+                *expected_counts.entry(type_.to_string()).or_default() += count;
+            } else if let Some(type_) = Self::record_type(&line) {
+                if let Some(count) = actual_counts.get_mut(type_) {
+                    *count += 1;
+                } else {
+                    actual_counts.insert(type_.to_string(), 1);
+                }
+            }
+            self.update_from_line(&line);
             if n % 10000 == 0 {
                 log::info!("Processed {n} lines from {expected_rir}");
             }
         }
+        for (type_, expected) in expected_counts {
+            let actual = actual_counts.get(&type_).copied().unwrap_or(0);
+            if actual != expected {
+                return Err(Error::RecordCountMismatch(
+                    expected_rir,
+                    type_,
+                    actual,
+                    expected,
+                ));
+            }
+        }
         Ok(true)
     }
 
+    /// Parse a summary line of a RIR statistics file
+    ///
+    /// If the line is a summary line, return the record type and the number
+    /// of records of that type.
+    fn parse_summary_line(line: &str) -> Option<(&str, u64)> {
+        let parts = line.split('|').collect::<Vec<_>>();
+        if parts.len() != 6 || parts[1] != "*" || parts[3] != "*" || parts[5] != "summary" {
+            return None;
+        }
+        Some((parts[2], parts[4].parse().ok()?))
+    }
+
+    /// Get the type of a record line, including records that we skip
+    ///
+    /// Returns None for comments, blank lines, summary lines, and lines with
+    /// missing fields.
+    fn record_type(line: &str) -> Option<&str> {
+        if line.starts_with('#') {
+            return None;
+        }
+        let parts = line.splitn(8, '|').collect::<Vec<_>>();
+        if parts.len() < 7 {
+            return None;
+        }
+        Some(parts[2])
+    }
+
     /// Parse and check the header of a RIR statistics file
     ///
     /// # Returns
@@ -299,13 +839,13 @@ impl Database {
     /// Parse a single line from a RIR statistics file
     ///
     /// If the line does not represent an ipv4/ipv6 record, return None.
-    /// Otherwise, return the country and CIDR block.
-    fn parse_line(line: &str) -> Option<(CountrySpec, Cidr)> {
+    /// Otherwise, return the country, CIDR block, and status.
+    fn parse_line(line: &str) -> Option<(CountrySpec, Cidr, Status)> {
         if line.starts_with('#') {
             return None;
         }
-        let parts = line.splitn(6, '|').collect::<Vec<_>>();
-        if parts.len() < 6 {
+        let parts = line.splitn(8, '|').collect::<Vec<_>>();
+        if parts.len() < 7 {
             return None;
         }
         let rir = parts[0].parse().ok()?;
@@ -313,6 +853,7 @@ impl Database {
         let af = parts[2];
         // Err: Probably a unallocated block or a summary line
         let country = CountrySpec::new(rir, country_code).ok()?;
+        let status = parts[6].parse().ok()?;
         match af {
             "ipv4" => {
                 let addr = parts[3].parse().ok()?;
@@ -321,18 +862,40 @@ impl Database {
                 if num_hosts >> num_hosts.trailing_zeros() != 1 {
                     log::error!("Number of hosts of {num_hosts} does not conform to CIDR");
                 }
-                Some((country, Cidr::V4(cidr)))
+                Some((country, Cidr::V4(cidr), status))
             }
             "ipv6" => {
                 let addr = parts[3].parse().ok()?;
                 let prefix_len = parts[4].parse().ok()?;
                 let cidr = Cidr6::new(addr, prefix_len);
-                Some((country, Cidr::V6(cidr)))
+                Some((country, Cidr::V6(cidr), status))
             }
             _ => None,
         }
     }
 
+    /// Parse a single ASN record from a RIR statistics file
+    ///
+    /// If the line does not represent an asn record, return None.
+    /// Otherwise, return the country, the first ASN, the number of ASNs,
+    /// and the status.
+    fn parse_asn_line(line: &str) -> Option<(CountrySpec, u32, u32, Status)> {
+        if line.starts_with('#') {
+            return None;
+        }
+        let parts = line.splitn(8, '|').collect::<Vec<_>>();
+        if parts.len() < 7 || parts[2] != "asn" {
+            return None;
+        }
+        let rir = parts[0].parse().ok()?;
+        // Err: Probably a unallocated block or a summary line
+        let country = CountrySpec::new(rir, parts[1]).ok()?;
+        let start = parts[3].parse().ok()?;
+        let count = parts[4].parse().ok()?;
+        let status = parts[6].parse().ok()?;
+        Some((country, start, count, status))
+    }
+
     /// Find out what RIR data we need to download
     fn needed_rirs(&self) -> HashSet<RirName> {
         self.country_specs.iter().map(CountrySpec::rir).collect()
@@ -340,9 +903,18 @@ impl Database {
 
     /// Update from a single line of a RIR statistics file
     fn update_from_line(&mut self, line: &str) {
-        if let Some((country, cidr)) = Self::parse_line(line) {
-            if !self.country_specs.contains(&country) {
-                // We don't care about this country
+        if let Some((country, start, count, status)) = Self::parse_asn_line(line) {
+            if self.country_specs.contains(&country) && self.statuses.contains(&status) {
+                self.asn_delegations
+                    .entry(country)
+                    .or_default()
+                    .push((start, count));
+            }
+            return;
+        }
+        if let Some((country, cidr, status)) = Self::parse_line(line) {
+            if !self.country_specs.contains(&country) || !self.statuses.contains(&status) {
+                // We don't care about this country or record
                 return;
             }
             match cidr {
@@ -360,6 +932,17 @@ impl Database {
         }
     }
 
+    /// Get the number of IPv4 and IPv6 prefixes delegated to a country
+    fn prefix_count(&self, country: &CountrySpec) -> usize {
+        self.ipv4_prefixes.get(country).map_or(0, Vec::len)
+            + self.ipv6_prefixes.get(country).map_or(0, Vec::len)
+    }
+
+    /// Get the ASN ranges (start ASN, count) delegated to a country
+    pub fn asns_for(&self, country: &CountrySpec) -> &[(u32, u32)] {
+        self.asn_delegations.get(country).map_or(&[], Vec::as_slice)
+    }
+
     /// Consumes the database and returns the country to CIDR maps
     pub fn into_prefixes(
         self,
@@ -377,13 +960,23 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(0), RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(3), RETRY_BASE_DELAY * 8);
+        // Large retry counts must not overflow
+        assert_eq!(retry_delay(32), RETRY_BASE_DELAY * 1024);
+        assert_eq!(retry_delay(u32::MAX), RETRY_BASE_DELAY * 1024);
+    }
+
     #[test]
     fn test_parse_line_v4() {
         let line = "apnic|CN|ipv4|103.37.72.0|1024|20140821|allocated";
-        let (country, cidr) = Database::parse_line(line).unwrap();
+        let (country, cidr, status) = Database::parse_line(line).unwrap();
         assert_eq!(country, "apnic:CN".parse().unwrap());
         let expected_addr: IpAddr = "103.37.72.0".parse().unwrap();
         assert_eq!(cidr.into_parts(), (expected_addr, 22));
+        assert_eq!(status, Status::Allocated);
     }
 
     #[test]
@@ -391,10 +984,11 @@ mod tests {
         // Note that the extended format should also be supported.
         let line =
             "arin|US|ipv6|2605:4340::|32|20190509|allocated|85009a96f1ed4d3b37a1c73955633b73";
-        let (country, cidr) = Database::parse_line(line).unwrap();
+        let (country, cidr, status) = Database::parse_line(line).unwrap();
         assert_eq!(country, "arin:US".parse().unwrap());
         let expected_addr: IpAddr = "2605:4340::".parse().unwrap();
         assert_eq!(cidr.into_parts(), (expected_addr, 32));
+        assert_eq!(status, Status::Allocated);
     }
 
     #[test]
@@ -406,6 +1000,585 @@ mod tests {
         // Test a line that we don't care about.
         let line = "lacnic|*|ipv4|*|19862|summary";
         assert!(Database::parse_line(line).is_none());
+
+        // Test a record without a status.
+        let line = "apnic|CN|ipv4|103.37.72.0|1024|20140821";
+        assert!(Database::parse_line(line).is_none());
+    }
+
+    #[test]
+    fn test_skip_undelegated_status() {
+        let country = "arin:US".parse().unwrap();
+        let mut db = Database::new(vec![country], true, true);
+        // Reserved and available blocks with a country are parsed but not imported
+        let reserved = "arin|US|ipv4|192.0.2.0|256|20240101|reserved|";
+        let (_, _, status) = Database::parse_line(reserved).unwrap();
+        assert_eq!(status, Status::Reserved);
+        db.update_from_line(reserved);
+        db.update_from_line("arin|US|ipv6|2001:db8::|32||available|");
+        db.update_from_line("arin|US|asn|64496|16|20240101|reserved|");
+        assert!(db.ipv4_prefixes.is_empty());
+        assert!(db.ipv6_prefixes.is_empty());
+        assert!(db.asns_for(&country).is_empty());
+        db.update_from_line("arin|US|ipv4|198.51.100.0|256|20240101|assigned|");
+        assert_eq!(
+            db.ipv4_prefixes[&country],
+            vec![Cidr4::new("198.51.100.0".parse().unwrap(), 24)]
+        );
+
+        // Unless explicitly included
+        let mut db = Database::new(vec![country], true, true);
+        db.set_statuses(vec![Status::Reserved]);
+        db.update_from_line(reserved);
+        db.update_from_line("arin|US|ipv4|198.51.100.0|256|20240101|assigned|");
+        assert_eq!(
+            db.ipv4_prefixes[&country],
+            vec![Cidr4::new("192.0.2.0".parse().unwrap(), 24)]
+        );
+        assert!(!db.has_same_config(&Database::new(vec![country], true, true)));
+    }
+
+    #[test]
+    fn test_parse_asn_line() {
+        let line = "apnic|CN|asn|4608|1|20000101|allocated";
+        let (country, start, count, status) = Database::parse_asn_line(line).unwrap();
+        assert_eq!(country, "apnic:CN".parse().unwrap());
+        assert_eq!((start, count), (4608, 1));
+        assert_eq!(status, Status::Allocated);
+        // Address records are not ASN records and vice versa
+        assert!(
+            Database::parse_asn_line("apnic|CN|ipv4|103.37.72.0|1024|20140821|allocated").is_none()
+        );
+        assert!(Database::parse_line(line).is_none());
+        assert!(Database::parse_asn_line("apnic|*|asn|*|12345|summary").is_none());
+    }
+
+    #[test]
+    fn test_asns_for() {
+        let cn = "apnic:CN".parse().unwrap();
+        let jp = "apnic:JP".parse().unwrap();
+        let mut db = Database::new(vec![cn], true, true);
+        db.update_from_line("apnic|CN|asn|4608|1|20000101|allocated");
+        db.update_from_line("apnic|CN|asn|9808|2|20000101|allocated");
+        db.update_from_line("apnic|JP|asn|2497|1|19920101|allocated");
+        assert_eq!(db.asns_for(&cn), &[(4608, 1), (9808, 2)]);
+        // Filtered by the country specs
+        assert!(db.asns_for(&jp).is_empty());
+    }
+
+    #[test]
+    fn test_compute_and_apply_diff() {
+        let us: CountrySpec = "arin:US".parse().unwrap();
+        let ca: CountrySpec = "arin:CA".parse().unwrap();
+        let mut old = Database::new(vec![us, ca], true, true);
+        old.update_from_line("arin|US|ipv4|192.0.2.0|256|20000101|allocated");
+        old.update_from_line("arin|US|ipv4|198.51.100.0|256|20000101|allocated");
+        old.update_from_line("arin|CA|ipv6|2001:db8::|32|20000101|allocated");
+        let mut new = Database::new(vec![us, ca], true, true);
+        new.update_from_line("arin|US|ipv4|198.51.100.0|256|20000101|allocated");
+        new.update_from_line("arin|US|ipv4|203.0.113.0|256|20000101|allocated");
+        let diff = DatabaseDiff::compute_diff(&old, &new, &HashSet::from([RirName::Arin]), None);
+        assert_eq!(diff.new_ipv4[&us], vec!["203.0.113.0/24".parse().unwrap()]);
+        assert_eq!(
+            diff.withdrawn_ipv4[&us],
+            vec!["192.0.2.0/24".parse().unwrap()]
+        );
+        // Countries absent from the new database are not withdrawn
+        assert!(diff.withdrawn_ipv6.is_empty());
+        assert!(diff.shrunk_countries.is_empty());
+        diff.apply_to(&mut old);
+        assert_eq!(old.ipv4_prefixes[&us], new.ipv4_prefixes[&us]);
+    }
+
+    #[test]
+    fn test_diff_from_advertised() {
+        let us: CountrySpec = "arin:US".parse().unwrap();
+        let ca: CountrySpec = "arin:CA".parse().unwrap();
+        let mut old = Database::new(vec![us, ca], true, true);
+        old.update_from_line("arin|US|ipv4|192.0.2.0|256|20000101|allocated");
+        old.update_from_line("arin|CA|ipv6|2001:db8::|32|20000101|allocated");
+        let mut new = Database::new(vec![us, ca], true, true);
+        new.update_from_line("arin|US|ipv4|198.51.100.0|256|20000101|allocated");
+        let diff = DatabaseDiff::from_advertised(&old.ipv4_prefixes, &old.ipv6_prefixes, &new);
+        assert_eq!(diff.new_ipv4[&us], vec!["198.51.100.0/24".parse().unwrap()]);
+        assert_eq!(
+            diff.withdrawn_ipv4[&us],
+            vec!["192.0.2.0/24".parse().unwrap()]
+        );
+        // Countries absent from the new database are withdrawn as well
+        assert_eq!(
+            diff.withdrawn_ipv6[&ca],
+            vec!["2001:db8::/32".parse().unwrap()]
+        );
+        assert!(diff.new_ipv6.is_empty());
+    }
+
+    #[test]
+    fn test_withdraw_all_for_rir() {
+        let us: CountrySpec = "arin:US".parse().unwrap();
+        let ca: CountrySpec = "arin:CA".parse().unwrap();
+        let jp: CountrySpec = "apnic:JP".parse().unwrap();
+        let mut db = Database::new(vec![us, ca, jp], true, true);
+        db.update_from_line("arin|US|ipv4|192.0.2.0|256|20000101|allocated");
+        db.update_from_line("arin|US|ipv4|198.51.100.0|256|20000101|allocated");
+        db.update_from_line("arin|CA|ipv6|2001:db8::|32|20000101|allocated");
+        db.update_from_line("apnic|JP|ipv4|203.0.113.0|256|20000101|allocated");
+        db.serial_numbers.insert(RirName::Arin, 20_240_101);
+        db.serial_numbers.insert(RirName::Apnic, 20_240_101);
+        let old = db.clone();
+        let diff = db.withdraw_all_for_rir(RirName::Arin);
+        assert_eq!(
+            diff.withdrawn_ipv4,
+            HashMap::from([(us, old.ipv4_prefixes[&us].clone())])
+        );
+        assert_eq!(
+            diff.withdrawn_ipv6,
+            HashMap::from([(ca, old.ipv6_prefixes[&ca].clone())])
+        );
+        assert!(diff.new_ipv4.is_empty() && diff.new_ipv6.is_empty());
+        assert_eq!(diff.summary().total.withdrawn_ipv4, 2);
+        // Other RIRs are untouched
+        assert_eq!(db.ipv4_prefixes.keys().collect::<Vec<_>>(), vec![&jp]);
+        assert!(db.ipv6_prefixes.is_empty());
+        assert_eq!(
+            db.serial_numbers,
+            HashMap::from([(RirName::Apnic, 20_240_101)])
+        );
+        // Applying the diff to the old database has the same effect
+        let mut applied = old;
+        diff.apply_to(&mut applied);
+        assert!(applied.ipv4_prefixes[&us].is_empty());
+        assert!(applied.ipv6_prefixes[&ca].is_empty());
+    }
+
+    #[test]
+    fn test_diff_summary() {
+        let us: CountrySpec = "arin:US".parse().unwrap();
+        let ca: CountrySpec = "arin:CA".parse().unwrap();
+        let diff = DatabaseDiff {
+            new_ipv4: HashMap::from([
+                (
+                    us,
+                    vec![
+                        "192.0.2.0/24".parse().unwrap(),
+                        "198.51.100.0/24".parse().unwrap(),
+                    ],
+                ),
+                (ca, vec!["203.0.113.0/24".parse().unwrap()]),
+            ]),
+            withdrawn_ipv6: HashMap::from([(ca, vec!["2001:db8::/32".parse().unwrap()])]),
+            ..Default::default()
+        };
+        let summary = diff.summary();
+        assert_eq!(
+            summary.total,
+            PrefixCounts {
+                new_ipv4: 3,
+                new_ipv6: 0,
+                withdrawn_ipv4: 0,
+                withdrawn_ipv6: 1,
+            }
+        );
+        assert_eq!(summary.countries[&us].new_ipv4, 2);
+        assert_eq!(
+            summary.countries[&ca],
+            PrefixCounts {
+                new_ipv4: 1,
+                withdrawn_ipv6: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "3 new IPv4, 0 new IPv6, 0 withdrawn IPv4, 1 withdrawn IPv6\n  \
+             ARIN:CA: 1 new IPv4, 0 new IPv6, 0 withdrawn IPv4, 1 withdrawn IPv6\n  \
+             ARIN:US: 2 new IPv4, 0 new IPv6, 0 withdrawn IPv4, 0 withdrawn IPv6"
+        );
+        assert_eq!(DatabaseDiff::default().summary(), DiffSummary::default());
+    }
+
+    #[test]
+    fn test_compute_diff_shrunk() {
+        let us: CountrySpec = "arin:US".parse().unwrap();
+        let ca: CountrySpec = "arin:CA".parse().unwrap();
+        let mut old = Database::new(vec![us, ca], true, true);
+        old.update_from_line("arin|US|ipv4|192.0.2.0|256|20000101|allocated");
+        old.update_from_line("arin|US|ipv4|198.51.100.0|256|20000101|allocated");
+        old.update_from_line("arin|US|ipv4|203.0.113.0|256|20000101|allocated");
+        old.update_from_line("arin|CA|ipv6|2001:db8::|32|20000101|allocated");
+        old.update_from_line("arin|CA|ipv6|2001:db8:1::|48|20000101|allocated");
+        let mut new = Database::new(vec![us, ca], true, true);
+        new.update_from_line("arin|US|ipv4|198.51.100.0|256|20000101|allocated");
+        new.update_from_line("arin|CA|ipv6|2001:db8::|32|20000101|allocated");
+        let rirs = HashSet::from([RirName::Arin]);
+        // US lost 2/3 and CA lost 1/2 of the prefixes
+        let diff = DatabaseDiff::compute_diff(&old, &new, &rirs, Some(0.5));
+        assert_eq!(diff.shrunk_countries, vec![(us, 3, 1)]);
+        let diff = DatabaseDiff::compute_diff(&old, &new, &rirs, Some(0.4));
+        assert_eq!(diff.shrunk_countries.len(), 2);
+        let diff = DatabaseDiff::compute_diff(&old, &new, &rirs, Some(0.7));
+        assert!(diff.shrunk_countries.is_empty());
+        // Countries of RIRs that were not updated are never flagged
+        let diff = DatabaseDiff::compute_diff(&old, &new, &HashSet::new(), Some(0.0));
+        assert!(diff.shrunk_countries.is_empty());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let country = "apnic:CN".parse().unwrap();
+        let mut db = Database::new(vec![country], true, true);
+        db.update_from_line("apnic|CN|ipv4|103.37.72.0|1024|20140821|allocated");
+        db.update_from_line("apnic|CN|ipv6|2001:250::|35|20000426|allocated");
+        db.serial_numbers.insert(RirName::Apnic, 20_240_101);
+        let path =
+            std::env::temp_dir().join(format!("delegation-feed-test-{}.cache", std::process::id()));
+        db.save_to(&path).unwrap();
+        let loaded = Database::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.has_same_config(&db));
+        assert_eq!(loaded.serial_numbers, db.serial_numbers);
+        assert_eq!(loaded.ipv4_prefixes, db.ipv4_prefixes);
+        assert_eq!(loaded.ipv6_prefixes, db.ipv6_prefixes);
+        // Missing cache file
+        assert!(matches!(Database::load_from(&path), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_load_corrupt() {
+        let path = std::env::temp_dir().join(format!(
+            "delegation-feed-test-corrupt-{}.cache",
+            std::process::id()
+        ));
+        std::fs::write(&path, b"\xff\xff\xff\xff\xff\xff\xff\xffnot a database").unwrap();
+        let result = Database::load_from(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(Error::Cache(_))));
+    }
+
+    /// Fetcher that serves statistics files from memory
+    struct MockFetcher(HashMap<&'static str, &'static str>);
+
+    impl RirFetcher for MockFetcher {
+        fn fetch(&self, url: &str) -> Result<Box<dyn BufRead>, Error> {
+            self.0.get(url).map_or_else(
+                || Err(Error::HttpStatus(StatusCode::NOT_FOUND)),
+                |content| Ok(Box::new(content.as_bytes()) as Box<dyn BufRead>),
+            )
+        }
+    }
+
+    #[test]
+    fn test_update_all_with_fetcher() {
+        let fetcher = MockFetcher(HashMap::from([
+            (
+                APNIC_URL,
+                "2|apnic|20240101|2|19830613|20231231|+1000\n\
+                 apnic|*|ipv4|*|1|summary\n\
+                 apnic|JP|ipv4|43.252.240.0|1024|20140417|allocated\n\
+                 apnic|JP|ipv6|2001:44a8::|32|20141120|allocated\n",
+            ),
+            (
+                "https://example.com/arin",
+                "2|arin|20240101|1|19830613|20231231|-0500\n\
+                 arin|CA|ipv4|192.174.4.0|1024|20000101|assigned\n",
+            ),
+        ]));
+        let jp = "apnic:JP".parse().unwrap();
+        let ca = "arin:CA".parse().unwrap();
+        let mut db = Database::new(vec![jp, ca], true, true);
+        db.set_source(
+            RirName::Arin,
+            Source::Url("https://example.com/arin".to_string()),
+        );
+        let updated = db.update_all(&fetcher).unwrap();
+        assert_eq!(updated, HashSet::from([RirName::Apnic, RirName::Arin]));
+        assert_eq!(
+            db.ipv4_prefixes[&jp],
+            vec![Cidr4::new("43.252.240.0".parse().unwrap(), 22)]
+        );
+        assert_eq!(
+            db.ipv6_prefixes[&jp],
+            vec![Cidr6::new("2001:44a8::".parse().unwrap(), 32)]
+        );
+        assert_eq!(
+            db.ipv4_prefixes[&ca],
+            vec![Cidr4::new("192.174.4.0".parse().unwrap(), 22)]
+        );
+        // A newer file replaces the prefixes instead of adding to them
+        let fetcher = MockFetcher(HashMap::from([(
+            APNIC_URL,
+            "2|apnic|20240102|1|19830613|20231231|+1000\n\
+             apnic|*|ipv4|*|1|summary\n\
+             apnic|JP|ipv4|43.252.244.0|1024|20140417|allocated\n",
+        )]));
+        db.set_source(RirName::Arin, Source::Url(String::new()));
+        assert_eq!(
+            db.update_all(&fetcher).unwrap(),
+            HashSet::from([RirName::Apnic])
+        );
+        assert_eq!(
+            db.ipv4_prefixes[&jp],
+            vec![Cidr4::new("43.252.244.0".parse().unwrap(), 22)]
+        );
+        assert!(!db.ipv6_prefixes.contains_key(&jp));
+        // Fetch errors are reported as usual
+        let mut db = Database::new(vec![ca], true, true);
+        assert!(matches!(
+            db.update_all(&fetcher),
+            Err(Error::HttpStatus(StatusCode::NOT_FOUND))
+        ));
+    }
+
+    /// Write a temporary statistics file for testing
+    fn write_test_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "delegation-feed-test-{name}-{}.txt",
+            std::process::id()
+        ));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_update_all_from_file() {
+        let path = write_test_file(
+            "apnic",
+            "# comment\n\
+             2|apnic|20240101|3|19830613|20231231|+1000\n\
+             apnic|*|ipv4|*|2|summary\n\
+             apnic|JP|ipv4|43.252.240.0|1024|20140417|allocated\n\
+             apnic|CN|ipv4|103.37.72.0|1024|20140821|allocated\n\
+             apnic|JP|ipv6|2001:44a8::|32|20141120|allocated\n",
+        );
+        let country = "apnic:JP".parse().unwrap();
+        let mut db = Database::new(vec![country], true, true);
+        db.set_source(RirName::Apnic, Source::File(path.clone()));
+        let updated = db.update_all(&UreqFetcher::default()).unwrap();
+        assert_eq!(updated, HashSet::from([RirName::Apnic]));
+        assert_eq!(
+            db.ipv4_prefixes[&country],
+            vec![Cidr4::new("43.252.240.0".parse().unwrap(), 22)]
+        );
+        assert_eq!(
+            db.ipv6_prefixes[&country],
+            vec![Cidr6::new("2001:44a8::".parse().unwrap(), 32)]
+        );
+        // Same serial number, so nothing should be updated
+        let diff = db.update_with_diff(&UreqFetcher::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(diff.new_ipv4.is_empty() && diff.withdrawn_ipv4.is_empty());
+        assert_eq!(db.ipv4_prefixes[&country].len(), 1);
+    }
+
+    #[test]
+    fn test_parse_summary_line() {
+        assert_eq!(
+            Database::parse_summary_line("lacnic|*|ipv4|*|19862|summary"),
+            Some(("ipv4", 19862))
+        );
+        assert!(
+            Database::parse_summary_line("apnic|CN|ipv4|103.37.72.0|1024|20140821|allocated")
+                .is_none()
+        );
+        assert!(Database::parse_summary_line("lacnic|*|ipv4|*|many|summary").is_none());
+        assert_eq!(Database::record_type("lacnic|*|ipv4|*|19862|summary"), None);
+        assert_eq!(
+            Database::record_type("lacnic||ipv4|45.68.184.0|256||reserved|"),
+            Some("ipv4")
+        );
+    }
+
+    #[test]
+    fn test_update_all_truncated() {
+        // The last record and part of the one before are missing
+        let path = write_test_file(
+            "truncated",
+            "2|apnic|20240101|4|19830613|20231231|+1000\n\
+             apnic|*|asn|*|1|summary\n\
+             apnic|*|ipv4|*|3|summary\n\
+             apnic|JP|asn|2497|1|19970101|allocated\n\
+             apnic|JP|ipv4|43.252.240.0|1024|20140417|allocated\n\
+             apnic|CN|ipv4|103.37.72.0|1024|20140821|allocated\n\
+             apnic|JP|ipv4|192.0.2.0|256|2014",
+        );
+        let country = "apnic:JP".parse().unwrap();
+        let mut db = Database::new(vec![country], true, false);
+        db.set_source(RirName::Apnic, Source::File(path.clone()));
+        let result = db.update_all(&UreqFetcher::default());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(Error::RecordCountMismatch(RirName::Apnic, ref type_, 2, 3)) if type_ == "ipv4"
+        ));
+        // Nothing is imported from the truncated file
+        assert!(db.ipv4_prefixes.is_empty());
+        assert!(db.serial_numbers.is_empty());
+    }
+
+    #[test]
+    fn test_update_with_diff_too_many_withdrawals() {
+        let path = write_test_file(
+            "shrink",
+            "2|apnic|20240101|3|19830613|20231231|+1000\n\
+             apnic|JP|ipv4|43.252.240.0|1024|20140417|allocated\n\
+             apnic|JP|ipv4|192.0.2.0|256|20140417|allocated\n\
+             apnic|JP|ipv4|198.51.100.0|256|20140417|allocated\n",
+        );
+        let country = "apnic:JP".parse().unwrap();
+        let mut db = Database::new(vec![country], true, false);
+        db.set_source(RirName::Apnic, Source::File(path.clone()));
+        db.set_max_withdraw_fraction(0.5);
+        db.update_with_diff(&UreqFetcher::default()).unwrap();
+        assert_eq!(db.ipv4_prefixes[&country].len(), 3);
+        std::fs::write(
+            &path,
+            "2|apnic|20240102|1|19830613|20231231|+1000\n\
+             apnic|JP|ipv4|43.252.240.0|1024|20140417|allocated\n",
+        )
+        .unwrap();
+        let result = db.update_with_diff(&UreqFetcher::default());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(Error::TooManyWithdrawals(c, 3, 1)) if c == country
+        ));
+        // The old data is kept and the update is retried next time
+        assert_eq!(db.ipv4_prefixes[&country].len(), 3);
+        assert_eq!(db.serial_numbers[&RirName::Apnic], 20_240_101);
+    }
+
+    #[test]
+    fn test_update_with_diff_withdraw_on_failure() {
+        let country = "apnic:JP".parse().unwrap();
+        for withdraw in [false, true] {
+            let path = write_test_file(
+                "withdraw",
+                "2|apnic|20240101|2|19830613|20231231|+1000\n\
+                 apnic|JP|ipv4|43.252.240.0|1024|20140417|allocated\n\
+                 apnic|JP|ipv6|2001:db8::|32|20140417|allocated\n",
+            );
+            let mut db = Database::new(vec![country], true, true);
+            db.set_source(RirName::Apnic, Source::File(path.clone()));
+            db.set_withdraw_on_failure(withdraw);
+            db.update_with_diff(&UreqFetcher::default()).unwrap();
+            // The file format changes to an unknown version
+            std::fs::write(
+                &path,
+                "3|apnic|20240102|1|19830613|20231231|+1000\n\
+                 apnic|JP|ipv4|43.252.240.0|1024|20140417|allocated\n",
+            )
+            .unwrap();
+            let result = db.update_with_diff(&UreqFetcher::default());
+            std::fs::remove_file(&path).unwrap();
+            if withdraw {
+                let diff = result.unwrap();
+                assert_eq!(
+                    diff.withdrawn_ipv4[&country],
+                    vec!["43.252.240.0/22".parse().unwrap()]
+                );
+                assert_eq!(
+                    diff.withdrawn_ipv6[&country],
+                    vec!["2001:db8::/32".parse().unwrap()]
+                );
+                assert!(db.ipv4_prefixes.is_empty());
+                assert!(db.ipv6_prefixes.is_empty());
+                assert!(db.serial_numbers.is_empty());
+            } else {
+                assert!(matches!(result, Err(Error::UnsupportedVersion(..))));
+                assert_eq!(db.ipv4_prefixes[&country].len(), 1);
+                assert_eq!(db.ipv6_prefixes[&country].len(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_update_all_with_statuses() {
+        let path = write_test_file(
+            "statuses",
+            "2|apnic|20240101|2|19830613|20231231|+1000\n\
+             apnic|*|ipv4|*|2|summary\n\
+             apnic|JP|ipv4|43.252.240.0|1024|20140417|allocated\n\
+             apnic|JP|ipv4|192.0.2.0|256|20140417|reserved\n",
+        );
+        let country = "apnic:JP".parse().unwrap();
+        let mut db = Database::new(vec![country], true, false);
+        db.set_source(RirName::Apnic, Source::File(path.clone()));
+        db.set_statuses(vec![Status::Reserved]);
+        db.update_with_diff(&UreqFetcher::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            db.ipv4_prefixes[&country],
+            vec![Cidr4::new("192.0.2.0".parse().unwrap(), 24)]
+        );
+    }
+
+    #[test]
+    fn test_update_all_partial_failure() {
+        let path = write_test_file(
+            "arin",
+            "2|arin|20240101|1|19830613|20231231|-0500\n\
+             arin|CA|ipv4|192.174.4.0|1024|20140417|allocated\n",
+        );
+        let missing = std::env::temp_dir().join("delegation-feed-test-nonexistent");
+        let arin_country = "arin:CA".parse().unwrap();
+        let ripe_country = "ripencc:DE".parse().unwrap();
+        let mut db = Database::new(vec![arin_country, ripe_country], true, true);
+        db.set_source(RirName::Arin, Source::File(path.clone()));
+        db.set_source(RirName::Ripencc, Source::File(missing.clone()));
+        let updated = db.update_all(&UreqFetcher::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(updated, HashSet::from([RirName::Arin]));
+        assert_eq!(db.ipv4_prefixes[&arin_country].len(), 1);
+        assert!(!db.serial_numbers.contains_key(&RirName::Ripencc));
+        // Everything failing is an error
+        db.set_source(RirName::Arin, Source::File(missing));
+        assert!(matches!(
+            db.update_all(&UreqFetcher::default()),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_download_retry() {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            for response in [
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).unwrap();
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        let mut body = String::new();
+        UreqFetcher::new(1)
+            .fetch(&url)
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "ok");
+        server.join().unwrap();
+        // No retries left
+        assert!(matches!(
+            UreqFetcher::new(0).fetch(&url),
+            Err(Error::Ureq(_))
+        ));
+    }
+
+    #[test]
+    fn test_source_from_str() {
+        assert_eq!(
+            "https://example.com/delegated".parse(),
+            Ok(Source::Url("https://example.com/delegated".to_string()))
+        );
+        assert_eq!(
+            "/tmp/delegated".parse(),
+            Ok(Source::File("/tmp/delegated".into()))
+        );
     }
 
     #[test]
@@ -413,7 +1586,7 @@ mod tests {
     fn test_update_all_jp() {
         let country = "apnic:JP".parse().unwrap();
         let mut db = Database::new(vec![country], true, true);
-        db.update_all().unwrap();
+        db.update_all(&UreqFetcher::default()).unwrap();
         assert!(!db.ipv4_prefixes.is_empty());
         let should_be_in = Cidr4::new("43.252.240.0".parse().unwrap(), 22);
         assert!(db.ipv4_prefixes[&country].contains(&should_be_in));
@@ -427,7 +1600,7 @@ mod tests {
     fn test_update_all_ca() {
         let country = "arin:CA".parse().unwrap();
         let mut db = Database::new(vec![country], true, true);
-        db.update_all().unwrap();
+        db.update_all(&UreqFetcher::default()).unwrap();
         assert!(!db.ipv4_prefixes.is_empty());
         let should_be_in = Cidr4::new("192.174.4.0".parse().unwrap(), 22);
         assert!(db.ipv4_prefixes[&country].contains(&should_be_in));
@@ -16,6 +16,8 @@ pub enum Error {
     InvalidCountryCode,
     #[error("Missing country code")]
     MissingCountryCode,
+    #[error("Invalid record status")]
+    InvalidStatus,
 }
 
 /// Names of the five Regional Internet Registries
@@ -118,6 +120,50 @@ impl FromStr for CountrySpec {
     }
 }
 
+/// Status of a record in a RIR statistics file
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Status {
+    /// Delegated to a LIR for further assignment
+    Allocated,
+    /// Delegated to an end user
+    Assigned,
+    /// Not yet delegated
+    Available,
+    /// Held back by the RIR
+    Reserved,
+}
+
+impl Status {
+    /// Statuses of records that are actually delegated
+    pub const DELEGATED: [Self; 2] = [Self::Allocated, Self::Assigned];
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Allocated => write!(f, "allocated"),
+            Self::Assigned => write!(f, "assigned"),
+            Self::Available => write!(f, "available"),
+            Self::Reserved => write!(f, "reserved"),
+        }
+    }
+}
+
+impl FromStr for Status {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        match s.as_str() {
+            "allocated" => Ok(Self::Allocated),
+            "assigned" => Ok(Self::Assigned),
+            "available" => Ok(Self::Available),
+            "reserved" => Ok(Self::Reserved),
+            _ => Err(Error::InvalidStatus),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -154,4 +200,21 @@ mod tests {
         assert!(":US".parse::<CountrySpec>().is_err());
         assert!("arin:".parse::<CountrySpec>().is_err());
     }
+
+    #[test]
+    fn test_status_from_str() {
+        assert_eq!("allocated".parse(), Ok(Status::Allocated));
+        assert_eq!("ASSIGNED".parse(), Ok(Status::Assigned));
+        assert_eq!("available".parse(), Ok(Status::Available));
+        assert_eq!("reserved".parse(), Ok(Status::Reserved));
+        assert_eq!("summary".parse::<Status>(), Err(Error::InvalidStatus));
+        for status in [
+            Status::Allocated,
+            Status::Assigned,
+            Status::Available,
+            Status::Reserved,
+        ] {
+            assert_eq!(status.to_string().parse(), Ok(status));
+        }
+    }
 }
@@ -2,20 +2,35 @@
 
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use crate::rirstat::DatabaseDiff;
+use crate::rirstat::rirbase::CountrySpec;
+use crate::rirstat::{Database, DatabaseDiff};
 use bytes::Bytes;
 use futures_util::{SinkExt, StreamExt};
-use pabgp::capability::{self, Afi, Capabilities, CapabilitiesBuilder, Safi};
-use pabgp::path::{AsSegmentType, Origin};
-use pabgp::route::Routes;
+use pabgp::capability::{self, Afi, Capabilities, CapabilitiesBuilder, RfcRole, Safi};
+use pabgp::cidr::{Cidr, Cidr4, Cidr6};
+use pabgp::path::{self, AsSegmentType, Communities, MpNextHop, MpNlri, Origin, PathAttributes};
+use pabgp::route::{self, Routes};
 use pabgp::{
-    Codec, Error as PacketError, Message, Notification, NotificationErrorCode, Open,
-    OpenMessageErrorSubcode, UpdateBuilder, BGP_VERSION,
+    CeaseSubcode, Codec, Error as PacketError, Message, Notification, NotificationErrorCode, Open,
+    OpenMessageErrorSubcode, RouteRefresh, UpdateBuilder, UpdateMessageErrorSubcode, BGP_VERSION,
+    MAX_EXTENDED_MESSAGE_LEN,
 };
-use tokio::net::{tcp, TcpStream};
-use tokio::sync::broadcast;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::time::Duration;
+use tokio::net::{tcp, TcpSocket, TcpStream};
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::time::{interval_at, sleep_until, Instant};
 use tokio_util::codec::{FramedRead, FramedWrite};
 
+/// Hold time we propose in our OPEN message
+const LOCAL_HOLD_TIME: u16 = 180;
+
+/// Pause between bursts of messages, see [`Feeder::set_updates_per_burst`]
+const BURST_PAUSE: Duration = Duration::from_millis(10);
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -28,179 +43,1161 @@ pub enum Error {
     UnexpectedMessage,
     #[error("peer sent a notification: {:?}({}), data: {:?}", .0.error_code, .0.error_subcode, .0.data)]
     PeerNotification(pabgp::Notification),
+    #[error("hold timer expired")]
+    HoldTimerExpired,
+    #[error("peer sent more than {0} prefixes")]
+    MaxPrefixesExceeded(usize),
+    #[error("peer AS {0} is not allowed")]
+    PeerRejected(u32),
+    #[error("invalid BGP identifier {0}")]
+    InvalidBgpId(std::net::Ipv4Addr),
+    #[error("unacceptable hold time {0}")]
+    UnacceptableHoldTime(u16),
+    #[error("unsupported optional parameter {0}")]
+    UnsupportedOptionalParameter(u8),
+    #[error("closed to resolve a connection collision")]
+    ConnectionCollision,
+    #[error("peer closed the connection")]
+    ConnectionClosed,
+    #[error("peer closed the connection in the middle of a message ({0} bytes received)")]
+    Truncated(usize),
+}
+
+/// Connection collision detection (RFC 4271 Section 6.8)
+///
+/// Called with the peer's BGP Identifier when the session enters OpenConfirm.
+/// Returns `None` if this session loses to an existing one. Otherwise, the
+/// returned receiver fires if a later session wins over this one before it
+/// is established.
+pub type CollisionCheck = Box<dyn FnOnce(Ipv4Addr) -> Option<oneshot::Receiver<()>> + Send>;
+
+/// Longest TCP MD5 signature key supported by Linux
+pub const TCP_MD5_MAX_KEY_LEN: usize = 80;
+
+/// Argument of the `TCP_MD5SIG` socket option (`struct tcp_md5sig` in `linux/tcp.h`)
+#[cfg(all(feature = "tcp-md5", target_os = "linux"))]
+#[repr(C)]
+struct TcpMd5Sig {
+    addr: libc::sockaddr_storage,
+    flags: u8,
+    prefixlen: u8,
+    keylen: u16,
+    ifindex: libc::c_int,
+    key: [u8; TCP_MD5_MAX_KEY_LEN],
+}
+
+/// Sign the TCP segments exchanged with `peer` on `socket` (RFC 2385)
+///
+/// This must be done before connecting, or on the listening socket before
+/// the peer connects. IPv4 peers of an IPv6 socket have to be given as
+/// IPv4-mapped addresses.
+#[cfg(all(feature = "tcp-md5", target_os = "linux"))]
+pub fn set_tcp_md5sig(
+    socket: &impl std::os::fd::AsRawFd,
+    peer: IpAddr,
+    key: &[u8],
+) -> std::io::Result<()> {
+    if key.len() > TCP_MD5_MAX_KEY_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "TCP MD5 key too long",
+        ));
+    }
+    let mut sig = TcpMd5Sig {
+        addr: socket2::SockAddr::from(SocketAddr::new(peer, 0)).as_storage(),
+        flags: 0,
+        prefixlen: 0,
+        keylen: u16::try_from(key.len()).expect("checked above"),
+        ifindex: 0,
+        key: [0; TCP_MD5_MAX_KEY_LEN],
+    };
+    sig.key[..key.len()].copy_from_slice(key);
+    let len =
+        libc::socklen_t::try_from(std::mem::size_of::<TcpMd5Sig>()).expect("tcp_md5sig is small");
+    // SAFETY: `sig` is a valid `struct tcp_md5sig` that outlives the call
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MD5SIG,
+            std::ptr::addr_of!(sig).cast(),
+            len,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Sign the TCP segments exchanged with `peer` on `socket` (RFC 2385)
+///
+/// Always fails because TCP MD5 signatures are only supported on Linux with
+/// the `tcp-md5` feature.
+#[cfg(not(all(feature = "tcp-md5", target_os = "linux")))]
+pub fn set_tcp_md5sig<S>(_socket: &S, _peer: IpAddr, _key: &[u8]) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "TCP MD5 signatures require the `tcp-md5` feature on Linux",
+    ))
+}
+
+/// Number of messages of each type
+#[derive(Clone, Debug, Default)]
+pub struct MessageCounts {
+    pub open: usize,
+    pub update: usize,
+    pub notification: usize,
+    pub keepalive: usize,
+    pub route_refresh: usize,
+}
+
+impl MessageCounts {
+    fn count(&mut self, message: &Message) {
+        match message {
+            Message::Open(_) => self.open += 1,
+            Message::Update(_) => self.update += 1,
+            Message::Notification(_) => self.notification += 1,
+            Message::Keepalive => self.keepalive += 1,
+            Message::RouteRefresh(_) => self.route_refresh += 1,
+        }
+    }
+}
+
+/// Counters of a BGP session
+#[derive(Clone, Debug, Default)]
+pub struct SessionStats {
+    /// Messages sent to the peer
+    pub sent: MessageCounts,
+    /// Messages received from the peer
+    pub received: MessageCounts,
+    /// Routes advertised to the peer
+    pub routes_advertised: usize,
+    /// Routes withdrawn from the peer
+    pub routes_withdrawn: usize,
+    /// Time of the last KEEPALIVE received from the peer
+    pub last_keepalive: Option<Instant>,
+}
+
+/// Capabilities advertised by both us and the peer
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NegotiationResult {
+    /// Route Refresh (RFC 2918)
+    pub route_refresh: bool,
+    /// Extended Messages (RFC 8654)
+    pub extended_message: bool,
+    /// Four-octet AS numbers (RFC 6793)
+    pub four_octet_asn: bool,
+    /// AFI/SAFI pairs of Multiprotocol Extensions (RFC 4760)
+    pub multi_protocol: Vec<capability::MultiProtocol>,
+    /// Capabilities we advertised but the peer did not
+    pub not_negotiated: Vec<capability::Value>,
+}
+
+impl NegotiationResult {
+    /// Compare the capabilities we advertised with the peer's
+    pub fn new(local_caps: &Capabilities, peer_caps: &Capabilities) -> Self {
+        let mut result = Self::default();
+        for cap in local_caps.iter() {
+            // Multiprotocol Extensions are negotiated per AFI/SAFI; other
+            // capabilities only need to be present on both sides
+            let mutual = peer_caps.iter().any(|peer_cap| match (cap, peer_cap) {
+                (capability::Value::MultiProtocol(_), capability::Value::MultiProtocol(_)) => {
+                    cap == peer_cap
+                }
+                (
+                    capability::Value::Unsupported(code, _),
+                    capability::Value::Unsupported(peer_code, _),
+                ) => code == peer_code,
+                _ => std::mem::discriminant(cap) == std::mem::discriminant(peer_cap),
+            });
+            if !mutual {
+                result.not_negotiated.push(cap.clone());
+                continue;
+            }
+            match cap {
+                capability::Value::RouteRefresh => result.route_refresh = true,
+                capability::Value::ExtendedMessage => result.extended_message = true,
+                capability::Value::FourOctetAsNumber(_) => result.four_octet_asn = true,
+                capability::Value::MultiProtocol(mp) => result.multi_protocol.push(*mp),
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// Whether Multiprotocol Extensions are negotiated for an AFI/SAFI
+    pub fn has_multi_protocol(&self, afi: Afi, safi: Safi) -> bool {
+        self.multi_protocol
+            .iter()
+            .any(|mp| mp.afi == afi && mp.safi == safi)
+    }
+}
+
+/// Session parameters negotiated with the peer
+#[derive(Clone, Debug)]
+pub struct Negotiated {
+    peer_asn: u32,
+    hold_time: u16,
+    peer_caps: Capabilities,
+    capabilities: NegotiationResult,
+}
+
+impl Negotiated {
+    /// AS number of the peer, taken from the four-octet AS number capability if present
+    pub const fn peer_asn(&self) -> u32 {
+        self.peer_asn
+    }
+
+    /// Negotiated hold time in seconds, zero if KEEPALIVEs are disabled
+    pub const fn hold_time(&self) -> u16 {
+        self.hold_time
+    }
+
+    /// Capabilities advertised by the peer
+    pub const fn peer_capabilities(&self) -> &Capabilities {
+        &self.peer_caps
+    }
+
+    /// Capabilities advertised by both sides
+    pub const fn capabilities(&self) -> &NegotiationResult {
+        &self.capabilities
+    }
+}
+
+/// Log a NOTIFICATION received from the peer, including any shutdown communication
+fn log_peer_notification(notification: &Notification) {
+    log::warn!(
+        "Received NOTIFICATION message from peer: {:?} {}",
+        notification.error_code,
+        notification.error_subcode
+    );
+    if let Some(communication) = notification.shutdown_communication() {
+        log::warn!("Peer shutdown communication: {communication}");
+    }
+}
+
+/// AS number of the peer, preferring the four-octet AS number capability
+/// over the two-octet field in the OPEN header (RFC 6793 Section 4.1)
+fn peer_asn(open: &Open) -> u32 {
+    open.opt_params
+        .all_capabilities()
+        .four_octet_asn()
+        .unwrap_or_else(|| u32::from(open.asn))
+}
+
+/// Capabilities advertised if `Feeder::set_capabilities` is not called
+fn default_capabilities(local_as: u32) -> Capabilities {
+    CapabilitiesBuilder::new()
+        .mp_ipv4_unicast()
+        .mp_ipv6_unicast()
+        .route_refresh()
+        .extended_message()
+        .enh_ipv4_over_ipv6()
+        .four_octet_as_number_if_needed(local_as)
+        .build()
+}
+
+/// Number of routes advertised and withdrawn in an UPDATE message
+pub fn route_counts(update: &pabgp::Update) -> (usize, usize) {
+    let mut advertised = update.nlri.len();
+    let mut withdrawn = update.withdrawn_routes.len();
+    for attr in update.path_attributes.iter() {
+        match &attr.data {
+            path::Data::MpReachNlri(reach) => advertised += reach.nlri.len(),
+            path::Data::MpUnreachNlri(unreach) => withdrawn += unreach.withdrawn_routes.len(),
+            _ => {}
+        }
+    }
+    (advertised, withdrawn)
+}
+
+/// Why routes were sent to the peer, recorded in the change log
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChangeReason {
+    /// All routes, sent when the session is established or on ROUTE-REFRESH
+    Initial,
+    /// Routes changed by a database update
+    Diff,
+}
+
+impl std::fmt::Display for ChangeReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Initial => "initial",
+            Self::Diff => "diff",
+        })
+    }
 }
 
-/// A simple passive BGP speaker
+/// Write a change log line for each route in an UPDATE message sent to the peer
+///
+/// Lines are tab-separated: seconds since the Unix epoch, `announce` or
+/// `withdraw`, the prefix, the next hop (`-` for withdrawals), and the reason.
+pub fn write_change_log(
+    writer: &mut dyn Write,
+    update: &pabgp::Update,
+    timestamp: Duration,
+    reason: ChangeReason,
+) -> std::io::Result<()> {
+    let timestamp = format!("{}.{:03}", timestamp.as_secs(), timestamp.subsec_millis());
+    let mut write_routes = |action: &str, routes: &Routes, afi: Afi, next_hop: &str| {
+        for route in routes.iter() {
+            let prefix = match afi {
+                Afi::Ipv4 => route.to_cidr4().map(Cidr::V4),
+                Afi::Ipv6 => route.to_cidr6().map(Cidr::V6),
+                _ => None,
+            };
+            let Some(prefix) = prefix else { continue };
+            // One write per line so that concurrent sessions do not interleave
+            writer.write_all(
+                format!("{timestamp}\t{action}\t{prefix}\t{next_hop}\t{reason}\n").as_bytes(),
+            )?;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    write_routes("withdraw", &update.withdrawn_routes, Afi::Ipv4, "-")?;
+    let mut next_hop = None;
+    for attr in update.path_attributes.iter() {
+        match &attr.data {
+            path::Data::NextHop(ip) => next_hop = Some(ip.to_string()),
+            path::Data::MpUnreachNlri(unreach) => {
+                if let MpNlri::Routes(routes) = &unreach.withdrawn_routes {
+                    write_routes("withdraw", routes, unreach.afi, "-")?;
+                }
+            }
+            path::Data::MpReachNlri(reach) => {
+                let next_hop = match reach.next_hop {
+                    MpNextHop::Empty => "-".to_string(),
+                    MpNextHop::Single(ip) => ip.to_string(),
+                    MpNextHop::V6AndLL(ip, _) => ip.to_string(),
+                };
+                if let MpNlri::Routes(routes) = &reach.nlri {
+                    write_routes("announce", routes, reach.afi, &next_hop)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    write_routes(
+        "announce",
+        &update.nlri,
+        Afi::Ipv4,
+        next_hop.as_deref().unwrap_or("-"),
+    )
+}
+
+/// Strictly check the routes in an UPDATE message from the peer for host bits
+///
+/// A dirty prefix is reported as an Invalid Network Field (RFC 4271 Section 6.3).
+fn check_route_host_bits(update: &pabgp::Update) -> Result<(), PacketError> {
+    let mut result = update
+        .withdrawn_routes
+        .check_host_bits()
+        .and_then(|()| update.nlri.check_host_bits());
+    for attr in update.path_attributes.iter() {
+        let routes = match &attr.data {
+            path::Data::MpReachNlri(reach) => &reach.nlri,
+            path::Data::MpUnreachNlri(unreach) => &unreach.withdrawn_routes,
+            _ => continue,
+        };
+        if let path::MpNlri::Routes(routes) = routes {
+            result = result.and_then(|()| routes.check_host_bits());
+        }
+    }
+    result.map_err(|e| {
+        log::warn!("Peer sent a prefix with host bits set: {e}");
+        PacketError::UpdateMessage(UpdateMessageErrorSubcode::InvalidNetworkField, Bytes::new())
+    })
+}
+
+/// Create an `UpdateBuilder` with the path attributes attached to all
+/// advertised routes
+pub fn base_update_builder(
+    enable_mp_bgp: bool,
+    max_message_len: Option<usize>,
+    local_as: u32,
+    next_hop: std::net::IpAddr,
+    local_pref: Option<u32>,
+    med: Option<u32>,
+) -> UpdateBuilder {
+    let mut builder = UpdateBuilder::new(enable_mp_bgp, max_message_len)
+        .set_next_hop(next_hop.into())
+        .set_origin(Origin::Igp)
+        .set_as_path(AsSegmentType::AsSequence, vec![local_as]);
+    if let Some(local_pref) = local_pref {
+        builder = builder.set_local_pref(local_pref);
+    }
+    if let Some(med) = med {
+        builder = builder.set_med(med);
+    }
+    builder
+}
+
+/// Create copies of `base` advertising the routes of each country.
+///
+/// Countries with a community get their own builder tagged with it, and
+/// the routes of all other countries share a single untagged builder.
+pub fn country_update_builders(
+    base: &UpdateBuilder,
+    ipv4_routes: &HashMap<CountrySpec, Vec<Cidr4>>,
+    ipv6_routes: &HashMap<CountrySpec, Vec<Cidr6>>,
+    country_communities: &HashMap<CountrySpec, u32>,
+) -> Vec<UpdateBuilder> {
+    let mut builders = Vec::new();
+    let mut untagged_v4 = Vec::new();
+    let mut untagged_v6 = Vec::new();
+    let countries: HashSet<&CountrySpec> = ipv4_routes.keys().chain(ipv6_routes.keys()).collect();
+    for country in countries {
+        let v4 = ipv4_routes.get(country).map_or(&[][..], Vec::as_slice);
+        let v6 = ipv6_routes.get(country).map_or(&[][..], Vec::as_slice);
+        match country_communities.get(country) {
+            Some(&community) if !v4.is_empty() || !v6.is_empty() => {
+                let tag = Communities(vec![community]);
+                builders.push(
+                    base.clone()
+                        .add_ipv4_routes_tagged(v4, tag.clone())
+                        .add_ipv6_routes_tagged(v6, tag),
+                );
+            }
+            Some(_) => {}
+            None => {
+                untagged_v4.extend_from_slice(v4);
+                untagged_v6.extend_from_slice(v6);
+            }
+        }
+    }
+    builders.push(
+        base.clone()
+            .add_ipv4_routes(untagged_v4)
+            .add_ipv6_routes(untagged_v6),
+    );
+    builders
+}
+
+/// A simple BGP speaker that feeds routes to a single peer
+///
+/// The session is opened passively with [`Feeder::idle`] on an accepted
+/// connection, or actively with [`Feeder::active`] after [`Feeder::connect_to`].
 pub struct Feeder {
-    init_ipv4_routes: Option<Routes>,
-    init_ipv6_routes: Option<Routes>,
+    /// IPv4 routes currently advertised to the peer
+    ipv4_routes: HashMap<CountrySpec, Vec<Cidr4>>,
+    /// IPv6 routes currently advertised to the peer
+    ipv6_routes: HashMap<CountrySpec, Vec<Cidr6>>,
+    /// Current database, to resynchronize from if database updates are missed
+    database: watch::Receiver<Database>,
     recv_updates: broadcast::Receiver<DatabaseDiff>,
     local_as: u32,
     local_id: std::net::Ipv4Addr,
     next_hop: std::net::IpAddr,
     rx: FramedRead<tcp::OwnedReadHalf, Codec>,
     tx: FramedWrite<tcp::OwnedWriteHalf, Codec>,
-    peer_hold_time: Option<u16>,
-    peer_caps: Capabilities,
-    // Default to true unless the peer does not support it
-    enable_mp_bgp: bool,
+    /// Time of the last message received from the peer
+    last_recv: Instant,
+    /// Allowed IPv4 prefix lengths (min, max)
+    prefix_len_v4: (u8, u8),
+    /// Allowed IPv6 prefix lengths (min, max)
+    prefix_len_v6: (u8, u8),
+    /// Maximum number of prefixes the peer may advertise to us
+    max_prefixes: Option<usize>,
+    /// Number of messages sent before each flush and pause, or `None` to
+    /// send everything at once
+    updates_per_burst: Option<NonZeroUsize>,
+    /// Messages waiting for the next burst, see `send_all`
+    pending: VecDeque<Message>,
+    /// When the next burst of `pending` may be sent
+    next_burst: Option<Instant>,
+    /// Unicast routes advertised by the peer (adj-RIB-in), see `received_routes`
+    adj_rib_in: HashMap<(Afi, route::Value), PathAttributes>,
+    stats: SessionStats,
+    /// Receives a shutdown communication when the session should be closed
+    shutdown_signal: Option<broadcast::Receiver<String>>,
+    /// Our BGP role (RFC 9234) advertised to the peer
+    role: Option<RfcRole>,
+    /// Capabilities advertised in our OPEN message
+    local_caps: Capabilities,
+    /// Community attached to the routes of each country
+    country_communities: HashMap<CountrySpec, u32>,
+    /// Parameters negotiated with the peer, known after its OPEN message is accepted
+    negotiated: Option<Negotiated>,
+    /// Whether the session has reached the Established state
+    established: bool,
+    /// LOCAL_PREF attached to routes advertised to an internal peer
+    local_pref: Option<u32>,
+    /// MULTI_EXIT_DISC attached to advertised routes
+    med: Option<u32>,
+    /// AS numbers allowed to peer with us, or any if empty
+    allowed_asns: Vec<u32>,
+    /// BGP Identifier of the peer, known after its OPEN message is received
+    peer_id: Option<Ipv4Addr>,
+    /// Connection collision detection, if there can be other sessions to the same peer
+    collision_check: Option<CollisionCheck>,
+    /// Log of the routes sent to the peer, see [`write_change_log`]
+    change_log: Option<Box<dyn Write + Send>>,
 }
 
 impl Feeder {
+    /// Dial the peer at `addr` for an actively opened session
+    ///
+    /// The connection is signed with `md5_key` if given (RFC 2385).
+    pub async fn connect_to(
+        addr: SocketAddr,
+        md5_key: Option<&[u8]>,
+        database: watch::Receiver<Database>,
+        recv_updates: broadcast::Receiver<DatabaseDiff>,
+        local_as: u32,
+        local_id: std::net::Ipv4Addr,
+        next_hop: std::net::IpAddr,
+    ) -> Result<Self, Error> {
+        log::info!("Connecting to peer at {addr}");
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(key) = md5_key {
+            set_tcp_md5sig(&socket, addr.ip(), key)?;
+        }
+        let socket = socket.connect(addr).await?;
+        Ok(Self::new(
+            database,
+            recv_updates,
+            socket,
+            local_as,
+            local_id,
+            next_hop,
+        ))
+    }
+
+    /// Create a session advertising the routes in `database`, followed by
+    /// the diffs from `recv_updates`
     pub fn new(
-        init_ipv4_routes: Option<Routes>,
-        init_ipv6_routes: Option<Routes>,
+        mut database: watch::Receiver<Database>,
         recv_updates: broadcast::Receiver<DatabaseDiff>,
         socket: TcpStream,
         local_as: u32,
         local_id: std::net::Ipv4Addr,
         next_hop: std::net::IpAddr,
     ) -> Self {
+        let (ipv4_routes, ipv6_routes) = database.borrow_and_update().clone().into_prefixes();
         let (rx, tx) = socket.into_split();
-        let codec = Codec;
-        let rx = FramedRead::new(rx, codec);
+        // Two-octet AS numbers until the capability is negotiated
+        let codec = Codec::default().with_four_octet_asn(false);
+        let rx = FramedRead::new(rx, codec.clone());
         let tx = FramedWrite::new(tx, codec);
         Self {
-            init_ipv4_routes,
-            init_ipv6_routes,
+            ipv4_routes,
+            ipv6_routes,
+            database,
             recv_updates,
             local_as,
             local_id,
             next_hop,
             rx,
             tx,
-            peer_hold_time: None,
-            peer_caps: Capabilities::default(),
-            enable_mp_bgp: true,
+            last_recv: Instant::now(),
+            prefix_len_v4: (0, 32),
+            prefix_len_v6: (0, 128),
+            max_prefixes: None,
+            updates_per_burst: None,
+            pending: VecDeque::new(),
+            next_burst: None,
+            adj_rib_in: HashMap::new(),
+            stats: SessionStats::default(),
+            shutdown_signal: None,
+            role: None,
+            local_caps: default_capabilities(local_as),
+            country_communities: HashMap::new(),
+            negotiated: None,
+            established: false,
+            local_pref: None,
+            med: None,
+            allowed_asns: Vec::new(),
+            peer_id: None,
+            collision_check: None,
+            change_log: None,
+        }
+    }
+
+    /// Get the negotiated session parameters, or `None` if the session has
+    /// not reached the Established state
+    pub fn negotiated(&self) -> Option<&Negotiated> {
+        self.negotiated.as_ref().filter(|_| self.established)
+    }
+
+    /// Whether routes of `afi` are passed in MP_* path attributes, assumed
+    /// until the peer's capabilities are known
+    fn mp_unicast(&self, afi: Afi) -> bool {
+        self.negotiated.as_ref().is_none_or(|negotiated| {
+            negotiated
+                .capabilities
+                .has_multi_protocol(afi, Safi::Unicast)
+        })
+    }
+
+    /// Whether Multiprotocol Extensions are used at all
+    fn enable_mp_bgp(&self) -> bool {
+        self.mp_unicast(Afi::Ipv4) || self.mp_unicast(Afi::Ipv6)
+    }
+
+    /// Whether the peer accepts our next hop for routes of the other address
+    /// family (RFC 8950), assumed until the peer's capabilities are known
+    fn extended_next_hop(&self) -> bool {
+        let (afi, next_hop_afi) = if self.next_hop.is_ipv4() {
+            (Afi::Ipv6, Afi::Ipv4)
+        } else {
+            (Afi::Ipv4, Afi::Ipv6)
+        };
+        self.negotiated.as_ref().is_none_or(|negotiated| {
+            negotiated
+                .peer_caps
+                .has_extended_next_hop(afi, Safi::Unicast, next_hop_afi)
+        })
+    }
+
+    /// Maximum UPDATE message length, raised if the peer supports Extended Messages
+    fn max_message_len(&self) -> Option<usize> {
+        self.negotiated
+            .as_ref()
+            .filter(|negotiated| negotiated.capabilities.extended_message)
+            .map(|_| MAX_EXTENDED_MESSAGE_LEN)
+    }
+
+    /// AS number of the peer, known after its OPEN message is accepted
+    fn peer_asn(&self) -> Option<u32> {
+        self.negotiated.as_ref().map(Negotiated::peer_asn)
+    }
+
+    /// Get the session counters
+    pub const fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    /// Queue a message to the peer, updating the counters
+    async fn feed(&mut self, message: Message) -> Result<(), Error> {
+        self.record_sent(&message);
+        self.tx.feed(message).await?;
+        Ok(())
+    }
+
+    /// Send a batch of messages with as few writes as possible, updating the counters
+    ///
+    /// With `updates_per_burst` set, only the first burst is sent right away.
+    /// The rest is left in `pending` for the main loop to send with
+    /// `send_burst`, so that the session keeps handling the peer's messages
+    /// and KEEPALIVEs in the meantime.
+    async fn send_all(&mut self, messages: Vec<Message>) -> Result<(), Error> {
+        if self.updates_per_burst.is_none() && self.pending.is_empty() {
+            for message in &messages {
+                self.record_sent(message);
+            }
+            // Anything queued before is already in the buffer, so the order is kept
+            let codec = self.tx.encoder().clone();
+            codec.encode_all(messages, self.tx.write_buffer_mut())?;
+            self.tx.flush().await?;
+            return Ok(());
+        }
+        self.pending.extend(messages);
+        if self.next_burst.is_none() {
+            self.send_burst().await?;
+        }
+        Ok(())
+    }
+
+    /// Send the next burst of `pending` messages and schedule the one after
+    async fn send_burst(&mut self) -> Result<(), Error> {
+        let burst = self
+            .updates_per_burst
+            .map_or(self.pending.len(), NonZeroUsize::get)
+            .min(self.pending.len());
+        let messages: Vec<_> = self.pending.drain(..burst).collect();
+        for message in &messages {
+            self.record_sent(message);
+        }
+        let codec = self.tx.encoder().clone();
+        codec.encode_all(messages, self.tx.write_buffer_mut())?;
+        self.tx.flush().await?;
+        self.next_burst = (!self.pending.is_empty()).then(|| Instant::now() + BURST_PAUSE);
+        Ok(())
+    }
+
+    /// Record the routes in UPDATE messages in the change log
+    ///
+    /// Failures are only logged so that the session is not affected.
+    fn log_changes(&mut self, messages: &[Message], reason: ChangeReason) {
+        let Some(writer) = &mut self.change_log else {
+            return;
+        };
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        for message in messages {
+            if let Message::Update(update) = message {
+                if let Err(e) = write_change_log(writer, update, timestamp, reason) {
+                    log::warn!("Failed to write the change log: {e}");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Record a message sent to the peer
+    fn record_sent(&mut self, message: &Message) {
+        self.stats.sent.count(message);
+        if let Message::Update(update) = message {
+            let (advertised, withdrawn) = route_counts(update);
+            self.stats.routes_advertised += advertised;
+            self.stats.routes_withdrawn += withdrawn;
+        }
+    }
+
+    /// Record a message received from the peer
+    fn record_received(&mut self, message: &Message) {
+        self.last_recv = Instant::now();
+        self.stats.received.count(message);
+        if matches!(message, Message::Keepalive) {
+            self.stats.last_keepalive = Some(self.last_recv);
+        }
+    }
+
+    /// Wait for a message from the peer
+    async fn recv(&mut self) -> Result<Message, Error> {
+        let packet = self.rx.next().await;
+        let packet = self.check_received(packet).await?;
+        self.record_received(&packet);
+        Ok(packet)
+    }
+
+    /// Find out why the session ends if the peer did not send a message
+    async fn check_received(
+        &mut self,
+        packet: Option<Result<Message, PacketError>>,
+    ) -> Result<Message, Error> {
+        match packet {
+            Some(Ok(packet)) => Ok(packet),
+            // Transition to Idle in all other cases
+            None => Err(Error::ConnectionClosed),
+            Some(Err(PacketError::Truncated(len))) => Err(Error::Truncated(len)),
+            Some(Err(e)) => {
+                self.notify_decode_error(&e).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Tell the peer why its message could not be decoded before closing the session
+    async fn notify_decode_error(&mut self, e: &PacketError) -> Result<(), Error> {
+        if matches!(e, PacketError::Io(_)) {
+            // The connection itself failed
+            return Ok(());
+        }
+        log::warn!("Received malformed message from peer: {e}");
+        self.feed(Message::Notification(Notification::from(e)))
+            .await?;
+        self.tx.flush().await?;
+        Ok(())
+    }
+
+    /// Close the session with an Administrative Shutdown once a message is received on `signal`
+    pub fn set_shutdown_signal(&mut self, signal: broadcast::Receiver<String>) {
+        self.shutdown_signal = Some(signal);
+    }
+
+    /// Send a Cease/Administrative Shutdown NOTIFICATION and close the connection
+    pub async fn shutdown(&mut self, communication: Option<&str>) -> Result<(), Error> {
+        log::info!(
+            "Shutting down session: {}",
+            communication.unwrap_or_default()
+        );
+        let notification = Notification::administrative_shutdown(communication);
+        self.feed(Message::Notification(notification)).await?;
+        self.tx.close().await?;
+        Ok(())
+    }
+
+    /// Advertise a BGP role (RFC 9234) to the peer
+    pub fn set_role(&mut self, role: Option<RfcRole>) {
+        self.role = role;
+    }
+
+    /// Set the capabilities advertised in our OPEN message
+    ///
+    /// The BGP role from `set_role` is added if it is not already present.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.local_caps = capabilities;
+    }
+
+    /// Tag the routes of each country with a community
+    pub fn set_country_communities(&mut self, communities: HashMap<CountrySpec, u32>) {
+        self.country_communities = communities;
+    }
+
+    /// Attach LOCAL_PREF to routes advertised to an internal peer, or
+    /// nothing if `None`
+    pub fn set_local_pref(&mut self, local_pref: Option<u32>) {
+        self.local_pref = local_pref;
+    }
+
+    /// Attach MULTI_EXIT_DISC to advertised routes, or nothing if `None`
+    pub fn set_med(&mut self, med: Option<u32>) {
+        self.med = med;
+    }
+
+    /// Get the peer's BGP Identifier, or `None` if its OPEN has not been received
+    pub const fn peer_id(&self) -> Option<Ipv4Addr> {
+        self.peer_id
+    }
+
+    /// Detect connection collisions with other sessions before this one is
+    /// established
+    pub fn set_collision_check(&mut self, check: CollisionCheck) {
+        self.collision_check = Some(check);
+    }
+
+    /// Record the routes sent to the peer in `writer`, see [`write_change_log`]
+    pub fn set_change_log(&mut self, writer: Option<Box<dyn Write + Send>>) {
+        self.change_log = writer;
+    }
+
+    /// Only accept peers with these AS numbers, or any peer if empty
+    pub fn set_allowed_asns(&mut self, asns: Vec<u32>) {
+        self.allowed_asns = asns;
+    }
+
+    /// Tear down the session if the peer advertises more than `max_prefixes` prefixes
+    pub fn set_max_prefixes(&mut self, max_prefixes: Option<usize>) {
+        self.max_prefixes = max_prefixes;
+    }
+
+    /// Flush and pause briefly after every `updates_per_burst` messages
+    ///
+    /// This avoids filling a slow peer's receive window during a large table
+    /// push. Messages from the peer and KEEPALIVEs are still handled during
+    /// the pauses. By default (`None`), all messages are queued and flushed
+    /// at once.
+    pub fn set_updates_per_burst(&mut self, updates_per_burst: Option<NonZeroUsize>) {
+        self.updates_per_burst = updates_per_burst;
+    }
+
+    /// Only advertise routes with prefix lengths in the given ranges
+    pub fn set_prefix_len_filter(&mut self, min_v4: u8, max_v4: u8, min_v6: u8, max_v6: u8) {
+        self.prefix_len_v4 = (min_v4, max_v4);
+        self.prefix_len_v6 = (min_v6, max_v6);
+    }
+
+    /// Drop the routes of address families the peer has not negotiated
+    fn filter_negotiated_afis(&self, mut builder: UpdateBuilder) -> UpdateBuilder {
+        if !self.mp_unicast(Afi::Ipv6) {
+            if !builder.nlri_ipv6_routes.is_empty() || !builder.withdrawn_ipv6_routes.is_empty() {
+                log::debug!("Not sending IPv6 routes to peer without IPv6 unicast support");
+            }
+            builder.nlri_ipv6_routes = Routes::default();
+            builder.withdrawn_ipv6_routes = Routes::default();
+        }
+        // Withdrawals carry no next hop, so only announcements are affected
+        if !self.extended_next_hop() {
+            if self.next_hop.is_ipv4() {
+                builder.nlri_ipv6_routes = Routes::default();
+            } else {
+                builder.nlri_ipv4_routes = Routes::default();
+            }
+        }
+        builder
+    }
+
+    /// Create an `UpdateBuilder` with our path attributes
+    fn update_builder(&self) -> UpdateBuilder {
+        let mut builder = base_update_builder(
+            self.enable_mp_bgp(),
+            self.max_message_len(),
+            self.local_as,
+            self.next_hop,
+            // RFC 4271 Section 5.1.5: not sent to external peers
+            self.local_pref
+                .filter(|_| self.peer_asn() == Some(self.local_as)),
+            self.med,
+        )
+        .set_extended_next_hop(self.extended_next_hop())
+        .set_four_octet_asn(
+            self.negotiated
+                .as_ref()
+                .is_some_and(|negotiated| negotiated.capabilities.four_octet_asn),
+        );
+        match self.peer_asn() {
+            // RFC 4271 Section 5.1.2: routes originated to internal peers
+            // have an empty AS_PATH, so there is no loop to detect
+            Some(peer_asn) if peer_asn == self.local_as => {
+                builder.as_path.0.clear();
+                builder
+            }
+            Some(peer_asn) => builder.drop_if_as_in_path(peer_asn),
+            None => builder,
         }
     }
 
+    /// Create the `UpdateBuilder`s advertising some routes of each country,
+    /// see [`country_update_builders`].
+    fn announcement_builders(
+        &self,
+        ipv4_routes: &HashMap<CountrySpec, Vec<Cidr4>>,
+        ipv6_routes: &HashMap<CountrySpec, Vec<Cidr6>>,
+    ) -> Vec<UpdateBuilder> {
+        let builders = country_update_builders(
+            &self.update_builder(),
+            ipv4_routes,
+            ipv6_routes,
+            &self.country_communities,
+        );
+        let (min_v4, max_v4) = self.prefix_len_v4;
+        let (min_v6, max_v6) = self.prefix_len_v6;
+        builders
+            .into_iter()
+            .map(|builder| {
+                self.filter_negotiated_afis(builder)
+                    .filter_prefix_len(min_v4, max_v4, min_v6, max_v6)
+            })
+            .collect()
+    }
+
     pub async fn idle(&mut self) -> Result<(), Error> {
         log::debug!("Idle state");
-        let packet = self.rx.next().await.ok_or(Error::Io(std::io::Error::new(
-            std::io::ErrorKind::UnexpectedEof,
-            "EOF",
-        )))??;
+        let open = self.recv_open().await?;
+        self.connect(open).await
+    }
+
+    /// Actively open the session by sending OPEN first
+    pub async fn active(&mut self) -> Result<(), Error> {
+        log::debug!("Active state");
+        self.send_open().await?;
+        // Transition to OpenSent
+        let open = self.recv_open().await?;
+        self.check_peer_open(open).await?;
+        self.open_sent_confirm().await
+    }
+
+    /// Wait for the peer's OPEN message
+    async fn recv_open(&mut self) -> Result<Open, Error> {
+        let packet = self.recv().await?;
         if let Message::Open(open) = packet {
-            log::trace!("Peer OPEN message: {open:?}");
-            let peer_version = open.version;
-            let peer_asn = open.asn;
-            let peer_hold_time = open.hold_time;
-            let peer_bgp_id = open.bgp_id;
-            let peer_opt_params = open.opt_params;
-            log::info!("Received OPEN message from peer (ASN: {peer_asn}, BGP ID: {peer_bgp_id})");
-            self.connect(
-                peer_version,
-                peer_asn,
-                peer_hold_time,
-                peer_bgp_id,
-                peer_opt_params,
-            )
-            .await
+            log::trace!("Peer OPEN message: {open}");
+            log::info!(
+                "Received OPEN message from peer (ASN: {}, BGP ID: {})",
+                peer_asn(&open),
+                open.bgp_id
+            );
+            Ok(open)
         } else {
             log::warn!("Received non-OPEN message from peer");
             Err(Error::UnexpectedMessage)
         }
     }
 
-    fn parse_peer_capabilities(&mut self) {
-        for cap in self.peer_caps.iter() {
-            log::debug!("Peer advertised capability: {cap:?}");
+    /// Record the parameters negotiated with the peer from its OPEN message
+    fn negotiate(&mut self, open: &Open) {
+        let peer_caps = open.opt_params.all_capabilities();
+        for cap in peer_caps.iter() {
+            log::debug!("Peer advertised capability: {cap}");
         }
-        // Whether the peer supports passing routes in a MP_* path attribute
-        self.enable_mp_bgp =
-            self.peer_caps.has_mp_ipv4_unicast() || self.peer_caps.has_mp_ipv6_unicast();
-        if !self
-            .peer_caps
-            .has_extended_next_hop(Afi::Ipv6, Safi::Unicast, Afi::Ipv4)
-            && self.next_hop.is_ipv4()
-        {
-            log::warn!("Peer does not support IPv4 next-hop in IPv6 routes");
+        let negotiation = NegotiationResult::new(&self.local_caps, &peer_caps);
+        let peer_role = peer_caps.role();
+        log::info!(
+            "Negotiated capabilities: route refresh {}, extended message {}, four-octet AS {}, multiprotocol {:?}",
+            negotiation.route_refresh,
+            negotiation.extended_message,
+            negotiation.four_octet_asn,
+            negotiation.multi_protocol
+        );
+        for cap in &negotiation.not_negotiated {
+            log::info!("Capability not supported by peer: {cap}");
         }
-        if !self
-            .peer_caps
-            .has_extended_next_hop(Afi::Ipv4, Safi::Unicast, Afi::Ipv6)
-            && self.next_hop.is_ipv6()
-        {
-            log::warn!("Peer does not support IPv6 next-hop in IPv4 routes");
+        let codec = Codec::new(negotiation.extended_message)
+            .with_four_octet_asn(negotiation.four_octet_asn)
+            .with_add_path(self.local_caps.add_path_receive(&peer_caps));
+        *self.rx.decoder_mut() = codec.clone();
+        *self.tx.encoder_mut() = codec;
+        self.negotiated = Some(Negotiated {
+            peer_asn: peer_asn(open),
+            hold_time: LOCAL_HOLD_TIME.min(open.hold_time),
+            peer_caps,
+            capabilities: negotiation,
+        });
+        if !self.mp_unicast(Afi::Ipv6) {
+            log::warn!("Peer does not support IPv6 unicast, IPv6 routes will not be advertised");
+        }
+        if !self.extended_next_hop() {
+            if self.next_hop.is_ipv4() {
+                log::warn!("Peer does not support IPv4 next-hop in IPv6 routes, IPv6 routes will not be advertised");
+            } else {
+                log::warn!("Peer does not support IPv6 next-hop in IPv4 routes, IPv4 routes will not be advertised");
+            }
+        }
+        if let Some(role) = self.role {
+            match peer_role {
+                Some(peer_role) if peer_role != role.expected_peer_role() => {
+                    log::warn!("Role mismatch: we are {role:?} but peer is {peer_role:?}");
+                }
+                Some(_) => {}
+                None => log::debug!("Peer did not advertise a BGP role"),
+            }
         }
     }
 
-    async fn connect(
-        &mut self,
-        peer_version: u8,
-        peer_asn: u16,
-        peer_hold_time: u16,
-        peer_bgp_id: std::net::Ipv4Addr,
-        mut peer_opt_params: capability::OptionalParameters,
-    ) -> Result<(), Error> {
+    async fn connect(&mut self, open: Open) -> Result<(), Error> {
         log::debug!("Connect state");
-        log::info!("Connection from peer (ASN: {peer_asn}, BGP ID: {peer_bgp_id})");
-        if peer_version != BGP_VERSION {
-            log::warn!("Peer version mismatch: expected {BGP_VERSION}, got {peer_version}");
-            let notification = Message::Notification(Notification::new(
-                NotificationErrorCode::OpenMessageError,
-                OpenMessageErrorSubcode::UnsupportedVersionNumber as u8,
-                Bytes::new(),
-            ));
-            // Send notification
-            self.tx.feed(notification).await?;
+        log::info!(
+            "Connection from peer (ASN: {}, BGP ID: {})",
+            peer_asn(&open),
+            open.bgp_id
+        );
+        self.check_peer_open(open).await?;
+        // Respond with OPEN
+        self.send_open().await?;
+        // Transition to OpenSent
+        self.open_sent_confirm().await
+    }
+
+    /// Send an OPEN Message Error notification to the peer
+    async fn notify_open_error(&mut self, subcode: OpenMessageErrorSubcode) -> Result<(), Error> {
+        let notification = Message::Notification(Notification::new(
+            NotificationErrorCode::OpenMessageError,
+            subcode as u8,
+            Bytes::new(),
+        ));
+        self.feed(notification).await?;
+        self.tx.flush().await?;
+        Ok(())
+    }
+
+    /// Validate the peer's OPEN message and record the negotiated parameters
+    async fn check_peer_open(&mut self, open: Open) -> Result<(), Error> {
+        if open.version != BGP_VERSION {
+            log::warn!(
+                "Peer version mismatch: expected {BGP_VERSION}, got {}",
+                open.version
+            );
+            self.notify_open_error(OpenMessageErrorSubcode::UnsupportedVersionNumber)
+                .await?;
             // Transition to Idle
             return Err(Error::InvalidVersion);
         }
-        // Respond with OPEN
-        let capabilities = CapabilitiesBuilder::new()
-            .mp_ipv4_unicast()
-            .mp_ipv6_unicast()
-            .enh_ipv4_over_ipv6()
-            .four_octet_as_number_if_needed(self.local_as)
-            .build();
-        // Make sure the peer hold time is longer than or equal to our hold time,
-        // so we don't have to worry about sending keepalives before they do it
-        // for us. (This is cheating, but it's a simple implementation)
+        let unsupported = open.opt_params.iter().find_map(|param| match param {
+            capability::OptionalParameterValue::Unsupported(type_, _) => Some(*type_),
+            _ => None,
+        });
+        if let Some(type_) = unsupported {
+            log::warn!("Peer sent unsupported optional parameter {type_}");
+            self.feed(Message::Notification(
+                Notification::unsupported_optional_parameter(type_),
+            ))
+            .await?;
+            self.tx.flush().await?;
+            // Transition to Idle
+            return Err(Error::UnsupportedOptionalParameter(type_));
+        }
+        if let Err(subcode) = open.validate_hold_time() {
+            log::warn!(
+                "Peer proposed an unacceptable hold time of {}",
+                open.hold_time
+            );
+            self.notify_open_error(subcode).await?;
+            // Transition to Idle
+            return Err(Error::UnacceptableHoldTime(open.hold_time));
+        }
+        self.peer_id = Some(open.bgp_id);
+        let asn = peer_asn(&open);
+        // RFC 7607 Section 2: AS 0 must not be used in an OPEN
+        if asn == 0 || (!self.allowed_asns.is_empty() && !self.allowed_asns.contains(&asn)) {
+            log::warn!("Rejecting peer AS {asn}, which is not allowed");
+            self.notify_open_error(OpenMessageErrorSubcode::BadPeerAs)
+                .await?;
+            // Transition to Idle
+            return Err(Error::PeerRejected(asn));
+        }
+        // RFC 6286 Section 2.2: internal peers must have a different identifier
+        let id_check = open.validate_identifier().and_then(|()| {
+            if asn == self.local_as && open.bgp_id == self.local_id {
+                Err(OpenMessageErrorSubcode::BadBgpIdentifier)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(subcode) = id_check {
+            log::warn!("Rejecting peer with BGP ID {}", open.bgp_id);
+            self.notify_open_error(subcode).await?;
+            // Transition to Idle
+            return Err(Error::InvalidBgpId(open.bgp_id));
+        }
+        if asn == self.local_as {
+            log::info!("Peer is in our AS {asn}, using internal BGP");
+        }
+        self.negotiate(&open);
+        Ok(())
+    }
+
+    /// Send our OPEN message
+    async fn send_open(&mut self) -> Result<(), Error> {
+        let mut capabilities = self.local_caps.clone();
+        if let Some(role) = self.role.filter(|_| capabilities.role().is_none()) {
+            let mut values = capabilities.to_vec();
+            values.push(capability::Value::Role(role));
+            capabilities = values.into();
+        }
         let open = Message::Open(Open::new_easy(
             self.local_as,
-            180.min(peer_hold_time),
+            LOCAL_HOLD_TIME,
             self.local_id,
             capabilities,
         ));
-        self.peer_hold_time = Some(peer_hold_time);
-        while let Some(op) = peer_opt_params.0.pop() {
-            #[allow(irrefutable_let_patterns)]
-            if let capability::OptionalParameterValue::Capabilities(caps) = op {
-                self.peer_caps = caps;
-            }
-            self.parse_peer_capabilities();
-        }
-        self.tx.feed(open).await?;
+        self.feed(open).await?;
         self.tx.flush().await?;
         log::info!("Sent OPEN message to peer");
-        // Transition to OpenSent
-        self.open_sent_confirm().await
+        Ok(())
+    }
+
+    /// Close the session that lost a connection collision
+    async fn close_collision(&mut self) -> Result<(), Error> {
+        log::warn!("Closing session to resolve a connection collision");
+        let notification = Message::Notification(Notification::new(
+            NotificationErrorCode::Cease,
+            CeaseSubcode::ConnectionCollisionResolution as u8,
+            Bytes::new(),
+        ));
+        self.feed(notification).await?;
+        self.tx.flush().await?;
+        // Transition to Idle
+        Err(Error::ConnectionCollision)
     }
 
     async fn open_sent_confirm(&mut self) -> Result<(), Error> {
         log::debug!("OpenSent state");
-        let packet = self.rx.next().await.ok_or(Error::Io(std::io::Error::new(
-            std::io::ErrorKind::UnexpectedEof,
-            "EOF",
-        )))??;
+        let mut lost = None;
+        if let (Some(check), Some(peer_id)) = (self.collision_check.take(), self.peer_id) {
+            match check(peer_id) {
+                Some(receiver) => lost = Some(receiver),
+                None => return self.close_collision().await,
+            }
+        }
+        let packet = tokio::select! {
+            packet = self.recv() => packet?,
+            Ok(()) = async { lost.as_mut().expect("checked by precondition").await }, if lost.is_some() => {
+                return self.close_collision().await;
+            }
+        };
+        // Later sessions now lose to this one
+        drop(lost);
         match packet {
             Message::Keepalive => {
                 log::info!("Received KEEPALIVE message from peer");
                 log::debug!("OpenConfirm state");
                 // Just send the exact same message back
-                self.tx.feed(packet).await?;
+                self.feed(packet).await?;
                 self.tx.flush().await?;
                 // Transition to Established
                 self.established().await
             }
             Message::Notification(notification) => {
-                log::warn!(
-                    "Received NOTIFICATION message from peer: {:?} {}",
-                    notification.error_code,
-                    notification.error_subcode
-                );
+                log_peer_notification(&notification);
                 // Transition to Idle
                 Err(Error::PeerNotification(notification))
             }
@@ -212,107 +1209,1593 @@ impl Feeder {
     }
 
     async fn handle_peer_packet(&mut self, packet: Message) -> Result<(), Error> {
+        self.record_received(&packet);
         match packet {
             Message::Keepalive => {
                 log::debug!("Received KEEPALIVE message from peer");
-                // Just send the exact same message back
-                self.tx.feed(packet).await?;
-                self.tx.flush().await?;
             }
             Message::Notification(notification) => {
-                log::warn!(
-                    "Received NOTIFICATION message from peer: {:?} {}",
-                    notification.error_code,
-                    notification.error_subcode
-                );
+                log_peer_notification(&notification);
                 // Transition to Idle
                 return Err(Error::PeerNotification(notification));
             }
             Message::Update(update) => {
                 log::debug!("Received UPDATE message from peer.");
+                if let Err(e) = update
+                    .path_attributes
+                    .validate(!update.nlri.is_empty())
+                    .and_then(|()| check_route_host_bits(&update))
+                {
+                    log::warn!("Received invalid UPDATE message from peer: {e}");
+                    if e.update_message_subcode().is_some() {
+                        self.feed(Message::Notification(Notification::from(&e)))
+                            .await?;
+                        self.tx.flush().await?;
+                    }
+                    // Transition to Idle
+                    return Err(e.into());
+                }
                 log::debug!("Peer withdrew {} routes", update.withdrawn_routes.len());
+                for route in update.withdrawn_routes.iter() {
+                    match route.to_cidr4() {
+                        Some(cidr) => log::trace!("Peer withdrew {cidr}"),
+                        None => log::warn!("Peer withdrew an invalid IPv4 route: {route:?}"),
+                    }
+                }
                 log::debug!("Peer added {} OLD BGP routes", update.nlri.len());
+                for route in update.nlri.iter() {
+                    match route.to_cidr4() {
+                        Some(cidr) => log::trace!("Peer advertised {cidr}"),
+                        None => log::warn!("Peer advertised an invalid IPv4 route: {route:?}"),
+                    }
+                }
                 log::debug!(
                     "Peer packet contains {} path attributes",
                     update.path_attributes.len()
                 );
-                log::debug!("No further processing implemented");
+                if let Some(as_path) = update.path_attributes.merged_as_path() {
+                    log::debug!("Peer AS path: {as_path}");
+                }
+                self.update_adj_rib_in(&update);
+                self.check_max_prefixes().await?;
             }
             Message::Open(_) => {
-                log::warn!("Received unexpected OPEN message from peer: {:?}", packet);
+                log::warn!("Received unexpected OPEN message from peer: {packet}");
+            }
+            Message::RouteRefresh(refresh) => {
+                log::info!("Received {packet} from peer");
+                if !self
+                    .negotiated
+                    .as_ref()
+                    .is_some_and(|negotiated| negotiated.capabilities.route_refresh)
+                {
+                    log::warn!("Ignoring ROUTE-REFRESH message without negotiated capability");
+                } else if refresh.subtype != RouteRefresh::SUBTYPE_NORMAL {
+                    // RFC 7313 Section 5: ignore messages with an unknown subtype
+                    log::warn!("Ignoring unsupported ROUTE-REFRESH request: {refresh:?}");
+                } else if let Some((afi, Safi::Unicast)) = refresh.afi_safi() {
+                    self.send_initial_updates(Some(afi)).await?;
+                } else {
+                    // RFC 2918 Section 4: ignore requests for address
+                    // families we did not advertise
+                    log::warn!(
+                        "Ignoring ROUTE-REFRESH request for unadvertised AFI/SAFI: {refresh:?}"
+                    );
+                }
             }
         }
         Ok(())
     }
 
-    async fn send_initial_updates(&mut self) -> Result<(), Error> {
-        let packets = UpdateBuilder::new(self.enable_mp_bgp)
-            .set_next_hop(self.next_hop.into())
-            .set_origin(Origin::Igp)
-            .set_as_path(AsSegmentType::AsSequence, vec![self.local_as])
-            .add_ipv4_routes(
-                self.init_ipv4_routes
-                    .take()
-                    .expect("Initial IPv4 routes not set"),
-            )
-            .add_ipv6_routes(
-                self.init_ipv6_routes
-                    .take()
-                    .expect("Initial IPv6 routes not set"),
-            )
-            .build()?;
-        for packet in packets {
-            log::trace!("Sending initial route packet: {packet:?}");
-            self.tx.feed(Message::Update(packet)).await?;
+    /// Get the unicast routes currently advertised by the peer with their
+    /// path attributes
+    ///
+    /// The attributes exclude `MP_UNREACH_NLRI` and the NLRI of
+    /// `MP_REACH_NLRI`, whose next hop is kept.
+    pub const fn received_routes(&self) -> &HashMap<(Afi, route::Value), PathAttributes> {
+        &self.adj_rib_in
+    }
+
+    /// Apply the withdrawals and then the announcements of an UPDATE from
+    /// the peer to the adj-RIB-in
+    fn update_adj_rib_in(&mut self, update: &pabgp::Update) {
+        for route in update.withdrawn_routes.iter() {
+            self.adj_rib_in.remove(&(Afi::Ipv4, route.clone()));
         }
+        let mut attributes = PathAttributes::default();
+        let mut mp_reach = None;
+        for attr in update.path_attributes.iter() {
+            match &attr.data {
+                path::Data::MpUnreachNlri(unreach) => {
+                    if let (Safi::Unicast, MpNlri::Routes(routes)) =
+                        (unreach.safi, &unreach.withdrawn_routes)
+                    {
+                        for route in routes.iter() {
+                            self.adj_rib_in.remove(&(unreach.afi, route.clone()));
+                        }
+                    }
+                }
+                path::Data::MpReachNlri(reach) => {
+                    mp_reach = Some(reach);
+                    attributes.0.push(path::Value {
+                        flags: attr.flags,
+                        data: path::Data::MpReachNlri(path::MpReachNlri {
+                            nlri: MpNlri::Routes(Routes::default()),
+                            ..reach.clone()
+                        }),
+                    });
+                }
+                _ => attributes.0.push(attr.clone()),
+            }
+        }
+        for route in update.nlri.iter() {
+            self.adj_rib_in
+                .insert((Afi::Ipv4, route.clone()), attributes.clone());
+        }
+        if let Some(reach) = mp_reach {
+            if let (Safi::Unicast, MpNlri::Routes(routes)) = (reach.safi, &reach.nlri) {
+                for route in routes.iter() {
+                    self.adj_rib_in
+                        .insert((reach.afi, route.clone()), attributes.clone());
+                }
+            }
+        }
+    }
+
+    /// Count the distinct prefixes in the adj-RIB-in and send a Cease
+    /// NOTIFICATION if there are too many (RFC 4486 Section 4)
+    ///
+    /// Re-announcing a prefix replaces it, so it is only counted once.
+    async fn check_max_prefixes(&mut self) -> Result<(), Error> {
+        let Some(max_prefixes) = self.max_prefixes else {
+            return Ok(());
+        };
+        let received_prefixes = self.adj_rib_in.len();
+        if received_prefixes <= max_prefixes {
+            return Ok(());
+        }
+        log::warn!(
+            "Peer advertised {received_prefixes} prefixes, more than the limit of {max_prefixes}"
+        );
+        let notification = Message::Notification(Notification::new(
+            NotificationErrorCode::Cease,
+            CeaseSubcode::MaximumNumberOfPrefixesReached as u8,
+            Bytes::new(),
+        ));
+        self.feed(notification).await?;
         self.tx.flush().await?;
+        // Transition to Idle
+        Err(Error::MaxPrefixesExceeded(max_prefixes))
+    }
+
+    /// Send all currently advertised routes of `afi`, or of both address
+    /// families if `afi` is `None`
+    async fn send_initial_updates(&mut self, afi: Option<Afi>) -> Result<(), Error> {
+        let no_ipv4 = HashMap::new();
+        let no_ipv6 = HashMap::new();
+        let builders = self.announcement_builders(
+            if afi.is_none_or(|afi| afi == Afi::Ipv4) {
+                &self.ipv4_routes
+            } else {
+                &no_ipv4
+            },
+            if afi.is_none_or(|afi| afi == Afi::Ipv6) {
+                &self.ipv6_routes
+            } else {
+                &no_ipv6
+            },
+        );
+        let mut messages = Vec::new();
+        for builder in builders {
+            for packet in builder.build()? {
+                log::trace!("Sending initial route packet: {packet}");
+                messages.push(Message::Update(packet));
+            }
+        }
+        self.log_changes(&messages, ChangeReason::Initial);
+        self.send_all(messages).await?;
         log::info!("Sent initial routes to peer");
         Ok(())
     }
 
+    /// Send an End-of-RIB marker for each negotiated address family (RFC 4724 Section 2)
+    async fn send_end_of_rib(&mut self) -> Result<(), Error> {
+        let mut afis = Vec::new();
+        if !self.enable_mp_bgp() || self.mp_unicast(Afi::Ipv4) {
+            afis.push(Afi::Ipv4);
+        }
+        if self.enable_mp_bgp() && self.mp_unicast(Afi::Ipv6) {
+            afis.push(Afi::Ipv6);
+        }
+        let markers = afis
+            .into_iter()
+            .map(|afi| Message::Update(UpdateBuilder::end_of_rib(afi, Safi::Unicast)))
+            .collect();
+        self.send_all(markers).await?;
+        log::debug!("Sent End-of-RIB markers to peer");
+        Ok(())
+    }
+
+    /// Interval between KEEPALIVE messages, or `None` if they are disabled
+    fn keepalive_interval(&self) -> Option<Duration> {
+        // RFC 4271 Section 4.4: one third of the hold time, and no KEEPALIVE
+        // messages if the hold time is zero
+        let interval = self.negotiated.as_ref()?.hold_time / 3;
+        (interval > 0).then(|| Duration::from_secs(u64::from(interval)))
+    }
+
+    /// Send a database diff to the peer and apply it to the advertised routes
+    ///
+    /// Applying a diff is idempotent, so one already contained in a database
+    /// snapshot may be sent again.
+    async fn send_diff(&mut self, diff: DatabaseDiff) -> Result<(), Error> {
+        log::info!("Database update: {}", diff.summary().total);
+        let (min_v4, max_v4) = self.prefix_len_v4;
+        let (min_v6, max_v6) = self.prefix_len_v6;
+        // Stream the routes from the diff without collecting them first
+        let builder = self
+            .update_builder()
+            .withdraw_ipv4_routes(diff.withdrawn_ipv4.values().flatten())
+            .withdraw_ipv6_routes(diff.withdrawn_ipv6.values().flatten());
+        let builder = self
+            .filter_negotiated_afis(builder)
+            .filter_prefix_len(min_v4, max_v4, min_v6, max_v6);
+        // Flush all withdrawals before advertising anything new
+        let withdrawals: Vec<_> = builder
+            .build_withdrawals()?
+            .into_iter()
+            .map(Message::Update)
+            .collect();
+        self.log_changes(&withdrawals, ChangeReason::Diff);
+        self.send_all(withdrawals).await?;
+        // New routes are tagged per country
+        let mut announcements = Vec::new();
+        for builder in self.announcement_builders(&diff.new_ipv4, &diff.new_ipv6) {
+            let packets = builder.build_announcements()?;
+            announcements.extend(packets.into_iter().map(Message::Update));
+        }
+        self.log_changes(&announcements, ChangeReason::Diff);
+        self.send_all(announcements).await?;
+        // Keep track of what we advertise so that a ROUTE-REFRESH can replay it
+        for (country, prefixes) in &diff.withdrawn_ipv4 {
+            if let Some(routes) = self.ipv4_routes.get_mut(country) {
+                let prefixes: HashSet<&Cidr4> = prefixes.iter().collect();
+                routes.retain(|route| !prefixes.contains(route));
+            }
+        }
+        for (country, prefixes) in &diff.withdrawn_ipv6 {
+            if let Some(routes) = self.ipv6_routes.get_mut(country) {
+                let prefixes: HashSet<&Cidr6> = prefixes.iter().collect();
+                routes.retain(|route| !prefixes.contains(route));
+            }
+        }
+        for (country, prefixes) in diff.new_ipv4 {
+            let routes = self.ipv4_routes.entry(country).or_default();
+            let known: HashSet<Cidr4> = routes.iter().copied().collect();
+            routes.extend(
+                prefixes
+                    .into_iter()
+                    .filter(|prefix| !known.contains(prefix)),
+            );
+        }
+        for (country, prefixes) in diff.new_ipv6 {
+            let routes = self.ipv6_routes.entry(country).or_default();
+            let known: HashSet<Cidr6> = routes.iter().copied().collect();
+            routes.extend(
+                prefixes
+                    .into_iter()
+                    .filter(|prefix| !known.contains(prefix)),
+            );
+        }
+        log::info!("Sent database update to peer");
+        Ok(())
+    }
+
     async fn established(&mut self) -> Result<(), Error> {
         log::debug!("Established state");
+        self.established = true;
         log::info!("Peer connection established");
-        self.send_initial_updates().await?;
+        self.send_initial_updates(None).await?;
+        self.send_end_of_rib().await?;
+        let mut keepalive_timer = self
+            .keepalive_interval()
+            .map(|period| interval_at(Instant::now() + period, period));
+        let hold_time = self.negotiated.as_ref().map(Negotiated::hold_time);
+        log::debug!("Negotiated hold time: {hold_time:?}");
+        let hold_duration = hold_time
+            .filter(|&hold_time| hold_time > 0)
+            .map(|hold_time| Duration::from_secs(u64::from(hold_time)));
+        self.last_recv = Instant::now();
+        let mut shutdown_signal = self.shutdown_signal.take();
+        let mut updates_open = true;
         loop {
             tokio::select! {
-                diffres = self.recv_updates.recv() => {
-                    log::info!("Received database update");
-                    let diff = diffres.expect("Database updater task exited");
-                    let new_ipv4: pabgp::route::Routes = diff.new_ipv4.values().flatten().into();
-                    let new_ipv6: pabgp::route::Routes = diff.new_ipv6.values().flatten().into();
-                    let withdrawn_ipv4: pabgp::route::Routes = diff.withdrawn_ipv4.values().flatten().into();
-                    let withdrawn_ipv6: pabgp::route::Routes = diff.withdrawn_ipv6.values().flatten().into();
-                    log::info!(
-                        "Database update: {} new IPv4, {} new IPv6, {} withdrawn IPv4, {} withdrawn IPv6",
-                        new_ipv4.len(),
-                        new_ipv6.len(),
-                        withdrawn_ipv4.len(),
-                        withdrawn_ipv6.len()
-                    );
-                    let packets = UpdateBuilder::new(self.enable_mp_bgp)
-                        .set_next_hop(self.next_hop.into())
-                        .set_origin(Origin::Igp)
-                        .set_as_path(AsSegmentType::AsSequence, vec![self.local_as])
-                        .add_ipv4_routes(new_ipv4)
-                        .add_ipv6_routes(new_ipv6)
-                        .withdraw_ipv4_routes(withdrawn_ipv4)
-                        .withdraw_ipv6_routes(withdrawn_ipv6)
-                        .build()?;
-                    for packet in packets {
-                        self.tx.feed(Message::Update(packet)).await?;
-                    }
+                Ok(communication) = async { shutdown_signal.as_mut().expect("checked by precondition").recv().await }, if shutdown_signal.is_some() => {
+                    self.shutdown(Some(&communication)).await?;
+                    // Transition to Idle
+                    return Ok(());
+                }
+                () = sleep_until(self.last_recv + hold_duration.unwrap_or_default()), if hold_duration.is_some() => {
+                    log::warn!("Hold timer expired");
+                    let notification = Message::Notification(Notification::new(
+                        NotificationErrorCode::HoldTimerExpired,
+                        0,
+                        Bytes::new(),
+                    ));
+                    self.feed(notification).await?;
+                    self.tx.flush().await?;
+                    // Transition to Idle
+                    return Err(Error::HoldTimerExpired);
+                }
+                _ = async { keepalive_timer.as_mut().expect("checked by precondition").tick().await }, if keepalive_timer.is_some() => {
+                    log::debug!("Sending KEEPALIVE message to peer");
+                    self.feed(Message::Keepalive).await?;
                     self.tx.flush().await?;
-                    log::info!("Sent database update to peer");
+                }
+                () = sleep_until(self.next_burst.unwrap_or_else(Instant::now)), if self.next_burst.is_some() => {
+                    self.send_burst().await?;
+                }
+                diffres = self.recv_updates.recv(), if updates_open => {
+                    let diff = match diffres {
+                        Ok(diff) => diff,
+                        Err(broadcast::error::RecvError::Closed) => {
+                            // The updater only exits when shutting down,
+                            // which the shutdown signal takes care of
+                            log::debug!("Database updater exited, no more updates to send");
+                            updates_open = false;
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            log::warn!("Missed {skipped} database updates, resynchronizing with the database");
+                            let diff = DatabaseDiff::from_advertised(
+                                &self.ipv4_routes,
+                                &self.ipv6_routes,
+                                &self.database.borrow_and_update(),
+                            );
+                            self.send_diff(diff).await?;
+                            continue;
+                        }
+                    };
+                    log::info!("Received database update");
+                    self.send_diff(diff).await?;
                 }
                 packet = self.rx.next() => {
-                    let packet = packet.ok_or(Error::Io(std::io::Error::new(
-                        std::io::ErrorKind::UnexpectedEof,
-                        "EOF",
-                    )))??;
+                    let packet = self.check_received(packet).await?;
                     self.handle_peer_packet(packet).await?;
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    fn country() -> CountrySpec {
+        "arin:US".parse().unwrap()
+    }
+
+    /// Codec of a test peer, which does not negotiate four-octet AS numbers
+    /// with a feeder in AS 65000
+    fn peer_codec() -> Codec {
+        Codec::default().with_four_octet_asn(false)
+    }
+
+    /// OPEN message of a peer in `asn`
+    fn peer_open(asn: u32, hold_time: u16, capabilities: Capabilities) -> Open {
+        Open::new_easy(asn, hold_time, "192.0.2.2".parse().unwrap(), capabilities)
+    }
+
+    /// Snapshot of a database without any routes
+    fn empty_database() -> watch::Receiver<Database> {
+        watch::channel(Database::new(vec![country()], true, true)).1
+    }
+
+    /// Create a `Feeder` connected to a peer that never sends anything
+    async fn silent_peer() -> (Feeder, TcpStream, broadcast::Sender<DatabaseDiff>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let (send_updates, recv_updates) = broadcast::channel(1);
+        let feeder = Feeder::new(
+            empty_database(),
+            recv_updates,
+            socket,
+            65000,
+            "192.0.2.1".parse().unwrap(),
+            "192.0.2.1".parse().unwrap(),
+        );
+        (feeder, peer, send_updates)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_updates_per_burst() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.set_updates_per_burst(NonZeroUsize::new(2));
+        let start = Instant::now();
+        feeder.send_all(vec![Message::Keepalive; 5]).await.unwrap();
+        // Only the first burst is sent right away
+        assert_eq!(feeder.stats().sent.keepalive, 2);
+        while let Some(next_burst) = feeder.next_burst {
+            sleep_until(next_burst).await;
+            feeder.send_burst().await.unwrap();
+        }
+        // Three bursts with a pause between each
+        assert!(start.elapsed() >= BURST_PAUSE * 2);
+        assert_eq!(feeder.stats().sent.keepalive, 5);
+        drop(feeder);
+        let mut peer_rx = FramedRead::new(peer, peer_codec());
+        let mut received = 0;
+        while let Some(Ok(Message::Keepalive)) = peer_rx.next().await {
+            received += 1;
+        }
+        assert_eq!(received, 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peer_handled_between_bursts() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.set_updates_per_burst(NonZeroUsize::new(1));
+        // A push that takes 100 seconds to send
+        feeder.pending = vec![Message::Keepalive; 10_000].into();
+        let session = tokio::spawn(async move {
+            let result = feeder.established().await;
+            (feeder, result)
+        });
+        let (_peer_rx, peer_tx) = peer.into_split();
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        peer_tx.send(Message::Keepalive).await.unwrap();
+        peer_tx
+            .send(Message::Notification(Notification::new(
+                NotificationErrorCode::Cease,
+                CeaseSubcode::AdministrativeShutdown as u8,
+                Bytes::new(),
+            )))
+            .await
+            .unwrap();
+        let (feeder, result) = session.await.unwrap();
+        assert!(matches!(result, Err(Error::PeerNotification(_))));
+        assert_eq!(feeder.stats().received.keepalive, 1);
+        assert!(!feeder.pending.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hold_timer_expired() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.negotiate(&peer_open(65001, 3, default_capabilities(65001)));
+        let result = feeder.established().await;
+        assert!(matches!(result, Err(Error::HoldTimerExpired)));
+        let stats = feeder.stats().clone();
+        assert_eq!(stats.sent.notification, 1);
+        assert!(stats.sent.keepalive > 0);
+        assert_eq!(stats.received.keepalive, 0);
+        assert!(stats.last_keepalive.is_none());
+        drop(feeder);
+        // The peer should see some KEEPALIVEs followed by the NOTIFICATION
+        let mut peer_rx = FramedRead::new(peer, peer_codec());
+        let mut last = None;
+        while let Some(packet) = peer_rx.next().await {
+            last = Some(packet.unwrap());
+        }
+        let Some(Message::Notification(notification)) = last else {
+            panic!("expected a NOTIFICATION, got {last:?}");
+        };
+        assert_eq!(
+            notification.error_code,
+            NotificationErrorCode::HoldTimerExpired
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_updater_exited() {
+        let (mut feeder, _peer, send_updates) = silent_peer().await;
+        feeder.negotiate(&peer_open(65001, 3, default_capabilities(65001)));
+        drop(send_updates);
+        // The session keeps running until something else ends it
+        let result = feeder.established().await;
+        assert!(matches!(result, Err(Error::HoldTimerExpired)));
+    }
+
+    #[tokio::test]
+    async fn test_updates_lagged() {
+        let (mut feeder, peer, send_updates) = silent_peer().await;
+        feeder.ipv4_routes = HashMap::from([(country(), vec!["192.0.2.0/24".parse().unwrap()])]);
+        // The channel holds one diff, so the session misses the first one
+        let diffs = [
+            DatabaseDiff {
+                new_ipv4: HashMap::from([(country(), vec!["198.51.100.0/24".parse().unwrap()])]),
+                withdrawn_ipv4: HashMap::from([(country(), vec!["192.0.2.0/24".parse().unwrap()])]),
+                ..Default::default()
+            },
+            DatabaseDiff {
+                new_ipv4: HashMap::from([(country(), vec!["203.0.113.0/24".parse().unwrap()])]),
+                ..Default::default()
+            },
+        ];
+        // The database already contains both diffs
+        let mut database = Database::new(vec![country()], true, true);
+        for diff in &diffs {
+            diff.clone().apply_to(&mut database);
+        }
+        let (_send_database, recv_database) = watch::channel(database);
+        feeder.database = recv_database;
+        for diff in diffs {
+            send_updates.send(diff).unwrap();
+        }
+        let session = tokio::spawn(async move { feeder.established().await });
+        let (peer_rx, _peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let initial = next_reach_update(&mut peer_rx).await;
+        let expected = MpNlri::Routes(vec!["192.0.2.0/24".parse::<Cidr4>().unwrap()].into());
+        assert!(initial.path_attributes.iter().any(
+            |attr| matches!(&attr.data, path::Data::MpReachNlri(reach) if reach.nlri == expected)
+        ));
+        // The missed diff is recovered from the database
+        let withdrawal = loop {
+            let Some(Ok(Message::Update(update))) = peer_rx.next().await else {
+                panic!("expected an UPDATE");
+            };
+            if update
+                .path_attributes
+                .iter()
+                .any(|attr| matches!(&attr.data, path::Data::MpUnreachNlri(unreach) if !unreach.withdrawn_routes.is_empty()))
+            {
+                break update;
+            }
+        };
+        assert!(withdrawal.path_attributes.iter().any(|attr| matches!(
+            &attr.data,
+            path::Data::MpUnreachNlri(unreach) if unreach.withdrawn_routes == expected
+        )));
+        let resynced = next_reach_update(&mut peer_rx).await;
+        assert!(resynced.path_attributes.iter().any(|attr| matches!(
+            &attr.data,
+            path::Data::MpReachNlri(reach) if matches!(&reach.nlri, MpNlri::Routes(routes) if routes.iter().any(|route| route.to_cidr4() == Some("198.51.100.0/24".parse().unwrap())))
+        )));
+        // The diff still queued was already in the database, but sending it
+        // again does no harm
+        let update = next_reach_update(&mut peer_rx).await;
+        let expected = MpNlri::Routes(vec!["203.0.113.0/24".parse::<Cidr4>().unwrap()].into());
+        assert!(update.path_attributes.iter().any(
+            |attr| matches!(&attr.data, path::Data::MpReachNlri(reach) if reach.nlri == expected)
+        ));
+        assert!(!session.is_finished());
+        session.abort();
+    }
+
+    /// Read UPDATE messages until one that advertises routes
+    async fn next_reach_update(
+        peer_rx: &mut FramedRead<tcp::OwnedReadHalf, Codec>,
+    ) -> pabgp::Update {
+        loop {
+            let Some(Ok(Message::Update(update))) = peer_rx.next().await else {
+                panic!("expected an UPDATE");
+            };
+            if update
+                .path_attributes
+                .iter()
+                .any(|attr| matches!(&attr.data, pabgp::path::Data::MpReachNlri(reach) if !reach.nlri.is_empty()))
+            {
+                return update;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_refresh() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.ipv4_routes = HashMap::from([(country(), vec!["192.0.2.0/24".parse().unwrap()])]);
+        feeder.negotiate(&peer_open(65001, 90, default_capabilities(65001)));
+        let session = tokio::spawn(async move { feeder.established().await });
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        let initial = next_reach_update(&mut peer_rx).await;
+        peer_tx
+            .send(Message::RouteRefresh(RouteRefresh::new(
+                Afi::Ipv4,
+                Safi::Unicast,
+            )))
+            .await
+            .unwrap();
+        let refreshed = next_reach_update(&mut peer_rx).await;
+        assert_eq!(initial, refreshed);
+        session.abort();
+    }
+
+    #[tokio::test]
+    async fn test_route_refresh_unknown_afi() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.ipv4_routes = HashMap::from([(country(), vec!["192.0.2.0/24".parse().unwrap()])]);
+        feeder.negotiate(&peer_open(65001, 90, default_capabilities(65001)));
+        let session = tokio::spawn(async move { feeder.established().await });
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        let initial = next_reach_update(&mut peer_rx).await;
+        // Neither the AFI nor the SAFI is known, so this is ignored
+        peer_tx
+            .send(Message::RouteRefresh(RouteRefresh {
+                afi: 0x4000,
+                subtype: RouteRefresh::SUBTYPE_NORMAL,
+                safi: 0xfe,
+            }))
+            .await
+            .unwrap();
+        peer_tx
+            .send(Message::RouteRefresh(RouteRefresh::new(
+                Afi::Ipv4,
+                Safi::Unicast,
+            )))
+            .await
+            .unwrap();
+        let refreshed = next_reach_update(&mut peer_rx).await;
+        assert_eq!(initial, refreshed);
+        assert!(!session.is_finished());
+        session.abort();
+    }
+
+    #[tokio::test]
+    async fn test_update_builder_ibgp() {
+        let (mut feeder, _peer, _send_updates) = silent_peer().await;
+        let route: Cidr = "192.0.2.0/24".parse().unwrap();
+        // The UPDATE advertising the route
+        let build = |feeder: &Feeder| {
+            let updates = feeder.update_builder().add_route(route).build().unwrap();
+            updates
+                .into_iter()
+                .find(|update| {
+                    update.path_attributes.iter().any(|attr| {
+                        matches!(&attr.data, path::Data::MpReachNlri(reach) if !reach.nlri.is_empty())
+                    })
+                })
+                .expect("route is not advertised")
+        };
+        // The internal peer gets the route with an empty AS_PATH
+        feeder.negotiate(&peer_open(65000, 90, default_capabilities(65000)));
+        let update = build(&feeder);
+        assert_eq!(
+            update.path_attributes.merged_as_path(),
+            Some(path::AsPath(vec![]))
+        );
+        // External peers see our AS
+        feeder.negotiate(&peer_open(65001, 90, default_capabilities(65001)));
+        let update = build(&feeder);
+        let as_path = update.path_attributes.merged_as_path().unwrap();
+        assert_eq!(as_path.0[0].asns, [65000]);
+    }
+
+    #[tokio::test]
+    async fn test_local_pref_ibgp_only() {
+        let (mut feeder, _peer, _send_updates) = silent_peer().await;
+        feeder.set_local_pref(Some(200));
+        let has_local_pref = |feeder: &Feeder| {
+            let updates = feeder
+                .update_builder()
+                .add_route("192.0.2.0/24".parse().unwrap())
+                .build()
+                .unwrap();
+            updates.iter().any(|update| {
+                update
+                    .path_attributes
+                    .iter()
+                    .any(|attr| attr.data == path::Data::LocalPref(200))
+            })
+        };
+        feeder.negotiate(&peer_open(65000, 90, default_capabilities(65000)));
+        assert!(has_local_pref(&feeder));
+        feeder.negotiate(&peer_open(65001, 90, default_capabilities(65001)));
+        assert!(!has_local_pref(&feeder));
+    }
+
+    #[tokio::test]
+    async fn test_adj_rib_in() {
+        let (mut feeder, _peer, _send_updates) = silent_peer().await;
+        let v4: Cidr = "192.0.2.0/24".parse().unwrap();
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        let announce = UpdateBuilder::new(true, None)
+            .set_extended_next_hop(true)
+            .set_next_hop(std::net::IpAddr::from([192, 0, 2, 2]).into())
+            .set_origin(Origin::Igp)
+            .set_as_path(AsSegmentType::AsSequence, vec![65001])
+            .add_route(v4)
+            .add_route(v6)
+            .build()
+            .unwrap();
+        for update in announce {
+            feeder
+                .handle_peer_packet(Message::Update(update))
+                .await
+                .unwrap();
+        }
+        let received = feeder.received_routes();
+        assert_eq!(received.len(), 2);
+        assert!(received.contains_key(&(Afi::Ipv4, v4.into())));
+        let attributes = &received[&(Afi::Ipv6, v6.into())];
+        assert!(attributes.iter().any(|attr| matches!(
+            &attr.data,
+            path::Data::AsPath(as_path) if as_path.0[0].asns == [65001]
+        )));
+        assert!(attributes.iter().all(|attr| match &attr.data {
+            path::Data::MpReachNlri(reach) => reach.nlri.is_empty(),
+            _ => true,
+        }));
+        let withdraw = UpdateBuilder::new(true, None)
+            .set_extended_next_hop(true)
+            .set_next_hop(std::net::IpAddr::from([192, 0, 2, 2]).into())
+            .set_origin(Origin::Igp)
+            .set_as_path(AsSegmentType::AsSequence, vec![65001])
+            .withdraw_route(v4)
+            .withdraw_route(v6)
+            .build()
+            .unwrap();
+        for update in withdraw {
+            feeder
+                .handle_peer_packet(Message::Update(update))
+                .await
+                .unwrap();
+        }
+        assert!(feeder.received_routes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_signal() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        let (send_shutdown, recv_shutdown) = broadcast::channel(1);
+        feeder.set_shutdown_signal(recv_shutdown);
+        let session = tokio::spawn(async move { feeder.established().await });
+        let mut peer_rx = FramedRead::new(peer, peer_codec());
+        // Wait for the session to be established before asking it to shut down
+        let Some(Ok(Message::Update(_))) = peer_rx.next().await else {
+            panic!("expected an UPDATE");
+        };
+        send_shutdown.send("maintenance".to_string()).unwrap();
+        assert!(session.await.unwrap().is_ok());
+        let mut last = None;
+        while let Some(packet) = peer_rx.next().await {
+            last = Some(packet.unwrap());
+        }
+        let Some(Message::Notification(notification)) = last else {
+            panic!("expected a NOTIFICATION, got {last:?}");
+        };
+        assert_eq!(
+            notification,
+            Notification::administrative_shutdown(Some("maintenance"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_active_sends_open_first() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_send_updates, recv_updates) = broadcast::channel(1);
+        let session = tokio::spawn(async move {
+            let mut feeder = Feeder::connect_to(
+                addr,
+                None,
+                empty_database(),
+                recv_updates,
+                65000,
+                "192.0.2.1".parse().unwrap(),
+                "192.0.2.1".parse().unwrap(),
+            )
+            .await?;
+            feeder.active().await
+        });
+        let (peer, _) = listener.accept().await.unwrap();
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        let Some(Ok(Message::Open(open))) = peer_rx.next().await else {
+            panic!("expected an OPEN");
+        };
+        assert_eq!(open.asn, 65000);
+        // Reply with an OPEN of the wrong version
+        let mut reply = Open::new_easy(
+            65001,
+            90,
+            "192.0.2.2".parse().unwrap(),
+            Capabilities::default(),
+        );
+        reply.version = 3;
+        peer_tx.send(Message::Open(reply)).await.unwrap();
+        let Some(Ok(Message::Notification(notification))) = peer_rx.next().await else {
+            panic!("expected a NOTIFICATION");
+        };
+        assert_eq!(
+            notification.error_code,
+            NotificationErrorCode::OpenMessageError
+        );
+        let result = session.await.unwrap();
+        assert!(matches!(result, Err(Error::InvalidVersion)));
+    }
+
+    #[tokio::test]
+    async fn test_max_prefixes() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.set_max_prefixes(Some(2));
+        let session = tokio::spawn(async move { feeder.established().await });
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        for prefix in ["192.0.2.0/24", "198.51.100.0/24", "203.0.113.0/24"] {
+            let updates = UpdateBuilder::new(false, None)
+                .set_next_hop(std::net::IpAddr::from([192, 0, 2, 2]).into())
+                .set_origin(Origin::Igp)
+                .set_as_path(AsSegmentType::AsSequence, vec![65001])
+                .add_route(prefix.parse().unwrap())
+                .build()
+                .unwrap();
+            for update in updates {
+                peer_tx.send(Message::Update(update)).await.unwrap();
+            }
+        }
+        let notification = loop {
+            match peer_rx.next().await {
+                Some(Ok(Message::Notification(notification))) => break notification,
+                Some(Ok(_)) => {}
+                other => panic!("expected a NOTIFICATION, got {other:?}"),
+            }
+        };
+        assert_eq!(notification.error_code, NotificationErrorCode::Cease);
+        assert_eq!(
+            notification.error_subcode,
+            CeaseSubcode::MaximumNumberOfPrefixesReached as u8
+        );
+        let result = session.await.unwrap();
+        assert!(matches!(result, Err(Error::MaxPrefixesExceeded(2))));
+    }
+
+    #[tokio::test]
+    async fn test_max_prefixes_reannounced() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.set_max_prefixes(Some(2));
+        let session = tokio::spawn(async move { feeder.established().await });
+        let (_peer_rx, peer_tx) = peer.into_split();
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        // Attribute changes of the same two prefixes stay within the limit
+        for med in 0..3 {
+            let updates = UpdateBuilder::new(false, None)
+                .set_next_hop(std::net::IpAddr::from([192, 0, 2, 2]).into())
+                .set_origin(Origin::Igp)
+                .set_as_path(AsSegmentType::AsSequence, vec![65001])
+                .set_med(med)
+                .add_route("192.0.2.0/24".parse().unwrap())
+                .add_route("198.51.100.0/24".parse().unwrap())
+                .build()
+                .unwrap();
+            for update in updates {
+                peer_tx.send(Message::Update(update)).await.unwrap();
+            }
+        }
+        drop(peer_tx);
+        let result = session.await.unwrap();
+        assert!(matches!(result, Err(Error::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_max_prefixes_unknown_withdrawn() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.set_max_prefixes(Some(2));
+        let session = tokio::spawn(async move { feeder.established().await });
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        let announce = |prefix: &str| {
+            UpdateBuilder::new(false, None)
+                .set_next_hop(std::net::IpAddr::from([192, 0, 2, 2]).into())
+                .set_origin(Origin::Igp)
+                .set_as_path(AsSegmentType::AsSequence, vec![65001])
+                .add_route(prefix.parse().unwrap())
+                .build()
+                .unwrap()
+        };
+        // Withdrawing prefixes that were never received does not make room
+        let withdraw = UpdateBuilder::new(false, None)
+            .withdraw_route("10.0.0.0/8".parse().unwrap())
+            .withdraw_route("172.16.0.0/12".parse().unwrap())
+            .build()
+            .unwrap();
+        let updates = announce("192.0.2.0/24")
+            .into_iter()
+            .chain(withdraw)
+            .chain(announce("198.51.100.0/24"))
+            .chain(announce("203.0.113.0/24"));
+        for update in updates {
+            peer_tx.send(Message::Update(update)).await.unwrap();
+        }
+        let notification = loop {
+            match peer_rx.next().await {
+                Some(Ok(Message::Notification(notification))) => break notification,
+                Some(Ok(_)) => {}
+                other => panic!("expected a NOTIFICATION, got {other:?}"),
+            }
+        };
+        assert_eq!(
+            notification.error_subcode,
+            CeaseSubcode::MaximumNumberOfPrefixesReached as u8
+        );
+        let result = session.await.unwrap();
+        assert!(matches!(result, Err(Error::MaxPrefixesExceeded(2))));
+    }
+
+    #[tokio::test]
+    async fn test_route_host_bits() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        let session = tokio::spawn(async move { feeder.established().await });
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        let mut update = UpdateBuilder::new(false, None)
+            .set_next_hop(std::net::IpAddr::from([192, 0, 2, 2]).into())
+            .set_origin(Origin::Igp)
+            .set_as_path(AsSegmentType::AsSequence, vec![65001])
+            .add_route("203.20.64.0/18".parse().unwrap())
+            .build()
+            .unwrap()
+            .into_iter()
+            .find(|update| !update.nlri.is_empty())
+            .unwrap();
+        // 203.20.67.0/18 has host bits set
+        update.nlri.0[0].prefix = Bytes::from_static(&[203, 20, 67]);
+        peer_tx.send(Message::Update(update)).await.unwrap();
+        let notification = loop {
+            match peer_rx.next().await {
+                Some(Ok(Message::Notification(notification))) => break notification,
+                Some(Ok(_)) => {}
+                other => panic!("expected a NOTIFICATION, got {other:?}"),
+            }
+        };
+        assert_eq!(
+            notification.error_code,
+            NotificationErrorCode::UpdateMessageError
+        );
+        assert_eq!(
+            notification.error_subcode,
+            UpdateMessageErrorSubcode::InvalidNetworkField as u8
+        );
+        assert!(session.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prefix_len_too_long() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        let session = tokio::spawn(async move { feeder.established().await });
+        // UPDATE with an IPv4 /40 in the NLRI
+        let mut update = vec![0xff; 16];
+        update.extend_from_slice(&[0x00, 0x1d, 0x02, 0x00, 0x00, 0x00, 0x00]);
+        update.extend_from_slice(&[0x28, 0x0a, 0x00, 0x00, 0x00, 0x00]);
+        peer.writable().await.unwrap();
+        assert_eq!(peer.try_write(&update).unwrap(), update.len());
+        let mut peer_rx = FramedRead::new(peer, peer_codec());
+        let notification = loop {
+            match peer_rx.next().await {
+                Some(Ok(Message::Notification(notification))) => break notification,
+                Some(Ok(_)) => {}
+                other => panic!("expected a NOTIFICATION, got {other:?}"),
+            }
+        };
+        assert_eq!(
+            notification.error_code,
+            NotificationErrorCode::UpdateMessageError
+        );
+        assert_eq!(
+            notification.error_subcode,
+            UpdateMessageErrorSubcode::InvalidNetworkField as u8
+        );
+        assert!(matches!(
+            session.await.unwrap(),
+            Err(Error::Packet(PacketError::UpdateMessage(
+                UpdateMessageErrorSubcode::InvalidNetworkField,
+                _
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_custom_capabilities() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.set_capabilities(CapabilitiesBuilder::new().mp_ipv4_unicast().build());
+        feeder.set_role(Some(RfcRole::Customer));
+        feeder.send_open().await.unwrap();
+        let mut peer_rx = FramedRead::new(peer, peer_codec());
+        let Some(Ok(Message::Open(open))) = peer_rx.next().await else {
+            panic!("expected an OPEN");
+        };
+        let Some(capability::OptionalParameterValue::Capabilities(caps)) = open.opt_params.first()
+        else {
+            panic!("expected capabilities");
+        };
+        assert!(caps.has_mp_ipv4_unicast());
+        assert!(!caps.has_mp_ipv6_unicast());
+        assert_eq!(caps.role(), Some(RfcRole::Customer));
+        assert_eq!(caps.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_peer_without_ipv6() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.ipv4_routes = HashMap::from([(country(), vec!["192.0.2.0/24".parse().unwrap()])]);
+        feeder.ipv6_routes = HashMap::from([(country(), vec!["2001:db8::/32".parse().unwrap()])]);
+        feeder.negotiate(&peer_open(
+            65001,
+            90,
+            CapabilitiesBuilder::new().mp_ipv4_unicast().build(),
+        ));
+        feeder.send_initial_updates(None).await.unwrap();
+        drop(feeder);
+        let mut peer_rx = FramedRead::new(peer, peer_codec());
+        let mut afis = Vec::new();
+        while let Some(Ok(Message::Update(update))) = peer_rx.next().await {
+            for attr in update.path_attributes.iter() {
+                if let path::Data::MpReachNlri(reach) = &attr.data {
+                    if !reach.nlri.is_empty() {
+                        afis.push(reach.afi);
+                    }
+                }
+            }
+        }
+        assert_eq!(afis, vec![Afi::Ipv4]);
+    }
+
+    /// In-memory change log that can be read while the `Feeder` owns it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Change log whose writes always fail
+    struct BrokenWriter;
+
+    impl Write for BrokenWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::StorageFull.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_change_log() {
+        let (mut feeder, _peer, _send_updates) = silent_peer().await;
+        feeder.ipv4_routes = HashMap::from([(country(), vec!["192.0.2.0/24".parse().unwrap()])]);
+        feeder.ipv6_routes = HashMap::from([(country(), vec!["2001:db8::/32".parse().unwrap()])]);
+        let log = SharedBuffer::default();
+        feeder.set_change_log(Some(Box::new(log.clone())));
+        feeder.send_initial_updates(None).await.unwrap();
+        let text = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Vec<&str>> = text
+            .lines()
+            .map(|line| line.split('\t').collect())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        for line in &lines {
+            assert!(line[0].parse::<f64>().is_ok());
+            assert_eq!(line[4], "initial");
+        }
+        assert_eq!(lines[0][1..4], ["announce", "192.0.2.0/24", "192.0.2.1"]);
+        assert_eq!(lines[1][1..4], ["announce", "2001:db8::/32", "192.0.2.1"]);
+        // Failing to write the log does not affect the session
+        feeder.set_change_log(Some(Box::new(BrokenWriter)));
+        feeder.send_initial_updates(None).await.unwrap();
+    }
+
+    #[test]
+    fn test_write_change_log_withdraw() {
+        let timestamp = Duration::from_millis(1_700_000_000_123);
+        let mut log = Vec::new();
+        for update in UpdateBuilder::new(false, None)
+            .withdraw_ipv4_routes(["192.0.2.0/24".parse::<Cidr4>().unwrap()])
+            .build()
+            .unwrap()
+        {
+            write_change_log(&mut log, &update, timestamp, ChangeReason::Diff).unwrap();
+        }
+        for update in UpdateBuilder::new(true, None)
+            .set_next_hop(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)).into())
+            .withdraw_ipv6_routes(["2001:db8::/32".parse::<Cidr6>().unwrap()])
+            .build()
+            .unwrap()
+        {
+            write_change_log(&mut log, &update, timestamp, ChangeReason::Diff).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(log).unwrap(),
+            "1700000000.123\twithdraw\t192.0.2.0/24\t-\tdiff\n\
+             1700000000.123\twithdraw\t2001:db8::/32\t-\tdiff\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peer_without_extended_next_hop() {
+        for (caps, expected) in [
+            (
+                CapabilitiesBuilder::new()
+                    .mp_ipv4_unicast()
+                    .mp_ipv6_unicast(),
+                vec![Afi::Ipv4],
+            ),
+            (
+                CapabilitiesBuilder::new()
+                    .mp_ipv4_unicast()
+                    .mp_ipv6_unicast()
+                    .enh_ipv6_over_ipv4(),
+                vec![Afi::Ipv4, Afi::Ipv6],
+            ),
+        ] {
+            let (mut feeder, peer, _send_updates) = silent_peer().await;
+            feeder.ipv4_routes =
+                HashMap::from([(country(), vec!["192.0.2.0/24".parse().unwrap()])]);
+            feeder.ipv6_routes =
+                HashMap::from([(country(), vec!["2001:db8::/32".parse().unwrap()])]);
+            feeder.negotiate(&peer_open(65001, 90, caps.build()));
+            feeder.send_initial_updates(None).await.unwrap();
+            drop(feeder);
+            let mut peer_rx = FramedRead::new(peer, peer_codec());
+            let mut afis = Vec::new();
+            while let Some(Ok(Message::Update(update))) = peer_rx.next().await {
+                for attr in update.path_attributes.iter() {
+                    if let path::Data::MpReachNlri(reach) = &attr.data {
+                        if !reach.nlri.is_empty() {
+                            afis.push(reach.afi);
+                        }
+                    }
+                }
+            }
+            assert_eq!(afis, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_end_of_rib() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.ipv4_routes = HashMap::from([(country(), vec!["192.0.2.0/24".parse().unwrap()])]);
+        let session = tokio::spawn(async move { feeder.established().await });
+        let mut peer_rx = FramedRead::new(peer, peer_codec());
+        let mut markers = Vec::new();
+        while markers.len() < 2 {
+            let Some(Ok(Message::Update(update))) = peer_rx.next().await else {
+                panic!("expected an UPDATE");
+            };
+            if update == UpdateBuilder::end_of_rib(Afi::Ipv4, Safi::Unicast) {
+                markers.push(Afi::Ipv4);
+            } else if update == UpdateBuilder::end_of_rib(Afi::Ipv6, Safi::Unicast) {
+                markers.push(Afi::Ipv6);
+            } else {
+                // Routes must come before the markers
+                assert!(markers.is_empty());
+            }
+        }
+        assert_eq!(markers, vec![Afi::Ipv4, Afi::Ipv6]);
+        session.abort();
+    }
+
+    #[tokio::test]
+    async fn test_country_community() {
+        let (mut feeder, peer, send_updates) = silent_peer().await;
+        let tag = Communities::community(65000, 1);
+        feeder.set_country_communities(HashMap::from([(country(), tag)]));
+        let session = tokio::spawn(async move { feeder.established().await });
+        let (peer_rx, _peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        // Wait for the initial updates to be sent
+        while let Some(Ok(Message::Update(update))) = peer_rx.next().await {
+            if update == UpdateBuilder::end_of_rib(Afi::Ipv6, Safi::Unicast) {
+                break;
+            }
+        }
+        let other: CountrySpec = "arin:CA".parse().unwrap();
+        let diff = DatabaseDiff {
+            new_ipv4: HashMap::from([
+                (country(), vec!["192.0.2.0/24".parse().unwrap()]),
+                (other, vec!["198.51.100.0/24".parse().unwrap()]),
+            ]),
+            ..Default::default()
+        };
+        send_updates.send(diff).unwrap();
+        let mut seen = Vec::new();
+        while seen.len() < 2 {
+            let update = next_reach_update(&mut peer_rx).await;
+            let communities = update
+                .path_attributes
+                .iter()
+                .find_map(|attr| match &attr.data {
+                    path::Data::Communities(communities) => Some(communities.clone()),
+                    _ => None,
+                });
+            let nlri = update
+                .path_attributes
+                .iter()
+                .find_map(|attr| match &attr.data {
+                    path::Data::MpReachNlri(reach) => Some(reach.nlri.clone()),
+                    _ => None,
+                });
+            seen.push((nlri.unwrap(), communities));
+        }
+        seen.sort_by_key(|(_, communities)| communities.is_none());
+        let tagged: Routes = vec!["192.0.2.0/24".parse::<Cidr4>().unwrap()].into();
+        let untagged: Routes = vec!["198.51.100.0/24".parse::<Cidr4>().unwrap()].into();
+        assert_eq!(
+            seen,
+            vec![
+                (path::MpNlri::Routes(tagged), Some(Communities(vec![tag]))),
+                (path::MpNlri::Routes(untagged), None),
+            ]
+        );
+        session.abort();
+    }
+
+    #[test]
+    fn test_negotiation_result() {
+        let local_caps = CapabilitiesBuilder::new()
+            .mp_ipv4_unicast()
+            .mp_ipv6_unicast()
+            .route_refresh()
+            .extended_message()
+            .four_octet_as_number(4_200_000_000)
+            .build();
+        let peer_caps = CapabilitiesBuilder::new()
+            .mp_ipv6_unicast()
+            .route_refresh()
+            .four_octet_as_number(4_200_000_001)
+            .build();
+        let result = NegotiationResult::new(&local_caps, &peer_caps);
+        assert!(result.route_refresh);
+        assert!(!result.extended_message);
+        assert!(result.four_octet_asn);
+        assert!(!result.has_multi_protocol(Afi::Ipv4, Safi::Unicast));
+        assert!(result.has_multi_protocol(Afi::Ipv6, Safi::Unicast));
+        assert_eq!(
+            result.not_negotiated,
+            vec![
+                capability::Value::MultiProtocol(capability::MultiProtocol {
+                    afi: Afi::Ipv4,
+                    safi: Safi::Unicast
+                }),
+                capability::Value::ExtendedMessage,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_negotiated() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_send_updates, recv_updates) = broadcast::channel(1);
+        let session = tokio::spawn(async move {
+            let mut feeder = Feeder::connect_to(
+                addr,
+                None,
+                empty_database(),
+                recv_updates,
+                65000,
+                "192.0.2.1".parse().unwrap(),
+                "192.0.2.1".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+            assert!(feeder.negotiated().is_none());
+            let result = feeder.active().await;
+            (feeder, result)
+        });
+        let (peer, _) = listener.accept().await.unwrap();
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        let Some(Ok(Message::Open(_))) = peer_rx.next().await else {
+            panic!("expected an OPEN");
+        };
+        let peer_caps = CapabilitiesBuilder::new()
+            .mp_ipv4_unicast()
+            .four_octet_as_number(4_200_000_000)
+            .build();
+        let reply = Open::new_easy(
+            4_200_000_000,
+            90,
+            "192.0.2.2".parse().unwrap(),
+            peer_caps.clone(),
+        );
+        peer_tx.send(Message::Open(reply)).await.unwrap();
+        peer_tx.send(Message::Keepalive).await.unwrap();
+        // Wait for the session to be established
+        let Some(Ok(Message::Keepalive)) = peer_rx.next().await else {
+            panic!("expected a KEEPALIVE");
+        };
+        peer_tx
+            .send(Message::Notification(Notification::new(
+                NotificationErrorCode::Cease,
+                CeaseSubcode::AdministrativeShutdown as u8,
+                Bytes::new(),
+            )))
+            .await
+            .unwrap();
+        let (feeder, result) = session.await.unwrap();
+        assert!(matches!(result, Err(Error::PeerNotification(_))));
+        let negotiated = feeder.negotiated().unwrap();
+        assert_eq!(negotiated.peer_asn(), 4_200_000_000);
+        assert_eq!(negotiated.hold_time(), 90);
+        assert_eq!(negotiated.peer_capabilities(), &peer_caps);
+        let capabilities = negotiated.capabilities();
+        assert!(!capabilities.route_refresh);
+        assert!(!capabilities.extended_message);
+        assert_eq!(
+            capabilities.multi_protocol,
+            vec![capability::MultiProtocol {
+                afi: Afi::Ipv4,
+                safi: Safi::Unicast
+            }]
+        );
+    }
+
+    #[test]
+    fn test_peer_asn_split_capabilities() {
+        let mut open = peer_open(
+            u32::from(pabgp::AS_TRANS),
+            90,
+            CapabilitiesBuilder::new().mp_ipv4_unicast().build(),
+        );
+        // RFC 5492 allows the capabilities to be spread over several parameters
+        open.opt_params
+            .0
+            .push(capability::OptionalParameterValue::Capabilities(
+                CapabilitiesBuilder::new()
+                    .four_octet_as_number(4_200_000_000)
+                    .build(),
+            ));
+        assert_eq!(peer_asn(&open), 4_200_000_000);
+    }
+
+    #[tokio::test]
+    async fn test_peer_asn_rejected() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.set_allowed_asns(vec![65001]);
+        let session = tokio::spawn(async move { feeder.idle().await });
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        let open = Open::new_easy(
+            65002,
+            90,
+            "192.0.2.2".parse().unwrap(),
+            Capabilities::default(),
+        );
+        peer_tx.send(Message::Open(open)).await.unwrap();
+        let Some(Ok(Message::Notification(notification))) = peer_rx.next().await else {
+            panic!("expected a NOTIFICATION");
+        };
+        assert_eq!(
+            notification.error_code,
+            NotificationErrorCode::OpenMessageError
+        );
+        assert_eq!(
+            notification.error_subcode,
+            OpenMessageErrorSubcode::BadPeerAs as u8
+        );
+        let result = session.await.unwrap();
+        assert!(matches!(result, Err(Error::PeerRejected(65002))));
+    }
+
+    #[tokio::test]
+    async fn test_bad_bgp_id() {
+        for (asn, bgp_id) in [(65002, "0.0.0.0"), (65000, "192.0.2.1")] {
+            let (mut feeder, peer, _send_updates) = silent_peer().await;
+            let session = tokio::spawn(async move { feeder.idle().await });
+            let (peer_rx, peer_tx) = peer.into_split();
+            let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+            let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+            // The second peer is internal and uses our identifier
+            let open = Open::new_easy(asn, 90, bgp_id.parse().unwrap(), Capabilities::default());
+            peer_tx.send(Message::Open(open)).await.unwrap();
+            let Some(Ok(Message::Notification(notification))) = peer_rx.next().await else {
+                panic!("expected a NOTIFICATION");
+            };
+            assert_eq!(
+                notification.error_subcode,
+                OpenMessageErrorSubcode::BadBgpIdentifier as u8
+            );
+            let result = session.await.unwrap();
+            assert!(matches!(result, Err(Error::InvalidBgpId(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unacceptable_hold_time() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        let session = tokio::spawn(async move { feeder.idle().await });
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        let open = Open::new_easy(
+            65001,
+            2,
+            "192.0.2.2".parse().unwrap(),
+            Capabilities::default(),
+        );
+        peer_tx.send(Message::Open(open)).await.unwrap();
+        let Some(Ok(Message::Notification(notification))) = peer_rx.next().await else {
+            panic!("expected a NOTIFICATION");
+        };
+        assert_eq!(
+            notification.error_subcode,
+            OpenMessageErrorSubcode::UnacceptableHoldTime as u8
+        );
+        let result = session.await.unwrap();
+        assert!(matches!(result, Err(Error::UnacceptableHoldTime(2))));
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_optional_parameter() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        let session = tokio::spawn(async move { feeder.idle().await });
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        let mut open = Open::new_easy(
+            65001,
+            90,
+            "192.0.2.2".parse().unwrap(),
+            Capabilities::default(),
+        );
+        open.opt_params =
+            capability::OptionalParameters(vec![capability::OptionalParameterValue::Unsupported(
+                0x99,
+                Bytes::from_static(b"\xab"),
+            )]);
+        peer_tx.send(Message::Open(open)).await.unwrap();
+        let Some(Ok(Message::Notification(notification))) = peer_rx.next().await else {
+            panic!("expected a NOTIFICATION");
+        };
+        assert_eq!(
+            notification,
+            Notification::unsupported_optional_parameter(0x99)
+        );
+        let result = session.await.unwrap();
+        assert!(matches!(
+            result,
+            Err(Error::UnsupportedOptionalParameter(0x99))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connection_collision() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        feeder.set_collision_check(Box::new(|peer_id| {
+            assert_eq!(peer_id, "192.0.2.2".parse::<Ipv4Addr>().unwrap());
+            None
+        }));
+        let session = tokio::spawn(async move {
+            let result = feeder.idle().await;
+            (feeder, result)
+        });
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, peer_codec());
+        let open = Open::new_easy(
+            65001,
+            90,
+            "192.0.2.2".parse().unwrap(),
+            Capabilities::default(),
+        );
+        peer_tx.send(Message::Open(open)).await.unwrap();
+        let Some(Ok(Message::Open(_))) = peer_rx.next().await else {
+            panic!("expected an OPEN");
+        };
+        let Some(Ok(Message::Notification(notification))) = peer_rx.next().await else {
+            panic!("expected a NOTIFICATION");
+        };
+        assert_eq!(notification.error_code, NotificationErrorCode::Cease);
+        assert_eq!(
+            notification.error_subcode,
+            CeaseSubcode::ConnectionCollisionResolution as u8
+        );
+        let (feeder, result) = session.await.unwrap();
+        assert!(matches!(result, Err(Error::ConnectionCollision)));
+        // The session closed before reaching Established
+        assert!(feeder.negotiated().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_message() {
+        use pabgp::MessageHeaderErrorSubcode;
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        let session = tokio::spawn(async move { feeder.established().await });
+        let (peer_rx, peer_tx) = peer.into_split();
+        let mut peer_rx = FramedRead::new(peer_rx, peer_codec());
+        let mut peer_tx = FramedWrite::new(peer_tx, tokio_util::codec::BytesCodec::new());
+        // A KEEPALIVE with a broken marker
+        let mut keepalive = [0xff; 19];
+        keepalive[0] = 0;
+        keepalive[16..].copy_from_slice(&[0, 19, 4]);
+        peer_tx
+            .send(Bytes::copy_from_slice(&keepalive))
+            .await
+            .unwrap();
+        let notification = loop {
+            match peer_rx.next().await {
+                Some(Ok(Message::Notification(notification))) => break notification,
+                Some(Ok(_)) => {}
+                other => panic!("expected a NOTIFICATION, got {other:?}"),
+            }
+        };
+        assert_eq!(
+            notification.error_code,
+            NotificationErrorCode::MessageHeaderError
+        );
+        assert_eq!(
+            notification.error_subcode,
+            MessageHeaderErrorSubcode::ConnectionNotSynchronized as u8
+        );
+        let result = session.await.unwrap();
+        assert!(matches!(result, Err(Error::Packet(PacketError::Marker))));
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        drop(peer);
+        let result = feeder.idle().await;
+        assert!(matches!(result, Err(Error::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_connection_truncated() {
+        let (mut feeder, peer, _send_updates) = silent_peer().await;
+        let session = tokio::spawn(async move { feeder.idle().await });
+        let (_, peer_tx) = peer.into_split();
+        let mut peer_tx = FramedWrite::new(peer_tx, tokio_util::codec::BytesCodec::new());
+        // Half of a message header
+        peer_tx.send(Bytes::from_static(&[0xff; 10])).await.unwrap();
+        drop(peer_tx);
+        let result = session.await.unwrap();
+        assert!(matches!(result, Err(Error::Truncated(10))));
+    }
+
+    #[cfg(all(feature = "tcp-md5", target_os = "linux"))]
+    #[tokio::test]
+    async fn test_tcp_md5sig() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        set_tcp_md5sig(&listener, addr.ip(), b"secret").unwrap();
+        let (_send_updates, recv_updates) = broadcast::channel(1);
+        let feeder = Feeder::connect_to(
+            addr,
+            Some(b"secret"),
+            empty_database(),
+            recv_updates,
+            65000,
+            "192.0.2.1".parse().unwrap(),
+            "192.0.2.1".parse().unwrap(),
+        );
+        let (feeder, accepted) = tokio::join!(feeder, listener.accept());
+        assert!(feeder.is_ok());
+        assert!(accepted.is_ok());
+        let key = [0; TCP_MD5_MAX_KEY_LEN + 1];
+        let err = set_tcp_md5sig(&listener, addr.ip(), &key).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(not(all(feature = "tcp-md5", target_os = "linux")))]
+    #[tokio::test]
+    async fn test_tcp_md5sig_unsupported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let err = set_tcp_md5sig(&listener, addr.ip(), b"secret").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    }
+}
@@ -7,52 +7,134 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use crate::endec::Component;
+use crate::prelude::*;
 use bytes::{Buf, BufMut, Bytes};
+use core::fmt;
+use core::ops::Deref;
 use enum_primitive_derive::Primitive;
 use num_traits::FromPrimitive;
-use std::ops::Deref;
 
 /// Check if the remaining buffer length is enough for the expected length
 macro_rules! check_remaining_len {
     ($src:expr, $len:expr, $name:expr) => {
         let cmp = $src.remaining().cmp(&$len);
         match $src.remaining().cmp(&$len) {
-            std::cmp::Ordering::Equal => {}
+            core::cmp::Ordering::Equal => {}
             _ => return Err($crate::Error::InternalLength($name, cmp)),
         }
     };
 }
+
+/// Optional parameters length and type marking the extended format (RFC 9072 Section 2)
+const EXTENDED_PARAMS_MARKER: u8 = 255;
+
 /// A list of BGP optional parameters
 #[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct OptionalParameters(pub Vec<OptionalParameterValue>);
 
 impl Component for OptionalParameters {
     fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
         // RFC 4271 4.2 Optional Parameters Length
-        let len = src.get_u8() as usize;
+        check_min_len!(src, 1, "optional parameter length");
+        let len = src.get_u8();
+        // RFC 9072 2. Non-Ext OP Len and Non-Ext OP Type
+        let extended =
+            len == EXTENDED_PARAMS_MARKER && src.first() == Some(&EXTENDED_PARAMS_MARKER);
+        let len = if extended {
+            if src.remaining() < 3 {
+                return Err(crate::Error::InternalLength(
+                    "optional parameter length",
+                    core::cmp::Ordering::Less,
+                ));
+            }
+            src.advance(1);
+            src.get_u16() as usize
+        } else {
+            len as usize
+        };
         check_remaining_len!(src, len, "optional parameter length");
         let mut opt_params = Vec::new();
         // RFC 4271 4.2 Optional Parameters
         while src.has_remaining() {
-            let param = OptionalParameterValue::from_bytes(src)?;
+            let param = OptionalParameterValue::from_bytes_extended(src, extended)?;
             opt_params.push(param);
         }
         Ok(Self(opt_params))
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_to_bytes(dst)
+            .expect("Optional parameters length overflow")
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        let too_long =
+            |_| crate::Error::InternalLength("optional parameters", core::cmp::Ordering::Greater);
+        let extended = self.needs_extended();
         let mut len = 0;
-        let length_pos = dst.len();
-        dst.put_u8(0); // Placeholder for length
+        let length_pos = if extended {
+            dst.put_u8(EXTENDED_PARAMS_MARKER);
+            dst.put_u8(EXTENDED_PARAMS_MARKER);
+            let length_pos = dst.len();
+            dst.put_u16(0); // Placeholder for length
+            length_pos
+        } else {
+            let length_pos = dst.len();
+            dst.put_u8(0); // Placeholder for length
+            length_pos
+        };
         for param in self.0 {
-            len += param.to_bytes(dst);
+            len += param.try_to_bytes_extended(dst, extended)?;
+        }
+        if extended {
+            let len_bytes = u16::try_from(len).map_err(too_long)?.to_be_bytes();
+            dst[length_pos..length_pos + 2].copy_from_slice(&len_bytes);
+            Ok(len + 4) // Markers and length
+        } else {
+            dst[length_pos] = u8::try_from(len).map_err(too_long)?;
+            Ok(len + 1) // Length
         }
-        dst[length_pos] = u8::try_from(len).expect("Optional parameters length overflow");
-        len + 1 // Length
     }
 
     fn encoded_len(&self) -> usize {
-        self.0.iter().map(Component::encoded_len).sum::<usize>() + 1 // Length
+        let extended = self.needs_extended();
+        let len = self
+            .0
+            .iter()
+            .map(|param| param.encoded_len_extended(extended))
+            .sum::<usize>();
+        if extended {
+            len + 4 // Markers and length
+        } else {
+            len + 1 // Length
+        }
+    }
+}
+
+impl OptionalParameters {
+    /// Whether the parameters are too long for the single-octet length field
+    /// and need the extended format (RFC 9072 Section 2)
+    fn needs_extended(&self) -> bool {
+        self.0.iter().map(Component::encoded_len).sum::<usize>() > usize::from(u8::MAX)
+    }
+
+    /// Get the capabilities of every Capabilities parameter as one list
+    ///
+    /// RFC 5492 Section 4 allows a speaker to split its capabilities across
+    /// multiple parameters. Unsupported parameters are skipped.
+    #[must_use]
+    pub fn all_capabilities(&self) -> Capabilities {
+        self.0
+            .iter()
+            .filter_map(|param| match param {
+                OptionalParameterValue::Capabilities(caps) => Some(caps.iter()),
+                OptionalParameterValue::Unsupported(..) => None,
+            })
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>()
+            .into()
     }
 }
 
@@ -72,9 +154,15 @@ impl Deref for OptionalParameters {
 
 /// BGP optional parameter (RFC 4271 4.2)
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 pub enum OptionalParameterValue {
     Capabilities(Capabilities),
+    /// Other unsupported optional parameter
+    Unsupported(
+        u8,
+        #[cfg_attr(feature = "impl-serde", serde(with = "crate::serde_hex"))] Bytes,
+    ),
 }
 
 /// BGP optional parameter types
@@ -87,61 +175,142 @@ pub enum OptionalParameterType {
 
 impl Component for OptionalParameterValue {
     fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
+        Self::from_bytes_extended(src, false)
+    }
+
+    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.to_bytes_extended(dst, false)
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        self.try_to_bytes_extended(dst, false)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.encoded_len_extended(false)
+    }
+}
+
+impl OptionalParameterValue {
+    /// Decode an optional parameter, with a two-octet length if the extended
+    /// format (RFC 9072) is used.
+    ///
+    /// # Errors
+    /// Returns an error if the parameter is malformed.
+    pub fn from_bytes_extended(
+        src: &mut bytes::Bytes,
+        extended: bool,
+    ) -> Result<Self, crate::Error> {
+        check_min_len!(src, if extended { 3 } else { 2 }, "optional parameter");
         // RFC 5492 4. Optional Parameters -> Parameter Type
         let param_type = src.get_u8();
         // RFC 5492 4. Optional Parameters -> Parameter Length
-        let param_len = src.get_u8() as usize;
-        check_remaining_len!(src, param_len, "optional parameter");
+        let param_len = if extended {
+            src.get_u16() as usize
+        } else {
+            src.get_u8() as usize
+        };
+        if src.remaining() < param_len {
+            return Err(crate::Error::InternalLength(
+                "optional parameter",
+                core::cmp::Ordering::Less,
+            ));
+        }
+        // Avoid processing the following parameters
+        let mut src = src.split_to(param_len);
         match OptionalParameterType::from_u8(param_type) {
             Some(OptionalParameterType::Capabilities) => {
-                let cap = Capabilities::from_bytes(src)?;
+                let cap = Capabilities::from_bytes(&mut src)?;
                 Ok(Self::Capabilities(cap))
             }
-            _ => Err(crate::Error::InternalType(
-                "optional parameter",
-                u16::from(param_type),
-            )),
+            _ => Ok(Self::Unsupported(param_type, src)),
         }
     }
 
-    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
-        let type_pos = dst.len();
-        dst.put_u8(0); // Placeholder for type
-        let len_pos = dst.len();
-        dst.put_u8(0); // Placeholder for length
+    /// Encode an optional parameter, with a two-octet length if `extended`.
+    ///
+    /// Returns the number of bytes written.
+    ///
+    /// # Panics
+    /// Panics if the parameter is too long for its length field.
+    pub fn to_bytes_extended(self, dst: &mut bytes::BytesMut, extended: bool) -> usize {
+        self.try_to_bytes_extended(dst, extended)
+            .expect("Optional parameter length overflow")
+    }
+
+    /// Encode an optional parameter, with a two-octet length if `extended`.
+    ///
+    /// Returns the number of bytes written.
+    ///
+    /// # Errors
+    /// Returns `Error::InternalLength` if the parameter is too long for its length field.
+    pub fn try_to_bytes_extended(
+        self,
+        dst: &mut bytes::BytesMut,
+        extended: bool,
+    ) -> Result<usize, crate::Error> {
+        let too_long =
+            |_| crate::Error::InternalLength("optional parameter", core::cmp::Ordering::Greater);
+        let (param_type, data_len) = match &self {
+            Self::Capabilities(cap) => {
+                (OptionalParameterType::Capabilities as u8, cap.encoded_len())
+            }
+            Self::Unsupported(param_type, data) => (*param_type, data.len()),
+        };
+        dst.put_u8(param_type);
+        if extended {
+            dst.put_u16(u16::try_from(data_len).map_err(too_long)?);
+        } else {
+            dst.put_u8(u8::try_from(data_len).map_err(too_long)?);
+        }
         match self {
             Self::Capabilities(cap) => {
-                let len = cap.to_bytes(dst);
-                dst[type_pos] = OptionalParameterType::Capabilities as u8;
-                dst[len_pos] = u8::try_from(len).expect("Capabilities length overflow");
-                len + 2 // Type and length
+                cap.try_to_bytes(dst)?;
             }
+            Self::Unsupported(_, data) => dst.put_slice(&data),
         }
+        Ok(encoded_param_len(data_len, extended))
     }
 
-    fn encoded_len(&self) -> usize {
-        match self {
-            Self::Capabilities(cap) => cap.encoded_len() + 2, // Type and length
-        }
+    /// Find the encoded length of the parameter, with a two-octet length if `extended`.
+    #[must_use]
+    pub fn encoded_len_extended(&self, extended: bool) -> usize {
+        let data_len = match self {
+            Self::Capabilities(cap) => cap.encoded_len(),
+            Self::Unsupported(_, data) => data.len(),
+        };
+        encoded_param_len(data_len, extended)
+    }
+}
+
+/// Length of an optional parameter with `data_len` octets of data
+const fn encoded_param_len(data_len: usize, extended: bool) -> usize {
+    // Type and length
+    if extended {
+        data_len + 3
+    } else {
+        data_len + 2
     }
 }
 
 /// BGP capability
 // "a BGP speaker MUST be prepared to accept such multiple instances," so a Vec must be used
 #[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Capabilities(Vec<Value>);
 
 impl Component for Capabilities {
     fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
         let mut cap = Vec::new();
         while src.has_remaining() {
+            check_min_len!(src, 2, "capability");
             // RFC 5492 4. Optional Parameters -> Capability Code
             let code = src.get_u8();
             // RFC 5492 4. Optional Parameters -> Capability Length
             let len = src.get_u8() as usize;
+            check_min_len!(src, len, "capability");
             // Avoid processing trailing bytes
             let mut src = src.split_to(len);
-            check_remaining_len!(src, len, "capability");
             log::trace!("Capability code: {code}, length: {len}, data: {src:?}");
             let value = match Type::from_u8(code) {
                 Some(Type::MultiProtocol) => {
@@ -152,9 +321,18 @@ impl Component for Capabilities {
                     Value::ExtendedNextHop(ExtendedNextHop::from_bytes(&mut src)?)
                 }
                 Some(Type::ExtendedMessage) => Value::ExtendedMessage,
-                Some(Type::FourOctetAsNumber) => {
-                    Value::FourOctetAsNumber(FourOctetAsNumber { asn: src.get_u32() })
+                Some(Type::GracefulRestart) => {
+                    Value::GracefulRestart(GracefulRestart::from_bytes(&mut src)?)
                 }
+                Some(Type::FourOctetAsNumber) => Value::FourOctetAsNumber(FourOctetAsNumber {
+                    asn: u32::from_bytes(&mut src)?,
+                }),
+                Some(Type::AddPath) => Value::AddPath(AddPath::from_bytes(&mut src)?),
+                Some(Type::Role) if len == 1 => match RfcRole::from_u8(src[0]) {
+                    Some(role) => Value::Role(role),
+                    // Unassigned role value
+                    None => Value::Unsupported(code, src.copy_to_bytes(src.len())),
+                },
                 _ => Value::Unsupported(code, src.copy_to_bytes(src.len())),
             };
             cap.push(value);
@@ -163,6 +341,10 @@ impl Component for Capabilities {
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_to_bytes(dst).expect("Capability length overflow")
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
         let mut len = 0;
         for value in self.0 {
             dst.put_u8((&value).into());
@@ -172,16 +354,24 @@ impl Component for Capabilities {
                 Value::MultiProtocol(mp) => mp.to_bytes(dst),
                 Value::RouteRefresh | Value::ExtendedMessage => 0,
                 Value::ExtendedNextHop(enh) => enh.to_bytes(dst),
+                Value::GracefulRestart(gr) => gr.try_to_bytes(dst)?,
                 Value::FourOctetAsNumber(four) => four.asn.to_bytes(dst),
+                Value::AddPath(ap) => ap.to_bytes(dst),
+                Value::Role(role) => {
+                    dst.put_u8(role as u8);
+                    1
+                }
                 Value::Unsupported(_, data) => {
                     dst.put_slice(&data);
                     data.len()
                 }
             };
-            dst[len_pos] = u8::try_from(value_len).expect("Capability length overflow");
+            dst[len_pos] = u8::try_from(value_len).map_err(|_| {
+                crate::Error::InternalLength("capability", core::cmp::Ordering::Greater)
+            })?;
             len += value_len + 2; // Code and length
         }
-        len
+        Ok(len)
     }
 
     fn encoded_len(&self) -> usize {
@@ -192,7 +382,10 @@ impl Component for Capabilities {
                     Value::MultiProtocol(mp) => mp.encoded_len(),
                     Value::RouteRefresh | Value::ExtendedMessage => 0,
                     Value::ExtendedNextHop(enh) => enh.encoded_len(),
+                    Value::GracefulRestart(gr) => gr.encoded_len(),
                     Value::FourOctetAsNumber(_) => 4,
+                    Value::AddPath(ap) => ap.encoded_len(),
+                    Value::Role(_) => 1,
                     Value::Unsupported(_, data) => data.len(),
                 };
                 len + 2 // Code and length
@@ -218,7 +411,7 @@ impl Deref for Capabilities {
 impl Capabilities {
     /// Check if a specific capability is present
     pub fn has(&self, cap: &Value) -> bool {
-        self.0.iter().any(|v| *v == *cap)
+        self.0.contains(cap)
     }
 
     /// Check if ipv4 unicast multi-protocol capability is present
@@ -245,6 +438,48 @@ impl Capabilities {
         self.has(&Value::RouteRefresh)
     }
 
+    /// Check if extended message capability is present
+    #[must_use]
+    pub fn has_extended_message(&self) -> bool {
+        self.has(&Value::ExtendedMessage)
+    }
+
+    /// Get the graceful restart capability if present
+    #[must_use]
+    pub fn graceful_restart(&self) -> Option<&GracefulRestart> {
+        self.0.iter().find_map(|v| {
+            if let Value::GracefulRestart(gr) = v {
+                Some(gr)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Get the four-octet AS number if present (RFC 6793)
+    #[must_use]
+    pub fn four_octet_asn(&self) -> Option<u32> {
+        self.0.iter().find_map(|v| {
+            if let Value::FourOctetAsNumber(FourOctetAsNumber { asn }) = v {
+                Some(*asn)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Get the BGP role if present
+    #[must_use]
+    pub fn role(&self) -> Option<RfcRole> {
+        self.0.iter().find_map(|v| {
+            if let Value::Role(role) = v {
+                Some(*role)
+            } else {
+                None
+            }
+        })
+    }
+
     /// Check if an extended next hop capability is present
     #[must_use]
     pub fn has_extended_next_hop(&self, afi: Afi, safi: Safi, next_hop_afi: Afi) -> bool {
@@ -256,13 +491,51 @@ impl Capabilities {
         self.0.iter().any(|v| {
             // Find the extended next hop capability
             if let Value::ExtendedNextHop(enh) = v {
-                enh.0.iter().any(|v| *v == looking_for)
+                enh.0.contains(&looking_for)
             } else {
                 false
             }
         })
     }
 
+    /// Get the ADD-PATH send/receive mode for an AFI/SAFI if present
+    #[must_use]
+    pub fn add_path(&self, afi: Afi, safi: Safi) -> Option<AddPathMode> {
+        self.0.iter().find_map(|v| {
+            if let Value::AddPath(ap) = v {
+                ap.0.iter()
+                    .find(|t| t.afi == afi && t.safi == safi)
+                    .map(|t| t.send_receive)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// AFI/SAFI pairs for which path identifiers are received, given that
+    /// these are the capabilities we advertised and `peer` are the peer's
+    ///
+    /// ADD-PATH is negotiated for receiving if we are able to receive and the
+    /// peer is able to send multiple paths (RFC 7911 Section 4).
+    #[must_use]
+    pub fn add_path_receive(&self, peer: &Self) -> Vec<(Afi, Safi)> {
+        self.0
+            .iter()
+            .filter_map(|v| match v {
+                Value::AddPath(ap) => Some(ap.0.iter()),
+                _ => None,
+            })
+            .flatten()
+            .filter(|t| {
+                t.send_receive.can_receive()
+                    && peer
+                        .add_path(t.afi, t.safi)
+                        .is_some_and(AddPathMode::can_send)
+            })
+            .map(|t| (t.afi, t.safi))
+            .collect()
+    }
+
     /// Get the value of an unsupported capability
     #[must_use]
     pub fn get_unsupported(&self, code: u8) -> Option<&Bytes> {
@@ -282,6 +555,7 @@ impl Capabilities {
 
 /// BGP capability (RFC 3392/5492)
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 pub enum Value {
     /// BGP multi-protocol capability (RFC 2858)
@@ -292,10 +566,19 @@ pub enum Value {
     ExtendedNextHop(ExtendedNextHop),
     /// BGP extended message capability (RFC 8654)
     ExtendedMessage,
+    /// BGP graceful restart capability (RFC 4724)
+    GracefulRestart(GracefulRestart),
     /// BGP four-octet AS number capability (RFC 6793)
     FourOctetAsNumber(FourOctetAsNumber),
+    /// BGP ADD-PATH capability (RFC 7911)
+    AddPath(AddPath),
+    /// BGP role capability (RFC 9234)
+    Role(RfcRole),
     /// Other unsupported capability
-    Unsupported(u8, Bytes),
+    Unsupported(
+        u8,
+        #[cfg_attr(feature = "impl-serde", serde(with = "crate::serde_hex"))] Bytes,
+    ),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Primitive)]
@@ -306,7 +589,59 @@ pub enum Type {
     RouteRefresh = 2,
     ExtendedNextHop = 5,
     ExtendedMessage = 6,
+    Role = 9,
+    GracefulRestart = 64,
     FourOctetAsNumber = 65,
+    AddPath = 69,
+}
+
+/// Compact summary of the capability, e.g. `MP(Ipv4 Unicast)`
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MultiProtocol(mp) => write!(f, "MP({:?} {:?})", mp.afi, mp.safi),
+            Self::RouteRefresh => f.write_str("ROUTE_REFRESH"),
+            Self::ExtendedNextHop(enh) => {
+                f.write_str("EXTENDED_NEXT_HOP(")?;
+                for (i, value) in enh.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(
+                        f,
+                        "{:?} {:?} via {:?}",
+                        value.afi, value.safi, value.next_hop_afi
+                    )?;
+                }
+                f.write_str(")")
+            }
+            Self::ExtendedMessage => f.write_str("EXTENDED_MESSAGE"),
+            Self::GracefulRestart(gr) => {
+                write!(f, "GRACEFUL_RESTART({}s", gr.restart_time)?;
+                for entry in &gr.entries {
+                    write!(f, ", {:?} {:?}", entry.afi, entry.safi)?;
+                }
+                f.write_str(")")
+            }
+            Self::FourOctetAsNumber(four) => write!(f, "AS4({})", four.asn),
+            Self::AddPath(ap) => {
+                f.write_str("ADD_PATH(")?;
+                for (i, tuple) in ap.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(
+                        f,
+                        "{:?} {:?} {:?}",
+                        tuple.afi, tuple.safi, tuple.send_receive
+                    )?;
+                }
+                f.write_str(")")
+            }
+            Self::Role(role) => write!(f, "ROLE({role:?})"),
+            Self::Unsupported(code, data) => write!(f, "UNKNOWN({code})={} bytes", data.len()),
+        }
+    }
 }
 
 impl From<&Value> for u8 {
@@ -316,14 +651,44 @@ impl From<&Value> for u8 {
             Value::RouteRefresh => Type::RouteRefresh as Self,
             Value::ExtendedNextHop(_) => Type::ExtendedNextHop as Self,
             Value::ExtendedMessage => Type::ExtendedMessage as Self,
+            Value::GracefulRestart(_) => Type::GracefulRestart as Self,
             Value::FourOctetAsNumber(_) => Type::FourOctetAsNumber as Self,
+            Value::AddPath(_) => Type::AddPath as Self,
+            Value::Role(_) => Type::Role as Self,
             Value::Unsupported(code, _) => *code,
         }
     }
 }
 
+/// BGP role (RFC 9234 Section 4.1)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Primitive)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum RfcRole {
+    Provider = 0,
+    RouteServer = 1,
+    RouteServerClient = 2,
+    Customer = 3,
+    Peer = 4,
+}
+
+impl RfcRole {
+    /// The role the peer must have for the roles to match (RFC 9234 Section 4.2)
+    #[must_use]
+    pub const fn expected_peer_role(self) -> Self {
+        match self {
+            Self::Provider => Self::Customer,
+            Self::RouteServer => Self::RouteServerClient,
+            Self::RouteServerClient => Self::RouteServer,
+            Self::Customer => Self::Provider,
+            Self::Peer => Self::Peer,
+        }
+    }
+}
+
 /// BGP multi-protocol capability value field (RFC 2858 Section 7)
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MultiProtocol {
     pub afi: Afi,
     pub safi: Safi,
@@ -331,13 +696,14 @@ pub struct MultiProtocol {
 
 impl Component for MultiProtocol {
     fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
+        check_min_len!(src, 4, "MultiProtocol");
         let afi = src.get_u16();
         let afi =
             Afi::try_from(afi).map_err(|_| crate::Error::InternalType("MultiProtocol AFI", afi))?;
         let _ = src.get_u8(); // Reserved
-        let safi = src.get_u8().into();
+        let safi = src.get_u8();
         let safi = Safi::try_from(safi)
-            .map_err(|_| crate::Error::InternalType("MultiProtocol SAFI", safi))?;
+            .map_err(|_| crate::Error::InternalType("MultiProtocol SAFI", u16::from(safi)))?;
         Ok(Self { afi, safi })
     }
 
@@ -357,28 +723,46 @@ impl Component for MultiProtocol {
 ///
 /// # References
 /// [Address Family Numbers](https://www.iana.org/assignments/address-family-numbers/address-family-numbers.xhtml)
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Primitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 #[repr(u16)]
 pub enum Afi {
     Ipv4 = 1,
     Ipv6 = 2,
+    /// Layer 2 VPN (RFC 4761, RFC 7432)
+    L2Vpn = 25,
 }
 
 /// BGP subsequent address family identifier
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Primitive)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
-#[repr(u16)]
+#[repr(u8)]
 pub enum Safi {
     Unicast = 1,
     Multicast = 2,
     MplsLabel = 4,
+    /// Ethernet VPN (RFC 7432)
+    Evpn = 70,
+    /// MPLS-labeled VPN address (RFC 4364), IANA SAFI 128
     Vpn = 128,
     VpnMulticast = 129,
+    /// Flow Specification (RFC 8955)
+    FlowSpecUnicast = 133,
+}
+
+impl Safi {
+    /// Whether routes in this SAFI carry labels and a route distinguisher (RFC 4364, RFC 6513)
+    #[must_use]
+    pub const fn is_vpn(self) -> bool {
+        matches!(self, Self::Vpn | Self::VpnMulticast)
+    }
 }
 
 /// BGP extended next hop capability (RFC 8950)
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ExtendedNextHop(pub Vec<ExtendedNextHopValue>);
 
 impl From<Vec<ExtendedNextHopValue>> for ExtendedNextHop {
@@ -397,6 +781,7 @@ impl Deref for ExtendedNextHop {
 
 /// BGP extended next hop value field (RFC 8950)
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ExtendedNextHopValue {
     pub afi: Afi,
     pub safi: Safi,
@@ -407,6 +792,7 @@ impl Component for ExtendedNextHop {
     fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
         let mut values = Vec::with_capacity(src.len() / 6);
         while src.has_remaining() {
+            check_min_len!(src, 6, "ExtendedNextHop");
             let afi = src.get_u16();
             let afi = Afi::try_from(afi)
                 .map_err(|_| crate::Error::InternalType("ExtendedNextHop AFI", afi))?;
@@ -441,8 +827,100 @@ impl Component for ExtendedNextHop {
     }
 }
 
+/// BGP graceful restart capability value field (RFC 4724 Section 3)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GracefulRestart {
+    /// Restart flags (the high 4 bits of the first two octets)
+    pub flags: u8,
+    /// Restart time in seconds (the low 12 bits of the first two octets)
+    pub restart_time: u16,
+    pub entries: Vec<GracefulRestartValue>,
+}
+
+impl GracefulRestart {
+    /// Restart State flag
+    pub const RESTART_STATE: u8 = 0x8;
+
+    /// Check if the Restart State flag is set
+    #[must_use]
+    pub const fn is_restarting(&self) -> bool {
+        self.flags & Self::RESTART_STATE != 0
+    }
+}
+
+/// Per-AFI/SAFI entry of the graceful restart capability (RFC 4724 Section 3)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GracefulRestartValue {
+    pub afi: Afi,
+    pub safi: Safi,
+    /// Whether the forwarding state has been preserved for this AFI/SAFI
+    pub forwarding_state: bool,
+}
+
+impl Component for GracefulRestart {
+    fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
+        check_min_len!(src, 2, "GracefulRestart");
+        let flags_time = src.get_u16();
+        let [flags_high, _] = flags_time.to_be_bytes();
+        let flags = flags_high >> 4;
+        let restart_time = flags_time & 0x0fff;
+        let mut entries = Vec::with_capacity(src.len() / 4);
+        while src.has_remaining() {
+            check_min_len!(src, 4, "GracefulRestart");
+            let afi = src.get_u16();
+            let safi = src.get_u8();
+            let af_flags = src.get_u8();
+            // The peer may support graceful restart for AFI/SAFI pairs we do
+            // not know, which must not prevent the session from coming up
+            let (Ok(afi), Ok(safi)) = (Afi::try_from(afi), Safi::try_from(safi)) else {
+                log::debug!("Ignoring graceful restart for unknown AFI {afi} SAFI {safi}");
+                continue;
+            };
+            entries.push(GracefulRestartValue {
+                afi,
+                safi,
+                forwarding_state: af_flags & 0x80 != 0,
+            });
+        }
+        Ok(Self {
+            flags,
+            restart_time,
+            entries,
+        })
+    }
+
+    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_to_bytes(dst)
+            .expect("GracefulRestart field overflow")
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        if self.flags > 0x0f || self.restart_time > 0x0fff {
+            return Err(crate::Error::InternalLength(
+                "GracefulRestart",
+                core::cmp::Ordering::Greater,
+            ));
+        }
+        let len = self.encoded_len();
+        dst.put_u16((u16::from(self.flags) << 12) | self.restart_time);
+        for entry in self.entries {
+            dst.put_u16(entry.afi as u16);
+            dst.put_u8(entry.safi as u8);
+            dst.put_u8(if entry.forwarding_state { 0x80 } else { 0 });
+        }
+        Ok(len)
+    }
+
+    fn encoded_len(&self) -> usize {
+        2 + self.entries.len() * 4
+    }
+}
+
 /// BGP four-octet AS number capability value field (RFC 6793)
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FourOctetAsNumber {
     pub asn: u32,
 }
@@ -461,11 +939,110 @@ impl Deref for FourOctetAsNumber {
     }
 }
 
+/// BGP ADD-PATH capability (RFC 7911 Section 4)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AddPath(pub Vec<AddPathTuple>);
+
+impl From<Vec<AddPathTuple>> for AddPath {
+    fn from(values: Vec<AddPathTuple>) -> Self {
+        Self(values)
+    }
+}
+
+impl Deref for AddPath {
+    type Target = Vec<AddPathTuple>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// BGP ADD-PATH capability tuple (RFC 7911 Section 4)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AddPathTuple {
+    pub afi: Afi,
+    pub safi: Safi,
+    pub send_receive: AddPathMode,
+}
+
+/// Whether the sender is able to send and/or receive multiple paths
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Primitive)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum AddPathMode {
+    Receive = 1,
+    Send = 2,
+    Both = 3,
+}
+
+impl AddPathMode {
+    /// Check if the sender is able to receive multiple paths
+    #[must_use]
+    pub const fn can_receive(self) -> bool {
+        self as u8 & Self::Receive as u8 != 0
+    }
+
+    /// Check if the sender is able to send multiple paths
+    #[must_use]
+    pub const fn can_send(self) -> bool {
+        self as u8 & Self::Send as u8 != 0
+    }
+}
+
+impl Component for AddPath {
+    fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
+        let mut values = Vec::with_capacity(src.len() / 4);
+        while src.has_remaining() {
+            check_min_len!(src, 4, "AddPath");
+            let afi = src.get_u16();
+            let safi = src.get_u8();
+            let send_receive = src.get_u8();
+            // Tuples we cannot interpret are left out of the negotiation
+            // instead of failing the whole OPEN
+            let (Ok(afi), Ok(safi), Some(send_receive)) = (
+                Afi::try_from(afi),
+                Safi::try_from(safi),
+                AddPathMode::from_u8(send_receive),
+            ) else {
+                log::debug!(
+                    "Ignoring ADD-PATH for AFI {afi} SAFI {safi} with Send/Receive {send_receive}"
+                );
+                continue;
+            };
+            values.push(AddPathTuple {
+                afi,
+                safi,
+                send_receive,
+            });
+        }
+        Ok(Self(values))
+    }
+
+    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        let len = self.encoded_len();
+        for value in self.0 {
+            dst.put_u16(value.afi as u16);
+            dst.put_u8(value.safi as u8);
+            dst.put_u8(value.send_receive as u8);
+        }
+        len
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.len() * 4
+    }
+}
+
 /// Builder for BGP capabilities
 #[derive(Debug, Default)]
 pub struct CapabilitiesBuilder {
     data: Vec<Value>,
+    /// Number of capabilities at the start of `data` from `from_existing`
+    existing: usize,
     extended_next_hops: Vec<ExtendedNextHopValue>,
+    add_paths: Vec<AddPathTuple>,
 }
 
 impl CapabilitiesBuilder {
@@ -475,6 +1052,21 @@ impl CapabilitiesBuilder {
         Self::default()
     }
 
+    /// Create a capabilities builder starting with existing capabilities
+    ///
+    /// They are kept as they are, in the same order and including duplicates
+    /// such as repeated multi-protocol or unsupported capabilities.
+    /// Extended next hop and ADD-PATH tuples added later are merged into
+    /// the existing capabilities of the same type.
+    #[must_use]
+    pub fn from_existing(capabilities: Capabilities) -> Self {
+        Self {
+            existing: capabilities.0.len(),
+            data: capabilities.0,
+            ..Self::default()
+        }
+    }
+
     /// Add a multi-protocol capability
     #[must_use]
     pub fn multi_protocol(mut self, afi: Afi, safi: Safi) -> Self {
@@ -502,6 +1094,13 @@ impl CapabilitiesBuilder {
         self
     }
 
+    /// Add an extended message capability
+    #[must_use]
+    pub fn extended_message(mut self) -> Self {
+        self.data.push(Value::ExtendedMessage);
+        self
+    }
+
     /// Add an extended next hop capability
     #[must_use]
     pub fn extended_next_hop(mut self, value: ExtendedNextHop) -> Self {
@@ -531,6 +1130,21 @@ impl CapabilitiesBuilder {
         self
     }
 
+    /// Add a graceful restart capability
+    #[must_use]
+    pub fn graceful_restart(
+        mut self,
+        restart_time: u16,
+        entries: Vec<GracefulRestartValue>,
+    ) -> Self {
+        self.data.push(Value::GracefulRestart(GracefulRestart {
+            flags: 0,
+            restart_time,
+            entries,
+        }));
+        self
+    }
+
     /// Add a four-octet AS number capability
     #[must_use]
     pub fn four_octet_as_number(mut self, asn: u32) -> Self {
@@ -549,6 +1163,24 @@ impl CapabilitiesBuilder {
         }
     }
 
+    /// Add a BGP role capability
+    #[must_use]
+    pub fn role(mut self, role: RfcRole) -> Self {
+        self.data.push(Value::Role(role));
+        self
+    }
+
+    /// Add an ADD-PATH capability tuple
+    #[must_use]
+    pub fn add_path(mut self, afi: Afi, safi: Safi, send_receive: AddPathMode) -> Self {
+        self.add_paths.push(AddPathTuple {
+            afi,
+            safi,
+            send_receive,
+        });
+        self
+    }
+
     /// Add an unsupported capability
     #[must_use]
     pub fn other(mut self, code: u8, data: Bytes) -> Self {
@@ -557,19 +1189,108 @@ impl CapabilitiesBuilder {
     }
 
     /// Build the capabilities
+    ///
+    /// Identical capabilities added to the builder are only kept once, in the
+    /// order they were first added, and are left out if they are already in
+    /// the capabilities from [`Self::from_existing`].
+    /// All extended next hop entries are coalesced into one capability.
     #[must_use]
     pub fn build(self) -> Capabilities {
-        let extended_next_hops = ExtendedNextHop(self.extended_next_hops);
         let mut data = self.data;
-        if !extended_next_hops.0.is_empty() {
-            data.push(Value::ExtendedNextHop(extended_next_hops));
+        let added = data.split_off(self.existing);
+        extend_new(&mut data, added);
+        if !self.extended_next_hops.is_empty() {
+            let existing = data.iter_mut().find_map(|value| match value {
+                Value::ExtendedNextHop(existing) => Some(&mut existing.0),
+                _ => None,
+            });
+            if let Some(existing) = existing {
+                extend_new(existing, self.extended_next_hops);
+            } else {
+                data.push(Value::ExtendedNextHop(ExtendedNextHop(dedup_in_order(
+                    self.extended_next_hops,
+                ))));
+            }
+        }
+        if !self.add_paths.is_empty() {
+            let existing = data.iter_mut().find_map(|value| match value {
+                Value::AddPath(existing) => Some(&mut existing.0),
+                _ => None,
+            });
+            if let Some(existing) = existing {
+                extend_new(existing, self.add_paths);
+            } else {
+                data.push(Value::AddPath(AddPath(dedup_in_order(self.add_paths))));
+            }
         }
         Capabilities(data)
     }
 }
 
+/// Append the items that are not in `existing` yet, keeping the first
+/// occurrence of each
+fn extend_new<T: PartialEq>(existing: &mut Vec<T>, items: Vec<T>) {
+    for item in items {
+        if !existing.contains(&item) {
+            existing.push(item);
+        }
+    }
+}
+
+/// Remove duplicate items, keeping the first occurrence of each
+fn dedup_in_order<T: PartialEq>(items: Vec<T>) -> Vec<T> {
+    let mut result = Vec::with_capacity(items.len());
+    for item in items {
+        if !result.contains(&item) {
+            result.push(item);
+        }
+    }
+    result
+}
+
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn test_four_octet_asn() {
+        use super::*;
+        let cap = CapabilitiesBuilder::new()
+            .mp_ipv4_unicast()
+            .four_octet_as_number_if_needed(4_200_000_000)
+            .build();
+        assert_eq!(cap.four_octet_asn(), Some(4_200_000_000));
+        let cap = CapabilitiesBuilder::new()
+            .four_octet_as_number_if_needed(65000)
+            .build();
+        assert_eq!(cap.four_octet_asn(), None);
+    }
+
+    #[test]
+    fn test_cap_builder_dedup() {
+        use super::*;
+        let cap = CapabilitiesBuilder::new()
+            .mp_ipv4_unicast()
+            .route_refresh()
+            .mp_ipv4_unicast()
+            .enh_ipv4_over_ipv6()
+            .enh_ipv4_over_ipv6()
+            .build();
+        assert_eq!(
+            cap.0,
+            vec![
+                Value::MultiProtocol(MultiProtocol {
+                    afi: Afi::Ipv4,
+                    safi: Safi::Unicast
+                }),
+                Value::RouteRefresh,
+                Value::ExtendedNextHop(ExtendedNextHop(vec![ExtendedNextHopValue {
+                    afi: Afi::Ipv4,
+                    safi: Safi::Unicast,
+                    next_hop_afi: Afi::Ipv6
+                }])),
+            ]
+        );
+    }
+
     #[test]
     fn test_cap_builder() {
         use super::*;
@@ -606,4 +1327,269 @@ mod tests {
             .iter()
             .any(|v| *v == Value::Unsupported(255, Bytes::from_static(&[1, 2, 3, 4]))));
     }
+
+    #[test]
+    fn test_graceful_restart() {
+        use super::*;
+        use crate::hex_to_bytes;
+        let mut src = hex_to_bytes("40 0a 8078 0001 01 80 0002 01 00");
+        let saved = src.clone();
+        let cap = Capabilities::from_bytes(&mut src).unwrap();
+        let gr = cap.graceful_restart().unwrap();
+        assert!(gr.is_restarting());
+        assert_eq!(gr.restart_time, 120);
+        assert_eq!(
+            gr.entries,
+            vec![
+                GracefulRestartValue {
+                    afi: Afi::Ipv4,
+                    safi: Safi::Unicast,
+                    forwarding_state: true,
+                },
+                GracefulRestartValue {
+                    afi: Afi::Ipv6,
+                    safi: Safi::Unicast,
+                    forwarding_state: false,
+                },
+            ]
+        );
+        let encoded_len = cap.encoded_len();
+        let mut dst = bytes::BytesMut::new();
+        cap.to_bytes(&mut dst);
+        assert_eq!(dst, saved);
+        assert_eq!(encoded_len, dst.len());
+    }
+
+    #[test]
+    fn test_graceful_restart_out_of_range() {
+        use super::*;
+        for (flags, restart_time) in [(0x10, 120), (0, 0x1000)] {
+            let cap: Capabilities = vec![Value::GracefulRestart(GracefulRestart {
+                flags,
+                restart_time,
+                entries: Vec::new(),
+            })]
+            .into();
+            assert!(matches!(
+                cap.try_to_bytes(&mut bytes::BytesMut::new()),
+                Err(crate::Error::InternalLength(
+                    "GracefulRestart",
+                    core::cmp::Ordering::Greater
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_graceful_restart_unknown_afi_safi() {
+        use super::*;
+        use crate::hex_to_bytes;
+        // Unknown AFI 0x4000 and unknown SAFI 254 around IPv4 unicast
+        let mut src = hex_to_bytes("40 0e 0078 4000 01 80 0001 01 80 0001 fe 00");
+        let cap = Capabilities::from_bytes(&mut src).unwrap();
+        let gr = cap.graceful_restart().unwrap();
+        assert_eq!(gr.restart_time, 120);
+        assert_eq!(
+            gr.entries,
+            vec![GracefulRestartValue {
+                afi: Afi::Ipv4,
+                safi: Safi::Unicast,
+                forwarding_state: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_add_path() {
+        use super::*;
+        use crate::hex_to_bytes;
+        let mut src = hex_to_bytes("45 08 0001 01 03 0002 01 01");
+        let saved = src.clone();
+        let cap = Capabilities::from_bytes(&mut src).unwrap();
+        assert_eq!(
+            cap.add_path(Afi::Ipv4, Safi::Unicast),
+            Some(AddPathMode::Both)
+        );
+        assert_eq!(
+            cap.add_path(Afi::Ipv6, Safi::Unicast),
+            Some(AddPathMode::Receive)
+        );
+        assert_eq!(cap.add_path(Afi::Ipv6, Safi::Multicast), None);
+        assert_eq!(
+            cap,
+            CapabilitiesBuilder::new()
+                .add_path(Afi::Ipv4, Safi::Unicast, AddPathMode::Both)
+                .add_path(Afi::Ipv6, Safi::Unicast, AddPathMode::Receive)
+                .build()
+        );
+        let encoded_len = cap.encoded_len();
+        let mut dst = bytes::BytesMut::new();
+        cap.to_bytes(&mut dst);
+        assert_eq!(dst, saved);
+        assert_eq!(encoded_len, dst.len());
+    }
+
+    #[test]
+    fn test_add_path_receive() {
+        use super::*;
+        let local = CapabilitiesBuilder::new()
+            .add_path(Afi::Ipv4, Safi::Unicast, AddPathMode::Both)
+            .add_path(Afi::Ipv6, Safi::Unicast, AddPathMode::Receive)
+            .add_path(Afi::Ipv4, Safi::Vpn, AddPathMode::Send)
+            .build();
+        let peer = CapabilitiesBuilder::new()
+            .add_path(Afi::Ipv4, Safi::Unicast, AddPathMode::Send)
+            .add_path(Afi::Ipv6, Safi::Unicast, AddPathMode::Receive)
+            .add_path(Afi::Ipv4, Safi::Vpn, AddPathMode::Both)
+            .build();
+        assert_eq!(
+            local.add_path_receive(&peer),
+            vec![(Afi::Ipv4, Safi::Unicast)]
+        );
+        assert_eq!(peer.add_path_receive(&local), vec![(Afi::Ipv4, Safi::Vpn)]);
+        assert!(local.add_path_receive(&Capabilities::default()).is_empty());
+    }
+
+    #[test]
+    fn test_add_path_uninterpretable_tuples() {
+        use super::*;
+        use crate::hex_to_bytes;
+        // Unknown SAFI 254 and an invalid Send/Receive of 7 are skipped
+        let mut src = hex_to_bytes("45 0c 0001 01 03 0001 fe 01 0002 01 07");
+        let cap = Capabilities::from_bytes(&mut src).unwrap();
+        assert_eq!(
+            cap.add_path(Afi::Ipv4, Safi::Unicast),
+            Some(AddPathMode::Both)
+        );
+        assert_eq!(cap.add_path(Afi::Ipv6, Safi::Unicast), None);
+    }
+
+    #[test]
+    fn test_multiprotocol_evpn() {
+        use super::*;
+        use crate::hex_to_bytes;
+        let mut src = hex_to_bytes("01 04 0019 00 46 05 06 0001 0085 0002");
+        let saved = src.clone();
+        let cap = Capabilities::from_bytes(&mut src).unwrap();
+        assert_eq!(
+            cap.0[0],
+            Value::MultiProtocol(MultiProtocol {
+                afi: Afi::L2Vpn,
+                safi: Safi::Evpn,
+            })
+        );
+        assert_eq!(
+            cap.0[1],
+            Value::ExtendedNextHop(ExtendedNextHop(vec![ExtendedNextHopValue {
+                afi: Afi::Ipv4,
+                safi: Safi::FlowSpecUnicast,
+                next_hop_afi: Afi::Ipv6,
+            }]))
+        );
+        assert_eq!(Safi::Vpn as u16, 128);
+        let encoded_len = cap.encoded_len();
+        let mut dst = bytes::BytesMut::new();
+        cap.to_bytes(&mut dst);
+        assert_eq!(dst, saved);
+        assert_eq!(encoded_len, dst.len());
+    }
+
+    #[test]
+    fn test_optional_parameters_short() {
+        use super::*;
+        use crate::hex_to_bytes;
+        let mut src = hex_to_bytes("0c 02 06 01 04 0001 0001 99 02 abcd");
+        let saved = src.clone();
+        let params = OptionalParameters::from_bytes(&mut src).unwrap();
+        assert_eq!(params.len(), 2);
+        assert_eq!(params.encoded_len(), saved.len());
+        let mut dst = bytes::BytesMut::new();
+        params.to_bytes(&mut dst);
+        assert_eq!(dst, saved);
+    }
+
+    #[test]
+    fn test_optional_parameters_extended() {
+        use super::*;
+        use crate::hex_to_bytes;
+        // RFC 9072 format, even though it would fit in the short form
+        let mut src = hex_to_bytes("ff ff 0009 02 0006 41 04 0000fde8");
+        let params = OptionalParameters::from_bytes(&mut src).unwrap();
+        let OptionalParameterValue::Capabilities(cap) = &params[0] else {
+            panic!("unexpected optional parameter type");
+        };
+        assert!(cap.has(&Value::FourOctetAsNumber(FourOctetAsNumber { asn: 65000 })));
+        // Capabilities too long for the short form
+        let mut builder = CapabilitiesBuilder::new();
+        for code in 200..203 {
+            builder = builder.other(code, Bytes::from(vec![code; 100]));
+        }
+        let params =
+            OptionalParameters(vec![OptionalParameterValue::Capabilities(builder.build())]);
+        let encoded_len = params.encoded_len();
+        let mut dst = bytes::BytesMut::new();
+        let written = params.clone().to_bytes(&mut dst);
+        assert_eq!(encoded_len, dst.len());
+        assert_eq!(written, dst.len());
+        // Markers, total length, parameter type, and parameter length
+        assert_eq!(dst[..7], [0xff, 0xff, 0x01, 0x35, 0x02, 0x01, 0x32]);
+        let decoded = OptionalParameters::from_bytes(&mut dst.freeze()).unwrap();
+        assert_eq!(decoded, params);
+    }
+
+    #[test]
+    fn test_role() {
+        use super::*;
+        use crate::hex_to_bytes;
+        let mut src = hex_to_bytes("09 01 03");
+        let saved = src.clone();
+        let cap = Capabilities::from_bytes(&mut src).unwrap();
+        assert_eq!(cap.role(), Some(RfcRole::Customer));
+        assert_eq!(cap.role().unwrap().expected_peer_role(), RfcRole::Provider);
+        let encoded_len = cap.encoded_len();
+        let mut dst = bytes::BytesMut::new();
+        cap.clone().to_bytes(&mut dst);
+        assert_eq!(dst, saved);
+        assert_eq!(encoded_len, dst.len());
+        assert_eq!(
+            CapabilitiesBuilder::new().role(RfcRole::Customer).build(),
+            cap
+        );
+        // Unassigned role values are kept as-is
+        let mut src = hex_to_bytes("09 01 05");
+        let cap = Capabilities::from_bytes(&mut src).unwrap();
+        assert_eq!(cap.role(), None);
+        assert_eq!(cap.get_unsupported(9), Some(&hex_to_bytes("05")));
+    }
+
+    #[test]
+    fn test_capability_truncated() {
+        use super::*;
+        use crate::hex_to_bytes;
+        // Claims 4 bytes of MultiProtocol but only has 2, or has no length
+        for data in ["01 04 0001", "01"] {
+            let mut src = hex_to_bytes(data);
+            assert!(
+                matches!(
+                    Capabilities::from_bytes(&mut src),
+                    Err(crate::Error::InternalLength(
+                        "capability",
+                        core::cmp::Ordering::Less
+                    ))
+                ),
+                "{data}"
+            );
+        }
+        // The same through the optional parameter, which also claims too much
+        for data in ["02 06 01 04 0001", "02 04 01 04 0001"] {
+            let mut src = hex_to_bytes(data);
+            assert!(
+                matches!(
+                    OptionalParameterValue::from_bytes(&mut src),
+                    Err(crate::Error::InternalLength(_, core::cmp::Ordering::Less))
+                ),
+                "{data}"
+            );
+        }
+    }
 }
@@ -4,11 +4,40 @@
 
 #![allow(clippy::module_name_repetitions)]
 
-use std::fmt;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::prelude::*;
+use core::fmt;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use core::str::FromStr;
+
+/// Error type for parsing CIDR blocks from strings
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("missing prefix length")]
+    MissingPrefixLength,
+    #[error(transparent)]
+    InvalidAddress(#[from] core::net::AddrParseError),
+    #[error("invalid prefix length")]
+    InvalidPrefixLength,
+}
+
+/// Split a CIDR string into an address and a validated prefix length
+fn split_cidr_str<A: FromStr<Err = core::net::AddrParseError>>(
+    s: &str,
+    max_prefix_len: u8,
+) -> Result<(A, u8), ParseError> {
+    let (addr, prefix_len) = s.split_once('/').ok_or(ParseError::MissingPrefixLength)?;
+    let addr = addr.parse()?;
+    let prefix_len = prefix_len
+        .parse()
+        .map_err(|_| ParseError::InvalidPrefixLength)?;
+    if prefix_len > max_prefix_len {
+        return Err(ParseError::InvalidPrefixLength);
+    }
+    Ok((addr, prefix_len))
+}
 
 /// A IPv4 CIDR block
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 #[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Cidr4 {
     pub addr: Ipv4Addr,
@@ -20,6 +49,16 @@ impl fmt::Display for Cidr4 {
         write!(f, "{}/{}", self.addr, self.prefix_len)
     }
 }
+
+impl FromStr for Cidr4 {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = split_cidr_str(s, 32)?;
+        Ok(Self { addr, prefix_len })
+    }
+}
+
 impl Cidr4 {
     #[must_use]
     pub const fn new(addr: Ipv4Addr, prefix_len: u8) -> Self {
@@ -38,10 +77,38 @@ impl Cidr4 {
             prefix_len,
         }
     }
+
+    /// Get the network mask of this CIDR block
+    #[must_use]
+    pub const fn mask(&self) -> u32 {
+        match u32::MAX.checked_shl(32 - self.prefix_len as u32) {
+            Some(mask) => mask,
+            None => 0,
+        }
+    }
+
+    /// Get the base address of this CIDR block with host bits cleared
+    #[must_use]
+    pub fn network(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.addr) & self.mask())
+    }
+
+    /// Check if this CIDR block contains another one
+    #[must_use]
+    pub fn contains(&self, other: &Self) -> bool {
+        self.prefix_len <= other.prefix_len
+            && u32::from(other.addr) & self.mask() == u32::from(self.network())
+    }
+
+    /// Check if this CIDR block overlaps with another one
+    #[must_use]
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.contains(other) || other.contains(self)
+    }
 }
 
 /// A IPv6 CIDR block
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 #[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Cidr6 {
     pub addr: Ipv6Addr,
@@ -54,15 +121,82 @@ impl fmt::Display for Cidr6 {
     }
 }
 
+impl FromStr for Cidr6 {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = split_cidr_str(s, 128)?;
+        Ok(Self { addr, prefix_len })
+    }
+}
+
 impl Cidr6 {
     #[must_use]
     pub const fn new(addr: Ipv6Addr, prefix_len: u8) -> Self {
         Self { addr, prefix_len }
     }
+
+    /// Create a list of aligned CIDR blocks covering `count` addresses from `start`
+    ///
+    /// If `start` is not aligned to the size of the block or `count` is not a
+    /// power of two, more than one block is needed.
+    #[must_use]
+    pub fn from_num_addresses(start: Ipv6Addr, count: u128) -> Vec<Self> {
+        let mut result = Vec::new();
+        let mut addr = u128::from(start);
+        let mut remaining = count;
+        while remaining > 0 {
+            // The largest block that `addr` is aligned to
+            let align_bits = addr.trailing_zeros();
+            // The largest block that fits in the remaining count
+            let fit_bits = remaining.ilog2();
+            let block_bits = align_bits.min(fit_bits);
+            let prefix_len = u8::try_from(128 - block_bits).expect("Invalid prefix length");
+            result.push(Self::new(Ipv6Addr::from(addr), prefix_len));
+            let block_size = 1u128 << block_bits;
+            remaining -= block_size;
+            let Some(next) = addr.checked_add(block_size) else {
+                break;
+            };
+            addr = next;
+        }
+        if result.len() > 1 {
+            log::warn!("{count} addresses from {start} do not form a single aligned CIDR block");
+        }
+        result
+    }
+
+    /// Get the network mask of this CIDR block
+    #[must_use]
+    pub const fn mask(&self) -> u128 {
+        match u128::MAX.checked_shl(128 - self.prefix_len as u32) {
+            Some(mask) => mask,
+            None => 0,
+        }
+    }
+
+    /// Get the base address of this CIDR block with host bits cleared
+    #[must_use]
+    pub fn network(&self) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(self.addr) & self.mask())
+    }
+
+    /// Check if this CIDR block contains another one
+    #[must_use]
+    pub fn contains(&self, other: &Self) -> bool {
+        self.prefix_len <= other.prefix_len
+            && u128::from(other.addr) & self.mask() == u128::from(self.network())
+    }
+
+    /// Check if this CIDR block overlaps with another one
+    #[must_use]
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.contains(other) || other.contains(self)
+    }
 }
 
 /// A CIDR block
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 #[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Cidr {
     V4(Cidr4),
@@ -78,6 +212,19 @@ impl fmt::Display for Cidr {
     }
 }
 
+impl FromStr for Cidr {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<Cidr4>() {
+            Ok(cidr) => Ok(Self::V4(cidr)),
+            // Only retry as IPv6 if the address itself is not IPv4
+            Err(ParseError::InvalidAddress(_)) => s.parse::<Cidr6>().map(Self::V6),
+            Err(e) => Err(e),
+        }
+    }
+}
+
 impl Cidr {
     #[must_use]
     pub const fn into_parts(self) -> (IpAddr, u8) {
@@ -87,3 +234,598 @@ impl Cidr {
         }
     }
 }
+
+/// Aggregate a list of prefixes given as `(address, prefix_len)` into a minimal covering set
+///
+/// `max_len` is the address length in bits, i.e. 32 for IPv4 and 128 for IPv6.
+/// Prefixes longer than `max_len` are invalid and dropped.
+fn aggregate_bits(mut prefixes: Vec<(u128, u8)>, max_len: u8) -> Vec<(u128, u8)> {
+    prefixes.retain(|&(_, len)| len <= max_len);
+    let mask = |len: u8| {
+        let host_bits = u32::from(max_len - len);
+        let all = u128::MAX >> (128 - u32::from(max_len));
+        all & all.checked_shl(host_bits).unwrap_or(0)
+    };
+    for (addr, len) in &mut prefixes {
+        *addr &= mask(*len);
+    }
+    prefixes.sort_unstable();
+    let mut result: Vec<(u128, u8)> = Vec::with_capacity(prefixes.len());
+    for (addr, len) in prefixes {
+        if let Some(&(top_addr, top_len)) = result.last() {
+            if top_len <= len && addr & mask(top_len) == top_addr {
+                // Already covered by the previous prefix
+                continue;
+            }
+        }
+        result.push((addr, len));
+        // Merge the top two prefixes as long as they are siblings
+        while let [.., (left_addr, left_len), (right_addr, right_len)] = result[..] {
+            if left_len != right_len || left_len == 0 {
+                break;
+            }
+            let block = 1u128 << (max_len - left_len);
+            if left_addr & block != 0 || left_addr + block != right_addr {
+                break;
+            }
+            result.truncate(result.len() - 2);
+            result.push((left_addr, left_len - 1));
+        }
+    }
+    result
+}
+
+/// Aggregate IPv4 prefixes into a minimal covering set
+///
+/// Host bits are cleared, prefixes covered by another one are removed, and
+/// adjacent prefixes that share a parent are merged. The result is sorted.
+/// Prefixes longer than the address itself are dropped.
+pub fn aggregate_v4(prefixes: &mut Vec<Cidr4>) {
+    let bits = prefixes
+        .iter()
+        .map(|cidr| (u128::from(u32::from(cidr.addr)), cidr.prefix_len))
+        .collect();
+    *prefixes = aggregate_bits(bits, 32)
+        .into_iter()
+        .map(|(addr, len)| {
+            let addr = u32::try_from(addr).expect("IPv4 address out of range");
+            Cidr4::new(Ipv4Addr::from(addr), len)
+        })
+        .collect();
+}
+
+/// Aggregate IPv6 prefixes into a minimal covering set
+///
+/// See [`aggregate_v4`] for details.
+pub fn aggregate_v6(prefixes: &mut Vec<Cidr6>) {
+    let bits = prefixes
+        .iter()
+        .map(|cidr| (u128::from(cidr.addr), cidr.prefix_len))
+        .collect();
+    *prefixes = aggregate_bits(bits, 128)
+        .into_iter()
+        .map(|(addr, len)| Cidr6::new(Ipv6Addr::from(addr), len))
+        .collect();
+}
+
+/// A node of a binary trie keyed by the most significant bits of a `u128`
+#[derive(Clone, Debug)]
+struct TrieNode<V> {
+    value: Option<V>,
+    children: [Option<Box<TrieNode<V>>>; 2],
+}
+
+impl<V> TrieNode<V> {
+    const fn new() -> Self {
+        Self {
+            value: None,
+            children: [None, None],
+        }
+    }
+
+    const fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children[0].is_none() && self.children[1].is_none()
+    }
+
+    /// Remove the value at the first `len` bits of `bits`, pruning nodes left empty
+    fn remove(&mut self, bits: u128, len: u8) -> Option<V> {
+        if len == 0 {
+            return self.value.take();
+        }
+        let child = &mut self.children[usize::from(bits >> 127 == 1)];
+        let removed = child.as_deref_mut()?.remove(bits << 1, len - 1);
+        if child.as_deref().is_some_and(Self::is_empty) {
+            *child = None;
+        }
+        removed
+    }
+}
+
+/// A binary prefix trie over left-aligned addresses
+///
+/// IPv4 addresses occupy the top 32 bits so that both families share one walk.
+#[derive(Clone, Debug)]
+struct PrefixTrie<V> {
+    root: TrieNode<V>,
+    len: usize,
+}
+
+impl<V> PrefixTrie<V> {
+    const fn new() -> Self {
+        Self {
+            root: TrieNode::new(),
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, mut bits: u128, len: u8, value: V) -> Option<V> {
+        let mut node = &mut self.root;
+        for _ in 0..len {
+            node = node.children[usize::from(bits >> 127 == 1)]
+                .get_or_insert_with(|| Box::new(TrieNode::new()));
+            bits <<= 1;
+        }
+        let old = node.value.replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    fn get(&self, mut bits: u128, len: u8) -> Option<&V> {
+        let mut node = &self.root;
+        for _ in 0..len {
+            node = node.children[usize::from(bits >> 127 == 1)].as_deref()?;
+            bits <<= 1;
+        }
+        node.value.as_ref()
+    }
+
+    fn remove(&mut self, bits: u128, len: u8) -> Option<V> {
+        let removed = self.root.remove(bits, len);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    fn longest_match(&self, mut bits: u128, max_len: u8) -> Option<&V> {
+        let mut node = &self.root;
+        let mut best = node.value.as_ref();
+        for _ in 0..max_len {
+            let Some(child) = node.children[usize::from(bits >> 127 == 1)].as_deref() else {
+                break;
+            };
+            node = child;
+            best = node.value.as_ref().or(best);
+            bits <<= 1;
+        }
+        best
+    }
+}
+
+/// Left-align an IPv4 address in a `u128` for [`PrefixTrie`]
+fn v4_bits(addr: Ipv4Addr) -> u128 {
+    u128::from(u32::from(addr)) << 96
+}
+
+/// A map from IPv4 CIDR blocks to values with longest-prefix-match lookups
+///
+/// Host bits of the inserted blocks are ignored.
+#[derive(Clone, Debug)]
+pub struct PrefixTrie4<V>(PrefixTrie<V>);
+
+impl<V> Default for PrefixTrie4<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> PrefixTrie4<V> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self(PrefixTrie::new())
+    }
+
+    /// Insert a value for a CIDR block, returning the one it replaces
+    pub fn insert(&mut self, cidr: Cidr4, value: V) -> Option<V> {
+        self.0.insert(v4_bits(cidr.addr), cidr.prefix_len, value)
+    }
+
+    /// Get the value stored for exactly this CIDR block
+    #[must_use]
+    pub fn get(&self, cidr: &Cidr4) -> Option<&V> {
+        self.0.get(v4_bits(cidr.addr), cidr.prefix_len)
+    }
+
+    /// Remove the value stored for exactly this CIDR block
+    pub fn remove(&mut self, cidr: &Cidr4) -> Option<V> {
+        self.0.remove(v4_bits(cidr.addr), cidr.prefix_len)
+    }
+
+    /// Get the value of the most specific CIDR block containing `addr`
+    #[must_use]
+    pub fn longest_match(&self, addr: Ipv4Addr) -> Option<&V> {
+        self.0.longest_match(v4_bits(addr), 32)
+    }
+
+    /// Number of CIDR blocks in the trie
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.0.len
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.0.len == 0
+    }
+}
+
+/// A map from IPv6 CIDR blocks to values with longest-prefix-match lookups
+///
+/// See [`PrefixTrie4`] for details.
+#[derive(Clone, Debug)]
+pub struct PrefixTrie6<V>(PrefixTrie<V>);
+
+impl<V> Default for PrefixTrie6<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> PrefixTrie6<V> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self(PrefixTrie::new())
+    }
+
+    /// Insert a value for a CIDR block, returning the one it replaces
+    pub fn insert(&mut self, cidr: Cidr6, value: V) -> Option<V> {
+        self.0.insert(u128::from(cidr.addr), cidr.prefix_len, value)
+    }
+
+    /// Get the value stored for exactly this CIDR block
+    #[must_use]
+    pub fn get(&self, cidr: &Cidr6) -> Option<&V> {
+        self.0.get(u128::from(cidr.addr), cidr.prefix_len)
+    }
+
+    /// Remove the value stored for exactly this CIDR block
+    pub fn remove(&mut self, cidr: &Cidr6) -> Option<V> {
+        self.0.remove(u128::from(cidr.addr), cidr.prefix_len)
+    }
+
+    /// Get the value of the most specific CIDR block containing `addr`
+    #[must_use]
+    pub fn longest_match(&self, addr: Ipv6Addr) -> Option<&V> {
+        self.0.longest_match(u128::from(addr), 128)
+    }
+
+    /// Number of CIDR blocks in the trie
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.0.len
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.0.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr_from_str() {
+        assert_eq!(
+            "192.0.2.0/24".parse(),
+            Ok(Cidr4::new(Ipv4Addr::new(192, 0, 2, 0), 24))
+        );
+        assert_eq!(
+            "2001:db8::/32".parse(),
+            Ok(Cidr6::new("2001:db8::".parse().unwrap(), 32))
+        );
+        assert_eq!(
+            "192.0.2.0/24".parse(),
+            Ok(Cidr::V4(Cidr4::new(Ipv4Addr::new(192, 0, 2, 0), 24)))
+        );
+        assert_eq!(
+            "2001:db8::/32".parse(),
+            Ok(Cidr::V6(Cidr6::new("2001:db8::".parse().unwrap(), 32)))
+        );
+        for cidr in ["0.0.0.0/0", "10.127.127.127/32", "fdc0::f000:0/110"] {
+            assert_eq!(cidr.parse::<Cidr>().unwrap().to_string(), cidr);
+        }
+    }
+
+    #[test]
+    fn test_cidr_from_str_invalid() {
+        assert_eq!(
+            "192.0.2.0".parse::<Cidr4>(),
+            Err(ParseError::MissingPrefixLength)
+        );
+        assert_eq!(
+            "192.0.2.0/33".parse::<Cidr4>(),
+            Err(ParseError::InvalidPrefixLength)
+        );
+        assert_eq!(
+            "192.0.2.0/abc".parse::<Cidr4>(),
+            Err(ParseError::InvalidPrefixLength)
+        );
+        assert_eq!(
+            "2001:db8::/129".parse::<Cidr6>(),
+            Err(ParseError::InvalidPrefixLength)
+        );
+        assert!(matches!(
+            "2001:db8::/32".parse::<Cidr4>(),
+            Err(ParseError::InvalidAddress(_))
+        ));
+        assert!("not-an-address/8".parse::<Cidr>().is_err());
+        assert_eq!(
+            "192.0.2.0/33".parse::<Cidr>(),
+            Err(ParseError::InvalidPrefixLength)
+        );
+    }
+
+    #[test]
+    fn test_cidr4_contains() {
+        let parent: Cidr4 = "172.16.0.0/12".parse().unwrap();
+        let child: Cidr4 = "172.23.227.0/24".parse().unwrap();
+        let disjoint: Cidr4 = "10.0.0.0/8".parse().unwrap();
+        assert!(parent.contains(&parent));
+        assert!(parent.overlaps(&parent));
+        assert!(parent.contains(&child));
+        assert!(!child.contains(&parent));
+        assert!(parent.overlaps(&child));
+        assert!(child.overlaps(&parent));
+        assert!(!parent.contains(&disjoint));
+        assert!(!parent.overlaps(&disjoint));
+        assert!(!disjoint.overlaps(&parent));
+        let default: Cidr4 = "0.0.0.0/0".parse().unwrap();
+        assert!(default.contains(&parent));
+        assert!(default.contains(&disjoint));
+    }
+
+    #[test]
+    fn test_cidr4_network_off_boundary() {
+        let cidr: Cidr4 = "172.24.0.0/12".parse().unwrap();
+        assert_eq!(cidr.network(), Ipv4Addr::new(172, 16, 0, 0));
+        assert!(cidr.contains(&"172.16.0.0/12".parse().unwrap()));
+        let cidr: Cidr4 = "10.127.127.127/32".parse().unwrap();
+        assert_eq!(cidr.network(), cidr.addr);
+    }
+
+    #[test]
+    fn test_cidr6_contains() {
+        let parent: Cidr6 = "fdc7:3c9d::/32".parse().unwrap();
+        let child: Cidr6 = "fdc7:3c9d:ff31:7::/64".parse().unwrap();
+        let disjoint: Cidr6 = "2001:db8::/32".parse().unwrap();
+        assert!(parent.contains(&parent));
+        assert!(parent.contains(&child));
+        assert!(!child.contains(&parent));
+        assert!(child.overlaps(&parent));
+        assert!(!parent.overlaps(&disjoint));
+        assert!(Cidr6::new(Ipv6Addr::UNSPECIFIED, 0).contains(&disjoint));
+    }
+
+    #[test]
+    fn test_cidr6_network_off_boundary() {
+        let cidr: Cidr6 = "fdc0::f000:0/110".parse().unwrap();
+        assert_eq!(cidr.network(), "fdc0::f000:0".parse::<Ipv6Addr>().unwrap());
+        let cidr: Cidr6 = "fdc0::f001:0/110".parse().unwrap();
+        assert_eq!(cidr.network(), "fdc0::f000:0".parse::<Ipv6Addr>().unwrap());
+    }
+
+    #[test]
+    fn test_aggregate_v4() {
+        let mut prefixes: Vec<Cidr4> = [
+            "192.0.2.0/24",
+            "192.0.0.0/24",
+            "192.0.3.0/24",
+            "192.0.1.0/24",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        aggregate_v4(&mut prefixes);
+        assert_eq!(prefixes, vec!["192.0.0.0/22".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_aggregate_v4_non_adjacent() {
+        // Adjacent but not siblings, separated, of different lengths, and covered
+        let mut prefixes: Vec<Cidr4> = [
+            "10.0.1.0/24",
+            "10.0.2.0/24",
+            "10.0.8.0/24",
+            "10.1.0.0/16",
+            "10.1.2.0/24",
+            "10.2.0.0/24",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        let expected: Vec<Cidr4> = [
+            "10.0.1.0/24",
+            "10.0.2.0/24",
+            "10.0.8.0/24",
+            "10.1.0.0/16",
+            "10.2.0.0/24",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        aggregate_v4(&mut prefixes);
+        assert_eq!(prefixes, expected);
+    }
+
+    #[test]
+    fn test_aggregate_invalid_prefix_len() {
+        let mut prefixes = vec![
+            Cidr4 {
+                addr: "192.0.2.0".parse().unwrap(),
+                prefix_len: 33,
+            },
+            "198.51.100.0/24".parse().unwrap(),
+        ];
+        aggregate_v4(&mut prefixes);
+        assert_eq!(prefixes, vec!["198.51.100.0/24".parse().unwrap()]);
+        let mut prefixes = vec![
+            Cidr6 {
+                addr: "2001:db8::".parse().unwrap(),
+                prefix_len: 129,
+            },
+            "2001:db8:1::/48".parse().unwrap(),
+        ];
+        aggregate_v6(&mut prefixes);
+        assert_eq!(prefixes, vec!["2001:db8:1::/48".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_aggregate_v6() {
+        let mut prefixes: Vec<Cidr6> = [
+            "2001:db8:3::/48",
+            "2001:db8::/48",
+            "2001:db8:1::/48",
+            "2001:db8:2::/48",
+            "2001:db8:4::/48",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        aggregate_v6(&mut prefixes);
+        assert_eq!(
+            prefixes,
+            vec![
+                "2001:db8::/46".parse().unwrap(),
+                "2001:db8:4::/48".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cidr6_from_num_addresses_aligned() {
+        let start = "2001:db8::".parse().unwrap();
+        assert_eq!(
+            Cidr6::from_num_addresses(start, 1 << 96),
+            vec![Cidr6::new(start, 32)]
+        );
+        assert_eq!(Cidr6::from_num_addresses(start, 0), vec![]);
+    }
+
+    #[test]
+    fn test_cidr6_from_num_addresses_not_power_of_two() {
+        let start = "2001:db8::".parse().unwrap();
+        // A /32 and a /33
+        assert_eq!(
+            Cidr6::from_num_addresses(start, (1 << 96) + (1 << 95)),
+            vec![
+                Cidr6::new(start, 32),
+                Cidr6::new("2001:db9::".parse().unwrap(), 33),
+            ]
+        );
+        // Three /48s
+        assert_eq!(
+            Cidr6::from_num_addresses(start, 3 << 80),
+            vec![
+                Cidr6::new(start, 47),
+                Cidr6::new("2001:db8:2::".parse().unwrap(), 48),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cidr6_from_num_addresses_unaligned() {
+        // Starting at a /48 boundary but asking for a /47 worth of addresses
+        let start = "2001:db8:1::".parse().unwrap();
+        assert_eq!(
+            Cidr6::from_num_addresses(start, 1 << 81),
+            vec![
+                Cidr6::new(start, 48),
+                Cidr6::new("2001:db8:2::".parse().unwrap(), 48),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cidr6_from_num_addresses_end_of_space() {
+        let start = "ffff:ffff:ffff:ffff:ffff:ffff:ffff:fffe".parse().unwrap();
+        assert_eq!(
+            Cidr6::from_num_addresses(start, 2),
+            vec![Cidr6::new(start, 127)]
+        );
+    }
+
+    #[test]
+    fn test_prefix_trie4() {
+        let mut trie = PrefixTrie4::new();
+        assert!(trie.is_empty());
+        assert_eq!(trie.longest_match(Ipv4Addr::new(192, 0, 2, 1)), None);
+        assert_eq!(trie.insert("0.0.0.0/0".parse().unwrap(), 0), None);
+        assert_eq!(trie.insert("192.0.0.0/16".parse().unwrap(), 16), None);
+        assert_eq!(trie.insert("192.0.2.0/24".parse().unwrap(), 24), None);
+        // Host bits are ignored
+        assert_eq!(trie.insert("192.0.2.77/24".parse().unwrap(), 240), Some(24));
+        assert_eq!(trie.len(), 3);
+        assert_eq!(trie.longest_match(Ipv4Addr::new(192, 0, 2, 1)), Some(&240));
+        assert_eq!(trie.longest_match(Ipv4Addr::new(192, 0, 3, 1)), Some(&16));
+        assert_eq!(trie.longest_match(Ipv4Addr::new(10, 0, 0, 1)), Some(&0));
+        assert_eq!(trie.get(&"192.0.0.0/16".parse().unwrap()), Some(&16));
+        assert_eq!(trie.get(&"192.0.0.0/15".parse().unwrap()), None);
+        // Removing a covering prefix keeps the more specific one
+        assert_eq!(trie.remove(&"192.0.0.0/16".parse().unwrap()), Some(16));
+        assert_eq!(trie.remove(&"192.0.0.0/16".parse().unwrap()), None);
+        assert_eq!(trie.longest_match(Ipv4Addr::new(192, 0, 2, 1)), Some(&240));
+        assert_eq!(trie.longest_match(Ipv4Addr::new(192, 0, 3, 1)), Some(&0));
+        assert_eq!(trie.remove(&"192.0.2.0/24".parse().unwrap()), Some(240));
+        assert_eq!(trie.remove(&"0.0.0.0/0".parse().unwrap()), Some(0));
+        assert!(trie.is_empty());
+        assert_eq!(trie.longest_match(Ipv4Addr::new(192, 0, 2, 1)), None);
+    }
+
+    #[test]
+    fn test_prefix_trie4_host_route() {
+        let mut trie = PrefixTrie4::new();
+        trie.insert("255.255.255.255/32".parse().unwrap(), "host");
+        trie.insert("255.255.255.254/31".parse().unwrap(), "pair");
+        assert_eq!(
+            trie.longest_match(Ipv4Addr::new(255, 255, 255, 255)),
+            Some(&"host")
+        );
+        assert_eq!(
+            trie.longest_match(Ipv4Addr::new(255, 255, 255, 254)),
+            Some(&"pair")
+        );
+        assert_eq!(trie.longest_match(Ipv4Addr::new(255, 255, 255, 253)), None);
+    }
+
+    #[test]
+    fn test_prefix_trie6() {
+        let mut trie = PrefixTrie6::new();
+        trie.insert("2001:db8::/32".parse().unwrap(), 32);
+        trie.insert("2001:db8:1::/48".parse().unwrap(), 48);
+        trie.insert("2001:db8:1::1/128".parse().unwrap(), 128);
+        assert_eq!(trie.len(), 3);
+        assert_eq!(
+            trie.longest_match("2001:db8:1::1".parse().unwrap()),
+            Some(&128)
+        );
+        assert_eq!(
+            trie.longest_match("2001:db8:1::2".parse().unwrap()),
+            Some(&48)
+        );
+        assert_eq!(
+            trie.longest_match("2001:db8:2::1".parse().unwrap()),
+            Some(&32)
+        );
+        assert_eq!(trie.longest_match("2001:db9::1".parse().unwrap()), None);
+        assert_eq!(trie.remove(&"2001:db8::/32".parse().unwrap()), Some(32));
+        assert_eq!(trie.longest_match("2001:db8:2::1".parse().unwrap()), None);
+        assert_eq!(
+            trie.longest_match("2001:db8:1::2".parse().unwrap()),
+            Some(&48)
+        );
+    }
+}
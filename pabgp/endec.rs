@@ -3,20 +3,113 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use super::Error;
-#[cfg(feature = "tokio-endec")]
-use super::{Message, Notification, Open, Update};
+use super::{Message, Notification, Open, RouteRefresh, Update};
+use crate::capability::{Afi, Safi};
 use bytes::{Buf, BufMut};
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use enum_primitive_derive::Primitive;
-#[cfg(feature = "tokio-endec")]
 use num_traits::FromPrimitive;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+#[cfg(feature = "std")]
+use std::io::Read;
 #[cfg(feature = "tokio-endec")]
 use tokio_util::codec::{Decoder, Encoder};
 
 /// BGP packet encoder
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
+#[cfg(feature = "tokio-endec")]
+pub struct BgpCodec {
+    /// Whether Extended Messages (RFC 8654) have been negotiated
+    pub extended_messages: bool,
+    /// Whether four-octet AS numbers (RFC 6793) have been negotiated, which
+    /// determines the ASN size of received `AS_PATH` attributes
+    pub four_octet_asn: bool,
+    /// AFI/SAFI pairs for which ADD-PATH (RFC 7911) has been negotiated for
+    /// receiving, whose received NLRI carry path identifiers
+    pub add_path: Vec<(Afi, Safi)>,
+}
+
+#[cfg(feature = "tokio-endec")]
+impl Default for BgpCodec {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
 #[cfg(feature = "tokio-endec")]
-pub struct BgpCodec;
+impl BgpCodec {
+    /// Create a new codec that expects four-octet AS numbers
+    #[must_use]
+    pub const fn new(extended_messages: bool) -> Self {
+        Self {
+            extended_messages,
+            four_octet_asn: true,
+            add_path: Vec::new(),
+        }
+    }
+
+    /// Set whether four-octet AS numbers have been negotiated
+    #[must_use]
+    pub const fn with_four_octet_asn(mut self, four_octet_asn: bool) -> Self {
+        self.four_octet_asn = four_octet_asn;
+        self
+    }
+
+    /// Set the AFI/SAFI pairs for which ADD-PATH has been negotiated for
+    /// receiving, see [`Capabilities::add_path_receive`]
+    ///
+    /// [`Capabilities::add_path_receive`]: crate::capability::Capabilities::add_path_receive
+    #[must_use]
+    pub fn with_add_path(mut self, add_path: Vec<(Afi, Safi)>) -> Self {
+        self.add_path = add_path;
+        self
+    }
+
+    /// Maximum length of a message accepted by this codec
+    #[must_use]
+    pub const fn max_message_len(&self) -> usize {
+        if self.extended_messages {
+            crate::MAX_EXTENDED_MESSAGE_LEN
+        } else {
+            crate::MAX_MESSAGE_LEN
+        }
+    }
+
+    /// Encode a message that is still needed afterwards, without cloning it
+    ///
+    /// # Errors
+    /// Returns `Error::InternalLength` if a length field would overflow.
+    /// Nothing is left in `dst` in that case.
+    pub fn encode_ref(&self, item: &Message, dst: &mut bytes::BytesMut) -> Result<(), Error> {
+        let start = dst.len();
+        let result = item.try_encode_to(dst);
+        if result.is_err() {
+            // Do not leave a partial message in the buffer
+            dst.truncate(start);
+        }
+        result.map(|_| ())
+    }
+
+    /// Append several messages to `dst` at once, so that they can be written
+    /// with a single call
+    ///
+    /// # Errors
+    /// Returns `Error::InternalLength` if a length field of any message would
+    /// overflow. Nothing is appended to `dst` in that case.
+    pub fn encode_all(
+        &self,
+        messages: impl IntoIterator<Item = Message>,
+        dst: &mut bytes::BytesMut,
+    ) -> Result<(), Error> {
+        let start = dst.len();
+        for message in messages {
+            if let Err(e) = message.try_to_bytes(dst) {
+                dst.truncate(start);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
 
 #[cfg(feature = "tokio-endec")]
 impl Decoder for BgpCodec {
@@ -24,76 +117,220 @@ impl Decoder for BgpCodec {
     type Error = Error;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < crate::MARKER.len() + 2 {
-            // Marker + length
+        let Some(length) = peek_message_len(src, self.max_message_len())? else {
             return Ok(None);
-        }
-        let length = u16::from_be_bytes([src[16], src[17]]) as usize;
+        };
         if src.len() < length {
             return Ok(None);
         }
-        // Now the packet is supposed to be complete and let's use the Buf methods
-        // to avoid manual indexing.
-        let marker = src.copy_to_bytes(16);
-        if *marker != crate::MARKER {
-            return Err(Error::Marker);
-        }
-        log::trace!("Valid BGP marker, length: {length}");
-        let length = (src.get_u16() - 19) as usize;
-        let msg_type = src.get_u8();
-        let msg_type = MessageType::from_u8(msg_type).ok_or(Error::MessageType(msg_type))?;
-        let mut buf = src.split_to(length).into();
-        let packet = match msg_type {
-            MessageType::Open => Message::Open(Open::from_bytes(&mut buf)?),
-            MessageType::Update => Message::Update(Update::from_bytes(&mut buf)?),
-            MessageType::Notification => Message::Notification(Notification::from_bytes(&mut buf)?),
-            MessageType::Keepalive => Message::Keepalive,
-        };
+        decode_message(
+            src.split_to(length).freeze(),
+            self.four_octet_asn,
+            &self.add_path,
+        )
+        .map(Some)
+    }
+
+    fn decode_eof(&mut self, buf: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(message) => Ok(Some(message)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(Error::Truncated(buf.len())),
+        }
+    }
+}
+
+#[cfg(feature = "tokio-endec")]
+impl Encoder<Message> for BgpCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Message, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        self.encode_ref(&item, dst)
+    }
+}
+
+/// Length of the BGP message header (RFC 4271 Section 4.1)
+const HEADER_LEN: usize = 19;
+
+/// Encode a complete message, including the header
+fn encode_message(item: &Message, dst: &mut bytes::BytesMut) -> Result<usize, Error> {
+    dst.put_slice(&crate::MARKER);
+    let len_pos = dst.len();
+    dst.put_u16(0); // Placeholder for length
+    let len = match item {
+        Message::Open(msg) => {
+            dst.put_u8(MessageType::Open as u8);
+            msg.try_encode_to(dst)?
+        }
+        Message::Update(msg) => {
+            dst.put_u8(MessageType::Update as u8);
+            msg.try_encode_to(dst)?
+        }
+        Message::Notification(msg) => {
+            dst.put_u8(MessageType::Notification as u8);
+            msg.try_encode_to(dst)?
+        }
+        Message::Keepalive => {
+            dst.put_u8(MessageType::Keepalive as u8);
+            0
+        }
+        Message::RouteRefresh(msg) => {
+            dst.put_u8(MessageType::RouteRefresh as u8);
+            msg.try_encode_to(dst)?
+        }
+    };
+    let len = u16::try_from(len + HEADER_LEN)
+        .map_err(|_| Error::InternalLength("message", core::cmp::Ordering::Greater))?;
+    dst[len_pos..len_pos + 2].copy_from_slice(&len.to_be_bytes());
+    Ok(usize::from(len))
+}
+
+/// Find the total length of the message at the start of `src`
+///
+/// Returns `None` if the marker and length fields are not complete yet.
+fn peek_message_len(src: &[u8], max_message_len: usize) -> Result<Option<usize>, Error> {
+    if src.len() < crate::MARKER.len() + 2 {
+        // Marker + length
+        return Ok(None);
+    }
+    let length = u16::from_be_bytes([src[16], src[17]]);
+    if usize::from(length) > max_message_len || usize::from(length) < HEADER_LEN {
+        return Err(Error::MessageLength(length));
+    }
+    Ok(Some(usize::from(length)))
+}
+
+/// Decode a complete message, including its header
+///
+/// `as4` and `add_path` are passed to [`Update::from_bytes_negotiated`].
+fn decode_message(
+    mut src: bytes::Bytes,
+    as4: bool,
+    add_path: &[(Afi, Safi)],
+) -> Result<Message, Error> {
+    if src[..crate::MARKER.len()] != crate::MARKER {
+        return Err(Error::Marker);
+    }
+    src.advance(crate::MARKER.len());
+    let length = src.get_u16();
+    log::trace!("Valid BGP marker, length: {length}");
+    let msg_type = src.get_u8();
+    let msg_type = MessageType::from_u8(msg_type).ok_or(Error::MessageType(msg_type))?;
+    // RFC 4271 Section 6.1, RFC 2918 Section 3
+    let length_ok = match msg_type {
+        MessageType::Open => length >= 29,
+        MessageType::Update => length >= 23,
+        MessageType::Notification => length >= 21,
+        MessageType::Keepalive => length == 19,
+        MessageType::RouteRefresh => length == 23,
+    };
+    if !length_ok {
+        return Err(Error::MessageLength(length));
+    }
+    let mut buf = src.split_to(usize::from(length) - HEADER_LEN);
+    let packet = match msg_type {
+        MessageType::Open => Open::from_bytes(&mut buf).map(Message::Open),
+        MessageType::Update => {
+            Update::from_bytes_negotiated(&mut buf, as4, add_path).map(Message::Update)
+        }
+        MessageType::Notification => Notification::from_bytes(&mut buf).map(Message::Notification),
+        MessageType::Keepalive => Ok(Message::Keepalive),
+        MessageType::RouteRefresh => RouteRefresh::from_bytes(&mut buf).map(Message::RouteRefresh),
+    };
+    let packet = packet.and_then(|packet| {
         if buf.has_remaining() {
             log::debug!("Remaining bytes after decoding: {buf:?}");
             Err(Error::InternalLength(
                 "message",
-                std::cmp::Ordering::Greater,
+                core::cmp::Ordering::Greater,
             ))
         } else {
-            Ok(Some(packet))
+            Ok(packet)
         }
-    }
+    });
+    // Attribute the error to the message so that the right NOTIFICATION
+    // can be sent (RFC 4271 Sections 6.2 and 6.3)
+    packet.map_err(|e| match msg_type {
+        MessageType::Open => e.into_open_message(),
+        MessageType::Update => e.into_update_message(),
+        MessageType::Notification | MessageType::Keepalive | MessageType::RouteRefresh => e,
+    })
 }
 
-#[cfg(feature = "tokio-endec")]
-impl Encoder<Message> for BgpCodec {
-    // tokio requires the Error type to be `From<io::Error>`, but actually ours is `!`
-    type Error = std::io::Error;
+/// Iterator over the messages in a byte stream, see [`decode_stream`]
+#[cfg(feature = "std")]
+struct MessageStream<R> {
+    reader: R,
+    done: bool,
+}
 
-    fn encode(&mut self, item: Message, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
-        dst.put_slice(&crate::MARKER);
-        let len_pos = dst.len();
-        dst.put_u16(0); // Placeholder for length
-        let len = match item {
-            Message::Open(msg) => {
-                dst.put_u8(MessageType::Open as u8);
-                msg.to_bytes(dst)
-            }
-            Message::Update(msg) => {
-                dst.put_u8(MessageType::Update as u8);
-                msg.to_bytes(dst)
-            }
-            Message::Notification(msg) => {
-                dst.put_u8(MessageType::Notification as u8);
-                msg.to_bytes(dst)
-            }
-            Message::Keepalive => {
-                dst.put_u8(MessageType::Keepalive as u8);
-                0
+#[cfg(feature = "std")]
+impl<R: Read> MessageStream<R> {
+    /// Fill `buf` from the reader, stopping early only at EOF
+    ///
+    /// # Returns
+    /// The number of bytes read.
+    fn fill(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
             }
-        };
-        let len = len + 19;
-        let len = u16::try_from(len).expect("Message length overflow");
-        let len_bytes = len.to_be_bytes();
-        dst[len_pos] = len_bytes[0];
-        dst[len_pos + 1] = len_bytes[1];
-        Ok(())
+        }
+        Ok(filled)
+    }
+
+    /// Read the next message, or `None` on a clean EOF between messages
+    ///
+    /// EOF in the middle of a message is reported as `Error::Truncated`,
+    /// like `BgpCodec::decode_eof` does.
+    fn read_message(&mut self) -> Result<Option<Message>, Error> {
+        let mut header = [0; HEADER_LEN];
+        match self.fill(&mut header)? {
+            0 => return Ok(None),
+            HEADER_LEN => {}
+            filled => return Err(Error::Truncated(filled)),
+        }
+        let length = peek_message_len(&header, crate::MAX_EXTENDED_MESSAGE_LEN)?
+            .expect("header is complete");
+        let mut message = bytes::BytesMut::zeroed(length);
+        message[..HEADER_LEN].copy_from_slice(&header);
+        let filled = HEADER_LEN + self.fill(&mut message[HEADER_LEN..])?;
+        if filled < length {
+            return Err(Error::Truncated(filled));
+        }
+        decode_message(message.freeze(), true, &[]).map(Some)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> Iterator for MessageStream<R> {
+    type Item = Result<Message, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_message().transpose();
+        // Framing is lost after an error, so stop there
+        self.done = !matches!(result, Some(Ok(_)));
+        result
+    }
+}
+
+/// Decode BGP messages from a byte stream, such as a raw TCP capture
+///
+/// Extended Messages (RFC 8654) and four-octet AS numbers are assumed.
+/// Iteration stops at the end of the stream or after the first error, which
+/// is `Error::Truncated` if the stream ends in the middle of a message.
+#[cfg(feature = "std")]
+pub fn decode_stream(reader: impl Read) -> impl Iterator<Item = Result<Message, Error>> {
+    MessageStream {
+        reader,
+        done: false,
     }
 }
 
@@ -104,6 +341,7 @@ pub enum MessageType {
     Update = 2,
     Notification = 3,
     Keepalive = 4,
+    RouteRefresh = 5,
 }
 
 /// BGP packet component with a fixed length or containing a length field
@@ -118,12 +356,95 @@ pub trait Component {
     /// Returns the number of bytes written.
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize;
 
+    /// Encode the component into a buffer, failing instead of panicking if
+    /// it cannot be represented on the wire.
+    ///
+    /// Returns the number of bytes written. On error, `dst` may contain part
+    /// of the component.
+    ///
+    /// # Errors
+    /// Returns `Error::InternalLength` if a length field would overflow.
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, Error>
+    where
+        Self: Sized,
+    {
+        Ok(self.to_bytes(dst))
+    }
+
+    /// Encode the component into a buffer without consuming it.
+    ///
+    /// Returns the number of bytes written. The default implementation
+    /// encodes a clone, so large components override it.
+    fn encode_to(&self, dst: &mut bytes::BytesMut) -> usize
+    where
+        Self: Clone + Sized,
+    {
+        self.clone().to_bytes(dst)
+    }
+
+    /// Encode the component into a buffer without consuming it, failing
+    /// instead of panicking if it cannot be represented on the wire.
+    ///
+    /// Returns the number of bytes written. On error, `dst` may contain part
+    /// of the component.
+    ///
+    /// # Errors
+    /// Returns `Error::InternalLength` if a length field would overflow.
+    fn try_encode_to(&self, dst: &mut bytes::BytesMut) -> Result<usize, Error>
+    where
+        Self: Clone + Sized,
+    {
+        self.clone().try_to_bytes(dst)
+    }
+
     /// Find out the length of the component, preferably without encoding it.
     fn encoded_len(&self) -> usize;
 }
 
+/// A complete message with its header (RFC 4271 Section 4.1)
+///
+/// Decoding takes one message from the start of `src`, accepting the
+/// length of Extended Messages (RFC 8654) and assuming four-octet AS numbers.
+impl Component for Message {
+    fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, Error> {
+        let Some(length) = peek_message_len(src, crate::MAX_EXTENDED_MESSAGE_LEN)? else {
+            return Err(Error::InternalLength("message", core::cmp::Ordering::Less));
+        };
+        check_min_len!(src, length, "message");
+        decode_message(src.split_to(length), true, &[])
+    }
+
+    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.encode_to(dst)
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, Error> {
+        encode_message(&self, dst)
+    }
+
+    fn encode_to(&self, dst: &mut bytes::BytesMut) -> usize {
+        encode_message(self, dst).expect("message length overflow")
+    }
+
+    fn try_encode_to(&self, dst: &mut bytes::BytesMut) -> Result<usize, Error> {
+        encode_message(self, dst)
+    }
+
+    fn encoded_len(&self) -> usize {
+        HEADER_LEN
+            + match self {
+                Self::Open(msg) => msg.encoded_len(),
+                Self::Update(msg) => msg.encoded_len(),
+                Self::Notification(msg) => msg.encoded_len(),
+                Self::Keepalive => 0,
+                Self::RouteRefresh(msg) => msg.encoded_len(),
+            }
+    }
+}
+
 impl Component for Ipv4Addr {
     fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, Error> {
+        check_min_len!(src, 4, "IPv4 address");
         let octets = src.get_u32();
         Ok(Self::from(octets))
     }
@@ -140,6 +461,7 @@ impl Component for Ipv4Addr {
 
 impl Component for Ipv6Addr {
     fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, Error> {
+        check_min_len!(src, 16, "IPv6 address");
         let mut octets = [0; 16];
         src.copy_to_slice(&mut octets);
         Ok(Self::from(octets))
@@ -164,7 +486,7 @@ impl Component for IpAddr {
         } else {
             Err(Error::InternalLength(
                 "IP address",
-                std::cmp::Ordering::Equal,
+                core::cmp::Ordering::Equal,
             ))
         }
     }
@@ -188,6 +510,7 @@ macro_rules! impl_component_for_intn {
     ($typ:ty, $getter:ident, $putter:ident, $n:expr) => {
         impl Component for $typ {
             fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, Error> {
+                check_min_len!(src, $n, stringify!($typ));
                 Ok(src.$getter())
             }
 
@@ -207,3 +530,60 @@ impl_component_for_intn!(u8, get_u8, put_u8, 1);
 impl_component_for_intn!(u16, get_u16, put_u16, 2);
 impl_component_for_intn!(u32, get_u32, put_u32, 4);
 impl_component_for_intn!(u64, get_u64, put_u64, 8);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex_to_bytes;
+
+    #[test]
+    fn test_message_keepalive() {
+        let data = hex_to_bytes("ffffffffffffffffffffffffffffffff 0013 04");
+        let msg = Message::from_bytes(&mut data.clone()).unwrap();
+        assert_eq!(msg, Message::Keepalive);
+        assert_eq!(msg.encoded_len(), data.len());
+        let mut dst = bytes::BytesMut::new();
+        assert_eq!(msg.to_bytes(&mut dst), data.len());
+        assert_eq!(dst.freeze(), data);
+    }
+
+    #[test]
+    fn test_message_open() {
+        // Followed by a keepalive that should be left in the buffer
+        let mut src = hex_to_bytes(
+            "ffffffffffffffffffffffffffffffff 001d 01 04 fd7d 0078 ac1706a5 00
+            ffffffffffffffffffffffffffffffff 0013 04",
+        );
+        let msg = Message::from_bytes(&mut src).unwrap();
+        let Message::Open(open) = &msg else {
+            panic!("expected OPEN");
+        };
+        assert_eq!(open.asn, 64893);
+        assert_eq!(open.hold_time, 120);
+        assert_eq!(open.bgp_id, Ipv4Addr::new(172, 23, 6, 165));
+        assert_eq!(Message::from_bytes(&mut src).unwrap(), Message::Keepalive);
+        assert!(src.is_empty());
+        let mut dst = bytes::BytesMut::new();
+        assert_eq!(msg.try_encode_to(&mut dst).unwrap(), 29);
+        assert_eq!(msg.encoded_len(), 29);
+        assert_eq!(Message::from_bytes(&mut dst.freeze()).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_message_truncated() {
+        for data in [
+            "ffffffffffffffffffffffffffffffff 00",
+            "ffffffffffffffffffffffffffffffff 001d 01 04 fd7d",
+        ] {
+            assert!(
+                matches!(
+                    Message::from_bytes(&mut hex_to_bytes(data)),
+                    Err(Error::InternalLength("message", core::cmp::Ordering::Less))
+                ),
+                "{data}"
+            );
+        }
+        let mut src = hex_to_bytes("00000000000000000000000000000000 0013 04");
+        assert!(matches!(Message::from_bytes(&mut src), Err(Error::Marker)));
+    }
+}
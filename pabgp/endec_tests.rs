@@ -7,6 +7,7 @@ use super::path::*;
 use super::route::*;
 use super::*;
 use bytes::{Bytes, BytesMut};
+use proptest::prelude::*;
 use std::net::Ipv6Addr;
 use tokio_util::codec::{Decoder, Encoder};
 
@@ -27,7 +28,7 @@ fn test_sanity_hex_to_bytes() {
 fn test_keepalive_message() {
     let data = hex_to_bytes("ffffffffffffffffffffffffffffffff001304");
     let mut bmut = data.clone().into();
-    let mut codec = BgpCodec;
+    let mut codec = BgpCodec::default();
     let msg = codec.decode(&mut bmut).unwrap().unwrap();
     assert_eq!(msg, Message::Keepalive);
     let mut bmut = BytesMut::new();
@@ -40,7 +41,7 @@ fn test_open_message_wsh_1() {
     // Dumped from a real BGP session (Wireshark and BIRD)
     let data = hex_to_bytes("ffffffffffffffffffffffffffffffff 001d 01 04 fd7d 0078 ac1706a5 00");
     let mut bmut = data.clone().into();
-    let mut codec = BgpCodec;
+    let mut codec = BgpCodec::default();
     let msg = codec.decode(&mut bmut).unwrap().unwrap();
     let Message::Open(msg) = msg else {
         panic!("unexpected message type");
@@ -60,7 +61,7 @@ fn test_open_message_wsh_2() {
     // Dumped from a real BGP session (Wireshark and BIRD)
     let data = hex_to_bytes("ffffffffffffffffffffffffffffffff004501045ba000f0ac1706a2280226010400010001010400020001020005060001000100020600400200784104fcde349d46004700");
     let mut bmut = data.clone().into();
-    let mut codec = BgpCodec;
+    let mut codec = BgpCodec::default();
     let msg = codec.decode(&mut bmut).unwrap().unwrap();
     let Message::Open(msg) = msg else {
         panic!("unexpected message type");
@@ -69,7 +70,9 @@ fn test_open_message_wsh_2() {
     assert_eq!(msg.asn, AS_TRANS);
     assert_eq!(msg.hold_time, 240);
     assert_eq!(msg.bgp_id, Ipv4Addr::new(172, 23, 6, 162));
-    let OptionalParameterValue::Capabilities(cap) = &msg.opt_params.first().unwrap();
+    let OptionalParameterValue::Capabilities(cap) = &msg.opt_params.first().unwrap() else {
+        panic!("unexpected optional parameter type");
+    };
     assert_eq!(cap.len(), 9);
     assert_eq!(
         *cap.first().unwrap(),
@@ -97,7 +100,11 @@ fn test_open_message_wsh_2() {
     assert_eq!(*cap.get(4).unwrap(), capability::Value::ExtendedMessage);
     assert_eq!(
         *cap.get(5).unwrap(),
-        capability::Value::Unsupported(0x40, Bytes::from_static(&[0x00, 0x78]))
+        capability::Value::GracefulRestart(GracefulRestart {
+            flags: 0,
+            restart_time: 120,
+            entries: vec![],
+        })
     );
     assert_eq!(
         *cap.get(6).unwrap(),
@@ -116,6 +123,81 @@ fn test_open_message_wsh_2() {
     assert_eq!(bmut.freeze(), data);
 }
 
+#[test]
+fn test_open_echo_capabilities() {
+    // Same OPEN as `test_open_message_wsh_2`
+    let data = hex_to_bytes("ffffffffffffffffffffffffffffffff004501045ba000f0ac1706a2280226010400010001010400020001020005060001000100020600400200784104fcde349d46004700");
+    let Message::Open(open) = Message::from_bytes(&mut data.clone()).unwrap() else {
+        panic!("unexpected message type");
+    };
+    let caps = open.echo_capabilities();
+    assert_eq!(caps.len(), 9);
+    let rebuilt = CapabilitiesBuilder::from_existing(caps.clone()).build();
+    assert_eq!(rebuilt, caps);
+    // A proxy re-advertising the capabilities sends the same OPEN
+    let relayed = Open::new_easy(
+        caps.four_octet_asn().unwrap(),
+        open.hold_time,
+        open.bgp_id,
+        rebuilt,
+    );
+    assert_eq!(relayed, open);
+    let mut bmut = BytesMut::new();
+    Message::Open(relayed).to_bytes(&mut bmut);
+    assert_eq!(bmut.freeze(), data);
+    // Added tuples are merged into the existing capability in place
+    let merged = CapabilitiesBuilder::from_existing(caps.clone())
+        .enh_ipv6_over_ipv4()
+        .build();
+    assert_eq!(merged.len(), 9);
+    assert_eq!(
+        merged[3],
+        capability::Value::ExtendedNextHop(ExtendedNextHop(vec![
+            ExtendedNextHopValue {
+                afi: Afi::Ipv4,
+                safi: Safi::Unicast,
+                next_hop_afi: Afi::Ipv6,
+            },
+            ExtendedNextHopValue {
+                afi: Afi::Ipv6,
+                safi: Safi::Unicast,
+                next_hop_afi: Afi::Ipv4,
+            },
+        ]))
+    );
+    assert_eq!(merged[7], caps[7]);
+    assert_eq!(merged[8], caps[8]);
+}
+
+#[test]
+fn test_builder_from_existing_keeps_duplicates() {
+    let caps = Capabilities::from(vec![
+        capability::Value::MultiProtocol(MultiProtocol {
+            afi: Afi::Ipv4,
+            safi: Safi::Unicast,
+        }),
+        capability::Value::Unsupported(128, Bytes::new()),
+        capability::Value::MultiProtocol(MultiProtocol {
+            afi: Afi::Ipv4,
+            safi: Safi::Unicast,
+        }),
+        capability::Value::Unsupported(128, Bytes::new()),
+    ]);
+    assert_eq!(
+        CapabilitiesBuilder::from_existing(caps.clone()).build(),
+        caps
+    );
+    // Only new capabilities are deduplicated
+    let built = CapabilitiesBuilder::from_existing(caps.clone())
+        .mp_ipv4_unicast()
+        .route_refresh()
+        .route_refresh()
+        .build();
+    assert_eq!(built.len(), 5);
+    assert_eq!(built[..4], caps[..]);
+    assert_eq!(built[4], capability::Value::RouteRefresh);
+}
+
 #[test]
 fn test_update_message_wsh_1() {
     // Dumped from a real BGP session (Wireshark and BIRD)
@@ -132,7 +214,8 @@ fn test_update_message_wsh_1() {
     18cb0486",
     );
     let mut bmut = data.clone().into();
-    let mut codec = BgpCodec;
+    // A two-octet AS speaker, hence the AS4_PATH
+    let mut codec = BgpCodec::default().with_four_octet_asn(false);
     let msg = codec.decode(&mut bmut).unwrap().unwrap();
     let Message::Update(msg) = msg else {
         panic!("unexpected message type");
@@ -205,7 +288,7 @@ fn test_update_message_wsh_2() {
         fcde3880 00000065 0000040c",
     );
     let mut bmut = data.clone().into();
-    let mut codec = BgpCodec;
+    let mut codec = BgpCodec::default();
     let msg = codec.decode(&mut bmut).unwrap().unwrap();
     let Message::Update(msg) = msg else {
         panic!("unexpected message type");
@@ -223,11 +306,11 @@ fn test_update_message_wsh_2() {
                     Ipv6Addr::new(0xfdc0, 0xd227, 0x0306, 0xee01, 0, 0, 0, 0x0161),
                     Ipv6Addr::new(0xfe80, 0, 0, 0, 0x84cf, 0x65ff, 0xfead, 0x2f30)
                 ),
-                nlri: Routes(vec![Cidr4 {
+                nlri: MpNlri::Routes(Routes(vec![Cidr4 {
                     addr: Ipv4Addr::new(172, 23, 227, 0),
                     prefix_len: 24,
                 }
-                .into()]),
+                .into()])),
             }),
         }
     );
@@ -260,25 +343,17 @@ fn test_update_message_wsh_2() {
         *msg.path_attributes.get(4).unwrap(),
         path::Value {
             flags: path::Flags(0xc0),
-            data: path::Data::Unsupported(
-                0x08,
-                Bytes::from_static(&[
-                    0xfb, 0xff, 0x00, 0x04, 0xfb, 0xff, 0x00, 0x18, 0xfb, 0xff, 0x00, 0x22
-                ])
-            ),
+            data: path::Data::Communities(Communities(vec![0xfbff_0004, 0xfbff_0018, 0xfbff_0022])),
         }
     );
     assert_eq!(
         *msg.path_attributes.get(5).unwrap(),
         path::Value {
             flags: path::Flags(0xe0),
-            data: path::Data::Unsupported(
-                0x20,
-                Bytes::from_static(&[
-                    0xfc, 0xde, 0x38, 0x80, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, 0x35, 0xfc,
-                    0xde, 0x38, 0x80, 0x00, 0x00, 0x00, 0x65, 0x00, 0x00, 0x04, 0x0c
-                ])
-            ),
+            data: path::Data::LargeCommunities(LargeCommunities(vec![
+                LargeCommunity::new(0xfcde_3880, 100, 53),
+                LargeCommunity::new(0xfcde_3880, 101, 1036),
+            ])),
         }
     );
     let mut bmut = BytesMut::new();
@@ -291,7 +366,7 @@ fn test_notification_message_wsh_1() {
     // Dumped from a real BGP session (Wireshark and BIRD)
     let data = hex_to_bytes("ffffffffffffffffffffffffffffffff 0015 03 06 02");
     let mut bmut = data.clone().into();
-    let mut codec = BgpCodec;
+    let mut codec = BgpCodec::default();
     let msg = codec.decode(&mut bmut).unwrap().unwrap();
     let Message::Notification(msg) = msg else {
         panic!("unexpected message type");
@@ -306,3 +381,820 @@ fn test_notification_message_wsh_1() {
     codec.encode(Message::Notification(msg), &mut bmut).unwrap();
     assert_eq!(bmut.freeze(), data);
 }
+
+#[test]
+fn test_route_refresh_message() {
+    let data = hex_to_bytes("ffffffffffffffffffffffffffffffff 0017 05 0002 00 01");
+    let mut bmut = data.clone().into();
+    let mut codec = BgpCodec::default();
+    let msg = codec.decode(&mut bmut).unwrap().unwrap();
+    assert_eq!(
+        msg,
+        Message::RouteRefresh(RouteRefresh::new(Afi::Ipv6, Safi::Unicast))
+    );
+    let mut bmut = BytesMut::new();
+    codec.encode(msg, &mut bmut).unwrap();
+    assert_eq!(bmut.freeze(), data);
+}
+
+#[test]
+fn test_route_refresh_unknown_afi_safi() {
+    let data = hex_to_bytes("ffffffffffffffffffffffffffffffff 0017 05 4000 00 fe");
+    let mut bmut = data.clone().into();
+    let mut codec = BgpCodec::default();
+    let msg = codec.decode(&mut bmut).unwrap().unwrap();
+    let Message::RouteRefresh(refresh) = msg else {
+        panic!("Expected ROUTE-REFRESH, got {msg:?}");
+    };
+    assert_eq!((refresh.afi, refresh.safi), (0x4000, 0xfe));
+    assert_eq!(refresh.afi_safi(), None);
+    let mut bmut = BytesMut::new();
+    codec.encode(msg, &mut bmut).unwrap();
+    assert_eq!(bmut.freeze(), data);
+}
+
+#[test]
+fn test_extended_message_length() {
+    // An UPDATE claiming 4097 bytes, with nothing but the header received
+    let data = hex_to_bytes("ffffffffffffffffffffffffffffffff 1001 02");
+    let mut bmut = data.clone().into();
+    let mut codec = BgpCodec::default();
+    assert!(matches!(
+        codec.decode(&mut bmut),
+        Err(Error::MessageLength(4097))
+    ));
+    let mut bmut = data.into();
+    let mut codec = BgpCodec::new(true);
+    assert!(codec.decode(&mut bmut).unwrap().is_none());
+}
+
+#[test]
+fn test_update_missing_next_hop() {
+    // ORIGIN and an empty AS_PATH, followed by NLRI 192.0.2.0/24
+    let mut data = hex_to_bytes("0000 0007 40010100 400200 18c00002");
+    let result = Update::from_bytes_validated(&mut data);
+    let Err(Error::UpdateMessage(subcode, data)) = result else {
+        panic!("expected an UPDATE message error, got {result:?}");
+    };
+    assert_eq!(
+        subcode,
+        UpdateMessageErrorSubcode::MissingWellKnownAttribute
+    );
+    assert_eq!(data, hex_to_bytes("03"));
+    // Adding NEXT_HOP makes it valid
+    let mut data = hex_to_bytes("0000 000e 40010100 400200 400304c0000201 18c00002");
+    let update = Update::from_bytes_validated(&mut data).unwrap();
+    assert_eq!(update.nlri.len(), 1);
+    // Withdrawals alone need no attributes
+    let mut data = hex_to_bytes("0004 18c00002 0000");
+    assert!(Update::from_bytes_validated(&mut data).is_ok());
+}
+
+#[test]
+fn test_administrative_shutdown() {
+    let msg = Notification::administrative_shutdown(Some("maintenance"));
+    let mut bmut = BytesMut::new();
+    BgpCodec::default()
+        .encode(Message::Notification(msg), &mut bmut)
+        .unwrap();
+    let expected =
+        hex_to_bytes("ffffffffffffffffffffffffffffffff 0021 03 06 02 0b 6d61696e74656e616e6365");
+    assert_eq!(bmut.freeze(), expected);
+    let msg = Notification::administrative_shutdown(None);
+    assert_eq!(msg.data, Bytes::new());
+    // Truncated on a character boundary
+    let long = "é".repeat(200);
+    let msg = Notification::administrative_shutdown(Some(&long));
+    assert_eq!(msg.data[0], 254);
+    assert_eq!(msg.data.len(), 255);
+}
+
+#[test]
+fn test_shutdown_communication() {
+    let data = hex_to_bytes(
+        "ffffffffffffffffffffffffffffffff 0026 03 06 02 10 7363686564756c6564206d61696e742e",
+    );
+    let mut bmut = data.clone().into();
+    let mut codec = BgpCodec::default();
+    let msg = codec.decode(&mut bmut).unwrap().unwrap();
+    let Message::Notification(msg) = msg else {
+        panic!("unexpected message type");
+    };
+    assert_eq!(
+        msg.shutdown_communication().as_deref(),
+        Some("scheduled maint.")
+    );
+    let mut bmut = BytesMut::new();
+    codec.encode(Message::Notification(msg), &mut bmut).unwrap();
+    assert_eq!(bmut.freeze(), data);
+    // No communication, truncated, or not a shutdown
+    let msg = Notification::administrative_shutdown(None);
+    assert_eq!(msg.shutdown_communication(), None);
+    let msg = Notification::new(
+        NotificationErrorCode::Cease,
+        CeaseSubcode::AdministrativeReset as u8,
+        hex_to_bytes("10 7363686564756c6564"),
+    );
+    assert_eq!(msg.shutdown_communication(), None);
+    let msg = Notification::new(
+        NotificationErrorCode::Cease,
+        CeaseSubcode::PeerDeconfigured as u8,
+        hex_to_bytes("02 6869"),
+    );
+    assert_eq!(msg.shutdown_communication(), None);
+}
+
+#[test]
+#[cfg(feature = "impl-serde")]
+fn test_json_round_trip() {
+    let fixtures = [
+        // OPEN with unsupported capabilities (Wireshark and BIRD)
+        "ffffffffffffffffffffffffffffffff004501045ba000f0ac1706a2280226010400010001010400020001020005060001000100020600400200784104fcde349d46004700",
+        // UPDATE with an unsupported attribute
+        "ffffffffffffffffffffffffffffffff 002e 02 0000 0013
+        40 01 01 00
+        40 02 00
+        40 03 04 c0000201
+        c0 63 02 abcd
+        18 c00002",
+        // NOTIFICATION with a shutdown communication
+        "ffffffffffffffffffffffffffffffff 0026 03 06 02 10 7363686564756c6564206d61696e742e",
+    ];
+    let mut codec = BgpCodec::default();
+    for fixture in fixtures {
+        let data = hex_to_bytes(fixture);
+        let mut bmut = data.clone().into();
+        let msg = codec.decode(&mut bmut).unwrap().unwrap();
+        let json = serde_json::to_string(&msg).unwrap();
+        let decoded: Message = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, msg);
+        let mut bmut = BytesMut::new();
+        codec.encode(decoded, &mut bmut).unwrap();
+        assert_eq!(bmut.freeze(), data);
+    }
+    // Bytes are hex strings
+    let msg = Notification::administrative_shutdown(Some("hi"));
+    let json = serde_json::to_value(&msg).unwrap();
+    assert_eq!(json["data"], "026869");
+}
+
+#[test]
+fn test_decode_stream() {
+    let data = hex_to_bytes(
+        "ffffffffffffffffffffffffffffffff 0013 04
+        ffffffffffffffffffffffffffffffff 0015 03 06 02",
+    );
+    let messages = decode_stream(&data[..])
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0], Message::Keepalive);
+    let Message::Notification(msg) = &messages[1] else {
+        panic!("unexpected message type");
+    };
+    assert_eq!(msg.error_code, NotificationErrorCode::Cease);
+    // A truncated message ends the stream with an error
+    let mut messages = decode_stream(&data[..data.len() - 1]);
+    assert!(matches!(messages.next(), Some(Ok(Message::Keepalive))));
+    assert!(matches!(messages.next(), Some(Err(Error::Truncated(20)))));
+    assert!(messages.next().is_none());
+    // Also in the middle of the header
+    let mut messages = decode_stream(&data[..19 + 5]);
+    assert!(matches!(messages.next(), Some(Ok(Message::Keepalive))));
+    assert!(matches!(messages.next(), Some(Err(Error::Truncated(5)))));
+}
+
+#[test]
+fn test_decode_byte_by_byte() {
+    let data = hex_to_bytes(
+        "
+    ffffffffffffffffffffffffffffffff 0048 02 0000 0031
+    90 0e 0020 0001 80
+        0c 0000000000000000 c0000201
+        00
+        70 000641 0000fde800000064 c63364
+    40 01 01 00
+    40 02 06 0201 0000fde8",
+    );
+    let mut codec = BgpCodec::default();
+    let mut src = BytesMut::new();
+    let mut messages = Vec::new();
+    for byte in &data {
+        src.extend_from_slice(&[*byte]);
+        if let Some(message) = codec.decode(&mut src).unwrap() {
+            messages.push(message);
+        }
+    }
+    assert_eq!(messages.len(), 1);
+    assert!(matches!(messages[0], Message::Update(_)));
+    assert!(src.is_empty());
+    // The marker is only checked once the whole message has arrived
+    let mut src = hex_to_bytes("00000000000000000000000000000000 0013").into();
+    assert!(matches!(codec.decode(&mut src), Ok(None)));
+    src.extend_from_slice(&[4]);
+    assert!(matches!(codec.decode(&mut src), Err(Error::Marker)));
+}
+
+#[test]
+fn test_decode_eof() {
+    let mut codec = BgpCodec::default();
+    let mut src = hex_to_bytes("ffffffffffffffffffffffffffffffff 0013 04").into();
+    assert!(matches!(
+        codec.decode_eof(&mut src),
+        Ok(Some(Message::Keepalive))
+    ));
+    assert!(matches!(codec.decode_eof(&mut src), Ok(None)));
+    let mut src = hex_to_bytes("ffffffffffffffffffffffffffffffff 00").into();
+    assert!(matches!(
+        codec.decode_eof(&mut src),
+        Err(Error::Truncated(17))
+    ));
+}
+
+#[test]
+fn test_message_too_short() {
+    let mut bmut = hex_to_bytes("ffffffffffffffffffffffffffffffff 0012 04").into();
+    let result = BgpCodec::default().decode(&mut bmut);
+    assert!(matches!(result, Err(Error::MessageLength(18))));
+}
+
+#[test]
+fn test_notification_from_decode_error() {
+    let decode = |hex| {
+        let mut bmut = hex_to_bytes(hex).into();
+        Notification::from(BgpCodec::default().decode(&mut bmut).unwrap_err())
+    };
+    let notification = decode("fffffffffffffffffffffffffffffff0 0013 04");
+    assert_eq!(
+        notification.error_code,
+        NotificationErrorCode::MessageHeaderError
+    );
+    assert_eq!(
+        notification.error_subcode,
+        MessageHeaderErrorSubcode::ConnectionNotSynchronized as u8
+    );
+    let notification = decode("ffffffffffffffffffffffffffffffff 0013 09");
+    assert_eq!(
+        notification.error_subcode,
+        MessageHeaderErrorSubcode::BadMessageType as u8
+    );
+    assert_eq!(notification.data, Bytes::from_static(&[9]));
+    let notification = decode("ffffffffffffffffffffffffffffffff 0012 04");
+    assert_eq!(notification, Notification::bad_message_length(18));
+    // KEEPALIVE with a body
+    let notification = decode("ffffffffffffffffffffffffffffffff 0014 04 00");
+    assert_eq!(notification, Notification::bad_message_length(20));
+    // OPEN with an optional parameter running past the end
+    let notification =
+        decode("ffffffffffffffffffffffffffffffff 001e 01 04 fde8 005a c0000202 01 02");
+    assert_eq!(
+        notification.error_code,
+        NotificationErrorCode::OpenMessageError
+    );
+    assert_eq!(
+        notification.error_subcode,
+        OpenMessageErrorSubcode::Unspecific as u8
+    );
+    // UPDATE whose total path attribute length is too long
+    let notification = decode("ffffffffffffffffffffffffffffffff 0017 02 0000 0001");
+    assert_eq!(
+        notification.error_code,
+        NotificationErrorCode::UpdateMessageError
+    );
+    assert_eq!(
+        notification.error_subcode,
+        UpdateMessageErrorSubcode::MalformedAttributeList as u8
+    );
+    let notification = decode("ffffffffffffffffffffffffffffffff 001d 02 0000 0000 28 0a00000000");
+    assert_eq!(
+        notification.error_code,
+        NotificationErrorCode::UpdateMessageError
+    );
+    assert_eq!(
+        notification.error_subcode,
+        UpdateMessageErrorSubcode::InvalidNetworkField as u8
+    );
+}
+
+#[test]
+fn test_notification_bad_message_length() {
+    let notification = Notification::bad_message_length(4097);
+    let mut dst = BytesMut::new();
+    notification.to_bytes(&mut dst);
+    assert_eq!(dst, hex_to_bytes("01 02 1001"));
+}
+
+#[test]
+fn test_notification_unsupported_capability() {
+    let cap = capability::Value::MultiProtocol(MultiProtocol {
+        afi: Afi::Ipv6,
+        safi: Safi::Unicast,
+    });
+    let notification = Notification::unsupported_capability(&cap);
+    let mut dst = BytesMut::new();
+    notification.to_bytes(&mut dst);
+    assert_eq!(dst, hex_to_bytes("02 07 01 04 0002 00 01"));
+    let cap = capability::Value::Unsupported(128, Bytes::from_static(&[0xab, 0xcd]));
+    let notification = Notification::unsupported_capability(&cap);
+    assert_eq!(notification.data, hex_to_bytes("80 02 abcd"));
+}
+
+#[test]
+fn test_notification_unsupported_optional_parameter() {
+    let notification = Notification::unsupported_optional_parameter(255);
+    let mut dst = BytesMut::new();
+    notification.to_bytes(&mut dst);
+    assert_eq!(dst, hex_to_bytes("02 04 ff"));
+}
+
+#[test]
+fn test_update_message_vpnv4() {
+    let data = hex_to_bytes(
+        "
+    ffffffffffffffffffffffffffffffff 0048 02 0000 0031
+    90 0e 0020 0001 80
+        0c 0000000000000000 c0000201
+        00
+        70 000641 0000fde800000064 c63364
+    40 01 01 00
+    40 02 06 0201 0000fde8",
+    );
+    let mut bmut = data.clone().into();
+    let mut codec = BgpCodec::default();
+    let msg = codec.decode(&mut bmut).unwrap().unwrap();
+    let Message::Update(msg) = msg else {
+        panic!("unexpected message type");
+    };
+    assert_eq!(
+        msg.path_attributes[0],
+        path::Value {
+            flags: path::Flags(0x90),
+            data: path::Data::MpReachNlri(MpReachNlri {
+                afi: Afi::Ipv4,
+                safi: Safi::Vpn,
+                next_hop: MpNextHop::Single(Ipv4Addr::new(192, 0, 2, 1).into()),
+                nlri: MpNlri::Vpn(VpnRoutes(vec![VpnValue {
+                    path_id: None,
+                    labels: vec![0x641],
+                    rd: RouteDistinguisher(0x0000_fde8_0000_0064),
+                    prefix_len: 24,
+                    prefix: hex_to_bytes("c63364"),
+                }])),
+            }),
+        }
+    );
+    let mut bmut = BytesMut::new();
+    codec.encode(Message::Update(msg), &mut bmut).unwrap();
+    assert_eq!(bmut.freeze(), data);
+}
+
+#[test]
+fn test_update_message_add_path() {
+    let data = hex_to_bytes(
+        "
+    ffffffffffffffffffffffffffffffff 006f 02
+    0008 00000001 18 c00002
+    0048
+    40 01 01 00
+    40 02 06 0201 0000fde8
+    40 03 04 c0000201
+    90 0e 001e 0002 01
+        10 20010db8000000000000000000000001
+        00
+        00000007 20 20010db8
+    90 0f 000e 0002 01
+        00000008 30 20010db80001
+    00000002 18 c63364",
+    );
+    // The path identifiers are misparsed as prefixes without ADD-PATH
+    let mut bmut = data.clone().into();
+    assert!(BgpCodec::default().decode(&mut bmut).is_err());
+    let mut bmut = data.clone().into();
+    let mut codec = BgpCodec::default()
+        .with_add_path(vec![(Afi::Ipv4, Safi::Unicast), (Afi::Ipv6, Safi::Unicast)]);
+    let msg = codec.decode(&mut bmut).unwrap().unwrap();
+    let Message::Update(msg) = msg else {
+        panic!("unexpected message type");
+    };
+    let with_path_id = |path_id, cidr: &str| route::Value {
+        path_id: Some(path_id),
+        ..route::Value::from(cidr.parse::<cidr::Cidr>().unwrap())
+    };
+    assert_eq!(
+        msg.withdrawn_routes,
+        Routes(vec![with_path_id(1, "192.0.2.0/24")])
+    );
+    assert_eq!(msg.nlri, Routes(vec![with_path_id(2, "198.51.100.0/24")]));
+    let path::Data::MpReachNlri(reach) = &msg.path_attributes[3].data else {
+        panic!("expected MP_REACH_NLRI");
+    };
+    assert_eq!(
+        reach.nlri,
+        MpNlri::Routes(Routes(vec![with_path_id(7, "2001:db8::/32")]))
+    );
+    let path::Data::MpUnreachNlri(unreach) = &msg.path_attributes[4].data else {
+        panic!("expected MP_UNREACH_NLRI");
+    };
+    assert_eq!(
+        unreach.withdrawn_routes,
+        MpNlri::Routes(Routes(vec![with_path_id(8, "2001:db8:1::/48")]))
+    );
+    let mut bmut = BytesMut::new();
+    codec.encode(Message::Update(msg), &mut bmut).unwrap();
+    assert_eq!(bmut.freeze(), data);
+}
+
+#[test]
+fn test_encode_ref() {
+    // VPN-IPv4 route with an unrecognized optional transitive attribute
+    let data = hex_to_bytes(
+        "
+    ffffffffffffffffffffffffffffffff 004d 02 0000 0036
+    90 0e 0020 0001 80
+        0c 0000000000000000 c0000201
+        00
+        70 000641 0000fde800000064 c63364
+    40 01 01 00
+    40 02 06 0201 0000fde8
+    c0 f0 02 abcd",
+    );
+    let mut bmut = data.clone().into();
+    let mut codec = BgpCodec::default();
+    let msg = codec.decode(&mut bmut).unwrap().unwrap();
+    let mut bmut = BytesMut::new();
+    codec.encode_ref(&msg, &mut bmut).unwrap();
+    assert_eq!(bmut.freeze(), data);
+    let Message::Update(update) = msg else {
+        panic!("unexpected message type");
+    };
+    let mut borrowed = BytesMut::new();
+    let len = update.encode_to(&mut borrowed);
+    let mut owned = BytesMut::new();
+    assert_eq!(update.to_bytes(&mut owned), len);
+    assert_eq!(borrowed, owned);
+    assert_eq!(len, data.len() - 19);
+}
+
+#[test]
+fn test_update_message_unsupported_nlri() {
+    // EVPN MAC/IP Advertisement route and a Flow Specification withdrawal
+    let data = hex_to_bytes(
+        "
+    ffffffffffffffffffffffffffffffff 0058 02 0000 0041
+    90 0e 002c 0019 46
+        04 c0000201
+        00
+        02 21 0000fde800000064 00000000000000000000 00000000 30 00005e005301 00 000641
+    90 0f 0009 0001 85
+        05 01 18 c00002
+    40 01 01 00",
+    );
+    let mut bmut = data.clone().into();
+    let mut codec = BgpCodec::default();
+    let msg = codec.decode(&mut bmut).unwrap().unwrap();
+    let Message::Update(msg) = msg else {
+        panic!("unexpected message type");
+    };
+    let path::Data::MpReachNlri(reach) = &msg.path_attributes[0].data else {
+        panic!("expected MP_REACH_NLRI");
+    };
+    assert_eq!(reach.afi, Afi::L2Vpn);
+    assert_eq!(reach.safi, Safi::Evpn);
+    assert!(matches!(&reach.nlri, MpNlri::Unsupported(nlri) if nlri.len() == 35));
+    let path::Data::MpUnreachNlri(unreach) = &msg.path_attributes[1].data else {
+        panic!("expected MP_UNREACH_NLRI");
+    };
+    assert_eq!(unreach.safi, Safi::FlowSpecUnicast);
+    assert_eq!(
+        unreach.withdrawn_routes,
+        MpNlri::Unsupported(hex_to_bytes("05 01 18 c00002"))
+    );
+    let mut bmut = BytesMut::new();
+    codec.encode(Message::Update(msg), &mut bmut).unwrap();
+    assert_eq!(bmut.freeze(), data);
+}
+
+#[test]
+fn test_open_message_unsupported_parameter() {
+    let data = hex_to_bytes(
+        "
+    ffffffffffffffffffffffffffffffff 0029 01 04 fde8 00b4 c0000201 0c
+    02 06 01 04 0001 0001
+    99 02 abcd",
+    );
+    let mut bmut = data.clone().into();
+    let mut codec = BgpCodec::default();
+    let msg = codec.decode(&mut bmut).unwrap().unwrap();
+    let Message::Open(msg) = msg else {
+        panic!("unexpected message type");
+    };
+    assert_eq!(msg.opt_params.len(), 2);
+    assert!(matches!(
+        msg.opt_params[0],
+        OptionalParameterValue::Capabilities(_)
+    ));
+    assert_eq!(
+        msg.opt_params[1],
+        OptionalParameterValue::Unsupported(0x99, hex_to_bytes("abcd"))
+    );
+    assert_eq!(msg.opt_params.encoded_len(), 13);
+    let mut bmut = BytesMut::new();
+    codec.encode(Message::Open(msg), &mut bmut).unwrap();
+    assert_eq!(bmut.freeze(), data);
+}
+
+#[test]
+fn test_open_message_multiple_capability_parameters() {
+    // Two Capabilities parameters around a deprecated Authentication parameter
+    let data = hex_to_bytes(
+        "
+    ffffffffffffffffffffffffffffffff 0031 01 04 fde8 00b4 c0000201 14
+    02 06 01 04 0001 0001
+    01 02 abcd
+    02 06 41 04 0000fde8",
+    );
+    let mut bmut = data.clone().into();
+    let mut codec = BgpCodec::default();
+    let msg = codec.decode(&mut bmut).unwrap().unwrap();
+    let Message::Open(msg) = msg else {
+        panic!("unexpected message type");
+    };
+    assert_eq!(msg.opt_params.len(), 3);
+    assert_eq!(
+        msg.opt_params[1],
+        OptionalParameterValue::Unsupported(1, hex_to_bytes("abcd"))
+    );
+    let caps = msg.opt_params.all_capabilities();
+    assert_eq!(
+        caps,
+        CapabilitiesBuilder::new()
+            .mp_ipv4_unicast()
+            .four_octet_as_number(65000)
+            .build()
+    );
+    assert_eq!(caps.four_octet_asn(), Some(65000));
+    let mut bmut = BytesMut::new();
+    codec.encode(Message::Open(msg), &mut bmut).unwrap();
+    assert_eq!(bmut.freeze(), data);
+}
+
+#[test]
+fn test_encode_all() {
+    let messages = vec![
+        Message::Keepalive,
+        Message::Update(crate::UpdateBuilder::end_of_rib(Afi::Ipv4, Safi::Unicast)),
+        Message::RouteRefresh(RouteRefresh::new(Afi::Ipv6, Safi::Unicast)),
+    ];
+    let mut codec = BgpCodec::default();
+    let mut expected = BytesMut::new();
+    for message in messages.clone() {
+        codec.encode(message, &mut expected).unwrap();
+    }
+    let mut dst = BytesMut::new();
+    codec.encode(Message::Keepalive, &mut dst).unwrap();
+    codec.encode_all(messages.clone(), &mut dst).unwrap();
+    assert_eq!(dst[19..], expected[..]);
+    // Nothing is appended if any message fails
+    let invalid = Update {
+        withdrawn_routes: Routes::default(),
+        path_attributes: PathAttributes(vec![path::Value {
+            flags: Flags::WELL_KNOWN_COMPLETE,
+            data: Data::AsPath(AsPath(vec![AsSegment {
+                type_: AsSegmentType::AsSequence,
+                asns: vec![65000; 256],
+                as4: false,
+            }])),
+        }]),
+        nlri: Routes::default(),
+    };
+    let mut with_invalid = messages;
+    with_invalid.push(Message::Update(invalid));
+    let mut dst = BytesMut::new();
+    codec.encode(Message::Keepalive, &mut dst).unwrap();
+    assert!(codec.encode_all(with_invalid, &mut dst).is_err());
+    assert_eq!(dst.len(), 19);
+}
+
+#[test]
+fn test_encode_invalid_update() {
+    // An AS segment cannot hold more than 255 ASNs
+    let update = Update {
+        withdrawn_routes: Routes::default(),
+        path_attributes: PathAttributes(vec![path::Value {
+            flags: Flags::WELL_KNOWN_COMPLETE,
+            data: Data::AsPath(AsPath(vec![AsSegment {
+                type_: AsSegmentType::AsSequence,
+                asns: vec![65000; 256],
+                as4: false,
+            }])),
+        }]),
+        nlri: Routes::default(),
+    };
+    let mut dst = BytesMut::new();
+    assert!(matches!(
+        update.clone().try_to_bytes(&mut dst),
+        Err(Error::InternalLength(
+            "AS segment",
+            std::cmp::Ordering::Greater
+        ))
+    ));
+    // The codec must not leave a partial message behind
+    let mut codec = BgpCodec::default();
+    let mut dst = BytesMut::new();
+    codec.encode(Message::Keepalive, &mut dst).unwrap();
+    assert!(codec.encode(Message::Update(update), &mut dst).is_err());
+    assert_eq!(dst.len(), 19);
+}
+
+#[test]
+fn test_update_prefix_len_too_long() {
+    // An IPv4 /40 in the top-level NLRI
+    let data = hex_to_bytes("ffffffffffffffffffffffffffffffff 001d 02 0000 0000 28 0a00000000");
+    let mut bmut = data.into();
+    let mut codec = BgpCodec::default();
+    let err = codec.decode(&mut bmut).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::UpdateMessage(UpdateMessageErrorSubcode::InvalidNetworkField, _)
+    ));
+    assert_eq!(
+        err.update_message_subcode(),
+        Some(UpdateMessageErrorSubcode::InvalidNetworkField)
+    );
+}
+
+#[test]
+fn test_open_validate_identifier() {
+    let mut open = Open::new_easy(
+        65000,
+        90,
+        Ipv4Addr::new(192, 0, 2, 1),
+        Capabilities::default(),
+    );
+    assert_eq!(open.validate_identifier(), Ok(()));
+    // RFC 6286 allows any non-zero value
+    open.bgp_id = Ipv4Addr::new(255, 255, 255, 255);
+    assert_eq!(open.validate_identifier(), Ok(()));
+    open.bgp_id = Ipv4Addr::UNSPECIFIED;
+    assert_eq!(
+        open.validate_identifier(),
+        Err(OpenMessageErrorSubcode::BadBgpIdentifier)
+    );
+}
+
+#[test]
+fn test_open_validate_hold_time() {
+    let mut open = Open::new_easy(
+        65000,
+        0,
+        Ipv4Addr::new(192, 0, 2, 1),
+        Capabilities::default(),
+    );
+    for (hold_time, valid) in [(0, true), (1, false), (2, false), (3, true), (180, true)] {
+        open.hold_time = hold_time;
+        let expected = if valid {
+            Ok(())
+        } else {
+            Err(OpenMessageErrorSubcode::UnacceptableHoldTime)
+        };
+        assert_eq!(open.validate_hold_time(), expected, "hold time {hold_time}");
+    }
+}
+
+#[test]
+fn test_display() {
+    let mut codec = BgpCodec::default().with_four_octet_asn(false);
+    let mut bmut = hex_to_bytes(
+        "
+    ffffffffffffffffffffffffffffffff 0042 02 0000 001b
+    40 01 01 00
+    40 02 04 0201 fd7d
+    40 03 04 ac1706a5
+    c0 11 06 0201 0000fd7d
+    162dff30
+    162dfe30
+    162d7b80
+    18cb0486",
+    )
+    .into();
+    let msg = codec.decode(&mut bmut).unwrap().unwrap();
+    // Only the first few prefixes are listed
+    assert_eq!(
+        msg.to_string(),
+        "UPDATE: withdrawn 0 routes, NLRI 4 routes [45.255.48.0/22, 45.254.48.0/22, \
+         45.123.128.0/22, ...], attrs=[ORIGIN=IGP, AS_PATH=64893, NEXT_HOP=172.23.6.165, \
+         AS4_PATH=64893]"
+    );
+    let mut bmut = hex_to_bytes(
+"ffffffffffffffffffffffffffffffff004501045ba000f0ac1706a2280226010400010001010400020001020005060001000100020600400200784104fcde349d46004700")
+    .into();
+    let msg = codec.decode(&mut bmut).unwrap().unwrap();
+    assert_eq!(
+        msg.to_string(),
+        "OPEN: version 4, AS 23456, hold time 240, ID 172.23.6.162, params=[MP(Ipv4 Unicast), \
+         MP(Ipv6 Unicast), ROUTE_REFRESH, EXTENDED_NEXT_HOP(Ipv4 Unicast via Ipv6), \
+         EXTENDED_MESSAGE, GRACEFUL_RESTART(120s), AS4(4242420893), UNKNOWN(70)=0 bytes, \
+         UNKNOWN(71)=0 bytes]"
+    );
+    assert_eq!(Message::Keepalive.to_string(), "KEEPALIVE");
+}
+
+/// Decode `data` with both message size limits and ASN sizes, and
+/// re-encode what is decoded
+fn decode_all(data: &[u8]) {
+    for extended_messages in [false, true] {
+        for four_octet_asn in [false, true] {
+            let mut codec = BgpCodec::new(extended_messages).with_four_octet_asn(four_octet_asn);
+            let mut src = BytesMut::from(data);
+            while let Ok(Some(message)) = codec.decode(&mut src) {
+                let _ = codec.encode_ref(&message, &mut BytesMut::new());
+            }
+        }
+    }
+    for _ in decode_stream(data) {}
+}
+
+#[test]
+fn test_decode_truncated_body() {
+    // Shorter than the minimum length of the message type
+    for data in [
+        "ffffffffffffffffffffffffffffffff 0013 01",
+        "ffffffffffffffffffffffffffffffff 0016 01 04 fd7d",
+        "ffffffffffffffffffffffffffffffff 0013 02",
+        "ffffffffffffffffffffffffffffffff 0015 02 0001",
+        "ffffffffffffffffffffffffffffffff 0014 03 06",
+        "ffffffffffffffffffffffffffffffff 0015 05 0001",
+    ] {
+        let mut bmut = hex_to_bytes(data).into();
+        assert!(
+            matches!(
+                BgpCodec::default().decode(&mut bmut),
+                Err(Error::MessageLength(_))
+            ),
+            "{data}"
+        );
+    }
+    // Long enough, but a length field inside runs past the end
+    for data in [
+        "ffffffffffffffffffffffffffffffff 0017 02 0000 0001",
+        "ffffffffffffffffffffffffffffffff 0019 02 0000 0002 4001",
+    ] {
+        let mut bmut = hex_to_bytes(data).into();
+        assert!(
+            matches!(
+                BgpCodec::default().decode(&mut bmut),
+                Err(Error::UpdateMessage(
+                    UpdateMessageErrorSubcode::MalformedAttributeList,
+                    _
+                ))
+            ),
+            "{data}"
+        );
+    }
+}
+
+/// Seed messages of the fuzz target in `fuzz/`
+fn fuzz_seeds() -> Vec<Vec<u8>> {
+    let corpus = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/decode");
+    let mut seeds = std::fs::read_dir(corpus)
+        .unwrap()
+        .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+        .collect::<Vec<_>>();
+    seeds.sort();
+    assert!(!seeds.is_empty());
+    seeds
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(4000))]
+
+    #[test]
+    fn test_decode_mutated_no_panic(
+        seed in prop::sample::select(fuzz_seeds()),
+        mutations in prop::collection::vec(
+            (0..4u8, any::<proptest::sample::Index>(), any::<u8>()),
+            1..=4,
+        ),
+        fix_len in prop::bool::weighted(0.75),
+    ) {
+        let mut data = seed;
+        for (kind, pos, byte) in mutations {
+            let pos = pos.index(data.len() + 1);
+            match kind {
+                0 if pos < data.len() => data[pos] = byte,
+                1 => data.insert(pos, byte),
+                2 if pos < data.len() => {
+                    data.remove(pos);
+                }
+                _ => data.truncate(pos),
+            }
+        }
+        // Keep the header length consistent most of the time so that the
+        // body is actually parsed
+        if data.len() >= 19 && fix_len {
+            let len = u16::try_from(data.len()).unwrap();
+            data[16..18].copy_from_slice(&len.to_be_bytes());
+        }
+        decode_all(&data);
+    }
+}
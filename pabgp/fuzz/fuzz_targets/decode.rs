@@ -0,0 +1,27 @@
+//! Feed arbitrary bytes through the message decoder
+//!
+//! Run with `cargo fuzz run decode` from the `pabgp` directory. The seed
+//! corpus in `corpus/decode` contains the messages from the unit tests.
+
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use pabgp::Codec;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    for extended_messages in [false, true] {
+        for four_octet_asn in [false, true] {
+            let mut codec = Codec::new(extended_messages).with_four_octet_asn(four_octet_asn);
+            let mut src = BytesMut::from(data);
+            // Errors are fine, panics are not
+            while let Ok(Some(message)) = codec.decode(&mut src) {
+                let _ = codec.encode_ref(&message, &mut BytesMut::new());
+            }
+        }
+    }
+    for _ in pabgp::decode_stream(data) {}
+});
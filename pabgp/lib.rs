@@ -3,6 +3,35 @@
 //! Structs here intends to represent the data instead of the on-wire format.
 
 // SPDX-License-Identifier: AGPL-3.0-or-later
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+/// Return `Error::InternalLength` if fewer than `$len` bytes are left to
+/// decode `$name` from
+macro_rules! check_min_len {
+    ($src:expr, $len:expr, $name:expr) => {
+        if bytes::Buf::remaining(&$src) < $len {
+            return Err($crate::Error::InternalLength(
+                $name,
+                core::cmp::Ordering::Less,
+            ));
+        }
+    };
+}
+
+/// Items from the `std` prelude that live in `alloc` under `no_std`
+#[allow(unused_imports)]
+mod prelude {
+    pub use alloc::{
+        borrow::ToOwned,
+        boxed::Box,
+        format,
+        string::{String, ToString},
+        vec,
+        vec::Vec,
+    };
+}
 
 pub mod capability;
 pub mod cidr;
@@ -11,21 +40,29 @@ mod endec;
 #[cfg(feature = "tokio-endec")]
 mod endec_tests;
 pub mod path;
+#[cfg(all(test, feature = "proptest-tests"))]
+mod roundtrip_tests;
 pub mod route;
+#[cfg(feature = "impl-serde")]
+mod serde_hex;
 mod update_builder;
 
+#[cfg(feature = "std")]
+pub use endec::decode_stream;
 #[cfg(feature = "tokio-endec")]
 pub use endec::BgpCodec as Codec;
+pub use endec::Component;
 pub use update_builder::UpdateBuilder;
 
 use bytes::{Buf, BufMut};
-use capability::{Capabilities, OptionalParameters};
-use endec::Component;
+use capability::{Afi, Capabilities, OptionalParameters, Safi};
+use core::fmt;
+use core::net::Ipv4Addr;
 use enum_primitive_derive::Primitive;
 use num_traits::FromPrimitive;
 use path::PathAttributes;
+use prelude::*;
 use route::Routes;
-use std::net::Ipv4Addr;
 
 /// Supported BGP version
 pub const BGP_VERSION: u8 = 4;
@@ -33,6 +70,15 @@ pub const BGP_VERSION: u8 = 4;
 /// ASN for AS4
 pub const AS_TRANS: u16 = 23456;
 
+/// Maximum BGP message length (RFC 4271 Section 4.1)
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Maximum BGP message length with Extended Messages (RFC 8654 Section 3)
+pub const MAX_EXTENDED_MESSAGE_LEN: usize = 65535;
+
+/// Maximum length of a shutdown communication (RFC 9003 Section 2)
+pub const MAX_SHUTDOWN_COMMUNICATION_LEN: usize = 255;
+
 /// BGP marker
 pub const MARKER: [u8; 16] = [
     0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
@@ -41,6 +87,7 @@ pub const MARKER: [u8; 16] = [
 /// BGP packet errors
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[cfg(feature = "std")]
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error("invalid or missing marker")]
@@ -48,26 +95,157 @@ pub enum Error {
     #[error("invalid message type")]
     MessageType(u8),
     #[error("invalid internal length at {0} ({1:?})")]
-    InternalLength(&'static str, std::cmp::Ordering),
+    InternalLength(&'static str, core::cmp::Ordering),
     #[error("invalid {0} type of {1}")]
     InternalType(&'static str, u16),
     #[error("requires MP-BGP capability")]
     NoMpBgp,
     #[error("attempting to update NLRI without next hop")]
     NoNextHop,
+    #[error("next hop cannot be used for {0:?} routes without Extended Next Hop Encoding")]
+    NextHopAfi(capability::Afi),
+    /// A route does not fit in the maximum message length, which needs to be at least this long
+    #[error("a route needs a message of at least {0} bytes")]
+    MessageTooSmall(usize),
+    /// Length field of the message header that is invalid for the message (RFC 4271 Section 6.1)
+    #[error("bad message length {0}")]
+    MessageLength(u16),
+    /// Malformed OPEN message with the NOTIFICATION subcode and data (RFC 4271 Section 6.2)
+    #[error("malformed OPEN message: {0:?}")]
+    OpenMessage(OpenMessageErrorSubcode, bytes::Bytes),
+    /// Malformed UPDATE message with the NOTIFICATION subcode and data (RFC 4271 Section 6.3)
+    #[error("malformed UPDATE message: {0:?}")]
+    UpdateMessage(UpdateMessageErrorSubcode, bytes::Bytes),
+    /// The stream ended with this many bytes of an incomplete message
+    #[error("stream ended in the middle of a message ({0} bytes left)")]
+    Truncated(usize),
+}
+
+impl Error {
+    /// Find the UPDATE Message Error subcode to report this error with, if any
+    #[must_use]
+    pub fn update_message_subcode(&self) -> Option<UpdateMessageErrorSubcode> {
+        match self {
+            Self::UpdateMessage(subcode, _) => Some(*subcode),
+            _ => None,
+        }
+    }
+
+    /// Turn an error in decoding an OPEN message into an OPEN Message Error
+    ///
+    /// Errors without a more specific subcode are reported as Unspecific.
+    #[must_use]
+    pub fn into_open_message(self) -> Self {
+        match self {
+            Self::OpenMessage(..) => self,
+            _ => {
+                log::debug!("Malformed OPEN message: {self}");
+                Self::OpenMessage(OpenMessageErrorSubcode::Unspecific, bytes::Bytes::new())
+            }
+        }
+    }
+
+    /// Turn an error in decoding an UPDATE message into an UPDATE Message Error
+    ///
+    /// Errors without a more specific subcode are reported as Malformed
+    /// Attribute List.
+    #[must_use]
+    pub fn into_update_message(self) -> Self {
+        if self.update_message_subcode().is_some() {
+            self
+        } else {
+            log::debug!("Malformed UPDATE message: {self}");
+            Self::UpdateMessage(
+                UpdateMessageErrorSubcode::MalformedAttributeList,
+                bytes::Bytes::new(),
+            )
+        }
+    }
+}
+
+/// NOTIFICATION to send to a peer whose message could not be decoded (RFC 4271 Section 6)
+///
+/// Errors that cannot be traced to a specific message are reported as a
+/// Message Header Error with the Unspecific (0) subcode.
+impl From<&Error> for Notification {
+    fn from(e: &Error) -> Self {
+        if let Some(subcode) = e.update_message_subcode() {
+            let data = match e {
+                Error::UpdateMessage(_, data) => data.clone(),
+                _ => bytes::Bytes::new(),
+            };
+            return Self::new(
+                NotificationErrorCode::UpdateMessageError,
+                subcode as u8,
+                data,
+            );
+        }
+        let (subcode, data) = match e {
+            Error::OpenMessage(subcode, data) => {
+                return Self::new(
+                    NotificationErrorCode::OpenMessageError,
+                    *subcode as u8,
+                    data.clone(),
+                );
+            }
+            Error::MessageLength(len) => return Self::bad_message_length(*len),
+            Error::Marker => (
+                MessageHeaderErrorSubcode::ConnectionNotSynchronized as u8,
+                bytes::Bytes::new(),
+            ),
+            Error::MessageType(msg_type) => (
+                MessageHeaderErrorSubcode::BadMessageType as u8,
+                bytes::Bytes::copy_from_slice(&[*msg_type]),
+            ),
+            _ => (0, bytes::Bytes::new()),
+        };
+        Self::new(NotificationErrorCode::MessageHeaderError, subcode, data)
+    }
+}
+
+impl From<Error> for Notification {
+    fn from(e: Error) -> Self {
+        Self::from(&e)
+    }
 }
 
 /// BGP message
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Message {
     Open(Open),
     Update(Update),
     Notification(Notification),
     Keepalive,
+    RouteRefresh(RouteRefresh),
+}
+
+/// Compact summary of the message, without dumping every route
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Open(open) => write!(f, "{open}"),
+            Self::Update(update) => write!(f, "{update}"),
+            Self::Notification(notification) => write!(
+                f,
+                "NOTIFICATION: {:?} subcode {}, {} bytes of data",
+                notification.error_code,
+                notification.error_subcode,
+                notification.data.len()
+            ),
+            Self::Keepalive => f.write_str("KEEPALIVE"),
+            Self::RouteRefresh(refresh) => write!(
+                f,
+                "ROUTE-REFRESH: AFI {} SAFI {} subtype {}",
+                refresh.afi, refresh.safi, refresh.subtype
+            ),
+        }
+    }
 }
 
 /// BGP open message
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Open {
     pub version: u8,
     pub asn: u16,
@@ -78,6 +256,7 @@ pub struct Open {
 
 impl Component for Open {
     fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
+        check_min_len!(src, 9, "OPEN");
         let version = src.get_u8();
         let asn = src.get_u16();
         let hold_time = src.get_u16();
@@ -93,13 +272,17 @@ impl Component for Open {
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_to_bytes(dst).expect("OPEN length overflow")
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
         let mut len = 0;
         len += self.version.to_bytes(dst);
         len += self.asn.to_bytes(dst);
         len += self.hold_time.to_bytes(dst);
         len += self.bgp_id.to_bytes(dst);
-        len += self.opt_params.to_bytes(dst);
-        len
+        len += self.opt_params.try_to_bytes(dst)?;
+        Ok(len)
     }
 
     fn encoded_len(&self) -> usize {
@@ -107,6 +290,44 @@ impl Component for Open {
     }
 }
 
+/// Write `items` separated by commas
+fn write_list<T: fmt::Display>(
+    f: &mut fmt::Formatter<'_>,
+    items: impl IntoIterator<Item = T>,
+) -> fmt::Result {
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{item}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Open {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "OPEN: version {}, AS {}, hold time {}, ID {}, params=[",
+            self.version, self.asn, self.hold_time, self.bgp_id
+        )?;
+        for (i, param) in self.opt_params.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            match param {
+                capability::OptionalParameterValue::Capabilities(caps) => {
+                    write_list(f, caps.iter())?
+                }
+                capability::OptionalParameterValue::Unsupported(type_, data) => {
+                    write!(f, "UNKNOWN({type_})={} bytes", data.len())?;
+                }
+            }
+        }
+        f.write_str("]")
+    }
+}
+
 impl Open {
     /// Create a new BGP open message
     #[must_use]
@@ -128,54 +349,96 @@ impl Open {
             .into(),
         }
     }
+
+    /// Get all capabilities in the order they appear in this message
+    ///
+    /// Capabilities from multiple optional parameters are concatenated, so
+    /// a proxy can re-advertise them with [`Open::new_easy`] or
+    /// [`capability::CapabilitiesBuilder::from_existing`].
+    #[must_use]
+    pub fn echo_capabilities(&self) -> Capabilities {
+        self.opt_params.all_capabilities()
+    }
+
+    /// Check that the BGP Identifier is non-zero (RFC 6286 Section 2.1)
+    ///
+    /// Uniqueness among internal peers (RFC 6286 Section 2.2) depends on the
+    /// local identifier and must be checked by the caller.
+    ///
+    /// # Errors
+    ///
+    /// [`OpenMessageErrorSubcode::BadBgpIdentifier`] if the identifier is zero
+    pub fn validate_identifier(&self) -> Result<(), OpenMessageErrorSubcode> {
+        if self.bgp_id.is_unspecified() {
+            Err(OpenMessageErrorSubcode::BadBgpIdentifier)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that the hold time is either zero or at least three seconds
+    /// (RFC 4271 Section 4.2)
+    ///
+    /// # Errors
+    ///
+    /// [`OpenMessageErrorSubcode::UnacceptableHoldTime`] if the hold time is
+    /// one or two seconds
+    pub const fn validate_hold_time(&self) -> Result<(), OpenMessageErrorSubcode> {
+        if matches!(self.hold_time, 1 | 2) {
+            Err(OpenMessageErrorSubcode::UnacceptableHoldTime)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 /// BGP update message
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Update {
     pub withdrawn_routes: Routes,
     pub path_attributes: PathAttributes,
     pub nlri: Routes,
 }
 
+/// Decoding assumes four-octet ASNs, see [`Update::from_bytes_as4`].
 impl Component for Update {
     fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
-        let withdrawn_len = src.get_u16() as usize;
-        let mut wdr_buf = src.split_to(withdrawn_len);
-        let withdrawn_routes = Routes::from_bytes(&mut wdr_buf)?;
-        let tpa_len = src.get_u16() as usize;
-        let mut tpa_buf = src.split_to(tpa_len);
-        let path_attributes = PathAttributes::from_bytes(&mut tpa_buf)?;
-        let nlri = Routes::from_bytes(src)?;
-        Ok(Self {
-            withdrawn_routes,
-            path_attributes,
-            nlri,
-        })
+        Self::from_bytes_as4(src, true)
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_to_bytes(dst).expect("UPDATE length overflow")
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        self.try_encode_to(dst)
+    }
+
+    fn encode_to(&self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_encode_to(dst).expect("UPDATE length overflow")
+    }
+
+    fn try_encode_to(&self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
         let mut len = 0;
         let wdr_len_pos = dst.len();
         len += 0u16.to_bytes(dst); // Placeholder for withdrawn routes length
-        let wdr_len = self.withdrawn_routes.to_bytes(dst);
+        let wdr_len = self.withdrawn_routes.encode_to(dst);
         len += wdr_len;
-        dst[wdr_len_pos..wdr_len_pos + 2].copy_from_slice(
-            &(u16::try_from(wdr_len)
-                .expect("Withdrawn routes length overflow")
-                .to_be_bytes()),
-        );
+        let wdr_len = u16::try_from(wdr_len).map_err(|_| {
+            crate::Error::InternalLength("withdrawn routes", core::cmp::Ordering::Greater)
+        })?;
+        dst[wdr_len_pos..wdr_len_pos + 2].copy_from_slice(&wdr_len.to_be_bytes());
         let tpa_len_pos = dst.len();
         len += 0u16.to_bytes(dst); // Placeholder for total path attributes length
-        let tpa_len = self.path_attributes.to_bytes(dst);
+        let tpa_len = self.path_attributes.try_encode_to(dst)?;
         len += tpa_len;
-        dst[tpa_len_pos..tpa_len_pos + 2].copy_from_slice(
-            &(u16::try_from(tpa_len)
-                .expect("Total path attributes length overflow")
-                .to_be_bytes()),
-        );
-        len += self.nlri.to_bytes(dst);
-        len
+        let tpa_len = u16::try_from(tpa_len).map_err(|_| {
+            crate::Error::InternalLength("total path attributes", core::cmp::Ordering::Greater)
+        })?;
+        dst[tpa_len_pos..tpa_len_pos + 2].copy_from_slice(&tpa_len.to_be_bytes());
+        len += self.nlri.encode_to(dst);
+        Ok(len)
     }
 
     fn encoded_len(&self) -> usize {
@@ -186,16 +449,93 @@ impl Component for Update {
     }
 }
 
+impl fmt::Display for Update {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "UPDATE: withdrawn {}, NLRI {}, attrs=[",
+            self.withdrawn_routes, self.nlri
+        )?;
+        write_list(f, self.path_attributes.iter())?;
+        f.write_str("]")
+    }
+}
+
+impl Update {
+    /// Decode an UPDATE message, with four-octet ASNs in `AS_PATH` if `as4`
+    ///
+    /// `as4` should be set if the four-octet AS number capability
+    /// (RFC 6793) has been negotiated with the peer.
+    ///
+    /// # Errors
+    /// Returns an error if the message is malformed.
+    pub fn from_bytes_as4(src: &mut bytes::Bytes, as4: bool) -> Result<Self, crate::Error> {
+        Self::from_bytes_negotiated(src, as4, &[])
+    }
+
+    /// Decode an UPDATE message, with four-octet ASNs if `as4` and path
+    /// identifiers in the NLRI of the AFI/SAFI pairs in `add_path`
+    ///
+    /// `add_path` should contain the AFI/SAFI pairs for which the ADD-PATH
+    /// capability (RFC 7911) has been negotiated for receiving, see
+    /// [`capability::Capabilities::add_path_receive`]. The top-level withdrawn
+    /// routes and NLRI are IPv4 unicast.
+    ///
+    /// # Errors
+    /// Returns an error if the message is malformed.
+    pub fn from_bytes_negotiated(
+        src: &mut bytes::Bytes,
+        as4: bool,
+        add_path: &[(Afi, Safi)],
+    ) -> Result<Self, crate::Error> {
+        let addpath = add_path.contains(&(Afi::Ipv4, Safi::Unicast));
+        check_min_len!(src, 2, "withdrawn routes length");
+        let withdrawn_len = src.get_u16() as usize;
+        // Followed by the total path attribute length
+        check_min_len!(src, withdrawn_len + 2, "withdrawn routes");
+        let mut wdr_buf = src.split_to(withdrawn_len);
+        let withdrawn_routes = Routes::from_bytes_addpath(&mut wdr_buf, addpath)?;
+        // The top-level routes are always IPv4
+        withdrawn_routes.validate(Afi::Ipv4)?;
+        let tpa_len = src.get_u16() as usize;
+        check_min_len!(src, tpa_len, "path attributes");
+        let mut tpa_buf = src.split_to(tpa_len);
+        let path_attributes = PathAttributes::from_bytes_negotiated(&mut tpa_buf, as4, add_path)?;
+        let nlri = Routes::from_bytes_addpath(src, addpath)?;
+        nlri.validate(Afi::Ipv4)?;
+        Ok(Self {
+            withdrawn_routes,
+            path_attributes,
+            nlri,
+        })
+    }
+
+    /// Decode an UPDATE message and check that the mandatory well-known
+    /// attributes are present
+    ///
+    /// # Errors
+    /// In addition to decoding errors, returns `Error::UpdateMessage` if a
+    /// mandatory attribute is missing.
+    pub fn from_bytes_validated(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
+        let update = Self::from_bytes(src)?;
+        update.path_attributes.validate(!update.nlri.is_empty())?;
+        Ok(update)
+    }
+}
+
 /// BGP notification message
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Notification {
     pub error_code: NotificationErrorCode,
     pub error_subcode: u8,
+    #[cfg_attr(feature = "impl-serde", serde(with = "crate::serde_hex"))]
     pub data: bytes::Bytes,
 }
 
 impl Component for Notification {
     fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
+        check_min_len!(src, 2, "NOTIFICATION");
         let error_code = src.get_u8();
         let error_subcode = src.get_u8();
         let data = src.copy_to_bytes(src.remaining());
@@ -232,10 +572,153 @@ impl Notification {
             data,
         }
     }
+
+    /// Create a Message Header Error/Bad Message Length notification with the
+    /// erroneous length as data (RFC 4271 Section 6.1)
+    #[must_use]
+    pub fn bad_message_length(len: u16) -> Self {
+        Self::new(
+            NotificationErrorCode::MessageHeaderError,
+            MessageHeaderErrorSubcode::BadMessageLength as u8,
+            bytes::Bytes::copy_from_slice(&len.to_be_bytes()),
+        )
+    }
+
+    /// Create an OPEN Message Error/Unsupported Capability notification with
+    /// the capability as data (RFC 5492 Section 5)
+    ///
+    /// # Panics
+    /// Panics if the capability value is longer than 255 octets.
+    #[must_use]
+    pub fn unsupported_capability(cap: &capability::Value) -> Self {
+        let capabilities = Capabilities::from(vec![cap.clone()]);
+        let mut data = bytes::BytesMut::with_capacity(capabilities.encoded_len());
+        capabilities.to_bytes(&mut data);
+        Self::new(
+            NotificationErrorCode::OpenMessageError,
+            OpenMessageErrorSubcode::UnsupportedCapability as u8,
+            data.freeze(),
+        )
+    }
+
+    /// Create an OPEN Message Error/Unsupported Optional Parameter notification
+    /// with the parameter type as data (RFC 4271 Section 6.2)
+    #[must_use]
+    pub fn unsupported_optional_parameter(type_: u8) -> Self {
+        Self::new(
+            NotificationErrorCode::OpenMessageError,
+            OpenMessageErrorSubcode::UnsupportedOptionalParameter as u8,
+            bytes::Bytes::copy_from_slice(&[type_]),
+        )
+    }
+
+    /// Create a Cease/Administrative Shutdown notification with an optional
+    /// shutdown communication (RFC 8203, RFC 9003 Section 2)
+    ///
+    /// The communication is truncated on a character boundary to fit in 255 octets.
+    #[must_use]
+    pub fn administrative_shutdown(communication: Option<&str>) -> Self {
+        let data = communication.map_or_else(bytes::Bytes::new, |text| {
+            let mut len = text.len().min(MAX_SHUTDOWN_COMMUNICATION_LEN);
+            while !text.is_char_boundary(len) {
+                len -= 1;
+            }
+            let mut data = bytes::BytesMut::with_capacity(len + 1);
+            data.put_u8(len as u8);
+            data.put_slice(&text.as_bytes()[..len]);
+            data.freeze()
+        });
+        Self::new(
+            NotificationErrorCode::Cease,
+            CeaseSubcode::AdministrativeShutdown as u8,
+            data,
+        )
+    }
+
+    /// Decode the shutdown communication of an Administrative Shutdown or Reset
+    /// notification (RFC 8203, RFC 9003 Section 2)
+    ///
+    /// Returns `None` if there is no communication or it is malformed.
+    pub fn shutdown_communication(&self) -> Option<String> {
+        if self.error_code != NotificationErrorCode::Cease
+            || (self.error_subcode != CeaseSubcode::AdministrativeShutdown as u8
+                && self.error_subcode != CeaseSubcode::AdministrativeReset as u8)
+        {
+            return None;
+        }
+        let (&len, text) = self.data.split_first()?;
+        let text = text
+            .get(..usize::from(len))
+            .filter(|text| !text.is_empty())?;
+        core::str::from_utf8(text).ok().map(String::from)
+    }
+}
+
+/// BGP route refresh message (RFC 2918 Section 3, RFC 7313 Section 3.2)
+///
+/// The AFI and SAFI are kept as raw values because a request for an
+/// address family we do not know must be ignored rather than rejected
+/// (RFC 2918 Section 4). Use [`RouteRefresh::afi_safi`] to interpret them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RouteRefresh {
+    pub afi: u16,
+    /// Message subtype (RFC 7313), 0 for a normal route refresh request
+    pub subtype: u8,
+    pub safi: u8,
+}
+
+impl Component for RouteRefresh {
+    fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
+        check_min_len!(src, 4, "ROUTE-REFRESH");
+        let afi = src.get_u16();
+        let subtype = src.get_u8();
+        let safi = src.get_u8();
+        Ok(Self { afi, subtype, safi })
+    }
+
+    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        dst.put_u16(self.afi);
+        dst.put_u8(self.subtype);
+        dst.put_u8(self.safi);
+        self.encoded_len()
+    }
+
+    fn encoded_len(&self) -> usize {
+        4
+    }
+}
+
+impl RouteRefresh {
+    /// Subtype of a normal route refresh request
+    pub const SUBTYPE_NORMAL: u8 = 0;
+    /// Subtype of a Beginning of Route Refresh (BoRR) message
+    pub const SUBTYPE_BORR: u8 = 1;
+    /// Subtype of an End of Route Refresh (EoRR) message
+    pub const SUBTYPE_EORR: u8 = 2;
+
+    /// Create a new normal route refresh request
+    #[must_use]
+    pub const fn new(afi: Afi, safi: Safi) -> Self {
+        Self {
+            afi: afi as u16,
+            subtype: Self::SUBTYPE_NORMAL,
+            safi: safi as u8,
+        }
+    }
+
+    /// Get the AFI and SAFI of this message, or `None` if either is unknown
+    #[must_use]
+    pub fn afi_safi(&self) -> Option<(Afi, Safi)> {
+        let afi = Afi::try_from(self.afi).ok()?;
+        let safi = Safi::try_from(self.safi).ok()?;
+        Some((afi, safi))
+    }
 }
 
 /// Notification error codes
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Primitive)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum NotificationErrorCode {
     MessageHeaderError = 1,
@@ -259,11 +742,14 @@ pub enum MessageHeaderErrorSubcode {
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Primitive)]
 #[repr(u8)]
 pub enum OpenMessageErrorSubcode {
+    Unspecific = 0,
     UnsupportedVersionNumber = 1,
     BadPeerAs = 2,
     BadBgpIdentifier = 3,
     UnsupportedOptionalParameter = 4,
     UnacceptableHoldTime = 6,
+    /// RFC 5492 Section 5
+    UnsupportedCapability = 7,
 }
 
 /// Notification error subcodes for `UpdateMessageError`
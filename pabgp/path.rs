@@ -2,39 +2,62 @@
 
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use crate::prelude::*;
 use crate::{
     capability::{Afi, Safi},
     endec::Component,
-    route::Routes,
+    route::{Routes, VpnRoutes},
+    UpdateMessageErrorSubcode,
 };
 use bytes::{Buf, BufMut, Bytes};
-use enum_primitive_derive::Primitive;
-use num_traits::FromPrimitive;
-use std::{
+use core::{
+    fmt,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     ops::Deref,
 };
+use enum_primitive_derive::Primitive;
+use num_traits::FromPrimitive;
+
+/// Error for an attribute whose length is wrong for its type (RFC 4271 Section 6.3)
+fn attribute_length_error() -> crate::Error {
+    crate::Error::UpdateMessage(
+        UpdateMessageErrorSubcode::AttributeLengthError,
+        Bytes::new(),
+    )
+}
 
 /// BGP path attributes
 #[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 #[allow(clippy::module_name_repetitions)]
 pub struct PathAttributes(pub Vec<Value>);
 
+/// Decoding assumes four-octet ASNs, see [`PathAttributes::from_bytes_as4`].
 impl Component for PathAttributes {
     fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
-        let mut attributes = Vec::new();
-        while src.has_remaining() {
-            attributes.push(Value::from_bytes(src)?);
-        }
-        Ok(Self(attributes))
+        Self::from_bytes_as4(src, true)
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_to_bytes(dst)
+            .expect("Path attributes length overflow")
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        self.try_encode_to(dst)
+    }
+
+    fn encode_to(&self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_encode_to(dst)
+            .expect("Path attributes length overflow")
+    }
+
+    fn try_encode_to(&self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
         let mut len = 0;
-        for attribute in self.0 {
-            len += attribute.to_bytes(dst);
+        for attribute in &self.0 {
+            len += attribute.try_encode_to(dst)?;
         }
-        len
+        Ok(len)
     }
 
     fn encoded_len(&self) -> usize {
@@ -50,41 +73,199 @@ impl Deref for PathAttributes {
     }
 }
 
+impl PathAttributes {
+    /// Decode path attributes, with four-octet ASNs in `AS_PATH` and
+    /// `AGGREGATOR` if `as4`.
+    ///
+    /// `as4` should be set if the four-octet AS number capability
+    /// (RFC 6793) has been negotiated with the peer.
+    ///
+    /// # Errors
+    /// Returns an error if any attribute is malformed.
+    pub fn from_bytes_as4(src: &mut Bytes, as4: bool) -> Result<Self, crate::Error> {
+        Self::from_bytes_negotiated(src, as4, &[])
+    }
+
+    /// Decode path attributes, with four-octet ASNs if `as4` and path
+    /// identifiers in the NLRI of `MP_REACH_NLRI` and `MP_UNREACH_NLRI` for
+    /// the AFI/SAFI pairs in `add_path`
+    ///
+    /// See [`Value::from_bytes_negotiated`].
+    ///
+    /// # Errors
+    /// Returns an error if any attribute is malformed.
+    pub fn from_bytes_negotiated(
+        src: &mut Bytes,
+        as4: bool,
+        add_path: &[(Afi, Safi)],
+    ) -> Result<Self, crate::Error> {
+        let mut attributes = Vec::new();
+        while src.has_remaining() {
+            attributes.push(Value::from_bytes_negotiated(src, as4, add_path)?);
+        }
+        Ok(Self(attributes))
+    }
+
+    /// Check if an attribute of `type_` is present
+    fn contains_type(&self, type_: Type) -> bool {
+        self.0
+            .iter()
+            .any(|attr| u8::from(&attr.data) == type_ as u8)
+    }
+
+    /// Check that the mandatory well-known attributes are present
+    /// (RFC 4271 Section 5.1, RFC 4760 Section 3)
+    ///
+    /// `has_nlri` indicates whether the UPDATE carries NLRI outside of
+    /// `MP_REACH_NLRI`, in which case `NEXT_HOP` is also required.
+    ///
+    /// # Errors
+    /// Returns `Error::UpdateMessage` with `MissingWellKnownAttribute` and the
+    /// type code of the first missing attribute as data.
+    pub fn validate(&self, has_nlri: bool) -> Result<(), crate::Error> {
+        if !has_nlri && !self.contains_type(Type::MpReachNlri) {
+            // Only withdrawals, nothing is mandatory
+            return Ok(());
+        }
+        let next_hop = has_nlri.then_some(Type::NextHop);
+        for type_ in [Some(Type::Origin), Some(Type::AsPath), next_hop]
+            .into_iter()
+            .flatten()
+        {
+            if !self.contains_type(type_) {
+                return Err(crate::Error::UpdateMessage(
+                    UpdateMessageErrorSubcode::MissingWellKnownAttribute,
+                    Bytes::copy_from_slice(&[type_ as u8]),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the AS path, reconstructed with `AS4_PATH` if present.
+    ///
+    /// This should only be used on UPDATEs from peers that do not support
+    /// four-octet AS numbers (RFC 6793 Section 4.2.3).
+    #[must_use]
+    pub fn merged_as_path(&self) -> Option<AsPath> {
+        let as_path = self.0.iter().find_map(|attr| match &attr.data {
+            Data::AsPath(as_path) => Some(as_path),
+            _ => None,
+        })?;
+        let as4_path = self.0.iter().find_map(|attr| match &attr.data {
+            Data::As4Path(as4_path) => Some(as4_path),
+            _ => None,
+        });
+        Some(as4_path.map_or_else(
+            || as_path.clone(),
+            |as4_path| merge_as4_path(as_path, as4_path),
+        ))
+    }
+}
+
 /// BGP path attribute
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Value {
     pub flags: Flags,
     pub data: Data,
 }
 
+/// Write `items` separated by spaces
+fn write_spaced<T: fmt::Display>(
+    f: &mut fmt::Formatter<'_>,
+    items: impl IntoIterator<Item = T>,
+) -> fmt::Result {
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            f.write_str(" ")?;
+        }
+        write!(f, "{item}")?;
+    }
+    Ok(())
+}
+
+/// Compact summary of the attribute, e.g. `AS_PATH=64500 64501`
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.data {
+            Data::Origin(origin) => write!(f, "ORIGIN={origin}"),
+            Data::AsPath(as_path) => write!(f, "AS_PATH={as_path}"),
+            Data::NextHop(next_hop) => write!(f, "NEXT_HOP={next_hop}"),
+            Data::MultiExitDisc(med) => write!(f, "MULTI_EXIT_DISC={med}"),
+            Data::LocalPref(local_pref) => write!(f, "LOCAL_PREF={local_pref}"),
+            Data::AtomicAggregate => f.write_str("ATOMIC_AGGREGATE"),
+            Data::Aggregator(agg) => write!(f, "AGGREGATOR=AS{} {}", agg.asn, agg.ip),
+            Data::Communities(communities) => {
+                f.write_str("COMMUNITIES=")?;
+                write_spaced(
+                    f,
+                    communities
+                        .iter()
+                        .map(|community| format!("{}:{}", community >> 16, community & 0xffff)),
+                )
+            }
+            Data::MpReachNlri(reach) => {
+                write!(f, "MP_REACH_NLRI={:?} {:?} via ", reach.afi, reach.safi)?;
+                match reach.next_hop {
+                    MpNextHop::Empty => f.write_str("none")?,
+                    MpNextHop::Single(ip) => write!(f, "{ip}")?,
+                    MpNextHop::V6AndLL(global, link_local) => {
+                        write!(f, "{global} and {link_local}")?;
+                    }
+                }
+                write!(f, ", {}", reach.nlri.summary(reach.afi))
+            }
+            Data::MpUnreachNlri(unreach) => write!(
+                f,
+                "MP_UNREACH_NLRI={:?} {:?}, {}",
+                unreach.afi,
+                unreach.safi,
+                unreach.withdrawn_routes.summary(unreach.afi)
+            ),
+            Data::As4Path(as_path) => write!(f, "AS4_PATH={as_path}"),
+            Data::As4Aggregator(agg) => write!(f, "AS4_AGGREGATOR=AS{} {}", agg.asn, agg.ip),
+            Data::LargeCommunities(communities) => {
+                f.write_str("LARGE_COMMUNITIES=")?;
+                write_spaced(
+                    f,
+                    communities
+                        .iter()
+                        .map(|c| format!("{}:{}:{}", c.global, c.local1, c.local2)),
+                )
+            }
+            Data::OnlyToCustomer(asn) => write!(f, "ONLY_TO_CUSTOMER={asn}"),
+            Data::Aigp(metric) => write!(f, "AIGP={metric}"),
+            Data::Unsupported(type_, data) => {
+                write!(f, "UNKNOWN({type_})={} bytes", data.len())
+            }
+        }
+    }
+}
+
+/// Decoding assumes four-octet ASNs, see [`Value::from_bytes_as4`].
 impl Component for Value {
     fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
-        let flags = Flags(src.get_u8());
-        let type_ = src.get_u8();
-        let len = if flags.is_extended_length() {
-            src.get_u16() as usize
-        } else {
-            src.get_u8() as usize
-        };
-        let mut src = src.split_to(len);
-        let data = match Type::from_u8(type_) {
-            Some(Type::Origin) => Data::Origin(Origin::from_bytes(&mut src)?),
-            Some(Type::AsPath) => Data::AsPath(AsPath::from_bytes(&mut src)?),
-            Some(Type::NextHop) => Data::NextHop(Ipv4Addr::from_bytes(&mut src)?),
-            Some(Type::MultiExitDisc) => Data::MultiExitDisc(src.get_u32()),
-            Some(Type::LocalPref) => Data::LocalPref(src.get_u32()),
-            Some(Type::AtomicAggregate) => Data::AtomicAggregate,
-            Some(Type::Aggregator) => Data::Aggregator(Aggregator::from_bytes(&mut src)?),
-            Some(Type::MpReachNlri) => Data::MpReachNlri(MpReachNlri::from_bytes(&mut src)?),
-            Some(Type::MpUnreachNlri) => Data::MpUnreachNlri(MpUnreachNlri::from_bytes(&mut src)?),
-            Some(Type::As4Path) => Data::As4Path(AsPath::from_bytes(&mut src)?),
-            // Some(Type::As4Aggregator) => Data::As4Aggregator(Aggregator::from_bytes(&mut src)?),
-            _ => Data::Unsupported(type_, src),
-        };
-        Ok(Self { flags, data })
+        Self::from_bytes_as4(src, true)
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_to_bytes(dst)
+            .expect("Path attribute length overflow")
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        self.try_encode_to(dst)
+    }
+
+    fn encode_to(&self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_encode_to(dst)
+            .expect("Path attribute length overflow")
+    }
+
+    fn try_encode_to(&self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        let too_long =
+            |_| crate::Error::InternalLength("path attribute", core::cmp::Ordering::Greater);
         let mut len = 0;
         dst.put_u8(self.flags.0); // Flags
         len += 1;
@@ -100,32 +281,37 @@ impl Component for Value {
             len += 1;
             false
         };
-        let data_len = match self.data {
+        let data_len = match &self.data {
             Data::Origin(origin) => origin.to_bytes(dst),
-            Data::AsPath(as_path) | Data::As4Path(as_path) => as_path.to_bytes(dst),
+            Data::AsPath(as_path) | Data::As4Path(as_path) => as_path.try_encode_to(dst)?,
             Data::NextHop(next_hop) => next_hop.to_bytes(dst),
             Data::MultiExitDisc(med) => med.to_bytes(dst),
             Data::LocalPref(local_pref) => local_pref.to_bytes(dst),
             Data::AtomicAggregate => 0,
             Data::Aggregator(agg) => agg.to_bytes(dst),
-            Data::MpReachNlri(mp_reach_nlri) => mp_reach_nlri.to_bytes(dst),
-            Data::MpUnreachNlri(mp_unreach_nlri) => mp_unreach_nlri.to_bytes(dst),
+            Data::As4Aggregator(agg) => agg.to_bytes(dst),
+            Data::Communities(communities) => communities.encode_to(dst),
+            Data::MpReachNlri(mp_reach_nlri) => mp_reach_nlri.try_encode_to(dst)?,
+            Data::MpUnreachNlri(mp_unreach_nlri) => mp_unreach_nlri.try_encode_to(dst)?,
+            Data::LargeCommunities(communities) => communities.encode_to(dst),
+            Data::OnlyToCustomer(asn) => asn.to_bytes(dst),
+            Data::Aigp(metric) => {
+                dst.put_u8(AIGP_TLV_TYPE);
+                dst.put_u16(AIGP_TLV_LEN);
+                3 + metric.to_bytes(dst)
+            }
             Data::Unsupported(_, data) => {
-                let len = data.len();
-                dst.unsplit(data.into());
-                len
+                dst.put_slice(data);
+                data.len()
             }
         };
         if two_byte_len {
-            dst[len_pos..len_pos + 2].copy_from_slice(
-                &(u16::try_from(data_len)
-                    .expect("Path attribute length overflow")
-                    .to_be_bytes()),
-            );
+            dst[len_pos..len_pos + 2]
+                .copy_from_slice(&u16::try_from(data_len).map_err(too_long)?.to_be_bytes());
         } else {
-            dst[len_pos] = u8::try_from(data_len).expect("Path attribute length overflow");
+            dst[len_pos] = u8::try_from(data_len).map_err(too_long)?;
         }
-        len + data_len
+        Ok(len + data_len)
     }
 
     fn encoded_len(&self) -> usize {
@@ -139,18 +325,99 @@ impl Component for Value {
                 Data::Origin(origin) => origin.encoded_len(),
                 Data::AsPath(as_path) | Data::As4Path(as_path) => as_path.encoded_len(),
                 Data::NextHop(next_hop) => next_hop.encoded_len(),
-                Data::MultiExitDisc(_) | Data::LocalPref(_) => 4,
+                Data::MultiExitDisc(_) | Data::LocalPref(_) | Data::OnlyToCustomer(_) => 4,
+                Data::Aigp(_) => usize::from(AIGP_TLV_LEN),
                 Data::AtomicAggregate => 0,
                 Data::Aggregator(agg) => agg.encoded_len(),
+                Data::As4Aggregator(agg) => agg.encoded_len(),
+                Data::Communities(communities) => communities.encoded_len(),
                 Data::MpReachNlri(mp_reach_nlri) => mp_reach_nlri.encoded_len(),
                 Data::MpUnreachNlri(mp_unreach_nlri) => mp_unreach_nlri.encoded_len(),
+                Data::LargeCommunities(communities) => communities.encoded_len(),
                 Data::Unsupported(_, data) => data.len(),
             }
     }
 }
 
+impl Value {
+    /// Decode a path attribute, with four-octet ASNs in `AS_PATH` and
+    /// `AGGREGATOR` if `as4`
+    ///
+    /// `AS4_PATH` and `AS4_AGGREGATOR` always use four-octet ASNs (RFC 6793
+    /// Section 3).
+    ///
+    /// # Errors
+    /// Returns an error if the attribute is malformed.
+    pub fn from_bytes_as4(src: &mut Bytes, as4: bool) -> Result<Self, crate::Error> {
+        Self::from_bytes_negotiated(src, as4, &[])
+    }
+
+    /// Decode a path attribute, with four-octet ASNs if `as4` and path
+    /// identifiers in the NLRI of `MP_REACH_NLRI` and `MP_UNREACH_NLRI` for
+    /// the AFI/SAFI pairs in `add_path`
+    ///
+    /// `add_path` should contain the AFI/SAFI pairs for which the ADD-PATH
+    /// capability (RFC 7911) has been negotiated for receiving, see
+    /// `Capabilities::add_path_receive`.
+    ///
+    /// # Errors
+    /// Returns an error if the attribute is malformed.
+    pub fn from_bytes_negotiated(
+        src: &mut Bytes,
+        as4: bool,
+        add_path: &[(Afi, Safi)],
+    ) -> Result<Self, crate::Error> {
+        let attribute = src.clone();
+        check_min_len!(src, 3, "path attribute");
+        let flags = Flags(src.get_u8());
+        let type_ = src.get_u8();
+        let len = if flags.is_extended_length() {
+            check_min_len!(src, 2, "path attribute");
+            src.get_u16() as usize
+        } else {
+            src.get_u8() as usize
+        };
+        if Type::from_u8(type_).is_some_and(|type_| !flags.is_valid_for(type_)) {
+            // RFC 4271 Section 6.3: the data field contains the erroneous attribute
+            let header_len = attribute.len() - src.len();
+            return Err(crate::Error::UpdateMessage(
+                UpdateMessageErrorSubcode::AttributeFlagsError,
+                attribute.slice(..(header_len + len).min(attribute.len())),
+            ));
+        }
+        check_min_len!(src, len, "path attribute");
+        let mut src = src.split_to(len);
+        let data = match Type::from_u8(type_) {
+            Some(Type::Origin) => Data::Origin(Origin::from_bytes(&mut src)?),
+            Some(Type::AsPath) => Data::AsPath(AsPath::from_bytes_as4(&mut src, as4)?),
+            Some(Type::NextHop) => Data::NextHop(Ipv4Addr::from_bytes(&mut src)?),
+            Some(Type::MultiExitDisc) => Data::MultiExitDisc(u32::from_bytes(&mut src)?),
+            Some(Type::LocalPref) => Data::LocalPref(u32::from_bytes(&mut src)?),
+            Some(Type::AtomicAggregate) => Data::AtomicAggregate,
+            Some(Type::Aggregator) => Data::Aggregator(Aggregator::from_bytes_as4(&mut src, as4)?),
+            Some(Type::Communities) => Data::Communities(Communities::from_bytes(&mut src)?),
+            Some(Type::MpReachNlri) => {
+                Data::MpReachNlri(MpReachNlri::from_bytes_addpath(&mut src, add_path)?)
+            }
+            Some(Type::MpUnreachNlri) => {
+                Data::MpUnreachNlri(MpUnreachNlri::from_bytes_addpath(&mut src, add_path)?)
+            }
+            Some(Type::As4Path) => Data::As4Path(AsPath::from_bytes_as4(&mut src, true)?),
+            Some(Type::LargeCommunities) => {
+                Data::LargeCommunities(LargeCommunities::from_bytes(&mut src)?)
+            }
+            Some(Type::As4Aggregator) => Data::As4Aggregator(As4Aggregator::from_bytes(&mut src)?),
+            Some(Type::OnlyToCustomer) => Data::OnlyToCustomer(u32::from_bytes(&mut src)?),
+            Some(Type::Aigp) => Data::Aigp(decode_aigp(&mut src)?),
+            _ => Data::Unsupported(type_, src),
+        };
+        Ok(Self { flags, data })
+    }
+}
+
 /// BGP path attribute flags
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Flags(pub u8);
 
 impl Flags {
@@ -158,11 +425,15 @@ impl Flags {
     pub const WELL_KNOWN_COMPLETE: Self = Self(0b0100_0000);
     /// Optional, Extended Length, Non-transitive, Complete
     pub const OPTIONAL_TRANSITIVE_EXTENDED: Self = Self(0b1001_0000);
+    /// Optional, non-transitive, complete
+    pub const OPTIONAL_NON_TRANSITIVE: Self = Self(0b1000_0000);
+    /// Optional, transitive, complete
+    pub const OPTIONAL_TRANSITIVE: Self = Self(0b1100_0000);
 
     /// Check if the attribute is optional
     #[must_use]
     pub const fn is_optional(self) -> bool {
-        self.0 & 0x80 == 0
+        self.0 & 0x80 != 0
     }
 
     /// Check if the attribute is transitive
@@ -182,10 +453,37 @@ impl Flags {
     pub const fn is_extended_length(self) -> bool {
         self.0 & 0x10 != 0
     }
+
+    /// Check if the flags are valid for an attribute type (RFC 4271 Section 5)
+    ///
+    /// Well-known attributes must be transitive and complete, and optional
+    /// non-transitive attributes must be complete.
+    #[must_use]
+    pub const fn is_valid_for(self, type_: Type) -> bool {
+        match type_ {
+            Type::Origin
+            | Type::AsPath
+            | Type::NextHop
+            | Type::LocalPref
+            | Type::AtomicAggregate => {
+                !self.is_optional() && self.is_transitive() && !self.is_partial()
+            }
+            Type::Aggregator
+            | Type::Communities
+            | Type::As4Path
+            | Type::As4Aggregator
+            | Type::LargeCommunities
+            | Type::OnlyToCustomer => self.is_optional() && self.is_transitive(),
+            Type::MultiExitDisc | Type::MpReachNlri | Type::MpUnreachNlri | Type::Aigp => {
+                self.is_optional() && !self.is_transitive() && !self.is_partial()
+            }
+        }
+    }
 }
 
 /// BGP path attribute data
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 pub enum Data {
     Origin(Origin),
@@ -196,11 +494,20 @@ pub enum Data {
     LocalPref(u32),
     AtomicAggregate,
     Aggregator(Aggregator),
-    MpReachNlri(MpReachNlri),     // RFC 4760
-    MpUnreachNlri(MpUnreachNlri), // RFC 4760
-    As4Path(AsPath),              // RFC 4893/6793
-    // As4Aggregator(Aggregator),    // RFC 4893/6793
-    Unsupported(u8, Bytes),
+    Communities(Communities),           // RFC 1997
+    MpReachNlri(MpReachNlri),           // RFC 4760
+    MpUnreachNlri(MpUnreachNlri),       // RFC 4760
+    As4Path(AsPath),                    // RFC 4893/6793
+    As4Aggregator(As4Aggregator),       // RFC 4893/6793
+    LargeCommunities(LargeCommunities), // RFC 8092
+    /// BGP Only to Customer (RFC 9234 Section 5)
+    OnlyToCustomer(u32),
+    /// Accumulated IGP metric (RFC 7311 Section 3)
+    Aigp(u64),
+    Unsupported(
+        u8,
+        #[cfg_attr(feature = "impl-serde", serde(with = "crate::serde_hex"))] Bytes,
+    ),
 }
 
 // It does not make sense to implement Component for Data because its length is given by the Flags
@@ -216,10 +523,14 @@ pub enum Type {
     LocalPref = 5,
     AtomicAggregate = 6,
     Aggregator = 7,
+    Communities = 8,
     MpReachNlri = 14,
     MpUnreachNlri = 15,
     As4Path = 17,
-    // As4Aggregator = 18,
+    As4Aggregator = 18,
+    Aigp = 26,
+    LargeCommunities = 32,
+    OnlyToCustomer = 35,
 }
 
 impl From<&Data> for u8 {
@@ -232,16 +543,63 @@ impl From<&Data> for u8 {
             Data::LocalPref(_) => Type::LocalPref as Self,
             Data::AtomicAggregate => Type::AtomicAggregate as Self,
             Data::Aggregator(_) => Type::Aggregator as Self,
+            Data::Communities(_) => Type::Communities as Self,
             Data::MpReachNlri(_) => Type::MpReachNlri as Self,
             Data::MpUnreachNlri(_) => Type::MpUnreachNlri as Self,
             Data::As4Path(_) => Type::As4Path as Self,
+            Data::As4Aggregator(_) => Type::As4Aggregator as Self,
+            Data::LargeCommunities(_) => Type::LargeCommunities as Self,
+            Data::OnlyToCustomer(_) => Type::OnlyToCustomer as Self,
+            Data::Aigp(_) => Type::Aigp as Self,
             Data::Unsupported(type_, _) => *type_,
         }
     }
 }
 
+/// Type of the AIGP TLV (RFC 7311 Section 3.1)
+const AIGP_TLV_TYPE: u8 = 1;
+/// Length of the AIGP TLV, including the type and length fields
+const AIGP_TLV_LEN: u16 = 11;
+
+/// Decode the accumulated IGP metric from the AIGP TLVs (RFC 7311 Section 3)
+///
+/// Only the first AIGP TLV is used, and TLVs of other types are skipped.
+fn decode_aigp(src: &mut Bytes) -> Result<u64, crate::Error> {
+    let mut metric = None;
+    while src.has_remaining() {
+        check_min_len!(src, 3, "AIGP TLV");
+        let tlv_type = src.get_u8();
+        // The length includes the type and length fields
+        let tlv_len = src.get_u16();
+        let value_len = usize::from(tlv_len)
+            .checked_sub(3)
+            .ok_or(crate::Error::InternalLength(
+                "AIGP TLV",
+                core::cmp::Ordering::Less,
+            ))?;
+        check_min_len!(src, value_len, "AIGP TLV");
+        let mut value = src.split_to(value_len);
+        if tlv_type != AIGP_TLV_TYPE || metric.is_some() {
+            continue;
+        }
+        if tlv_len != AIGP_TLV_LEN {
+            return Err(crate::Error::InternalLength(
+                "AIGP TLV",
+                tlv_len.cmp(&AIGP_TLV_LEN),
+            ));
+        }
+        metric = Some(value.get_u64());
+    }
+    // The attribute is too short if it does not include an AIGP TLV
+    metric.ok_or(crate::Error::InternalLength(
+        "AIGP",
+        core::cmp::Ordering::Less,
+    ))
+}
+
 /// BGP origin
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Primitive)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum Origin {
     Igp = 0,
@@ -249,9 +607,19 @@ pub enum Origin {
     Incomplete = 2,
 }
 
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Igp => "IGP",
+            Self::Egp => "EGP",
+            Self::Incomplete => "INCOMPLETE",
+        })
+    }
+}
+
 impl Component for Origin {
     fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
-        let value = src.get_u8();
+        let value = u8::from_bytes(src)?;
         Self::from_u8(value).map_or_else(
             || Err(crate::Error::InternalType("origin", u16::from(value))),
             Ok,
@@ -270,28 +638,237 @@ impl Component for Origin {
 
 /// BGP AS path
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AsPath(pub Vec<AsSegment>);
 
+/// Sequences are space-separated, sets are in braces, and confederation
+/// segments are in parentheses and brackets, e.g. `64500 {64501 64502}`
+impl fmt::Display for AsPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            let (open, close) = match segment.type_ {
+                AsSegmentType::AsSequence => ("", ""),
+                AsSegmentType::AsSet => ("{", "}"),
+                AsSegmentType::ConfedSequence => ("(", ")"),
+                AsSegmentType::ConfedSet => ("[", "]"),
+            };
+            f.write_str(open)?;
+            write_spaced(f, &segment.asns)?;
+            f.write_str(close)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decoding assumes four-octet ASNs, see [`AsPath::from_bytes_as4`].
 impl Component for AsPath {
     fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
-        let mut segments = Vec::new();
-        while src.has_remaining() {
-            segments.push(AsSegment::from_bytes(src)?);
-        }
-        Ok(Self(segments))
+        Self::from_bytes_as4(src, true)
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_to_bytes(dst).expect("AS path length overflow")
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        let as4 = self.is_encoded_as4();
         let mut len = 0;
         for segment in self.0 {
-            len += segment.to_bytes(dst);
+            len += segment.try_to_bytes_with_as4(dst, as4)?;
         }
-        len
+        Ok(len)
     }
 
     fn encoded_len(&self) -> usize {
-        self.0.iter().map(AsSegment::encoded_len).sum()
+        let asn_len = if self.is_encoded_as4() { 4 } else { 2 };
+        self.0
+            .iter()
+            .map(|segment| 2 + segment.asns.len() * asn_len)
+            .sum()
+    }
+}
+
+impl AsPath {
+    /// Decode an AS path, with four-octet ASNs if `as4`
+    ///
+    /// The ASN size cannot be told from the encoding itself, so `as4` should
+    /// be set if the four-octet AS number capability has been negotiated
+    /// with the peer (RFC 6793 Section 4).
+    ///
+    /// # Errors
+    /// Returns an error if any segment is malformed.
+    pub fn from_bytes_as4(src: &mut Bytes, as4: bool) -> Result<Self, crate::Error> {
+        let asn_len = if as4 { 4 } else { 2 };
+        let mut segments = Vec::new();
+        while src.has_remaining() {
+            segments.push(AsSegment::from_bytes_with_asn_len(src, asn_len)?);
+        }
+        Ok(Self(segments))
+    }
+
+    /// Whether this path is encoded with 4-byte ASNs
+    ///
+    /// All segments share one ASN size, so the whole path is promoted to
+    /// 4-byte ASNs if any segment is.
+    #[must_use]
+    pub fn is_encoded_as4(&self) -> bool {
+        self.0.iter().any(AsSegment::is_encoded_as4)
+    }
+
+    /// Prepend `asn` to the path `count` times.
+    ///
+    /// The ASNs are inserted into the leading `AS_SEQUENCE` segment, and new
+    /// segments are created if it is absent or full (RFC 4271 Section 5.1.2).
+    pub fn prepend(&mut self, asn: u32, count: usize) {
+        let as4 = asn > u32::from(u16::MAX);
+        let mut remaining = count;
+        if let Some(first) = self
+            .0
+            .first_mut()
+            .filter(|segment| segment.type_ == AsSegmentType::AsSequence)
+        {
+            let n = remaining.min(usize::from(u8::MAX).saturating_sub(first.asns.len()));
+            first.asns.splice(0..0, core::iter::repeat_n(asn, n));
+            first.as4 |= as4;
+            remaining -= n;
+        }
+        while remaining > 0 {
+            let n = remaining.min(usize::from(u8::MAX));
+            self.0.insert(
+                0,
+                AsSegment {
+                    type_: AsSegmentType::AsSequence,
+                    asns: vec![asn; n],
+                    as4,
+                },
+            );
+            remaining -= n;
+        }
+    }
+
+    /// Check if `asn` appears anywhere in the path
+    #[must_use]
+    pub fn contains_asn(&self, asn: u32) -> bool {
+        self.0.iter().any(|segment| segment.asns.contains(&asn))
+    }
+}
+
+/// Number of ASes in a path as counted for AS4_PATH reconstruction
+/// (RFC 6793 Section 4.2.3)
+fn as_path_count<'a>(segments: impl IntoIterator<Item = &'a AsSegment>) -> usize {
+    segments
+        .into_iter()
+        .map(|segment| match segment.type_ {
+            AsSegmentType::AsSequence => segment.asns.len(),
+            AsSegmentType::AsSet => 1,
+            AsSegmentType::ConfedSequence | AsSegmentType::ConfedSet => 0,
+        })
+        .sum()
+}
+
+/// Reconstruct the real AS path from `AS_PATH` and `AS4_PATH` (RFC 6793 Section 4.2.3)
+///
+/// The leading ASes of `as_path` are kept and the rest, which may contain
+/// `AS_TRANS` placeholders, are replaced by `as4_path`. If `as4_path` is longer
+/// than `as_path`, it is ignored.
+#[must_use]
+pub fn merge_as4_path(as_path: &AsPath, as4_path: &AsPath) -> AsPath {
+    // Confederation segments are not allowed in AS4_PATH
+    let as4_segments: Vec<&AsSegment> = as4_path
+        .iter()
+        .filter(|segment| {
+            matches!(
+                segment.type_,
+                AsSegmentType::AsSequence | AsSegmentType::AsSet
+            )
+        })
+        .collect();
+    let as4_count = as_path_count(as4_segments.iter().copied());
+    let Some(mut to_keep) = as_path_count(as_path.iter()).checked_sub(as4_count) else {
+        return as_path.clone();
+    };
+    let mut segments = Vec::new();
+    for segment in as_path.iter() {
+        let asns = match segment.type_ {
+            _ if to_keep == 0 => break,
+            AsSegmentType::AsSequence => {
+                let n = to_keep.min(segment.asns.len());
+                to_keep -= n;
+                segment.asns[..n].to_vec()
+            }
+            AsSegmentType::AsSet => {
+                to_keep -= 1;
+                segment.asns.clone()
+            }
+            AsSegmentType::ConfedSequence | AsSegmentType::ConfedSet => segment.asns.clone(),
+        };
+        segments.push(AsSegment {
+            type_: segment.type_,
+            asns,
+            as4: true,
+        });
+    }
+    segments.extend(as4_segments.into_iter().map(|segment| AsSegment {
+        as4: true,
+        ..segment.clone()
+    }));
+    AsPath(segments)
+}
+
+/// Split an AS path for a peer without four-octet AS numbers (RFC 6793 Section 4.2.2)
+///
+/// # Returns
+/// The `AS_PATH` with two-octet ASNs, where those that do not fit are
+/// replaced by `AS_TRANS`, and the real path for `AS4_PATH` if any ASN was
+/// replaced. Confederation segments are not included in `AS4_PATH`.
+#[must_use]
+pub fn split_as4_path(as_path: &AsPath) -> (AsPath, Option<AsPath>) {
+    let trans = u32::from(crate::AS_TRANS);
+    let two_octet = AsPath(
+        as_path
+            .iter()
+            .map(|segment| AsSegment {
+                type_: segment.type_,
+                asns: segment
+                    .asns
+                    .iter()
+                    .map(|&asn| {
+                        if asn > u32::from(u16::MAX) {
+                            trans
+                        } else {
+                            asn
+                        }
+                    })
+                    .collect(),
+                as4: false,
+            })
+            .collect(),
+    );
+    if !as_path
+        .iter()
+        .any(|segment| segment.asns.iter().any(|&asn| asn > u32::from(u16::MAX)))
+    {
+        return (two_octet, None);
     }
+    let as4_path = AsPath(
+        as_path
+            .iter()
+            .filter(|segment| {
+                matches!(
+                    segment.type_,
+                    AsSegmentType::AsSequence | AsSegmentType::AsSet
+                )
+            })
+            .map(|segment| AsSegment {
+                as4: true,
+                ..segment.clone()
+            })
+            .collect(),
+    );
+    (two_octet, Some(as4_path))
 }
 
 impl Deref for AsPath {
@@ -304,6 +881,7 @@ impl Deref for AsPath {
 
 /// BGP AS path segment (RFC 4271 Section 5.1.2, RFC 6793 Section 4)
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AsSegment {
     pub type_: AsSegmentType,
     pub asns: Vec<u32>,
@@ -314,6 +892,7 @@ pub struct AsSegment {
 
 /// BGP AS path segment type
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Primitive)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum AsSegmentType {
     AsSet = 1,
@@ -324,136 +903,623 @@ pub enum AsSegmentType {
 
 impl Component for AsSegment {
     fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
-        let type_ = src.get_u8();
-        let len = src.get_u8() as usize;
-        let remaining_len = src.remaining();
-        // RFC 6793 Section 4 updates the AS path segment length to 2 or 4 bytes (when both peers support 4-byte ASNs)
-        let per_asn_len = remaining_len / len;
-        let mut asns = Vec::with_capacity(len);
-        let as4 = if per_asn_len == 2 {
-            for _ in 0..len {
-                asns.push(u32::from(src.get_u16()));
-            }
-            false
-        } else if per_asn_len == 4 {
-            for _ in 0..len {
-                asns.push(src.get_u32());
-            }
-            true
-        } else {
+        if src.remaining() < 2 {
             return Err(crate::Error::InternalLength(
                 "AS segment",
-                std::cmp::Ordering::Equal,
+                core::cmp::Ordering::Less,
             ));
-        };
-        Ok(Self {
-            type_: AsSegmentType::from_u8(type_)
-                .ok_or_else(|| crate::Error::InternalType("AS segment type", u16::from(type_)))?,
-            asns,
-            as4,
-        })
+        }
+        let len = usize::from(src[1]);
+        let remaining_len = src.remaining() - 2;
+        // A standalone segment takes up the whole buffer, which gives the
+        // ASN size. A segment must contain at least one ASN (RFC 7606 Section 7.2)
+        if len == 0 || !remaining_len.is_multiple_of(len) {
+            return Err(crate::Error::InternalLength(
+                "AS segment",
+                core::cmp::Ordering::Equal,
+            ));
+        }
+        Self::from_bytes_with_asn_len(src, remaining_len / len)
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
-        let encoded_len = self.encoded_len();
+        self.try_to_bytes(dst).expect("AS segment length overflow")
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        let as4 = self.is_encoded_as4();
+        self.try_to_bytes_with_as4(dst, as4)
+    }
+
+    fn encoded_len(&self) -> usize {
+        2 + self.asns.len() * if self.is_encoded_as4() { 4 } else { 2 }
+    }
+}
+
+impl AsSegment {
+    /// Whether this segment is encoded with 4-byte ASNs
+    ///
+    /// A segment is promoted to 4-byte ASNs if any ASN does not fit in
+    /// two octets, even if `as4` is not set.
+    #[must_use]
+    pub fn is_encoded_as4(&self) -> bool {
+        self.as4 || self.asns.iter().any(|&asn| asn > u32::from(u16::MAX))
+    }
+
+    /// Encode this segment with 4-byte ASNs if `as4`, which must be set if
+    /// [`AsSegment::is_encoded_as4`] is
+    fn try_to_bytes_with_as4(
+        self,
+        dst: &mut bytes::BytesMut,
+        as4: bool,
+    ) -> Result<usize, crate::Error> {
+        debug_assert!(as4 || !self.is_encoded_as4());
+        let encoded_len = 2 + self.asns.len() * if as4 { 4 } else { 2 };
         dst.put_u8(self.type_ as u8);
-        let asns_len = self.asns.len();
-        dst.put_u8(u8::try_from(asns_len).expect("AS segment length overflow"));
+        let asns_len = u8::try_from(self.asns.len()).map_err(|_| {
+            crate::Error::InternalLength("AS segment", core::cmp::Ordering::Greater)
+        })?;
+        dst.put_u8(asns_len);
         for asn in self.asns {
-            if self.as4 {
+            if as4 {
                 dst.put_u32(asn);
             } else {
-                dst.put_u16(u16::try_from(asn).expect("4-byte ASN in 2-byte AS path"));
+                // Checked by `is_encoded_as4`
+                #[allow(clippy::cast_possible_truncation)]
+                dst.put_u16(asn as u16);
             }
         }
-        encoded_len
+        Ok(encoded_len)
     }
 
-    fn encoded_len(&self) -> usize {
-        2 + self.asns.len() * if self.as4 { 4 } else { 2 }
+    /// Decode a segment whose ASNs are `asn_len` (2 or 4) bytes long
+    fn from_bytes_with_asn_len(src: &mut Bytes, asn_len: usize) -> Result<Self, crate::Error> {
+        if src.remaining() < 2 {
+            return Err(crate::Error::InternalLength(
+                "AS segment",
+                core::cmp::Ordering::Less,
+            ));
+        }
+        let type_ = src.get_u8();
+        let len = usize::from(src.get_u8());
+        // RFC 6793 Section 4 updates the AS path segment length to 2 or 4 bytes (when both peers support 4-byte ASNs)
+        if len == 0 || !matches!(asn_len, 2 | 4) {
+            return Err(crate::Error::InternalLength(
+                "AS segment",
+                core::cmp::Ordering::Equal,
+            ));
+        }
+        if src.remaining() < len * asn_len {
+            return Err(crate::Error::InternalLength(
+                "AS segment",
+                core::cmp::Ordering::Less,
+            ));
+        }
+        let as4 = asn_len == 4;
+        let asns = (0..len)
+            .map(|_| {
+                if as4 {
+                    src.get_u32()
+                } else {
+                    u32::from(src.get_u16())
+                }
+            })
+            .collect();
+        Ok(Self {
+            type_: AsSegmentType::from_u8(type_)
+                .ok_or_else(|| crate::Error::InternalType("AS segment type", u16::from(type_)))?,
+            asns,
+            as4,
+        })
     }
 }
 
-/// BGP aggregator (RFC 4271 Section 5.1.7)
+/// BGP aggregator (RFC 4271 Section 5.1.7, RFC 6793 Section 4)
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Aggregator {
-    pub asn: u16,
+    pub asn: u32,
     pub ip: Ipv4Addr,
+    /// Whether the ASN was decoded from or should be encoded in four octets,
+    /// as between peers that negotiated four-octet AS numbers
+    pub as4: bool,
 }
 
+/// Decoding infers the ASN size from the length, see [`Aggregator::from_bytes_as4`].
 impl Component for Aggregator {
     fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
-        let asn = src.get_u16();
-        let ip = Ipv4Addr::from_bytes(src)?;
-        Ok(Self { asn, ip })
+        Self::from_bytes_as4(src, src.remaining() == 4 + 4)
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
-        dst.put_u16(self.asn);
-        self.ip.to_bytes(dst) + 2 // 2 bytes for ASN
+        let encoded_len = self.encoded_len();
+        if self.is_encoded_as4() {
+            dst.put_u32(self.asn);
+        } else {
+            // Checked by `is_encoded_as4`
+            #[allow(clippy::cast_possible_truncation)]
+            dst.put_u16(self.asn as u16);
+        }
+        self.ip.to_bytes(dst);
+        encoded_len
     }
 
     fn encoded_len(&self) -> usize {
-        4 + 2
+        4 + if self.is_encoded_as4() { 4 } else { 2 }
     }
 }
 
-/// BGP `MP_REACH_NLRI` (RFC 4760 Section 7)
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct MpReachNlri {
-    pub afi: Afi,
-    pub safi: Safi,
-    pub next_hop: MpNextHop,
-    pub nlri: Routes,
-}
-
-impl Component for MpReachNlri {
-    fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
-        let afi = src.get_u16();
-        let afi =
-            Afi::try_from(afi).map_err(|_| crate::Error::InternalType("MP_REACH_NLRI AFI", afi))?;
-        let safi = src.get_u8();
-        let safi = Safi::try_from(safi)
-            .map_err(|_| crate::Error::InternalType("MP_REACH_NLRI SAFI", u16::from(safi)))?;
-        let nh_len = src.get_u8() as usize;
-        let mut nh_src = src.split_to(nh_len);
-        let next_hop = MpNextHop::from_bytes(&mut nh_src)?;
-        let _ = src.get_u8(); // Reserved
-        let nlri = Routes::from_bytes(src)?;
-        Ok(Self {
-            afi,
-            safi,
-            next_hop,
-            nlri,
-        })
+impl Aggregator {
+    /// Decode an aggregator, with a four-octet ASN if `as4`
+    ///
+    /// Like [`AsPath::from_bytes_as4`], `as4` should be set if the
+    /// four-octet AS number capability has been negotiated with the peer.
+    ///
+    /// # Errors
+    /// Returns an Attribute Length Error `Error::UpdateMessage` if the length
+    /// does not match the ASN size.
+    pub fn from_bytes_as4(src: &mut Bytes, as4: bool) -> Result<Self, crate::Error> {
+        let len = 4 + if as4 { 4 } else { 2 };
+        if src.remaining() != len {
+            return Err(attribute_length_error());
+        }
+        let asn = if as4 {
+            src.get_u32()
+        } else {
+            u32::from(src.get_u16())
+        };
+        let ip = Ipv4Addr::from_bytes(src)?;
+        Ok(Self { asn, ip, as4 })
     }
 
-    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
-        let mut len = 0;
-        dst.put_u16(self.afi as u16);
-        len += 2;
-        dst.put_u8(u8::try_from(self.safi as u16).expect("MP_REACH_NLRI SAFI out of range"));
-        len += 1;
-        dst.put_u8(
-            u8::try_from(self.next_hop.encoded_len())
-                .expect("MP_REACH_NLRI next hop length overflow"),
-        );
-        len += 1;
-        len += self.next_hop.to_bytes(dst);
-        dst.put_u8(0); // Reserved
-        len += 1;
-        len += self.nlri.to_bytes(dst);
-        len
+    /// Whether the ASN is encoded in four octets
+    ///
+    /// An ASN that does not fit in two octets is always encoded in four,
+    /// even if `as4` is not set.
+    #[must_use]
+    pub fn is_encoded_as4(&self) -> bool {
+        self.as4 || self.asn > u32::from(u16::MAX)
     }
+}
 
-    fn encoded_len(&self) -> usize {
-        2 + 1 + 1 + self.next_hop.encoded_len() + 1 + self.nlri.encoded_len()
+/// BGP communities (RFC 1997)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Communities(pub Vec<u32>);
+
+impl Communities {
+    /// Well-known community `NO_EXPORT`
+    pub const NO_EXPORT: u32 = 0xffff_ff01;
+    /// Well-known community `NO_ADVERTISE`
+    pub const NO_ADVERTISE: u32 = 0xffff_ff02;
+    /// Well-known community `NO_EXPORT_SUBCONFED`
+    pub const NO_EXPORT_SUBCONFED: u32 = 0xffff_ff03;
+
+    /// Create a community from an ASN and a locally-significant value
+    #[must_use]
+    pub const fn community(asn: u16, value: u16) -> u32 {
+        (asn as u32) << 16 | value as u32
+    }
+
+    /// Create a list containing a single well-known community
+    #[must_use]
+    pub fn well_known(community: u32) -> Self {
+        Self(vec![community])
+    }
+
+    /// Shortcut for a list containing only `NO_EXPORT`
+    #[must_use]
+    pub fn no_export() -> Self {
+        Self::well_known(Self::NO_EXPORT)
+    }
+
+    /// Shortcut for a list containing only `NO_ADVERTISE`
+    #[must_use]
+    pub fn no_advertise() -> Self {
+        Self::well_known(Self::NO_ADVERTISE)
+    }
+
+    /// Shortcut for a list containing only `NO_EXPORT_SUBCONFED`
+    #[must_use]
+    pub fn no_export_subconfed() -> Self {
+        Self::well_known(Self::NO_EXPORT_SUBCONFED)
+    }
+}
+
+impl Component for Communities {
+    fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
+        if !src.remaining().is_multiple_of(4) {
+            return Err(crate::Error::InternalLength(
+                "COMMUNITIES",
+                core::cmp::Ordering::Equal,
+            ));
+        }
+        let mut communities = Vec::with_capacity(src.remaining() / 4);
+        while src.has_remaining() {
+            communities.push(src.get_u32());
+        }
+        Ok(Self(communities))
+    }
+
+    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        for community in &self.0 {
+            dst.put_u32(*community);
+        }
+        self.encoded_len()
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.len() * 4
+    }
+}
+
+impl From<Vec<u32>> for Communities {
+    fn from(communities: Vec<u32>) -> Self {
+        Self(communities)
+    }
+}
+
+impl Deref for Communities {
+    type Target = Vec<u32>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// BGP `AS4_AGGREGATOR` with a 4-byte ASN (RFC 6793 Section 3)
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct As4Aggregator {
+    pub asn: u32,
+    pub ip: Ipv4Addr,
+}
+
+/// Decoding returns an Attribute Length Error `Error::UpdateMessage` unless
+/// the length is exactly 8.
+impl Component for As4Aggregator {
+    fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
+        if src.remaining() != 4 + 4 {
+            return Err(attribute_length_error());
+        }
+        let asn = u32::from_bytes(src)?;
+        let ip = Ipv4Addr::from_bytes(src)?;
+        Ok(Self { asn, ip })
+    }
+
+    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        dst.put_u32(self.asn);
+        self.ip.to_bytes(dst) + 4 // 4 bytes for ASN
+    }
+
+    fn encoded_len(&self) -> usize {
+        4 + 4
+    }
+}
+
+/// BGP large communities (RFC 8092)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LargeCommunities(pub Vec<LargeCommunity>);
+
+/// BGP large community (RFC 8092 Section 3)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LargeCommunity {
+    pub global: u32,
+    pub local1: u32,
+    pub local2: u32,
+}
+
+impl LargeCommunity {
+    #[must_use]
+    pub const fn new(global: u32, local1: u32, local2: u32) -> Self {
+        Self {
+            global,
+            local1,
+            local2,
+        }
+    }
+}
+
+impl Component for LargeCommunities {
+    fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
+        if !src.remaining().is_multiple_of(12) {
+            return Err(crate::Error::InternalLength(
+                "LARGE_COMMUNITIES",
+                core::cmp::Ordering::Equal,
+            ));
+        }
+        let mut communities = Vec::with_capacity(src.remaining() / 12);
+        while src.has_remaining() {
+            let global = src.get_u32();
+            let local1 = src.get_u32();
+            let local2 = src.get_u32();
+            communities.push(LargeCommunity {
+                global,
+                local1,
+                local2,
+            });
+        }
+        Ok(Self(communities))
+    }
+
+    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        for community in &self.0 {
+            dst.put_u32(community.global);
+            dst.put_u32(community.local1);
+            dst.put_u32(community.local2);
+        }
+        self.encoded_len()
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.len() * 12
+    }
+}
+
+impl From<Vec<LargeCommunity>> for LargeCommunities {
+    fn from(communities: Vec<LargeCommunity>) -> Self {
+        Self(communities)
+    }
+}
+
+impl Deref for LargeCommunities {
+    type Target = Vec<LargeCommunity>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// BGP `MP_REACH_NLRI` (RFC 4760 Section 7)
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MpReachNlri {
+    pub afi: Afi,
+    pub safi: Safi,
+    pub next_hop: MpNextHop,
+    pub nlri: MpNlri,
+}
+
+impl Component for MpReachNlri {
+    fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
+        Self::from_bytes_addpath(src, &[])
+    }
+
+    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.encode_to(dst)
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        self.try_encode_to(dst)
+    }
+
+    fn encode_to(&self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_encode_to(dst)
+            .expect("MP_REACH_NLRI length overflow")
+    }
+
+    fn try_encode_to(&self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        let next_hop_len = u8::try_from(self.next_hop_len()).map_err(|_| {
+            crate::Error::InternalLength("MP_REACH_NLRI next hop", core::cmp::Ordering::Greater)
+        })?;
+        let mut len = 0;
+        dst.put_u16(self.afi as u16);
+        len += 2;
+        dst.put_u8(self.safi as u8);
+        len += 1;
+        dst.put_u8(next_hop_len);
+        len += 1;
+        len += if self.safi.is_vpn() {
+            self.next_hop.to_bytes_vpn(dst)
+        } else {
+            self.next_hop.to_bytes(dst)
+        };
+        dst.put_u8(0); // Reserved
+        len += 1;
+        len += self.nlri.try_encode_to(dst)?;
+        Ok(len)
+    }
+
+    fn encoded_len(&self) -> usize {
+        2 + 1 + 1 + self.next_hop_len() + 1 + self.nlri.encoded_len()
+    }
+}
+
+impl MpReachNlri {
+    /// Decode `MP_REACH_NLRI`, with path identifiers in the NLRI if its
+    /// AFI/SAFI is in `add_path`
+    ///
+    /// # Errors
+    /// Returns an error if the attribute is malformed.
+    pub fn from_bytes_addpath(
+        src: &mut Bytes,
+        add_path: &[(Afi, Safi)],
+    ) -> Result<Self, crate::Error> {
+        check_min_len!(src, 5, "MP_REACH_NLRI");
+        let afi = src.get_u16();
+        let afi =
+            Afi::try_from(afi).map_err(|_| crate::Error::InternalType("MP_REACH_NLRI AFI", afi))?;
+        let safi = src.get_u8();
+        let safi = Safi::try_from(safi)
+            .map_err(|_| crate::Error::InternalType("MP_REACH_NLRI SAFI", u16::from(safi)))?;
+        let nh_len = src.get_u8() as usize;
+        // Followed by the reserved octet
+        check_min_len!(src, nh_len + 1, "MP_REACH_NLRI");
+        let mut nh_src = src.split_to(nh_len);
+        let next_hop = if safi.is_vpn() {
+            MpNextHop::from_bytes_vpn(&mut nh_src)?
+        } else {
+            MpNextHop::from_bytes(&mut nh_src)?
+        };
+        // Reserved, should be ignored on receipt (RFC 4760 Section 3)
+        let reserved = src.get_u8();
+        if reserved != 0 {
+            log::debug!("Ignoring non-zero MP_REACH_NLRI reserved octet {reserved}");
+        }
+        let nlri = MpNlri::from_bytes_safi_addpath(src, safi, add_path.contains(&(afi, safi)))?;
+        nlri.validate(afi)?;
+        Ok(Self {
+            afi,
+            safi,
+            next_hop,
+            nlri,
+        })
+    }
+
+    /// Find the encoded length of the next hop
+    fn next_hop_len(&self) -> usize {
+        if self.safi.is_vpn() {
+            self.next_hop.vpn_encoded_len()
+        } else {
+            self.next_hop.encoded_len()
+        }
+    }
+}
+
+/// NLRI in `MP_REACH_NLRI` or `MP_UNREACH_NLRI`, encoded according to the SAFI
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MpNlri {
+    Routes(Routes),
+    /// Labeled VPN routes (RFC 4364 Section 4.3.4)
+    Vpn(VpnRoutes),
+    /// Raw NLRI of a SAFI whose encoding is not understood (e.g. EVPN, Flow Specification)
+    Unsupported(#[cfg_attr(feature = "impl-serde", serde(with = "crate::serde_hex"))] Bytes),
+}
+
+impl Component for MpNlri {
+    fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
+        Ok(Self::Routes(Routes::from_bytes(src)?))
+    }
+
+    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.encode_to(dst)
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        self.try_encode_to(dst)
+    }
+
+    fn encode_to(&self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_encode_to(dst).expect("MP NLRI length overflow")
+    }
+
+    fn try_encode_to(&self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        match self {
+            Self::Routes(routes) => Ok(routes.encode_to(dst)),
+            Self::Vpn(routes) => routes.try_encode_to(dst),
+            Self::Unsupported(data) => {
+                dst.put_slice(data);
+                Ok(data.len())
+            }
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        match self {
+            Self::Routes(routes) => routes.encoded_len(),
+            Self::Vpn(routes) => routes.encoded_len(),
+            Self::Unsupported(data) => data.len(),
+        }
+    }
+}
+
+impl MpNlri {
+    /// Check that the prefix lengths of plain routes are valid for `afi`
+    ///
+    /// # Errors
+    /// See `Routes::validate`.
+    pub fn validate(&self, afi: Afi) -> Result<(), crate::Error> {
+        match self {
+            Self::Routes(routes) => routes.validate(afi),
+            Self::Vpn(_) | Self::Unsupported(_) => Ok(()),
+        }
+    }
+
+    /// Decode NLRI with the encoding used by `safi`
+    ///
+    /// # Errors
+    /// Returns an error if the NLRI is malformed.
+    pub fn from_bytes_safi(src: &mut Bytes, safi: Safi) -> Result<Self, crate::Error> {
+        Self::from_bytes_safi_addpath(src, safi, false)
+    }
+
+    /// Decode NLRI with the encoding used by `safi`, with a path identifier
+    /// before each route if `addpath` (RFC 7911 Section 3)
+    ///
+    /// # Errors
+    /// Returns an error if the NLRI is malformed.
+    pub fn from_bytes_safi_addpath(
+        src: &mut Bytes,
+        safi: Safi,
+        addpath: bool,
+    ) -> Result<Self, crate::Error> {
+        match safi {
+            Safi::Unicast | Safi::Multicast => {
+                Ok(Self::Routes(Routes::from_bytes_addpath(src, addpath)?))
+            }
+            Safi::Vpn | Safi::VpnMulticast => {
+                Ok(Self::Vpn(VpnRoutes::from_bytes_addpath(src, addpath)?))
+            }
+            _ => Ok(Self::Unsupported(src.split_to(src.len()))),
+        }
+    }
+
+    /// Number of routes, always zero for unsupported NLRI
+    #[must_use]
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Routes(routes) => routes.len(),
+            Self::Vpn(routes) => routes.len(),
+            Self::Unsupported(_) => 0,
+        }
+    }
+
+    /// Whether there are no routes
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Unsupported(data) => data.is_empty(),
+            _ => self.len() == 0,
+        }
+    }
+
+    /// Summarize the NLRI as routes of `afi`
+    fn summary(&self, afi: Afi) -> String {
+        match self {
+            Self::Routes(routes) => routes.display(afi).to_string(),
+            Self::Vpn(routes) => format!("{} VPN routes", routes.len()),
+            Self::Unsupported(data) => format!("{} bytes of NLRI", data.len()),
+        }
+    }
+}
+
+impl Default for MpNlri {
+    fn default() -> Self {
+        Self::Routes(Routes::default())
+    }
+}
+
+impl From<Routes> for MpNlri {
+    fn from(routes: Routes) -> Self {
+        Self::Routes(routes)
+    }
+}
+
+impl From<VpnRoutes> for MpNlri {
+    fn from(routes: VpnRoutes) -> Self {
+        Self::Vpn(routes)
     }
 }
 
 /// Next hop for `MP_REACH_NLRI`
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MpNextHop {
+    /// No next hop, as used by Flow Specification (RFC 8955 Section 4)
+    Empty,
     Single(IpAddr),
     V6AndLL(Ipv6Addr, Ipv6Addr),
 }
@@ -461,6 +1527,7 @@ pub enum MpNextHop {
 impl Component for MpNextHop {
     fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
         match src.remaining() {
+            0 => Ok(Self::Empty),
             4 | 16 => Ok(Self::Single(IpAddr::from_bytes(src)?)),
             32 => {
                 let v6local = Ipv6Addr::from_bytes(src)?;
@@ -469,13 +1536,14 @@ impl Component for MpNextHop {
             }
             _ => Err(crate::Error::InternalLength(
                 "MP_NEXT_HOP",
-                std::cmp::Ordering::Equal,
+                core::cmp::Ordering::Equal,
             )),
         }
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
         match self {
+            Self::Empty => {}
             Self::Single(ip) => {
                 ip.to_bytes(dst);
             }
@@ -489,6 +1557,7 @@ impl Component for MpNextHop {
 
     fn encoded_len(&self) -> usize {
         match self {
+            Self::Empty => 0,
             Self::Single(IpAddr::V4(_)) => 4,
             Self::Single(IpAddr::V6(_)) => 16,
             Self::V6AndLL(_, _) => 32,
@@ -496,6 +1565,58 @@ impl Component for MpNextHop {
     }
 }
 
+impl MpNextHop {
+    /// Decode a next hop with each address preceded by a zero route
+    /// distinguisher (RFC 4364 Section 4.3.2, RFC 4659 Section 3.2)
+    fn from_bytes_vpn(src: &mut Bytes) -> Result<Self, crate::Error> {
+        match src.remaining() {
+            0 => Ok(Self::Empty),
+            12 | 24 => {
+                src.advance(8);
+                Ok(Self::Single(IpAddr::from_bytes(src)?))
+            }
+            48 => {
+                src.advance(8);
+                let v6local = Ipv6Addr::from_bytes(src)?;
+                src.advance(8);
+                let v6ll = Ipv6Addr::from_bytes(src)?;
+                Ok(Self::V6AndLL(v6local, v6ll))
+            }
+            _ => Err(crate::Error::InternalLength(
+                "MP_NEXT_HOP",
+                core::cmp::Ordering::Equal,
+            )),
+        }
+    }
+
+    /// Encode the next hop with zero route distinguishers
+    fn to_bytes_vpn(self, dst: &mut bytes::BytesMut) -> usize {
+        match self {
+            Self::Empty => {}
+            Self::Single(ip) => {
+                dst.put_u64(0);
+                ip.to_bytes(dst);
+            }
+            Self::V6AndLL(v6local, v6ll) => {
+                dst.put_u64(0);
+                v6local.to_bytes(dst);
+                dst.put_u64(0);
+                v6ll.to_bytes(dst);
+            }
+        };
+        self.vpn_encoded_len()
+    }
+
+    /// Find the encoded length of the next hop with route distinguishers
+    fn vpn_encoded_len(&self) -> usize {
+        match self {
+            Self::Empty => 0,
+            Self::Single(_) => 8 + self.encoded_len(),
+            Self::V6AndLL(_, _) => 8 + 8 + self.encoded_len(),
+        }
+    }
+}
+
 impl From<IpAddr> for MpNextHop {
     fn from(ip: IpAddr) -> Self {
         Self::Single(ip)
@@ -504,36 +1625,39 @@ impl From<IpAddr> for MpNextHop {
 
 /// BGP `MP_UNREACH_NLRI` (RFC 4760 Section 7)
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MpUnreachNlri {
     pub afi: Afi,
     pub safi: Safi,
-    pub withdrawn_routes: Routes,
+    pub withdrawn_routes: MpNlri,
 }
 
 impl Component for MpUnreachNlri {
     fn from_bytes(src: &mut Bytes) -> Result<Self, crate::Error> {
-        let afi = src.get_u16();
-        let afi = Afi::try_from(afi)
-            .map_err(|_| crate::Error::InternalType("MP_UNREACH_NLRI AFI", afi))?;
-        let safi = src.get_u8();
-        let safi = Safi::try_from(safi)
-            .map_err(|_| crate::Error::InternalType("MP_UNREACH_NLRI SAFI", u16::from(safi)))?;
-        let withdrawn_routes = Routes::from_bytes(src)?;
-        Ok(Self {
-            afi,
-            safi,
-            withdrawn_routes,
-        })
+        Self::from_bytes_addpath(src, &[])
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.encode_to(dst)
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        self.try_encode_to(dst)
+    }
+
+    fn encode_to(&self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_encode_to(dst)
+            .expect("MP_UNREACH_NLRI length overflow")
+    }
+
+    fn try_encode_to(&self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
         let mut len = 0;
         dst.put_u16(self.afi as u16);
         len += 2;
-        dst.put_u8(u8::try_from(self.safi as u16).expect("MP_UNREACH_NLRI SAFI out of range"));
+        dst.put_u8(self.safi as u8);
         len += 1;
-        len += self.withdrawn_routes.to_bytes(dst);
-        len
+        len += self.withdrawn_routes.try_encode_to(dst)?;
+        Ok(len)
     }
 
     fn encoded_len(&self) -> usize {
@@ -541,10 +1665,42 @@ impl Component for MpUnreachNlri {
     }
 }
 
+impl MpUnreachNlri {
+    /// Decode `MP_UNREACH_NLRI`, with path identifiers in the withdrawn
+    /// routes if its AFI/SAFI is in `add_path`
+    ///
+    /// # Errors
+    /// Returns an error if the attribute is malformed.
+    pub fn from_bytes_addpath(
+        src: &mut Bytes,
+        add_path: &[(Afi, Safi)],
+    ) -> Result<Self, crate::Error> {
+        check_min_len!(src, 3, "MP_UNREACH_NLRI");
+        let afi = src.get_u16();
+        let afi = Afi::try_from(afi)
+            .map_err(|_| crate::Error::InternalType("MP_UNREACH_NLRI AFI", afi))?;
+        let safi = src.get_u8();
+        let safi = Safi::try_from(safi)
+            .map_err(|_| crate::Error::InternalType("MP_UNREACH_NLRI SAFI", u16::from(safi)))?;
+        let withdrawn_routes =
+            MpNlri::from_bytes_safi_addpath(src, safi, add_path.contains(&(afi, safi)))?;
+        withdrawn_routes.validate(afi)?;
+        Ok(Self {
+            afi,
+            safi,
+            withdrawn_routes,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{cidr::Cidr4, hex_to_bytes};
+    use crate::{
+        cidr::Cidr4,
+        hex_to_bytes,
+        route::{RouteDistinguisher, VpnValue},
+    };
 
     #[test]
     fn test_origin() {
@@ -565,11 +1721,42 @@ mod tests {
         assert_eq!(encoded_len, dst.len());
     }
 
+    #[test]
+    fn test_origin_bad_flags() {
+        // ORIGIN marked as optional transitive
+        let data = hex_to_bytes("c0010100");
+        let result = Value::from_bytes(&mut data.clone());
+        let Err(crate::Error::UpdateMessage(subcode, attribute)) = result else {
+            panic!("expected an attribute flags error, got {result:?}");
+        };
+        assert_eq!(subcode, UpdateMessageErrorSubcode::AttributeFlagsError);
+        assert_eq!(attribute, data);
+        // ORIGIN marked as partial
+        let mut data = hex_to_bytes("60010100");
+        assert!(Value::from_bytes(&mut data).is_err());
+    }
+
+    #[test]
+    fn test_flags_valid_for() {
+        assert!(Flags::WELL_KNOWN_COMPLETE.is_valid_for(Type::Origin));
+        assert!(!Flags::WELL_KNOWN_COMPLETE.is_valid_for(Type::MultiExitDisc));
+        assert!(Flags::OPTIONAL_NON_TRANSITIVE.is_valid_for(Type::MultiExitDisc));
+        assert!(Flags::OPTIONAL_TRANSITIVE_EXTENDED.is_valid_for(Type::MpReachNlri));
+        assert!(Flags(0xe0).is_valid_for(Type::Communities));
+        assert!(!Flags(0xa0).is_valid_for(Type::MultiExitDisc));
+        // Unsupported attributes are accepted with any flags
+        let mut data = hex_to_bytes("40ff0100");
+        assert_eq!(
+            Value::from_bytes(&mut data).unwrap().data,
+            Data::Unsupported(0xff, hex_to_bytes("00"))
+        );
+    }
+
     #[test]
     fn test_as2_aspath_wsh_1() {
         let mut src = hex_to_bytes("40 0204 0201 fd7d");
         let saved = src.clone();
-        let pa = Value::from_bytes(&mut src).unwrap();
+        let pa = Value::from_bytes_as4(&mut src, false).unwrap();
         assert_eq!(
             pa,
             Value {
@@ -611,6 +1798,182 @@ mod tests {
         assert_eq!(encoded_len, dst.len());
     }
 
+    #[test]
+    fn test_as_path_prepend_empty() {
+        let mut as_path = AsPath::default();
+        as_path.prepend(65000, 3);
+        assert_eq!(
+            as_path,
+            AsPath(vec![AsSegment {
+                type_: AsSegmentType::AsSequence,
+                asns: vec![65000; 3],
+                as4: false,
+            }])
+        );
+        as_path.prepend(4_200_000_000, 1);
+        assert_eq!(as_path.len(), 1);
+        assert_eq!(as_path[0].asns, vec![4_200_000_000, 65000, 65000, 65000]);
+        assert!(as_path[0].as4);
+        assert!(as_path.contains_asn(65000));
+        assert!(as_path.contains_asn(4_200_000_000));
+        assert!(!as_path.contains_asn(65001));
+    }
+
+    #[test]
+    fn test_as_path_prepend_as_set() {
+        let set = AsSegment {
+            type_: AsSegmentType::AsSet,
+            asns: vec![64512, 64513],
+            as4: false,
+        };
+        let mut as_path = AsPath(vec![set.clone()]);
+        as_path.prepend(65000, 2);
+        assert_eq!(
+            as_path,
+            AsPath(vec![
+                AsSegment {
+                    type_: AsSegmentType::AsSequence,
+                    asns: vec![65000; 2],
+                    as4: false,
+                },
+                set,
+            ])
+        );
+        assert!(as_path.contains_asn(64513));
+    }
+
+    #[test]
+    fn test_as_path_prepend_overflow() {
+        let mut as_path = AsPath::default();
+        as_path.prepend(65000, 300);
+        assert_eq!(as_path.len(), 2);
+        assert_eq!(as_path[0].asns.len(), 45);
+        assert_eq!(as_path[1].asns.len(), 255);
+    }
+
+    #[test]
+    fn test_merge_as4_path() {
+        let trans = u32::from(crate::AS_TRANS);
+        let as_path = AsPath(vec![AsSegment {
+            type_: AsSegmentType::AsSequence,
+            asns: vec![65000, trans, 65001, trans],
+            as4: false,
+        }]);
+        let as4_path = AsPath(vec![AsSegment {
+            type_: AsSegmentType::AsSequence,
+            asns: vec![4_200_000_000, 65001, 4_200_000_001],
+            as4: true,
+        }]);
+        let expected = AsPath(vec![
+            AsSegment {
+                type_: AsSegmentType::AsSequence,
+                asns: vec![65000],
+                as4: true,
+            },
+            AsSegment {
+                type_: AsSegmentType::AsSequence,
+                asns: vec![4_200_000_000, 65001, 4_200_000_001],
+                as4: true,
+            },
+        ]);
+        assert_eq!(merge_as4_path(&as_path, &as4_path), expected);
+        let attrs = PathAttributes(vec![
+            Value {
+                flags: Flags::WELL_KNOWN_COMPLETE,
+                data: Data::AsPath(as_path.clone()),
+            },
+            Value {
+                flags: Flags(0xc0),
+                data: Data::As4Path(as4_path.clone()),
+            },
+        ]);
+        assert_eq!(attrs.merged_as_path(), Some(expected));
+        // AS4_PATH longer than AS_PATH is ignored
+        assert_eq!(merge_as4_path(&as4_path, &as_path), as4_path);
+    }
+
+    #[test]
+    fn test_split_as4_path() {
+        let trans = u32::from(crate::AS_TRANS);
+        let as_path = AsPath(vec![
+            AsSegment {
+                type_: AsSegmentType::ConfedSequence,
+                asns: vec![64512],
+                as4: true,
+            },
+            AsSegment {
+                type_: AsSegmentType::AsSequence,
+                asns: vec![4_200_000_000, 65001],
+                as4: true,
+            },
+        ]);
+        let (two_octet, as4_path) = split_as4_path(&as_path);
+        assert_eq!(
+            two_octet,
+            AsPath(vec![
+                AsSegment {
+                    type_: AsSegmentType::ConfedSequence,
+                    asns: vec![64512],
+                    as4: false,
+                },
+                AsSegment {
+                    type_: AsSegmentType::AsSequence,
+                    asns: vec![trans, 65001],
+                    as4: false,
+                },
+            ])
+        );
+        let as4_path = as4_path.unwrap();
+        assert_eq!(as4_path, AsPath(as_path.0[1..].to_vec()));
+        // Merging them back gives the real path outside the confederation
+        assert_eq!(
+            merge_as4_path(&AsPath(two_octet.0[1..].to_vec()), &as4_path),
+            as4_path
+        );
+        // No AS4_PATH if all ASNs fit in two octets
+        let (_, as4_path) = split_as4_path(&AsPath(vec![AsSegment {
+            type_: AsSegmentType::AsSequence,
+            asns: vec![65000],
+            as4: true,
+        }]));
+        assert_eq!(as4_path, None);
+    }
+
+    #[test]
+    fn test_merge_as4_path_with_set() {
+        let trans = u32::from(crate::AS_TRANS);
+        let as_path = AsPath(vec![
+            AsSegment {
+                type_: AsSegmentType::AsSequence,
+                asns: vec![65000, trans],
+                as4: false,
+            },
+            AsSegment {
+                type_: AsSegmentType::AsSet,
+                asns: vec![64512, trans],
+                as4: false,
+            },
+        ]);
+        let as4_path = AsPath(vec![
+            AsSegment {
+                type_: AsSegmentType::AsSequence,
+                asns: vec![4_200_000_000],
+                as4: true,
+            },
+            AsSegment {
+                type_: AsSegmentType::AsSet,
+                asns: vec![64512, 4_200_000_001],
+                as4: true,
+            },
+        ]);
+        let merged = merge_as4_path(&as_path, &as4_path);
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[0].asns, vec![65000]);
+        assert_eq!(merged[1].asns, vec![4_200_000_000]);
+        assert_eq!(merged[2].asns, vec![64512, 4_200_000_001]);
+        assert!(!merged.contains_asn(trans));
+    }
+
     #[test]
     fn test_next_hop() {
         let mut src = hex_to_bytes("40 03 04 7f000001");
@@ -672,6 +2035,119 @@ mod tests {
         assert_eq!(encoded_len, dst.len());
     }
 
+    #[test]
+    fn test_aggregator_negotiated_asn_size() {
+        let src = hex_to_bytes("c0 07 08 fa56ea00 c0000202");
+        let pa = Value::from_bytes_as4(&mut src.clone(), true).unwrap();
+        assert_eq!(
+            pa.data,
+            Data::Aggregator(Aggregator {
+                asn: 4_200_000_000,
+                ip: Ipv4Addr::new(192, 0, 2, 2),
+                as4: true,
+            })
+        );
+        let mut dst = bytes::BytesMut::new();
+        pa.to_bytes(&mut dst);
+        assert_eq!(dst, src);
+        // Four-octet AGGREGATOR from a peer that did not negotiate it
+        assert!(matches!(
+            Value::from_bytes_as4(&mut src.clone(), false),
+            Err(crate::Error::UpdateMessage(
+                UpdateMessageErrorSubcode::AttributeLengthError,
+                _
+            ))
+        ));
+        let mut src = hex_to_bytes("c0 07 06 fc00 c0000202");
+        assert_eq!(
+            Value::from_bytes_as4(&mut src, false).unwrap().data,
+            Data::Aggregator(Aggregator {
+                asn: 64512,
+                ip: Ipv4Addr::new(192, 0, 2, 2),
+                as4: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_as4_aggregator() {
+        let mut src = hex_to_bytes("c0 12 08 0000fd7d 7f000001");
+        let saved = src.clone();
+        let pa = Value::from_bytes(&mut src).unwrap();
+        assert_eq!(
+            pa,
+            Value {
+                flags: Flags(0xc0),
+                data: Data::As4Aggregator(As4Aggregator {
+                    asn: 0xfd7d,
+                    ip: Ipv4Addr::new(127, 0, 0, 1),
+                }),
+            }
+        );
+        let encoded_len = pa.encoded_len();
+        let mut dst = bytes::BytesMut::new();
+        pa.to_bytes(&mut dst);
+        assert_eq!(dst, saved);
+        assert_eq!(encoded_len, dst.len());
+    }
+
+    #[test]
+    fn test_as4_aggregator_wrong_length() {
+        for src in ["c0 12 06 0000fd7d 7f00", "c0 12 0a 0000fd7d 7f000001 0000"] {
+            assert!(matches!(
+                Value::from_bytes(&mut hex_to_bytes(src)),
+                Err(crate::Error::UpdateMessage(
+                    UpdateMessageErrorSubcode::AttributeLengthError,
+                    _
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_communities_wsh_1() {
+        let mut src = hex_to_bytes("c0 08 0c fbff0004 fbff0018 fbff0022");
+        let saved = src.clone();
+        let pa = Value::from_bytes(&mut src).unwrap();
+        assert_eq!(
+            pa,
+            Value {
+                flags: Flags(0xc0),
+                data: Data::Communities(Communities(vec![
+                    Communities::community(64511, 4),
+                    Communities::community(64511, 24),
+                    Communities::community(64511, 34),
+                ])),
+            }
+        );
+        let encoded_len = pa.encoded_len();
+        let mut dst = bytes::BytesMut::new();
+        pa.to_bytes(&mut dst);
+        assert_eq!(dst, saved);
+        assert_eq!(encoded_len, dst.len());
+    }
+
+    #[test]
+    fn test_well_known_communities() {
+        let mut src = hex_to_bytes("c0 08 08 ffffff01 ffffff02");
+        let pa = Value::from_bytes(&mut src).unwrap();
+        let Data::Communities(communities) = pa.data else {
+            panic!("unexpected path attribute type");
+        };
+        assert!(communities.contains(&Communities::NO_EXPORT));
+        assert!(communities.contains(&Communities::NO_ADVERTISE));
+        assert_eq!(Communities::no_export().0, vec![0xffff_ff01]);
+    }
+
+    #[test]
+    fn test_large_community_bad_length() {
+        let mut src = hex_to_bytes("c0 20 0b fcde31ef0000007800000014");
+        assert!(matches!(
+            Value::from_bytes(&mut src),
+            Err(crate::Error::InternalLength("LARGE_COMMUNITIES", _))
+        ));
+    }
+
     #[test]
     fn test_mp_reach_nlri_wsh_1() {
         let mut src = hex_to_bytes("90 0e 002a
@@ -689,7 +2165,11 @@ mod tests {
                         "::".parse().unwrap(),
                         "fe80::abcd".parse().unwrap()
                     ),
-                    nlri: Routes(vec![Cidr4::new(Ipv4Addr::new(10, 127, 127, 127), 32).into()])
+                    nlri: MpNlri::Routes(Routes(vec![Cidr4::new(
+                        Ipv4Addr::new(10, 127, 127, 127),
+                        32
+                    )
+                    .into()]))
                 }),
             }
         );
@@ -712,9 +2192,11 @@ mod tests {
                 data: Data::MpUnreachNlri(MpUnreachNlri {
                     afi: Afi::Ipv4,
                     safi: Safi::Unicast,
-                    withdrawn_routes: Routes(vec![
-                        Cidr4::new(Ipv4Addr::new(172, 23, 227, 0), 24).into()
-                    ])
+                    withdrawn_routes: MpNlri::Routes(Routes(vec![Cidr4::new(
+                        Ipv4Addr::new(172, 23, 227, 0),
+                        24
+                    )
+                    .into()]))
                 }),
             }
         );
@@ -726,7 +2208,58 @@ mod tests {
     }
 
     #[test]
-    fn test_other_large_community_wsh_1() {
+    fn test_mp_unreach_nlri_vpn() {
+        let mut src = hex_to_bytes("0001 80 70 800000 0000fde8 00000064 c00002");
+        let saved = src.clone();
+        let mp = MpUnreachNlri::from_bytes(&mut src).unwrap();
+        assert_eq!(
+            mp,
+            MpUnreachNlri {
+                afi: Afi::Ipv4,
+                safi: Safi::Vpn,
+                withdrawn_routes: MpNlri::Vpn(VpnRoutes(vec![VpnValue {
+                    path_id: None,
+                    labels: vec![VpnValue::WITHDRAW_LABEL],
+                    rd: RouteDistinguisher(0xfde8_0000_0064),
+                    prefix_len: 24,
+                    prefix: hex_to_bytes("c00002"),
+                }])),
+            }
+        );
+        let mut dst = bytes::BytesMut::new();
+        assert_eq!(mp.to_bytes(&mut dst), saved.len());
+        assert_eq!(dst, saved);
+    }
+
+    #[test]
+    fn test_mp_reach_nlri_vpn_too_long() {
+        let attribute = Value {
+            flags: Flags::OPTIONAL_TRANSITIVE_EXTENDED,
+            data: Data::MpReachNlri(MpReachNlri {
+                afi: Afi::Ipv4,
+                safi: Safi::Vpn,
+                next_hop: MpNextHop::Single(Ipv4Addr::new(192, 0, 2, 1).into()),
+                // The labels do not fit in the length octet of the route
+                nlri: MpNlri::Vpn(VpnRoutes(vec![VpnValue {
+                    path_id: None,
+                    labels: vec![0x640; 10],
+                    rd: RouteDistinguisher(0),
+                    prefix_len: 24,
+                    prefix: hex_to_bytes("c00002"),
+                }])),
+            }),
+        };
+        assert!(matches!(
+            attribute.try_encode_to(&mut bytes::BytesMut::new()),
+            Err(crate::Error::InternalLength(
+                "VPN route",
+                core::cmp::Ordering::Greater
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_large_community_wsh_1() {
         let mut src = hex_to_bytes(
             "c0 20 24fcde31ef0000007800000014fcde31ef0000008200000001fcde31ef0000008c00000035",
         );
@@ -736,12 +2269,106 @@ mod tests {
             pa,
             Value {
                 flags: Flags(0xc0),
-                data: Data::Unsupported(
-                    0x20,
-                    hex_to_bytes(
-                        "fcde31ef0000007800000014fcde31ef0000008200000001fcde31ef0000008c00000035"
-                    )
+                data: Data::LargeCommunities(LargeCommunities(vec![
+                    LargeCommunity::new(0xfcde_31ef, 120, 20),
+                    LargeCommunity::new(0xfcde_31ef, 130, 1),
+                    LargeCommunity::new(0xfcde_31ef, 140, 53),
+                ])),
+            }
+        );
+        let encoded_len = pa.encoded_len();
+        let mut dst = bytes::BytesMut::new();
+        pa.to_bytes(&mut dst);
+        assert_eq!(dst, saved);
+        assert_eq!(encoded_len, dst.len());
+    }
+
+    #[test]
+    fn test_mp_reach_nlri_flowspec() {
+        // Flow Specification routes have no next hop (RFC 8955 Section 4)
+        let src = hex_to_bytes("0001 85 00 00 05 01 18 c00002");
+        let reach = MpReachNlri::from_bytes(&mut src.clone()).unwrap();
+        assert_eq!(reach.safi, Safi::FlowSpecUnicast);
+        assert_eq!(reach.next_hop, MpNextHop::Empty);
+        assert_eq!(
+            reach.nlri,
+            MpNlri::Unsupported(hex_to_bytes("05 01 18 c00002"))
+        );
+        assert!(!reach.nlri.is_empty());
+        assert_eq!(reach.encoded_len(), src.len());
+        let mut dst = bytes::BytesMut::new();
+        reach.to_bytes(&mut dst);
+        assert_eq!(dst.freeze(), src);
+    }
+
+    #[test]
+    fn test_mp_reach_nlri_truncated() {
+        for data in [
+            "",
+            "0001 01",
+            // Next hop longer than the attribute
+            "0001 01 04 c000",
+            // Missing the reserved octet
+            "0001 01 04 c0000201",
+        ] {
+            let mut src = hex_to_bytes(data);
+            assert!(
+                matches!(
+                    MpReachNlri::from_bytes(&mut src),
+                    Err(crate::Error::InternalLength(
+                        "MP_REACH_NLRI",
+                        core::cmp::Ordering::Less
+                    ))
                 ),
+                "{data}"
+            );
+        }
+        // Through the attribute decoder
+        let mut src = hex_to_bytes("90 0e 0007 0001 01 10 c0000201");
+        assert!(Value::from_bytes(&mut src).is_err());
+        // Unexpected next hop lengths are still rejected
+        let mut src = hex_to_bytes("0001 01 05 c000020100 00");
+        assert!(matches!(
+            MpReachNlri::from_bytes(&mut src),
+            Err(crate::Error::InternalLength("MP_NEXT_HOP", _))
+        ));
+        // A non-zero reserved octet is ignored
+        let mut src = hex_to_bytes("0001 01 04 c0000201 ff 18 c00002");
+        let reach = MpReachNlri::from_bytes(&mut src).unwrap();
+        assert_eq!(reach.nlri.len(), 1);
+    }
+
+    #[test]
+    fn test_only_to_customer() {
+        let mut src = hex_to_bytes("c0 23 04 0000fde8");
+        let saved = src.clone();
+        let pa = Value::from_bytes(&mut src).unwrap();
+        assert_eq!(
+            pa,
+            Value {
+                flags: Flags(0xc0),
+                data: Data::OnlyToCustomer(65000),
+            }
+        );
+        let encoded_len = pa.encoded_len();
+        let mut dst = bytes::BytesMut::new();
+        pa.to_bytes(&mut dst);
+        assert_eq!(dst, saved);
+        assert_eq!(encoded_len, dst.len());
+        // OTC is optional transitive
+        assert!(!Flags::WELL_KNOWN_COMPLETE.is_valid_for(Type::OnlyToCustomer));
+    }
+
+    #[test]
+    fn test_aigp() {
+        let mut src = hex_to_bytes("80 1a 0b 01 000b 0000000000000064");
+        let saved = src.clone();
+        let pa = Value::from_bytes(&mut src).unwrap();
+        assert_eq!(
+            pa,
+            Value {
+                flags: Flags::OPTIONAL_NON_TRANSITIVE,
+                data: Data::Aigp(100),
             }
         );
         let encoded_len = pa.encoded_len();
@@ -749,5 +2376,136 @@ mod tests {
         pa.to_bytes(&mut dst);
         assert_eq!(dst, saved);
         assert_eq!(encoded_len, dst.len());
+        // Unknown TLVs and AIGP TLVs after the first are skipped
+        let mut src =
+            hex_to_bytes("80 1a 1a 02 0004 ab 01 000b 0000000000000064 01 000b 00000000000000c8");
+        let pa = Value::from_bytes(&mut src).unwrap();
+        assert_eq!(pa.data, Data::Aigp(100));
+        // No AIGP TLV at all
+        let mut src = hex_to_bytes("80 1a 0b 02 000b 0000000000000064");
+        assert!(matches!(
+            Value::from_bytes(&mut src),
+            Err(crate::Error::InternalLength(
+                "AIGP",
+                core::cmp::Ordering::Less
+            ))
+        ));
+        // A TLV running past the end of the attribute
+        let mut src = hex_to_bytes("80 1a 05 02 0008 abcd");
+        assert!(matches!(
+            Value::from_bytes(&mut src),
+            Err(crate::Error::InternalLength("AIGP TLV", _))
+        ));
+    }
+
+    #[test]
+    fn test_as_segment_promoted_to_as4() {
+        let segment = AsSegment {
+            type_: AsSegmentType::AsSequence,
+            asns: vec![70000],
+            as4: false,
+        };
+        assert!(segment.is_encoded_as4());
+        assert_eq!(segment.encoded_len(), 6);
+        let mut dst = bytes::BytesMut::new();
+        assert_eq!(segment.to_bytes(&mut dst), 6);
+        assert_eq!(dst, hex_to_bytes("02 01 00011170"));
+    }
+
+    #[test]
+    fn test_as_path_multiple_segments() {
+        // AS_SEQUENCE 65000 65001, AS_SET 64512 64513
+        let mut src = hex_to_bytes("02 02 fde8 fde9 01 02 fc00 fc01");
+        let as_path = AsPath::from_bytes_as4(&mut src, false).unwrap();
+        assert_eq!(as_path.len(), 2);
+        assert_eq!(as_path[0].asns, vec![65000, 65001]);
+        assert_eq!(as_path[1].asns, vec![64512, 64513]);
+        assert!(!as_path[1].as4);
+        let mut src = hex_to_bytes("02 01 fa56ea00 01 02 0000fde8 fa56ea01");
+        let as_path = AsPath::from_bytes(&mut src).unwrap();
+        assert_eq!(as_path[0].asns, vec![4_200_000_000]);
+        assert_eq!(as_path[1].asns, vec![65000, 4_200_000_001]);
+        assert!(as_path[1].as4);
+    }
+
+    #[test]
+    fn test_as_path_mixed_segments_promoted_to_as4() {
+        // A two-octet segment next to one that needs four octets
+        let as_path = AsPath(vec![
+            AsSegment {
+                type_: AsSegmentType::AsSequence,
+                asns: vec![65000, 65001],
+                as4: false,
+            },
+            AsSegment {
+                type_: AsSegmentType::AsSet,
+                asns: vec![4_200_000_000],
+                as4: false,
+            },
+        ]);
+        assert!(as_path.is_encoded_as4());
+        assert_eq!(as_path.encoded_len(), 16);
+        let mut dst = bytes::BytesMut::new();
+        assert_eq!(as_path.clone().to_bytes(&mut dst), 16);
+        assert_eq!(dst, hex_to_bytes("02 02 0000fde8 0000fde9 01 01 fa56ea00"));
+        let decoded = AsPath::from_bytes_as4(&mut dst.freeze(), true).unwrap();
+        assert_eq!(decoded[0].asns, as_path[0].asns);
+        assert_eq!(decoded[1].asns, as_path[1].asns);
+        assert!(decoded.iter().all(|segment| segment.as4));
+    }
+
+    #[test]
+    fn test_as_path_two_octet_fits_as_four_octet() {
+        // {SEQ 65000 65001} {SET 65002} also parses as one four-octet segment
+        let data = hex_to_bytes("02 02 fde8 fde9 01 01 fdea");
+        let as_path = AsPath::from_bytes_as4(&mut data.clone(), false).unwrap();
+        assert_eq!(
+            as_path,
+            AsPath(vec![
+                AsSegment {
+                    type_: AsSegmentType::AsSequence,
+                    asns: vec![65000, 65001],
+                    as4: false,
+                },
+                AsSegment {
+                    type_: AsSegmentType::AsSet,
+                    asns: vec![65002],
+                    as4: false,
+                },
+            ])
+        );
+        let mut dst = bytes::BytesMut::new();
+        as_path.encode_to(&mut dst);
+        assert_eq!(dst, data);
+        // Also through the attribute decoder
+        let mut src = hex_to_bytes("40 02 0a 02 02 fde8 fde9 01 01 fdea");
+        let Data::AsPath(decoded) = Value::from_bytes_as4(&mut src, false).unwrap().data else {
+            panic!("expected AS_PATH");
+        };
+        assert_eq!(decoded, as_path);
+    }
+
+    #[test]
+    fn test_as_segment_malformed() {
+        for segment in [
+            "",
+            "02",
+            "02 00",
+            "02 00 fd7d",
+            "02 02 fd7d 00",
+            "02 01 fd7d 00",
+        ] {
+            let mut src = hex_to_bytes(segment);
+            assert!(
+                matches!(
+                    AsSegment::from_bytes(&mut src),
+                    Err(crate::Error::InternalLength("AS segment", _))
+                ),
+                "{segment}"
+            );
+        }
+        // Also through the attribute decoder
+        let mut src = hex_to_bytes("40 02 02 02 00");
+        assert!(Value::from_bytes(&mut src).is_err());
     }
 }
@@ -0,0 +1,440 @@
+//! Property-based round-trip tests of the encoders and decoders
+//!
+//! Each test generates values with `proptest`, encodes them, decodes them
+//! back, and checks that the result is equal and that `encoded_len` matches
+//! the number of bytes written.
+
+use super::capability::{self, *};
+use super::endec::Component;
+use super::path::{self, Type, *};
+use super::route::{self, *};
+use super::*;
+use bytes::{Bytes, BytesMut};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::sample::select;
+use std::fmt::Debug;
+use std::net::{IpAddr, Ipv6Addr};
+
+fn bytes(max_len: usize) -> impl Strategy<Value = Bytes> {
+    vec(any::<u8>(), 0..=max_len).prop_map(Bytes::from)
+}
+
+/// A prefix of `prefix_len` bits with the host bits cleared
+fn prefix(prefix_len: u8) -> impl Strategy<Value = Bytes> {
+    let n_octets = usize::from(prefix_len).div_ceil(8);
+    vec(any::<u8>(), n_octets).prop_map(move |mut prefix| {
+        if let Some(last) = prefix.last_mut() {
+            let trailing_bits = prefix_len & 0x07;
+            if trailing_bits != 0 {
+                *last &= !(0xff >> trailing_bits);
+            }
+        }
+        prefix.into()
+    })
+}
+
+const fn max_prefix_len(afi: Afi) -> u8 {
+    if matches!(afi, Afi::Ipv4) {
+        32
+    } else {
+        128
+    }
+}
+
+/// Routes valid for `afi`, all with or all without path identifiers
+fn routes(afi: Afi, max_len: usize, addpath: bool) -> impl Strategy<Value = Routes> {
+    let route = (0..=max_prefix_len(afi))
+        .prop_flat_map(|prefix_len| (Just(prefix_len), prefix(prefix_len), any::<u32>()))
+        .prop_map(move |(prefix_len, prefix, path_id)| {
+            let mut route = route::Value::new(prefix_len, prefix).unwrap();
+            if addpath {
+                route.path_id = Some(path_id);
+            }
+            route
+        });
+    vec(route, 0..=max_len).prop_map(Routes)
+}
+
+fn vpn_routes(afi: Afi) -> impl Strategy<Value = VpnRoutes> {
+    // Only the last label has the bottom-of-stack bit
+    let value = vec(any::<u32>().prop_map(|label| label & 0xff_fff0), 0..=2)
+        .prop_flat_map(move |labels| {
+            // Labels, route distinguisher and prefix share an 8-bit length
+            let labels_len = 24 * (u8::try_from(labels.len()).unwrap() + 1);
+            let max_prefix_len = max_prefix_len(afi).min(255 - 64 - labels_len);
+            (Just(labels), any::<u32>(), any::<u64>(), 0..=max_prefix_len)
+        })
+        .prop_flat_map(|(labels, last, rd, prefix_len)| {
+            (
+                Just(labels),
+                Just(last),
+                Just(rd),
+                Just(prefix_len),
+                prefix(prefix_len),
+            )
+        })
+        .prop_map(|(mut labels, last, rd, prefix_len, prefix)| {
+            labels.push((last & 0xff_fff0) | VpnValue::BOTTOM_OF_STACK);
+            VpnValue {
+                path_id: None,
+                labels,
+                rd: RouteDistinguisher(rd),
+                prefix_len,
+                prefix,
+            }
+        });
+    vec(value, 0..=4).prop_map(VpnRoutes)
+}
+
+fn afi_safi() -> impl Strategy<Value = (Afi, Safi)> {
+    (
+        select(vec![Afi::Ipv4, Afi::Ipv6, Afi::L2Vpn]),
+        select(vec![
+            Safi::Unicast,
+            Safi::Multicast,
+            Safi::MplsLabel,
+            Safi::Evpn,
+            Safi::Vpn,
+            Safi::VpnMulticast,
+            Safi::FlowSpecUnicast,
+        ]),
+    )
+}
+
+/// NLRI that decodes back to the same value for `afi` and `safi`
+fn mp_nlri(afi: Afi, safi: Safi) -> BoxedStrategy<MpNlri> {
+    let afi = if afi == Afi::L2Vpn { Afi::Ipv4 } else { afi };
+    match safi {
+        // Multiprotocol routes are always decoded without path identifiers
+        Safi::Unicast | Safi::Multicast => routes(afi, 8, false).prop_map(MpNlri::Routes).boxed(),
+        Safi::Vpn | Safi::VpnMulticast => vpn_routes(afi).prop_map(MpNlri::Vpn).boxed(),
+        _ => bytes(16).prop_map(MpNlri::Unsupported).boxed(),
+    }
+}
+
+fn next_hop() -> impl Strategy<Value = MpNextHop> {
+    prop_oneof![
+        Just(MpNextHop::Empty),
+        any::<Ipv4Addr>().prop_map(|addr| MpNextHop::Single(IpAddr::V4(addr))),
+        any::<Ipv6Addr>().prop_map(|addr| MpNextHop::Single(IpAddr::V6(addr))),
+        any::<(Ipv6Addr, Ipv6Addr)>().prop_map(|(addr, ll)| MpNextHop::V6AndLL(addr, ll)),
+    ]
+}
+
+/// An AS path as it is decoded, with `as4` set exactly when the segments
+/// are encoded with four-octet ASNs
+fn as_path(as4: bool) -> impl Strategy<Value = AsPath> {
+    let asn = if as4 {
+        any::<u32>().boxed()
+    } else {
+        any::<u16>().prop_map(u32::from).boxed()
+    };
+    let segment = (
+        select(vec![
+            AsSegmentType::AsSet,
+            AsSegmentType::AsSequence,
+            AsSegmentType::ConfedSequence,
+            AsSegmentType::ConfedSet,
+        ]),
+        prop_oneof![vec(asn.clone(), 1..=4), vec(asn, 1..=255)],
+    )
+        .prop_map(move |(type_, asns)| AsSegment { type_, asns, as4 });
+    vec(segment, 0..=3).prop_map(AsPath)
+}
+
+/// An AS path whose segments each have their own `as4`, some of them with
+/// ASNs that need four octets
+fn mixed_as_path() -> impl Strategy<Value = AsPath> {
+    let segment = (
+        select(vec![AsSegmentType::AsSet, AsSegmentType::AsSequence]),
+        any::<bool>(),
+    )
+        .prop_flat_map(|(type_, as4)| {
+            let asn = if as4 {
+                any::<u32>().boxed()
+            } else {
+                any::<u16>().prop_map(u32::from).boxed()
+            };
+            vec(asn, 1..=4).prop_map(move |asns| AsSegment { type_, asns, as4 })
+        });
+    vec(segment, 1..=3).prop_map(AsPath)
+}
+
+fn attribute_data() -> impl Strategy<Value = path::Data> {
+    prop_oneof![
+        select(vec![Origin::Igp, Origin::Egp, Origin::Incomplete]).prop_map(path::Data::Origin),
+        // Attributes are decoded with four-octet ASNs
+        as_path(true).prop_map(path::Data::AsPath),
+        any::<Ipv4Addr>().prop_map(path::Data::NextHop),
+        any::<u32>().prop_map(path::Data::MultiExitDisc),
+        any::<u32>().prop_map(path::Data::LocalPref),
+        Just(path::Data::AtomicAggregate),
+        any::<(u32, Ipv4Addr)>().prop_map(|(asn, ip)| path::Data::Aggregator(Aggregator {
+            asn,
+            ip,
+            as4: true
+        })),
+        vec(any::<u32>(), 0..=8).prop_map(|c| path::Data::Communities(Communities(c))),
+        afi_safi()
+            .prop_flat_map(|(afi, safi)| (Just(afi), Just(safi), next_hop(), mp_nlri(afi, safi)))
+            .prop_map(|(afi, safi, next_hop, nlri)| {
+                path::Data::MpReachNlri(MpReachNlri {
+                    afi,
+                    safi,
+                    next_hop,
+                    nlri,
+                })
+            }),
+        afi_safi()
+            .prop_flat_map(|(afi, safi)| (Just(afi), Just(safi), mp_nlri(afi, safi)))
+            .prop_map(|(afi, safi, withdrawn_routes)| {
+                path::Data::MpUnreachNlri(MpUnreachNlri {
+                    afi,
+                    safi,
+                    withdrawn_routes,
+                })
+            }),
+        as_path(true).prop_map(path::Data::As4Path),
+        any::<(u32, Ipv4Addr)>()
+            .prop_map(|(asn, ip)| path::Data::As4Aggregator(As4Aggregator { asn, ip })),
+        vec(any::<(u32, u32, u32)>(), 0..=4).prop_map(|communities| {
+            path::Data::LargeCommunities(LargeCommunities(
+                communities
+                    .into_iter()
+                    .map(|(global, local1, local2)| LargeCommunity {
+                        global,
+                        local1,
+                        local2,
+                    })
+                    .collect(),
+            ))
+        }),
+        any::<u32>().prop_map(path::Data::OnlyToCustomer),
+        any::<u64>().prop_map(path::Data::Aigp),
+        // Unassigned attribute types
+        (select(vec![0, 19, 21, 42, 200, 255]), bytes(16))
+            .prop_map(|(type_, data)| path::Data::Unsupported(type_, data)),
+        // Long enough to need the extended length
+        bytes(600).prop_map(|data| path::Data::Unsupported(200, data)),
+    ]
+}
+
+/// A path attribute with flags that are valid for its type
+fn path_attribute() -> impl Strategy<Value = path::Value> {
+    (attribute_data(), any::<bool>(), any::<bool>()).prop_map(|(data, partial, extended)| {
+        let mut flags = match Type::from_u8(u8::from(&data)) {
+            Some(
+                Type::Origin
+                | Type::AsPath
+                | Type::NextHop
+                | Type::LocalPref
+                | Type::AtomicAggregate,
+            ) => Flags::WELL_KNOWN_COMPLETE,
+            Some(Type::MultiExitDisc | Type::MpReachNlri | Type::MpUnreachNlri | Type::Aigp) => {
+                Flags::OPTIONAL_NON_TRANSITIVE
+            }
+            // Optional transitive attributes may be partial
+            _ => Flags(Flags::OPTIONAL_TRANSITIVE.0 | if partial { 0x20 } else { 0 }),
+        };
+        let value = path::Value { flags, data };
+        if value.encoded_len() - 3 > usize::from(u8::MAX) || extended {
+            flags.0 |= 0x10;
+        }
+        path::Value { flags, ..value }
+    })
+}
+
+fn capability() -> impl Strategy<Value = capability::Value> {
+    prop_oneof![
+        afi_safi().prop_map(|(afi, safi)| {
+            capability::Value::MultiProtocol(MultiProtocol { afi, safi })
+        }),
+        Just(capability::Value::RouteRefresh),
+        vec(
+            (afi_safi(), select(vec![Afi::Ipv4, Afi::Ipv6])).prop_map(
+                |((afi, safi), next_hop_afi)| ExtendedNextHopValue {
+                    afi,
+                    safi,
+                    next_hop_afi,
+                }
+            ),
+            0..=4
+        )
+        .prop_map(|values| capability::Value::ExtendedNextHop(ExtendedNextHop(values))),
+        Just(capability::Value::ExtendedMessage),
+        (
+            any::<u8>(),
+            any::<u16>(),
+            vec(
+                (afi_safi(), any::<bool>()).prop_map(|((afi, safi), forwarding_state)| {
+                    GracefulRestartValue {
+                        afi,
+                        safi,
+                        forwarding_state,
+                    }
+                }),
+                0..=4
+            )
+        )
+            .prop_map(|(flags, restart_time, entries)| {
+                capability::Value::GracefulRestart(GracefulRestart {
+                    flags: flags & 0x0f,
+                    restart_time: restart_time & 0x0fff,
+                    entries,
+                })
+            }),
+        any::<u32>().prop_map(|asn| capability::Value::FourOctetAsNumber(asn.into())),
+        vec(
+            (
+                afi_safi(),
+                select(vec![
+                    AddPathMode::Receive,
+                    AddPathMode::Send,
+                    AddPathMode::Both
+                ])
+            )
+                .prop_map(|((afi, safi), send_receive)| AddPathTuple {
+                    afi,
+                    safi,
+                    send_receive,
+                }),
+            0..=4
+        )
+        .prop_map(|tuples| capability::Value::AddPath(AddPath(tuples))),
+        select(vec![
+            RfcRole::Provider,
+            RfcRole::RouteServer,
+            RfcRole::RouteServerClient,
+            RfcRole::Customer,
+            RfcRole::Peer,
+        ])
+        .prop_map(capability::Value::Role),
+        // Unassigned capability codes
+        (select(vec![3, 4, 70, 128, 255]), bytes(16))
+            .prop_map(|(code, data)| capability::Value::Unsupported(code, data)),
+    ]
+}
+
+fn open() -> impl Strategy<Value = Open> {
+    let param = prop_oneof![
+        1 => (select(vec![1, 3, 128]), bytes(16))
+            .prop_map(|(type_, data)| OptionalParameterValue::Unsupported(type_, data)),
+        3 => vec(capability(), 0..=3)
+            .prop_map(|caps| OptionalParameterValue::Capabilities(caps.into())),
+    ];
+    // Many parameters to sometimes need the extended format (RFC 9072)
+    let opt_params = prop_oneof![vec(param.clone(), 0..=3), vec(param, 0..=40)];
+    (any::<u16>(), any::<u16>(), any::<Ipv4Addr>(), opt_params).prop_map(
+        |(asn, hold_time, bgp_id, opt_params)| Open {
+            version: 4,
+            asn,
+            hold_time,
+            bgp_id,
+            opt_params: opt_params.into(),
+        },
+    )
+}
+
+fn update() -> impl Strategy<Value = Update> {
+    // The top-level routes are always decoded without path identifiers
+    (
+        routes(Afi::Ipv4, 8, false),
+        vec(path_attribute(), 0..=6),
+        routes(Afi::Ipv4, 8, false),
+    )
+        .prop_map(|(withdrawn_routes, path_attributes, nlri)| Update {
+            withdrawn_routes,
+            path_attributes: PathAttributes(path_attributes),
+            nlri,
+        })
+}
+
+/// Encode `value`, check `encoded_len`, and decode it back with `decode`
+fn check_round_trip<T: Component + Clone + Debug + PartialEq>(
+    value: &T,
+    decode: impl FnOnce(&mut Bytes) -> Result<T, Error>,
+) {
+    let mut buf = BytesMut::new();
+    let len = value.clone().to_bytes(&mut buf);
+    assert_eq!(len, buf.len(), "to_bytes length of {value:?}");
+    assert_eq!(value.encoded_len(), buf.len(), "encoded_len of {value:?}");
+    let mut borrowed = BytesMut::new();
+    assert_eq!(
+        value.encode_to(&mut borrowed),
+        len,
+        "encode_to of {value:?}"
+    );
+    assert_eq!(borrowed, buf, "encode_to of {value:?}");
+    let mut src = buf.freeze();
+    let decoded = decode(&mut src).unwrap_or_else(|e| panic!("decoding {value:?}: {e:?}"));
+    assert!(src.is_empty(), "trailing bytes after {value:?}");
+    assert_eq!(&decoded, value);
+}
+
+proptest! {
+    #[test]
+    fn test_round_trip_routes(
+        (afi, addpath, routes) in (select(vec![Afi::Ipv4, Afi::Ipv6]), any::<bool>())
+            .prop_flat_map(|(afi, addpath)| (Just(afi), Just(addpath), routes(afi, 16, addpath)))
+    ) {
+        check_round_trip(&routes, |src| {
+            let routes = Routes::from_bytes_addpath(src, addpath)?;
+            routes.validate(afi)?;
+            Ok(routes)
+        });
+    }
+
+    #[test]
+    fn test_round_trip_vpn_routes(
+        routes in select(vec![Afi::Ipv4, Afi::Ipv6]).prop_flat_map(vpn_routes)
+    ) {
+        check_round_trip(&routes, VpnRoutes::from_bytes);
+    }
+
+    #[test]
+    fn test_round_trip_as_path(
+        (as4, as_path) in any::<bool>().prop_flat_map(|as4| (Just(as4), as_path(as4)))
+    ) {
+        check_round_trip(&as_path, |src| AsPath::from_bytes_as4(src, as4));
+    }
+
+    #[test]
+    fn test_round_trip_mixed_as_path(as_path in mixed_as_path()) {
+        // The whole path is encoded with one ASN size
+        let as4 = as_path.is_encoded_as4();
+        let uniform = AsPath(
+            as_path
+                .iter()
+                .map(|segment| AsSegment { as4, ..segment.clone() })
+                .collect(),
+        );
+        let mut buf = BytesMut::new();
+        assert_eq!(as_path.clone().to_bytes(&mut buf), as_path.encoded_len());
+        let mut expected = BytesMut::new();
+        uniform.clone().to_bytes(&mut expected);
+        assert_eq!(buf, expected);
+        check_round_trip(&uniform, |src| AsPath::from_bytes_as4(src, as4));
+    }
+
+    #[test]
+    fn test_round_trip_path_attribute(attribute in path_attribute()) {
+        check_round_trip(&attribute, path::Value::from_bytes);
+    }
+
+    #[test]
+    fn test_round_trip_capability(capability in capability()) {
+        let capabilities: Capabilities = vec![capability].into();
+        check_round_trip(&capabilities, Capabilities::from_bytes);
+    }
+
+    #[test]
+    fn test_round_trip_open(open in open()) {
+        check_round_trip(&open, Open::from_bytes);
+    }
+
+    #[test]
+    fn test_round_trip_update(update in update()) {
+        check_round_trip(&update, Update::from_bytes);
+    }
+}
@@ -6,10 +6,16 @@
 
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use super::capability::Afi;
 use super::cidr::{Cidr, Cidr4, Cidr6};
 use super::endec::Component;
+use crate::prelude::*;
 use bytes::{Buf, BufMut, Bytes};
-use std::ops::Deref;
+use core::fmt;
+use core::ops::Deref;
+
+/// Number of prefixes shown when displaying routes
+const DISPLAY_PREFIXES: usize = 3;
 
 /// Compute the number of prefix octets from the prefix length
 fn n_prefix_octets(prefix_len: u8) -> usize {
@@ -22,40 +28,134 @@ fn n_prefix_octets(prefix_len: u8) -> usize {
     usize::from(result)
 }
 
+/// Error for a syntactically incorrect prefix (RFC 4271 Section 6.3)
+fn invalid_network_field() -> crate::Error {
+    crate::Error::UpdateMessage(
+        crate::UpdateMessageErrorSubcode::InvalidNetworkField,
+        Bytes::new(),
+    )
+}
+
 /// BGP route CIDR blocks
 ///
 /// Corresponding to a compact representation of a u8 prefix length and the
 /// minimum number of octets to represent the prefix.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Value {
+    /// Path identifier, only present if ADD-PATH is negotiated (RFC 7911 Section 3)
+    pub path_id: Option<u32>,
     pub prefix_len: u8,
+    #[cfg_attr(feature = "impl-serde", serde(with = "crate::serde_hex"))]
     pub prefix: Bytes,
 }
 
+impl Value {
+    /// Create a route from the prefix length and the minimal prefix octets
+    ///
+    /// # Errors
+    /// Returns `Error::InternalLength` if the number of octets does not match
+    /// `prefix_len`, and an Invalid Network Field `Error::UpdateMessage` if
+    /// any bit beyond `prefix_len` is set.
+    pub fn new(prefix_len: u8, prefix: Bytes) -> Result<Self, crate::Error> {
+        let expected_len = n_prefix_octets(prefix_len);
+        if prefix.len() != expected_len {
+            return Err(crate::Error::InternalLength(
+                "route prefix",
+                prefix.len().cmp(&expected_len),
+            ));
+        }
+        let route = Self {
+            path_id: None,
+            prefix_len,
+            prefix,
+        };
+        route.check_host_bits()?;
+        Ok(route)
+    }
+
+    /// Check that no bit beyond the prefix length is set (RFC 4271 Section 4.3)
+    ///
+    /// # Errors
+    /// Returns an Invalid Network Field `Error::UpdateMessage` if any host bit
+    /// is set (RFC 4271 Section 6.3).
+    pub fn check_host_bits(&self) -> Result<(), crate::Error> {
+        let trailing_bits = self.prefix_len & 0x07;
+        let dirty = match self.prefix.last() {
+            Some(last) if trailing_bits != 0 => last & (0xff >> trailing_bits) != 0,
+            _ => false,
+        };
+        if dirty {
+            return Err(invalid_network_field());
+        }
+        Ok(())
+    }
+
+    /// Find the encoded size of this route
+    const fn encoded_len(&self) -> usize {
+        let path_id_len = if self.path_id.is_some() { 4 } else { 0 };
+        path_id_len + 1 + self.prefix.len()
+    }
+
+    /// Zero-extend the prefix octets to a full address of `N` octets
+    ///
+    /// Returns `None` if the prefix length does not fit in `N` octets or
+    /// if the number of prefix octets does not match the prefix length.
+    fn zero_extended_prefix<const N: usize>(&self) -> Option<[u8; N]> {
+        if usize::from(self.prefix_len) > N * 8
+            || self.prefix.len() != n_prefix_octets(self.prefix_len)
+        {
+            return None;
+        }
+        let mut octets = [0; N];
+        octets[..self.prefix.len()].copy_from_slice(&self.prefix);
+        Some(octets)
+    }
+
+    /// Convert this route into an IPv4 CIDR block
+    ///
+    /// Returns `None` if the route is not a valid IPv4 prefix.
+    #[must_use]
+    pub fn to_cidr4(&self) -> Option<Cidr4> {
+        let octets: [u8; 4] = self.zero_extended_prefix()?;
+        Some(Cidr4::new(octets.into(), self.prefix_len))
+    }
+
+    /// Convert this route into an IPv6 CIDR block
+    ///
+    /// Returns `None` if the route is not a valid IPv6 prefix.
+    #[must_use]
+    pub fn to_cidr6(&self) -> Option<Cidr6> {
+        let octets: [u8; 16] = self.zero_extended_prefix()?;
+        Some(Cidr6::new(octets.into(), self.prefix_len))
+    }
+}
+
 /// BGP routes
 ///
 /// Corresponding to a compact list of CIDR blocks without a length field.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Routes(pub Vec<Value>);
 
 impl Component for Routes {
     fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
-        let mut routes = Vec::new();
-        while src.has_remaining() {
-            let prefix_len = src.get_u8();
-            let n_prefix_octets = n_prefix_octets(prefix_len);
-            let prefix = src.split_to(n_prefix_octets);
-            routes.push(Value { prefix_len, prefix });
-        }
-        Ok(Self(routes))
+        Self::from_bytes_addpath(src, false)
     }
 
     fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.encode_to(dst)
+    }
+
+    fn encode_to(&self, dst: &mut bytes::BytesMut) -> usize {
         let mut len = 0;
-        for route in self.0 {
+        for route in &self.0 {
+            len += route.encoded_len();
+            if let Some(path_id) = route.path_id {
+                dst.put_u32(path_id);
+            }
             dst.put_u8(route.prefix_len);
             dst.put_slice(&route.prefix);
-            len += 1 + route.prefix.len();
         }
         len
     }
@@ -73,20 +173,152 @@ impl Deref for Routes {
     }
 }
 
+/// Summarize the routes as IPv4 prefixes, like the top-level fields of an UPDATE
+impl fmt::Display for Routes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display(Afi::Ipv4).fmt(f)
+    }
+}
+
+/// Summary of routes in an address family, see [`Routes::display`]
+#[derive(Copy, Clone, Debug)]
+pub struct RoutesDisplay<'a> {
+    routes: &'a Routes,
+    afi: Afi,
+}
+
+impl fmt::Display for RoutesDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.routes.len();
+        write!(f, "{count} route{}", if count == 1 { "" } else { "s" })?;
+        if count == 0 {
+            return Ok(());
+        }
+        f.write_str(" [")?;
+        for (i, route) in self.routes.iter().take(DISPLAY_PREFIXES).enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            let cidr = match self.afi {
+                Afi::Ipv4 => route.to_cidr4().map(Cidr::V4),
+                Afi::Ipv6 => route.to_cidr6().map(Cidr::V6),
+                Afi::L2Vpn => None,
+            };
+            match cidr {
+                Some(cidr) => write!(f, "{cidr}")?,
+                // Not an IP prefix
+                None => write!(f, "?/{}", route.prefix_len)?,
+            }
+            if let Some(path_id) = route.path_id {
+                write!(f, " (path {path_id})")?;
+            }
+        }
+        if count > DISPLAY_PREFIXES {
+            f.write_str(", ...")?;
+        }
+        f.write_str("]")
+    }
+}
+
 impl Routes {
+    /// Summarize the routes as prefixes of `afi`: the count and the first few prefixes
+    #[must_use]
+    pub const fn display(&self, afi: Afi) -> RoutesDisplay<'_> {
+        RoutesDisplay { routes: self, afi }
+    }
+
+    /// Decode routes, optionally with a path identifier before each prefix.
+    ///
+    /// `addpath` should be set if the ADD-PATH capability (RFC 7911) has been
+    /// negotiated for receiving in the address family of these routes.
+    ///
+    /// # Errors
+    /// Returns an error if a route is truncated. Host bits are kept as-is;
+    /// see `Routes::from_bytes_strict` to reject them.
+    pub fn from_bytes_addpath(src: &mut bytes::Bytes, addpath: bool) -> Result<Self, crate::Error> {
+        let too_short = || crate::Error::InternalLength("NLRI", core::cmp::Ordering::Less);
+        let mut routes = Vec::new();
+        while src.has_remaining() {
+            let path_id = if addpath {
+                if src.remaining() < 4 {
+                    return Err(too_short());
+                }
+                Some(src.get_u32())
+            } else {
+                None
+            };
+            if !src.has_remaining() {
+                return Err(too_short());
+            }
+            let prefix_len = src.get_u8();
+            let n_prefix_octets = n_prefix_octets(prefix_len);
+            if src.remaining() < n_prefix_octets {
+                return Err(too_short());
+            }
+            let prefix = src.split_to(n_prefix_octets);
+            routes.push(Value {
+                path_id,
+                prefix_len,
+                prefix,
+            });
+        }
+        Ok(Self(routes))
+    }
+
+    /// Decode routes, rejecting any prefix with host bits set
+    ///
+    /// # Errors
+    /// Returns an error if a route is truncated, or an Invalid Network Field
+    /// `Error::UpdateMessage` if a prefix has bits set beyond its length.
+    pub fn from_bytes_strict(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
+        let routes = Self::from_bytes(src)?;
+        routes.check_host_bits()?;
+        Ok(routes)
+    }
+
+    /// Check that every prefix length is valid for `afi`
+    ///
+    /// Only IPv4 (at most 32 bits) and IPv6 (at most 128 bits) are checked.
+    ///
+    /// # Errors
+    /// Returns an Invalid Network Field `Error::UpdateMessage` if any prefix
+    /// length is too long (RFC 4271 Section 6.3).
+    pub fn validate(&self, afi: Afi) -> Result<(), crate::Error> {
+        let max_prefix_len = match afi {
+            Afi::Ipv4 => 32,
+            Afi::Ipv6 => 128,
+            _ => return Ok(()),
+        };
+        if self.iter().any(|route| route.prefix_len > max_prefix_len) {
+            return Err(invalid_network_field());
+        }
+        Ok(())
+    }
+
+    /// Check that no route has host bits set
+    ///
+    /// # Errors
+    /// See [`Value::check_host_bits`].
+    pub fn check_host_bits(&self) -> Result<(), crate::Error> {
+        self.0.iter().try_for_each(Value::check_host_bits)
+    }
+
     /// Find the encoded size of a slice of routes
     fn slice_encoded_len(routes: &[Value]) -> usize {
-        routes.iter().map(|r| 1 + r.prefix.len()).sum()
+        routes.iter().map(Value::encoded_len).sum()
     }
 
     /// Find a set of split points for the given routes, such that each
     /// set of routes encodes to a size less than or equal to `allowed_size`.
     ///
     /// If no split is required, the result will be `vec![len]`.
-    /// However, if the routes are too large to encode in the allowed size,
-    /// the result will be an empty vector.
-    #[must_use]
-    pub fn split_routes_to_allowed_size_each(&self, allowed_size: usize) -> Vec<usize> {
+    ///
+    /// # Errors
+    /// Returns `RouteTooLarge` if a single route does not fit in `allowed_size`.
+    pub fn split_routes_to_allowed_size_each(
+        &self,
+        allowed_size: usize,
+    ) -> Result<Vec<usize>, RouteTooLarge> {
         // The algorithm is to exponentially decrease the number of routes
         // to keep in each iteration to fit one set. For the next set, the
         // number of routes to keep is inherited from the previous set.
@@ -102,7 +334,10 @@ impl Routes {
             while encoded_len > allowed_size {
                 to_keep_each /= 2;
                 if to_keep_each == 0 {
-                    return Vec::new();
+                    return Err(RouteTooLarge {
+                        encoded_len: self[start].encoded_len(),
+                        allowed_size,
+                    });
                 }
                 end = len.min(start + to_keep_each);
                 encoded_len = Self::slice_encoded_len(&self[start..end]);
@@ -110,40 +345,118 @@ impl Routes {
             split_points.push(end);
             start += to_keep_each;
         }
-        split_points
+        Ok(split_points)
+    }
+
+    /// Similar to `split_routes_to_allowed_size_each`, but if
+    /// `reset_on_new_segment` is set, each set is filled from scratch with as
+    /// many routes as fit, which never needs more sets.
+    ///
+    /// Without `reset_on_new_segment`, this is the same as
+    /// `split_routes_to_allowed_size_each`.
+    ///
+    /// # Errors
+    /// Returns `RouteTooLarge` if a single route does not fit in `allowed_size`.
+    pub fn split_routes_with(
+        &self,
+        allowed_size: usize,
+        reset_on_new_segment: bool,
+    ) -> Result<Vec<usize>, RouteTooLarge> {
+        if !reset_on_new_segment {
+            return self.split_routes_to_allowed_size_each(allowed_size);
+        }
+        let mut split_points = Vec::new();
+        let mut encoded_len = 0;
+        for (i, route) in self.iter().enumerate() {
+            let route_len = route.encoded_len();
+            if route_len > allowed_size {
+                return Err(RouteTooLarge {
+                    encoded_len: route_len,
+                    allowed_size,
+                });
+            }
+            if encoded_len + route_len > allowed_size {
+                split_points.push(i);
+                encoded_len = 0;
+            }
+            encoded_len += route_len;
+        }
+        if !self.is_empty() {
+            split_points.push(self.len());
+        }
+        Ok(split_points)
     }
 
     /// Similar to `split_routes_to_allowed_size_each`, but returns the
     /// left boundary of each split instead of the right boundary and
     /// reverses the order of the split points. This is useful for
     /// calling `Vec::split_off` without having to offset the split points.
-    #[must_use]
-    pub fn split_routes_to_allowed_size_rev(&self, allowed_size: usize) -> Vec<usize> {
-        let mut split_points = self.split_routes_to_allowed_size_each(allowed_size);
+    ///
+    /// # Errors
+    /// Returns `RouteTooLarge` if a single route does not fit in `allowed_size`.
+    pub fn split_routes_to_allowed_size_rev(
+        &self,
+        allowed_size: usize,
+    ) -> Result<Vec<usize>, RouteTooLarge> {
+        let mut split_points = self.split_routes_to_allowed_size_each(allowed_size)?;
         // Remove tail and add 0 and reverse in place
         split_points.pop();
         split_points.reverse();
         split_points.push(0);
-        split_points
+        Ok(split_points)
     }
 }
 
-impl<I, T> From<I> for Routes
-where
-    I: IntoIterator<Item = T>,
-    T: Into<Value>,
-{
-    fn from(iter: I) -> Self {
+/// A route that does not fit in the size allowed when splitting routes
+#[derive(Copy, Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("route of {encoded_len} bytes does not fit in {allowed_size} bytes")]
+pub struct RouteTooLarge {
+    /// Encoded length of the offending route
+    pub encoded_len: usize,
+    /// Size that was allowed for each set of routes
+    pub allowed_size: usize,
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Routes {
+    fn from(routes: Vec<T>) -> Self {
+        routes.into_iter().collect()
+    }
+}
+
+impl<T: Into<Value>> FromIterator<T> for Routes {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
         Self(iter.into_iter().map(Into::into).collect())
     }
 }
 
+impl IntoIterator for Routes {
+    type Item = Value;
+    type IntoIter = alloc::vec::IntoIter<Value>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Routes {
+    type Item = &'a Value;
+    type IntoIter = core::slice::Iter<'a, Value>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
 impl From<Cidr4> for Value {
     fn from(cidr: Cidr4) -> Self {
         let prefix_len = cidr.prefix_len;
         let n_prefix_octets = n_prefix_octets(prefix_len);
         let prefix = Bytes::copy_from_slice(&cidr.addr.octets()[..n_prefix_octets]);
-        Self { prefix_len, prefix }
+        Self {
+            path_id: None,
+            prefix_len,
+            prefix,
+        }
     }
 }
 
@@ -152,7 +465,11 @@ impl From<Cidr6> for Value {
         let prefix_len = cidr.prefix_len;
         let n_prefix_octets = n_prefix_octets(prefix_len);
         let prefix = Bytes::copy_from_slice(&cidr.addr.octets()[..n_prefix_octets]);
-        Self { prefix_len, prefix }
+        Self {
+            path_id: None,
+            prefix_len,
+            prefix,
+        }
     }
 }
 
@@ -179,10 +496,170 @@ impl From<Cidr> for Value {
     }
 }
 
+/// Route distinguisher of a VPN route (RFC 4364 Section 4.2)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RouteDistinguisher(pub u64);
+
+/// BGP labeled VPN route (RFC 4364 Section 4.3.4, RFC 8277 Section 2)
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VpnValue {
+    /// Path identifier, only present if ADD-PATH is negotiated (RFC 7911 Section 3)
+    pub path_id: Option<u32>,
+    /// Label stack entries, each with the label in the high 20 bits and the
+    /// bottom-of-stack bit in the lowest bit
+    pub labels: Vec<u32>,
+    pub rd: RouteDistinguisher,
+    /// Length of the address prefix, excluding the labels and route distinguisher
+    pub prefix_len: u8,
+    #[cfg_attr(feature = "impl-serde", serde(with = "crate::serde_hex"))]
+    pub prefix: Bytes,
+}
+
+impl VpnValue {
+    /// Bottom-of-stack bit of a label stack entry (RFC 3032 Section 2.1)
+    pub const BOTTOM_OF_STACK: u32 = 0x01;
+    /// Label stack entry used when withdrawing a route (RFC 8277 Section 2.4)
+    pub const WITHDRAW_LABEL: u32 = 0x80_0000;
+
+    /// Find the encoded size of this route
+    fn encoded_len(&self) -> usize {
+        let path_id_len = if self.path_id.is_some() { 4 } else { 0 };
+        path_id_len + 1 + 3 * self.labels.len() + 8 + self.prefix.len()
+    }
+
+    /// Total length in bits of the labels, route distinguisher, and prefix
+    fn total_len_bits(&self) -> usize {
+        24 * self.labels.len() + 64 + usize::from(self.prefix_len)
+    }
+}
+
+/// BGP labeled VPN routes
+///
+/// Corresponding to the NLRI of `MP_REACH_NLRI` and `MP_UNREACH_NLRI` with
+/// SAFI 128 (RFC 4364 Section 4.3.4).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "impl-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VpnRoutes(pub Vec<VpnValue>);
+
+impl Component for VpnRoutes {
+    fn from_bytes(src: &mut bytes::Bytes) -> Result<Self, crate::Error> {
+        Self::from_bytes_addpath(src, false)
+    }
+
+    fn to_bytes(self, dst: &mut bytes::BytesMut) -> usize {
+        self.encode_to(dst)
+    }
+
+    fn try_to_bytes(self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        self.try_encode_to(dst)
+    }
+
+    fn encode_to(&self, dst: &mut bytes::BytesMut) -> usize {
+        self.try_encode_to(dst).expect("VPN route length overflow")
+    }
+
+    fn try_encode_to(&self, dst: &mut bytes::BytesMut) -> Result<usize, crate::Error> {
+        let mut len = 0;
+        for route in &self.0 {
+            let total_len_bits = u8::try_from(route.total_len_bits()).map_err(|_| {
+                crate::Error::InternalLength("VPN route", core::cmp::Ordering::Greater)
+            })?;
+            len += route.encoded_len();
+            if let Some(path_id) = route.path_id {
+                dst.put_u32(path_id);
+            }
+            dst.put_u8(total_len_bits);
+            for label in &route.labels {
+                dst.put_uint(u64::from(*label), 3);
+            }
+            dst.put_u64(route.rd.0);
+            dst.put_slice(&route.prefix);
+        }
+        Ok(len)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.iter().map(VpnValue::encoded_len).sum()
+    }
+}
+
+impl VpnRoutes {
+    /// Decode VPN routes, optionally with a path identifier before each route.
+    ///
+    /// `addpath` has the same meaning as in `Routes::from_bytes_addpath`.
+    ///
+    /// # Errors
+    /// Returns an error if a route is truncated.
+    pub fn from_bytes_addpath(src: &mut bytes::Bytes, addpath: bool) -> Result<Self, crate::Error> {
+        let too_short = || crate::Error::InternalLength("VPN NLRI", core::cmp::Ordering::Less);
+        let mut routes = Vec::new();
+        while src.has_remaining() {
+            let path_id = if addpath {
+                if src.remaining() < 4 {
+                    return Err(too_short());
+                }
+                Some(src.get_u32())
+            } else {
+                None
+            };
+            if !src.has_remaining() {
+                return Err(too_short());
+            }
+            let mut len_bits = usize::from(src.get_u8());
+            let mut labels = Vec::new();
+            loop {
+                if len_bits < 24 || src.remaining() < 3 {
+                    return Err(too_short());
+                }
+                let label = src.get_uint(3) as u32;
+                labels.push(label);
+                len_bits -= 24;
+                if label & VpnValue::BOTTOM_OF_STACK != 0 || label == VpnValue::WITHDRAW_LABEL {
+                    break;
+                }
+            }
+            if len_bits < 64 || src.remaining() < 8 {
+                return Err(too_short());
+            }
+            let rd = RouteDistinguisher(src.get_u64());
+            let prefix_len = u8::try_from(len_bits - 64).expect("at most 255 bits in total");
+            let n_prefix_octets = n_prefix_octets(prefix_len);
+            if src.remaining() < n_prefix_octets {
+                return Err(too_short());
+            }
+            let prefix = src.split_to(n_prefix_octets);
+            routes.push(VpnValue {
+                path_id,
+                labels,
+                rd,
+                prefix_len,
+                prefix,
+            });
+        }
+        Ok(Self(routes))
+    }
+}
+
+impl Deref for VpnRoutes {
+    type Target = Vec<VpnValue>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<Vec<VpnValue>> for VpnRoutes {
+    fn from(routes: Vec<VpnValue>) -> Self {
+        Self(routes)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::hex_to_bytes;
+    use crate::{hex_to_bytes, UpdateMessageErrorSubcode};
     use bytes::BytesMut;
 
     #[test]
@@ -226,6 +703,42 @@ mod tests {
         assert_eq!(route6.prefix_len, 110);
     }
 
+    #[test]
+    fn test_convert_route_to_cidr() {
+        let cidr4 = Cidr4::new("172.24.0.0".parse().unwrap(), 12);
+        let route4 = Value::from(cidr4);
+        assert_eq!(route4.to_cidr4(), Some(cidr4));
+        let cidr6 = Cidr6::new("fdc0::f000:0".parse().unwrap(), 110);
+        let route6 = Value::from(cidr6);
+        assert_eq!(route6.to_cidr6(), Some(cidr6));
+        // IPv6 prefixes are too long for IPv4
+        assert_eq!(route6.to_cidr4(), None);
+        // Short IPv6 prefixes are indistinguishable from IPv4 ones
+        let cidr6 = Cidr6::new("fdc7:3c9d::".parse().unwrap(), 32);
+        assert_eq!(
+            Value::from(cidr6).to_cidr4(),
+            Some(Cidr4::new("253.199.60.157".parse().unwrap(), 32))
+        );
+    }
+
+    #[test]
+    fn test_convert_route_to_cidr_invalid() {
+        let route = Value {
+            path_id: None,
+            prefix_len: 12,
+            prefix: hex_to_bytes("ac"),
+        };
+        assert_eq!(route.to_cidr4(), None);
+        assert_eq!(route.to_cidr6(), None);
+        let route = Value {
+            path_id: None,
+            prefix_len: 33,
+            prefix: hex_to_bytes("ac180000 00"),
+        };
+        assert_eq!(route.to_cidr4(), None);
+        assert!(route.to_cidr6().is_some());
+    }
+
     #[test]
     fn test_mpbgp_routes() {
         let routes_bytes = hex_to_bytes(
@@ -347,111 +860,124 @@ mod tests {
         assert_eq!(bytes.freeze(), routes_bytes);
     }
 
+    /// The 44net IPIP mesh table at one point in time
+    const MESH_ROUTES: &str = "1f 2c3f0102
+        18 2c1407
+        1d 2c3f07a0
+        1b 2c3f1fe0
+        1c 2c221100
+        19 2c3f7f80
+        1d 2c4c0018
+        1d 2c384010
+        20 2c3f002d
+        1d 2c3c2900
+        1d 2c3e0970
+        1d 2c3f0878
+        20 2c04261b
+        1c 2c18ab70
+        1d 2c3f08a0
+        18 2c2e20
+        1d 2c442a00
+        20 2c3f000b
+        1d 2c3f07c8
+        1d 2c381a00
+        1d 2c581040
+        1d 2c4c0118
+        18 2c142a
+        1c 2c3f1310
+        1b 2c448c00
+        1d 2c387e00
+        1c 2c210600
+        18 2c7f08
+        1d 2c3f0978
+        1d 2c040a28
+        1c 2c3f1160
+        1c 2c080110
+        20 2c2e000c
+        1c 2c3f11c0
+        18 2c4018
+        19 2c3f8180
+        1c 2c4c0b10
+        18 2c18c2
+        18 2c4818
+        1c 2c3f1010
+        1d 2c383e10
+        1d 2c023200
+        1a 2c3f3f80
+        18 2c6684
+        18 2c0e02
+        1b 2c442900
+        1b 2c3f20e0
+        1c 2c3f12c0
+        20 2c100901
+        1d 2c3f0928
+        18 2c4819
+        1c 2c3f1110
+        1d 2c383900
+        1d 2c622108
+        20 2c668381
+        20 2c3f0039
+        1d 2c3f08d8
+        20 2c1200ba
+        1d 2c5c0050
+        18 2c2e80
+        1b 2c3f1f40
+        1c 2c3e09d0
+        18 2c1425
+        1c 2c3f1270
+        1c 2c6462a0
+        1b 2c401200
+        1d 2c30be00
+        1d 2c668710
+        17 2c28a0
+        1d 2c3f0900
+        1d 2c381600
+        1d 2c400c40
+        1b 2c041020
+        1d 2c3f0728
+        1c 2c2401a0
+        1d 2c0402a0
+        1b 2c3f22a0
+        1c 2c3f0f70
+        1c 2c763900
+        1d 2c5c0000
+        1d 2c581100
+        18 2c1405
+        1c 2c048a00
+        18 2c5a2c
+        1c 2c3f1220
+        1d 2c384020
+        1b 2c100220
+        1c 2c446600
+        20 2c3f0042
+        1a 2c3c4800";
+
     #[test]
     fn test_split_routes_to_allowed_size_each_1() {
-        // Yes. This is the 44net IPIP mesh table at one point in time.
-        let mut routesraw = hex_to_bytes(
-            "1f 2c3f0102
-            18 2c1407
-            1d 2c3f07a0
-            1b 2c3f1fe0
-            1c 2c221100
-            19 2c3f7f80
-            1d 2c4c0018
-            1d 2c384010
-            20 2c3f002d
-            1d 2c3c2900
-            1d 2c3e0970
-            1d 2c3f0878
-            20 2c04261b
-            1c 2c18ab70
-            1d 2c3f08a0
-            18 2c2e20
-            1d 2c442a00
-            20 2c3f000b
-            1d 2c3f07c8
-            1d 2c381a00
-            1d 2c581040
-            1d 2c4c0118
-            18 2c142a
-            1c 2c3f1310
-            1b 2c448c00
-            1d 2c387e00
-            1c 2c210600
-            18 2c7f08
-            1d 2c3f0978
-            1d 2c040a28
-            1c 2c3f1160
-            1c 2c080110
-            20 2c2e000c
-            1c 2c3f11c0
-            18 2c4018
-            19 2c3f8180
-            1c 2c4c0b10
-            18 2c18c2
-            18 2c4818
-            1c 2c3f1010
-            1d 2c383e10
-            1d 2c023200
-            1a 2c3f3f80
-            18 2c6684
-            18 2c0e02
-            1b 2c442900
-            1b 2c3f20e0
-            1c 2c3f12c0
-            20 2c100901
-            1d 2c3f0928
-            18 2c4819
-            1c 2c3f1110
-            1d 2c383900
-            1d 2c622108
-            20 2c668381
-            20 2c3f0039
-            1d 2c3f08d8
-            20 2c1200ba
-            1d 2c5c0050
-            18 2c2e80
-            1b 2c3f1f40
-            1c 2c3e09d0
-            18 2c1425
-            1c 2c3f1270
-            1c 2c6462a0
-            1b 2c401200
-            1d 2c30be00
-            1d 2c668710
-            17 2c28a0
-            1d 2c3f0900
-            1d 2c381600
-            1d 2c400c40
-            1b 2c041020
-            1d 2c3f0728
-            1c 2c2401a0
-            1d 2c0402a0
-            1b 2c3f22a0
-            1c 2c3f0f70
-            1c 2c763900
-            1d 2c5c0000
-            1d 2c581100
-            18 2c1405
-            1c 2c048a00
-            18 2c5a2c
-            1c 2c3f1220
-            1d 2c384020
-            1b 2c100220
-            1c 2c446600
-            20 2c3f0042
-            1a 2c3c4800",
-        );
+        let mut routesraw = hex_to_bytes(MESH_ROUTES);
         let raw_len = routesraw.len();
         let routes = Routes::from_bytes(&mut routesraw).unwrap();
         for allowed_size in 1..=raw_len {
             let split_points = routes.split_routes_to_allowed_size_each(allowed_size);
             log::debug!("Allowed size: {allowed_size}, split points: {split_points:?}");
-            // The algorithm is allowed to change, so we only check if the result is correct
             if allowed_size < 5 {
                 // Won't fit even one route
-                assert_eq!(split_points, Vec::new());
-            } else if allowed_size == raw_len {
+                assert_eq!(
+                    split_points,
+                    Err(RouteTooLarge {
+                        encoded_len: 5,
+                        allowed_size
+                    })
+                );
+                assert_eq!(
+                    routes.split_routes_to_allowed_size_rev(allowed_size),
+                    split_points
+                );
+                continue;
+            }
+            let split_points = split_points.unwrap();
+            // The algorithm is allowed to change, so we only check if the result is correct
+            if allowed_size == raw_len {
                 assert_eq!(split_points, vec![routes.len()]);
             } else {
                 let mut new_routes = Vec::new();
@@ -465,16 +991,247 @@ mod tests {
                 }
                 assert_eq!(new_routes, routes.0);
             }
-            let split_points_rev = routes.split_routes_to_allowed_size_rev(allowed_size);
+            let split_points_rev = routes
+                .split_routes_to_allowed_size_rev(allowed_size)
+                .unwrap();
             // Compare to the reverse of the forward split points after removing [0] and prepending 0
             let should_be: Vec<usize> = split_points
                 .iter()
                 .rev()
                 .skip(1)
                 .copied()
-                .chain(std::iter::once(0))
+                .chain(core::iter::once(0))
                 .collect();
             assert_eq!(split_points_rev, should_be);
         }
     }
+
+    #[test]
+    fn test_split_routes_with_reset() {
+        let mut routesraw = hex_to_bytes(MESH_ROUTES);
+        let raw_len = routesraw.len();
+        let routes = Routes::from_bytes(&mut routesraw).unwrap();
+        for allowed_size in 1..=raw_len {
+            let old_split_points = routes.split_routes_to_allowed_size_each(allowed_size);
+            assert_eq!(
+                routes.split_routes_with(allowed_size, false),
+                old_split_points
+            );
+            let split_points = routes.split_routes_with(allowed_size, true);
+            if allowed_size < 5 {
+                assert_eq!(split_points, old_split_points);
+                continue;
+            }
+            let split_points = split_points.unwrap();
+            assert!(split_points.len() <= old_split_points.unwrap().len());
+            let mut start = 0;
+            for &end in &split_points {
+                assert!(start < end);
+                assert!(Routes::slice_encoded_len(&routes.0[start..end]) <= allowed_size);
+                start = end;
+            }
+            assert_eq!(start, routes.len());
+        }
+    }
+
+    #[test]
+    fn test_addpath_routes() {
+        let routes_bytes = hex_to_bytes(
+            "
+        00000001 18 ac17e3
+        00000002 10 0a00",
+        );
+        // Without ADD-PATH, the path identifiers would be misparsed as prefixes
+        let routes = Routes::from_bytes_addpath(&mut routes_bytes.clone(), true).unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(
+            routes.0[0],
+            Value {
+                path_id: Some(1),
+                ..Value::from(Cidr4::new("172.23.227.0".parse().unwrap(), 24))
+            }
+        );
+        assert_eq!(
+            routes.0[1],
+            Value {
+                path_id: Some(2),
+                ..Value::from(Cidr4::new("10.0.0.0".parse().unwrap(), 16))
+            }
+        );
+        assert_eq!(routes.encoded_len(), routes_bytes.len());
+        let mut bytes = BytesMut::new();
+        routes.to_bytes(&mut bytes);
+        assert_eq!(bytes.freeze(), routes_bytes);
+    }
+
+    #[test]
+    fn test_addpath_vpn_routes() {
+        let routes_bytes = hex_to_bytes("00000003 70 000641 0000fde800000064 c63364");
+        let routes = VpnRoutes::from_bytes_addpath(&mut routes_bytes.clone(), true).unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].path_id, Some(3));
+        assert_eq!(routes[0].prefix_len, 24);
+        assert_eq!(routes[0].prefix, hex_to_bytes("c63364"));
+        assert_eq!(routes.encoded_len(), routes_bytes.len());
+        let mut bytes = BytesMut::new();
+        routes.to_bytes(&mut bytes);
+        assert_eq!(bytes.freeze(), routes_bytes);
+    }
+
+    #[test]
+    fn test_vpn_routes() {
+        let routes_bytes = hex_to_bytes(
+            "
+        70 000641 0000fde800000064 c63364
+        78 000640 000651 0001c000020100c8 0a
+        70 800000 0000fde800000064 c63364",
+        );
+        let routes = VpnRoutes::from_bytes(&mut routes_bytes.clone()).unwrap();
+        assert_eq!(routes.len(), 3);
+        assert_eq!(
+            routes.0[0],
+            VpnValue {
+                path_id: None,
+                labels: vec![0x641],
+                rd: RouteDistinguisher(0x0000_fde8_0000_0064),
+                prefix_len: 24,
+                prefix: hex_to_bytes("c63364"),
+            }
+        );
+        // Label stack of two entries
+        assert_eq!(routes.0[1].labels, vec![0x640, 0x651]);
+        assert_eq!(routes.0[1].prefix_len, 8);
+        // Withdrawal without a bottom-of-stack bit
+        assert_eq!(routes.0[2].labels, vec![VpnValue::WITHDRAW_LABEL]);
+        assert_eq!(routes.encoded_len(), routes_bytes.len());
+        let mut bytes = BytesMut::new();
+        routes.to_bytes(&mut bytes);
+        assert_eq!(bytes.freeze(), routes_bytes);
+    }
+
+    #[test]
+    fn test_vpn_routes_too_long() {
+        // 10 labels, a route distinguisher, and a /24 need more than 255 bits
+        let routes = VpnRoutes(vec![VpnValue {
+            path_id: None,
+            labels: vec![0x640; 10],
+            rd: RouteDistinguisher(0),
+            prefix_len: 24,
+            prefix: hex_to_bytes("c63364"),
+        }]);
+        let mut dst = BytesMut::new();
+        assert!(matches!(
+            routes.try_encode_to(&mut dst),
+            Err(crate::Error::InternalLength(
+                "VPN route",
+                core::cmp::Ordering::Greater
+            ))
+        ));
+        assert!(dst.is_empty());
+    }
+
+    #[test]
+    fn test_vpn_routes_truncated() {
+        // Length covers a route distinguisher that is not there
+        let mut routes_bytes = hex_to_bytes("70 000641 0000fde8");
+        assert!(matches!(
+            VpnRoutes::from_bytes(&mut routes_bytes),
+            Err(crate::Error::InternalLength(
+                "VPN NLRI",
+                core::cmp::Ordering::Less
+            ))
+        ));
+        // Length too short for the label
+        let mut routes_bytes = hex_to_bytes("10 000641");
+        assert!(VpnRoutes::from_bytes(&mut routes_bytes).is_err());
+    }
+
+    #[test]
+    fn test_value_new() {
+        let route = Value::new(22, hex_to_bytes("ac17e0")).unwrap();
+        assert_eq!(
+            route,
+            Value::from(Cidr4::new("172.23.224.0".parse().unwrap(), 22))
+        );
+        assert!(Value::new(0, Bytes::new()).is_ok());
+        assert!(matches!(
+            Value::new(22, hex_to_bytes("ac17")),
+            Err(crate::Error::InternalLength(_, core::cmp::Ordering::Less))
+        ));
+        assert!(matches!(
+            Value::new(16, hex_to_bytes("ac1700")),
+            Err(crate::Error::InternalLength(
+                _,
+                core::cmp::Ordering::Greater
+            ))
+        ));
+        // 172.23.227.0/22 has host bits set
+        assert!(matches!(
+            Value::new(22, hex_to_bytes("ac17e3")),
+            Err(crate::Error::UpdateMessage(
+                UpdateMessageErrorSubcode::InvalidNetworkField,
+                _
+            ))
+        ));
+        let mut src = hex_to_bytes("18 ac17");
+        assert!(matches!(
+            Routes::from_bytes(&mut src),
+            Err(crate::Error::InternalLength(
+                "NLRI",
+                core::cmp::Ordering::Less
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_routes_host_bits() {
+        // 203.20.67.0/24 is clean, 203.20.64.0/18 has bits set in the last octet
+        let routes_bytes = hex_to_bytes("18 cb1443 12 cb1443");
+        let routes = Routes::from_bytes(&mut routes_bytes.clone()).unwrap();
+        assert_eq!(routes.len(), 2);
+        let mut bytes = BytesMut::new();
+        routes.clone().to_bytes(&mut bytes);
+        assert_eq!(bytes.freeze(), routes_bytes);
+        assert!(routes[0].check_host_bits().is_ok());
+        assert!(matches!(
+            routes.check_host_bits(),
+            Err(crate::Error::UpdateMessage(
+                UpdateMessageErrorSubcode::InvalidNetworkField,
+                _
+            ))
+        ));
+        assert!(matches!(
+            Routes::from_bytes_strict(&mut routes_bytes.clone()),
+            Err(crate::Error::UpdateMessage(
+                UpdateMessageErrorSubcode::InvalidNetworkField,
+                _
+            ))
+        ));
+        let mut src = hex_to_bytes("18 cb1443 12 cb1440");
+        assert_eq!(Routes::from_bytes_strict(&mut src).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_routes_validate() {
+        // A /40 IPv4 prefix with five octets
+        let mut src = hex_to_bytes("18 cb1443 28 0a00000000");
+        let routes = Routes::from_bytes(&mut src).unwrap();
+        assert!(matches!(
+            routes.validate(Afi::Ipv4),
+            Err(crate::Error::UpdateMessage(
+                UpdateMessageErrorSubcode::InvalidNetworkField,
+                _
+            ))
+        ));
+        assert!(routes.validate(Afi::Ipv6).is_ok());
+        let mut src = hex_to_bytes("81 00000000000000000000000000000000 00");
+        let routes = Routes::from_bytes(&mut src).unwrap();
+        assert!(matches!(
+            routes.validate(Afi::Ipv6),
+            Err(crate::Error::UpdateMessage(
+                UpdateMessageErrorSubcode::InvalidNetworkField,
+                _
+            ))
+        ));
+    }
 }
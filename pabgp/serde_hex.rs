@@ -0,0 +1,54 @@
+//! Serialize `bytes::Bytes` as hex strings
+
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::prelude::*;
+use bytes::{BufMut, Bytes, BytesMut};
+use core::fmt::Write;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub fn serialize<S: Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{byte:02x}").expect("writing to a String cannot fail");
+    }
+    serializer.serialize_str(&hex)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    if hex.len() % 2 != 0 {
+        return Err(D::Error::custom("odd number of hex digits"));
+    }
+    let mut bytes = BytesMut::with_capacity(hex.len() / 2);
+    for i in (0..hex.len()).step_by(2) {
+        let octet = hex
+            .get(i..i + 2)
+            .and_then(|octet| u8::from_str_radix(octet, 16).ok())
+            .ok_or_else(|| D::Error::custom("invalid hex digit"))?;
+        bytes.put_u8(octet);
+    }
+    Ok(bytes.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::value::{BorrowedStrDeserializer, Error};
+
+    #[test]
+    fn test_deserialize() {
+        let de = BorrowedStrDeserializer::<Error>::new("00ff10Ab");
+        assert_eq!(
+            deserialize(de).unwrap(),
+            Bytes::from_static(&[0x00, 0xff, 0x10, 0xab])
+        );
+        let de = BorrowedStrDeserializer::<Error>::new("0");
+        assert!(deserialize(de).is_err());
+        let de = BorrowedStrDeserializer::<Error>::new("zz");
+        assert!(deserialize(de).is_err());
+        let de = BorrowedStrDeserializer::<Error>::new("é0");
+        assert!(deserialize(de).is_err());
+    }
+}
@@ -3,11 +3,29 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use super::capability::{Afi, Safi};
-use super::cidr::Cidr;
+use super::cidr::{self, Cidr, Cidr4, Cidr6};
 use super::endec::Component;
-use super::path::{self, AsPath, AsSegment, AsSegmentType, MpNextHop, Origin, PathAttributes};
-use super::route::Routes;
-use std::net::IpAddr;
+use super::path::{
+    self, Aggregator, As4Aggregator, AsPath, AsSegment, AsSegmentType, Communities, MpNextHop,
+    MpNlri, Origin, PathAttributes,
+};
+use super::route::{Routes, Value};
+use super::MAX_MESSAGE_LEN;
+use crate::prelude::*;
+use core::net::{IpAddr, Ipv4Addr};
+
+/// Length of the BGP message header
+const BGP_HEADER_LEN: usize = 19;
+/// Length of the withdrawn routes length and total path attribute length fields
+const UPDATE_HEADER_LEN: usize = 2 + 2;
+/// Length of an `MP_UNREACH_NLRI` attribute without routes:
+/// extended attribute header, AFI, and SAFI
+const MP_UNREACH_NLRI_HEADER_LEN: usize = 4 + 2 + 1;
+/// Length of an `MP_REACH_NLRI` attribute without next hop and routes:
+/// extended attribute header, AFI, SAFI, next hop length, and reserved octet
+const MP_REACH_NLRI_HEADER_LEN: usize = 4 + 2 + 1 + 1 + 1;
+/// Length of a `NEXT_HOP` attribute
+const NEXT_HOP_LEN: usize = 3 + 4;
 
 #[derive(Clone, Debug, Default, PartialEq)]
 /// Builder for UPDATE messages.
@@ -18,32 +36,58 @@ pub struct UpdateBuilder {
     pub nlri_ipv6_routes: Routes,
     pub origin: Option<Origin>,
     pub as_path: AsPath,
+    pub med: Option<u32>,
+    pub local_pref: Option<u32>,
     pub next_hop: Option<MpNextHop>,
     pub other_path_attrs: PathAttributes,
     pub enable_mp_bgp: bool,
+    /// Allow a next hop of the other address family, as negotiated with the
+    /// Extended Next Hop Encoding capability (RFC 8950)
+    pub extended_next_hop: bool,
+    /// Encode the AS path with four-octet ASNs, as negotiated with the
+    /// four-octet AS number capability (RFC 6793)
+    pub four_octet_asn: bool,
+    /// Maximum length of each UPDATE message, `MAX_MESSAGE_LEN` if `None`
+    pub max_message_len: Option<usize>,
+    /// Drop all NLRI if this ASN is in the AS path
+    pub drop_if_as_in_path: Option<u32>,
+    /// Aggregator, encoded in `build` depending on `four_octet_asn`
+    pub aggregator: Option<As4Aggregator>,
 }
 
 impl UpdateBuilder {
     /// Create a new builder.
+    ///
+    /// `max_message_len` limits the length of each UPDATE message and defaults
+    /// to `MAX_MESSAGE_LEN`. It should only be raised (up to
+    /// `MAX_EXTENDED_MESSAGE_LEN`) if Extended Messages (RFC 8654) have been
+    /// negotiated with the peer.
     #[must_use]
-    pub fn new(enable_mp_bgp: bool) -> Self {
+    pub fn new(enable_mp_bgp: bool, max_message_len: Option<usize>) -> Self {
         Self {
             enable_mp_bgp,
+            max_message_len,
             ..Default::default()
         }
     }
 
     /// Withdraw some IPv4 routes.
     #[must_use]
-    pub fn withdraw_ipv4_routes(mut self, routes: Routes) -> Self {
-        self.withdrawn_ipv4_routes = routes;
+    pub fn withdraw_ipv4_routes(
+        mut self,
+        routes: impl IntoIterator<Item = impl Into<Value>>,
+    ) -> Self {
+        self.withdrawn_ipv4_routes = routes.into_iter().collect();
         self
     }
 
     /// Withdraw some IPv6 routes.
     #[must_use]
-    pub fn withdraw_ipv6_routes(mut self, routes: Routes) -> Self {
-        self.withdrawn_ipv6_routes = routes;
+    pub fn withdraw_ipv6_routes(
+        mut self,
+        routes: impl IntoIterator<Item = impl Into<Value>>,
+    ) -> Self {
+        self.withdrawn_ipv6_routes = routes.into_iter().collect();
         self
     }
 
@@ -58,19 +102,50 @@ impl UpdateBuilder {
     }
 
     /// Add some IPv4 routes.
+    ///
+    /// `routes` can be a [`Routes`] or any iterator of CIDR blocks, which are
+    /// converted as they are consumed.
     #[must_use]
-    pub fn add_ipv4_routes(mut self, routes: Routes) -> Self {
-        self.nlri_ipv4_routes = routes;
+    pub fn add_ipv4_routes(mut self, routes: impl IntoIterator<Item = impl Into<Value>>) -> Self {
+        self.nlri_ipv4_routes = routes.into_iter().collect();
         self
     }
 
     /// Add some IPv6 routes.
+    ///
+    /// `routes` can be a [`Routes`] or any iterator of CIDR blocks, which are
+    /// converted as they are consumed.
     #[must_use]
-    pub fn add_ipv6_routes(mut self, routes: Routes) -> Self {
-        self.nlri_ipv6_routes = routes;
+    pub fn add_ipv6_routes(mut self, routes: impl IntoIterator<Item = impl Into<Value>>) -> Self {
+        self.nlri_ipv6_routes = routes.into_iter().collect();
         self
     }
 
+    /// Add some IPv4 routes tagged with `communities` (RFC 1997).
+    ///
+    /// The communities apply to all routes of this builder, so routes with
+    /// different tags need separate builders.
+    #[must_use]
+    pub fn add_ipv4_routes_tagged(
+        self,
+        routes: impl IntoIterator<Item = impl Into<Value>>,
+        communities: Communities,
+    ) -> Self {
+        self.add_ipv4_routes(routes).add_communities(communities)
+    }
+
+    /// Add some IPv6 routes tagged with `communities` (RFC 1997).
+    ///
+    /// See [`UpdateBuilder::add_ipv4_routes_tagged`].
+    #[must_use]
+    pub fn add_ipv6_routes_tagged(
+        self,
+        routes: impl IntoIterator<Item = impl Into<Value>>,
+        communities: Communities,
+    ) -> Self {
+        self.add_ipv6_routes(routes).add_communities(communities)
+    }
+
     /// Add a single route.
     #[must_use]
     pub fn add_route(mut self, route: Cidr) -> Self {
@@ -81,6 +156,54 @@ impl UpdateBuilder {
         self
     }
 
+    /// Aggregate adjacent NLRI prefixes into a minimal covering set.
+    ///
+    /// This only affects routes added so far. Path identifiers are not preserved
+    /// and malformed routes are dropped.
+    #[must_use]
+    pub fn aggregate(mut self) -> Self {
+        let mut ipv4: Vec<Cidr4> = self
+            .nlri_ipv4_routes
+            .iter()
+            .filter_map(Value::to_cidr4)
+            .collect();
+        cidr::aggregate_v4(&mut ipv4);
+        self.nlri_ipv4_routes = ipv4.into();
+        let mut ipv6: Vec<Cidr6> = self
+            .nlri_ipv6_routes
+            .iter()
+            .filter_map(Value::to_cidr6)
+            .collect();
+        cidr::aggregate_v6(&mut ipv6);
+        self.nlri_ipv6_routes = ipv6.into();
+        self
+    }
+
+    /// Drop routes with prefix lengths outside `min_v4..=max_v4` (IPv4) or
+    /// `min_v6..=max_v6` (IPv6).
+    ///
+    /// This affects both NLRI and withdrawn routes added so far. Note that
+    /// filtering withdrawals with a different range than the one used when
+    /// the routes were advertised can leave stale routes on the peer.
+    #[must_use]
+    pub fn filter_prefix_len(mut self, min_v4: u8, max_v4: u8, min_v6: u8, max_v6: u8) -> Self {
+        let v4 = min_v4..=max_v4;
+        let v6 = min_v6..=max_v6;
+        self.nlri_ipv4_routes
+            .0
+            .retain(|route| v4.contains(&route.prefix_len));
+        self.withdrawn_ipv4_routes
+            .0
+            .retain(|route| v4.contains(&route.prefix_len));
+        self.nlri_ipv6_routes
+            .0
+            .retain(|route| v6.contains(&route.prefix_len));
+        self.withdrawn_ipv6_routes
+            .0
+            .retain(|route| v6.contains(&route.prefix_len));
+        self
+    }
+
     /// Set the origin.
     #[must_use]
     pub const fn set_origin(mut self, origin: Origin) -> Self {
@@ -88,6 +211,22 @@ impl UpdateBuilder {
         self
     }
 
+    /// Set the multi-exit discriminator.
+    #[must_use]
+    pub const fn set_med(mut self, med: u32) -> Self {
+        self.med = Some(med);
+        self
+    }
+
+    /// Set the local preference.
+    ///
+    /// This should only be sent to internal peers (RFC 4271 Section 5.1.5).
+    #[must_use]
+    pub const fn set_local_pref(mut self, local_pref: u32) -> Self {
+        self.local_pref = Some(local_pref);
+        self
+    }
+
     /// Add an AS path segment.
     #[must_use]
     pub fn set_as_path(mut self, type_: AsSegmentType, asns: Vec<u32>) -> Self {
@@ -97,6 +236,23 @@ impl UpdateBuilder {
         self
     }
 
+    /// Prepend an ASN to the AS path `count` times.
+    #[must_use]
+    pub fn prepend_as_path(mut self, asn: u32, count: usize) -> Self {
+        self.as_path.prepend(asn, count);
+        self
+    }
+
+    /// Do not advertise any route if `asn` appears in the AS path.
+    ///
+    /// The peer with this ASN would discard the routes anyway (RFC 4271
+    /// Section 9.1.2). Withdrawn routes are still sent.
+    #[must_use]
+    pub const fn drop_if_as_in_path(mut self, asn: u32) -> Self {
+        self.drop_if_as_in_path = Some(asn);
+        self
+    }
+
     /// Set the next hop.
     #[must_use]
     pub const fn set_next_hop(mut self, next_hop: MpNextHop) -> Self {
@@ -104,6 +260,28 @@ impl UpdateBuilder {
         self
     }
 
+    /// Allow the next hop to be used for routes of the other address family.
+    ///
+    /// Only enable this if Extended Next Hop Encoding (RFC 8950) has been
+    /// negotiated with the peer.
+    #[must_use]
+    pub const fn set_extended_next_hop(mut self, enable: bool) -> Self {
+        self.extended_next_hop = enable;
+        self
+    }
+
+    /// Encode all ASNs of the AS path in four octets.
+    ///
+    /// Enable this if four-octet AS numbers (RFC 6793) have been negotiated
+    /// with the peer, which then decodes every `AS_PATH` that way. Otherwise,
+    /// ASNs that do not fit in two octets are sent as `AS_TRANS` in `AS_PATH`
+    /// and in full in `AS4_PATH` (RFC 6793 Section 4.2.2).
+    #[must_use]
+    pub const fn set_four_octet_asn(mut self, enable: bool) -> Self {
+        self.four_octet_asn = enable;
+        self
+    }
+
     /// Add a path attribute.
     #[must_use]
     pub fn path_attribute(mut self, attr: path::Value) -> Self {
@@ -111,24 +289,97 @@ impl UpdateBuilder {
         self
     }
 
-    /// Find out how to represent the next hop. If MP-BGP is not enabled,
-    /// the next hop will be added to the path attributes.
+    /// Add communities to the `COMMUNITIES` attribute, creating it if needed.
+    #[must_use]
+    pub fn add_communities(mut self, communities: Communities) -> Self {
+        let existing = self
+            .other_path_attrs
+            .0
+            .iter_mut()
+            .find_map(|attr| match &mut attr.data {
+                path::Data::Communities(existing) => Some(existing),
+                _ => None,
+            });
+        if let Some(existing) = existing {
+            for community in communities.0 {
+                if !existing.contains(&community) {
+                    existing.0.push(community);
+                }
+            }
+        } else {
+            self.other_path_attrs.0.push(path::Value {
+                flags: path::Flags::OPTIONAL_TRANSITIVE,
+                data: path::Data::Communities(communities),
+            });
+        }
+        self
+    }
+
+    /// Set the aggregator, replacing any existing one.
+    ///
+    /// Depending on `four_octet_asn`, `asn` is sent in a four-octet
+    /// `AGGREGATOR`, or as `AS_TRANS` in `AGGREGATOR` and in full in
+    /// `AS4_AGGREGATOR` if it does not fit in two octets (RFC 6793 Section 4.2.2).
+    #[must_use]
+    pub fn set_aggregator(mut self, asn: u32, ip: Ipv4Addr) -> Self {
+        self.other_path_attrs.0.retain(|attr| {
+            !matches!(
+                attr.data,
+                path::Data::Aggregator(_) | path::Data::As4Aggregator(_)
+            )
+        });
+        self.aggregator = Some(As4Aggregator { asn, ip });
+        self
+    }
+
+    /// Add the `ATOMIC_AGGREGATE` attribute (RFC 4271 Section 5.1.6).
+    #[must_use]
+    pub fn set_atomic_aggregate(mut self) -> Self {
+        if !self
+            .other_path_attrs
+            .iter()
+            .any(|attr| attr.data == path::Data::AtomicAggregate)
+        {
+            self.other_path_attrs.0.push(path::Value {
+                flags: path::Flags::WELL_KNOWN_COMPLETE,
+                data: path::Data::AtomicAggregate,
+            });
+        }
+        self
+    }
+
+    /// Check that the next hop can be represented. If MP-BGP is not enabled,
+    /// the next hop must be IPv4 to go in the `NEXT_HOP` attribute.
+    /// Otherwise, it must be of the same address family as the routes unless
+    /// Extended Next Hop Encoding is enabled.
     ///
     /// After this method is called, if no next hop is set, it means that
     /// NLRI components are empty and no next hop is needed.
-    fn check_next_hop(&mut self) -> Result<(), crate::Error> {
+    fn check_next_hop(&self) -> Result<(), crate::Error> {
         if let Some(next_hop) = &self.next_hop {
-            if self.enable_mp_bgp {
-                Ok(())
-            } else if let MpNextHop::Single(IpAddr::V4(addr)) = next_hop {
-                self.other_path_attrs.0.push(path::Value {
-                    flags: path::Flags::WELL_KNOWN_COMPLETE,
-                    data: path::Data::NextHop(*addr),
-                });
-                Ok(())
-            } else {
-                Err(crate::Error::NoMpBgp)
+            if !self.enable_mp_bgp {
+                return if matches!(next_hop, MpNextHop::Single(IpAddr::V4(_))) {
+                    Ok(())
+                } else {
+                    Err(crate::Error::NoMpBgp)
+                };
+            }
+            let next_hop_afi = match next_hop {
+                MpNextHop::Empty => None,
+                MpNextHop::Single(IpAddr::V4(_)) => Some(Afi::Ipv4),
+                MpNextHop::Single(IpAddr::V6(_)) | MpNextHop::V6AndLL(..) => Some(Afi::Ipv6),
+            };
+            for (afi, routes) in [
+                (Afi::Ipv4, &self.nlri_ipv4_routes),
+                (Afi::Ipv6, &self.nlri_ipv6_routes),
+            ] {
+                let usable =
+                    next_hop_afi == Some(afi) || (self.extended_next_hop && next_hop_afi.is_some());
+                if !routes.is_empty() && !usable {
+                    return Err(crate::Error::NextHopAfi(afi));
+                }
             }
+            Ok(())
         } else if !self.nlri_ipv6_routes.is_empty() || !self.withdrawn_ipv6_routes.is_empty() {
             Err(crate::Error::NoNextHop)
         } else {
@@ -141,7 +392,7 @@ impl UpdateBuilder {
         let mp_unreach_nlri = path::MpUnreachNlri {
             afi,
             safi: Safi::Unicast,
-            withdrawn_routes: routes,
+            withdrawn_routes: MpNlri::Routes(routes),
         };
         path::Value {
             flags: path::Flags::OPTIONAL_TRANSITIVE_EXTENDED,
@@ -155,7 +406,7 @@ impl UpdateBuilder {
             afi,
             safi: Safi::Unicast,
             next_hop,
-            nlri: routes,
+            nlri: MpNlri::Routes(routes),
         };
         path::Value {
             flags: path::Flags::OPTIONAL_TRANSITIVE_EXTENDED,
@@ -163,15 +414,28 @@ impl UpdateBuilder {
         }
     }
 
+    /// Split routes into chunks that fit in `max_message_len` together with
+    /// `overhead` bytes of headers and path attributes.
+    fn split_routes(
+        routes: &Routes,
+        max_message_len: usize,
+        overhead: usize,
+    ) -> Result<Vec<usize>, crate::Error> {
+        routes
+            .split_routes_to_allowed_size_rev(max_message_len.saturating_sub(overhead))
+            .map_err(|e| crate::Error::MessageTooSmall(overhead + e.encoded_len))
+    }
+
     /// Make an `MP_UNREACH` UPDATE message from routes split into smaller chunks.
     fn make_mp_unreach_update(
         all_withdrawn_routes: Routes,
         afi: Afi,
-        allowed_size: usize,
+        max_message_len: usize,
+        overhead: usize,
         common_path_attributes: &PathAttributes,
         updates: &mut Vec<super::Update>,
-    ) {
-        let route_splits = all_withdrawn_routes.split_routes_to_allowed_size_rev(allowed_size);
+    ) -> Result<(), crate::Error> {
+        let route_splits = Self::split_routes(&all_withdrawn_routes, max_message_len, overhead)?;
         let mut leftover = all_withdrawn_routes.0;
         for end in route_splits {
             let withdrawn_routes = leftover.split_off(end);
@@ -185,18 +449,20 @@ impl UpdateBuilder {
                 nlri: Routes::default(),
             });
         }
+        Ok(())
     }
 
     /// Make an `MP_REACH` UPDATE message from routes split into smaller chunks.
     fn make_mp_reach_update(
         all_nlri_routes: Routes,
         afi: Afi,
-        allowed_size: usize,
+        max_message_len: usize,
+        overhead: usize,
         common_path_attributes: &PathAttributes,
         next_hop: MpNextHop,
         updates: &mut Vec<super::Update>,
-    ) {
-        let route_splits = all_nlri_routes.split_routes_to_allowed_size_rev(allowed_size);
+    ) -> Result<(), crate::Error> {
+        let route_splits = Self::split_routes(&all_nlri_routes, max_message_len, overhead)?;
         let mut leftover = all_nlri_routes.0;
         for end in route_splits {
             let nlri_routes = leftover.split_off(end);
@@ -212,6 +478,63 @@ impl UpdateBuilder {
                 nlri: Routes::default(),
             });
         }
+        Ok(())
+    }
+
+    /// Make an End-of-RIB marker for `afi` and `safi` (RFC 4724 Section 2).
+    ///
+    /// For IPv4 unicast, this is an UPDATE without any routes or path
+    /// attributes. Otherwise, it only contains an empty `MP_UNREACH_NLRI`.
+    #[must_use]
+    pub fn end_of_rib(afi: Afi, safi: Safi) -> super::Update {
+        let path_attributes = if afi == Afi::Ipv4 && safi == Safi::Unicast {
+            PathAttributes::default()
+        } else {
+            PathAttributes(vec![path::Value {
+                flags: path::Flags::OPTIONAL_NON_TRANSITIVE,
+                data: path::Data::MpUnreachNlri(path::MpUnreachNlri {
+                    afi,
+                    safi,
+                    withdrawn_routes: MpNlri::default(),
+                }),
+            }])
+        };
+        super::Update {
+            withdrawn_routes: Routes::default(),
+            path_attributes,
+            nlri: Routes::default(),
+        }
+    }
+
+    /// Build only the UPDATE messages withdrawing routes.
+    ///
+    /// Together with `build_announcements`, this allows the caller to send
+    /// all withdrawals before any new route is advertised.
+    ///
+    /// # Errors
+    ///
+    /// See [`UpdateBuilder::build`].
+    pub fn build_withdrawals(&self) -> Result<Vec<super::Update>, crate::Error> {
+        Self {
+            nlri_ipv4_routes: Routes::default(),
+            nlri_ipv6_routes: Routes::default(),
+            ..self.clone()
+        }
+        .build()
+    }
+
+    /// Build only the UPDATE messages advertising routes.
+    ///
+    /// # Errors
+    ///
+    /// See [`UpdateBuilder::build`].
+    pub fn build_announcements(&self) -> Result<Vec<super::Update>, crate::Error> {
+        Self {
+            withdrawn_ipv4_routes: Routes::default(),
+            withdrawn_ipv6_routes: Routes::default(),
+            ..self.clone()
+        }
+        .build()
     }
 
     /// Build one or more UPDATE messages depending on the size of routes.
@@ -220,20 +543,44 @@ impl UpdateBuilder {
     ///
     /// - [`crate::Error::NoNextHop`] if no next hop is set and there are NLRI components
     /// - [`crate::Error::NoMpBgp`] if MP-BGP is enabled but IPv6 is used
-    pub fn build(mut self) -> Result<Vec<super::Update>, crate::Error> {
+    /// - [`crate::Error::NextHopAfi`] if the next hop cannot be used for the
+    ///   routes of an address family
+    /// - [`crate::Error::MessageTooSmall`] if a route does not fit in the
+    ///   maximum message length together with the path attributes
+    pub fn build(self) -> Result<Vec<super::Update>, crate::Error> {
         // The algorithm is quite simple and not very efficient.
         self.check_next_hop()?;
         let Self {
             withdrawn_ipv4_routes,
             withdrawn_ipv6_routes,
-            nlri_ipv4_routes,
-            nlri_ipv6_routes,
+            mut nlri_ipv4_routes,
+            mut nlri_ipv6_routes,
             origin,
-            as_path,
+            mut as_path,
+            med,
+            local_pref,
             next_hop,
             other_path_attrs: mut small_attrs,
             enable_mp_bgp,
+            extended_next_hop: _,
+            four_octet_asn,
+            max_message_len,
+            drop_if_as_in_path,
+            aggregator,
         } = self;
+        if drop_if_as_in_path.is_some_and(|asn| as_path.contains_asn(asn)) {
+            nlri_ipv4_routes = Routes::default();
+            nlri_ipv6_routes = Routes::default();
+        }
+        let mut as4_path = None;
+        if four_octet_asn {
+            for segment in &mut as_path.0 {
+                segment.as4 = true;
+            }
+        } else {
+            (as_path, as4_path) = path::split_as4_path(&as_path);
+        }
+        let max_message_len = max_message_len.unwrap_or(MAX_MESSAGE_LEN);
         // Prepare path attributes that are common for all UPDATE messages
         if let Some(origin) = origin {
             let pa = path::Value {
@@ -246,53 +593,102 @@ impl UpdateBuilder {
             flags: path::Flags::WELL_KNOWN_COMPLETE,
             data: path::Data::AsPath(as_path),
         });
+        if let Some(as4_path) = as4_path {
+            small_attrs.0.push(path::Value {
+                flags: path::Flags::OPTIONAL_TRANSITIVE,
+                data: path::Data::As4Path(as4_path),
+            });
+        }
+        if let Some(med) = med {
+            small_attrs.0.push(path::Value {
+                flags: path::Flags::OPTIONAL_NON_TRANSITIVE,
+                data: path::Data::MultiExitDisc(med),
+            });
+        }
+        if let Some(local_pref) = local_pref {
+            small_attrs.0.push(path::Value {
+                flags: path::Flags::WELL_KNOWN_COMPLETE,
+                data: path::Data::LocalPref(local_pref),
+            });
+        }
+        if let Some(As4Aggregator { asn, ip }) = aggregator {
+            let oldbgp_asn = if four_octet_asn || asn <= u32::from(u16::MAX) {
+                asn
+            } else {
+                u32::from(crate::AS_TRANS)
+            };
+            small_attrs.0.push(path::Value {
+                flags: path::Flags::OPTIONAL_TRANSITIVE,
+                data: path::Data::Aggregator(Aggregator {
+                    asn: oldbgp_asn,
+                    ip,
+                    as4: four_octet_asn,
+                }),
+            });
+            if oldbgp_asn != asn {
+                small_attrs.0.push(path::Value {
+                    flags: path::Flags::OPTIONAL_TRANSITIVE,
+                    data: path::Data::As4Aggregator(As4Aggregator { asn, ip }),
+                });
+            }
+        }
         // Split the routes into smaller chunks and pack them into UPDATE messages
         let mut updates = Vec::new();
         if enable_mp_bgp {
             // First send withdrawn routes
-            let remaining_size = 4096 - 19 - 4 - 3 - small_attrs.encoded_len(); // 4096 - BGP header - UPDATE header - MP_UNREACH_NLRI header
+            let overhead = BGP_HEADER_LEN
+                + UPDATE_HEADER_LEN
+                + MP_UNREACH_NLRI_HEADER_LEN
+                + small_attrs.encoded_len();
             Self::make_mp_unreach_update(
                 withdrawn_ipv4_routes,
                 Afi::Ipv4,
-                remaining_size,
+                max_message_len,
+                overhead,
                 &small_attrs,
                 &mut updates,
-            );
+            )?;
             Self::make_mp_unreach_update(
                 withdrawn_ipv6_routes,
                 Afi::Ipv6,
-                remaining_size,
+                max_message_len,
+                overhead,
                 &small_attrs,
                 &mut updates,
-            );
+            )?;
             // Then send NLRI
             if let Some(next_hop) = next_hop {
-                let remaining_size =
-                    4096 - 19 - 4 - 4 - next_hop.encoded_len() - small_attrs.encoded_len(); // 4096 - BGP header - UPDATE header - MP_REACH_NLRI header - MP_NEXT_HOP
+                let overhead = BGP_HEADER_LEN
+                    + UPDATE_HEADER_LEN
+                    + MP_REACH_NLRI_HEADER_LEN
+                    + next_hop.encoded_len()
+                    + small_attrs.encoded_len();
                 Self::make_mp_reach_update(
                     nlri_ipv4_routes,
                     Afi::Ipv4,
-                    remaining_size,
+                    max_message_len,
+                    overhead,
                     &small_attrs,
                     next_hop,
                     &mut updates,
-                );
+                )?;
                 Self::make_mp_reach_update(
                     nlri_ipv6_routes,
                     Afi::Ipv6,
-                    remaining_size,
+                    max_message_len,
+                    overhead,
                     &small_attrs,
                     next_hop,
                     &mut updates,
-                );
+                )?;
             }
             // Else: `check_next_hop` ensures that there are no NLRI components
         } else {
             // Just IPv4 stuff for vanilla BGP-4
-            let remaining_size = 4096 - 19 - 4 - small_attrs.encoded_len(); // 4096 - BGP header - UPDATE header
-                                                                            // First send withdrawn routes
+            let overhead = BGP_HEADER_LEN + UPDATE_HEADER_LEN + small_attrs.encoded_len();
+            // First send withdrawn routes
             let route_splits =
-                withdrawn_ipv4_routes.split_routes_to_allowed_size_rev(remaining_size);
+                Self::split_routes(&withdrawn_ipv4_routes, max_message_len, overhead)?;
             let mut leftover = withdrawn_ipv4_routes.0;
             for end in route_splits {
                 let withdrawn_routes = leftover.split_off(end);
@@ -304,9 +700,11 @@ impl UpdateBuilder {
             }
             // Then send NLRI
             if let Some(MpNextHop::Single(IpAddr::V4(next_hop))) = next_hop {
-                let remaining_size = remaining_size - 4 - 3; // NEXT_HOP path attribute
-                let route_splits =
-                    nlri_ipv4_routes.split_routes_to_allowed_size_rev(remaining_size);
+                let route_splits = Self::split_routes(
+                    &nlri_ipv4_routes,
+                    max_message_len,
+                    overhead + NEXT_HOP_LEN,
+                )?;
                 let mut leftover = nlri_ipv4_routes.0;
                 small_attrs.0.push(path::Value {
                     flags: path::Flags::WELL_KNOWN_COMPLETE,
@@ -325,3 +723,512 @@ impl UpdateBuilder {
         Ok(updates)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MAX_EXTENDED_MESSAGE_LEN;
+    use proptest::prelude::*;
+
+    fn build_many_routes(max_message_len: Option<usize>) -> Vec<crate::Update> {
+        let routes: Routes = (0..2000u32)
+            .map(|i| Cidr4::new(Ipv4Addr::from(0x0a00_0000 + (i << 8)), 24))
+            .collect::<Vec<_>>()
+            .into();
+        UpdateBuilder::new(true, max_message_len)
+            .set_next_hop(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)).into())
+            .set_origin(Origin::Igp)
+            .set_as_path(AsSegmentType::AsSequence, vec![65000])
+            .add_ipv4_routes(routes)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_max_message_len() {
+        let normal = build_many_routes(None);
+        let extended = build_many_routes(Some(MAX_EXTENDED_MESSAGE_LEN));
+        assert!(extended.len() < normal.len());
+        for update in &normal {
+            assert!(update.encoded_len() + 19 <= crate::MAX_MESSAGE_LEN);
+        }
+        for update in &extended {
+            assert!(update.encoded_len() + 19 <= MAX_EXTENDED_MESSAGE_LEN);
+        }
+    }
+
+    #[test]
+    fn test_attributes_too_large_for_route() {
+        for enable_mp_bgp in [false, true] {
+            let builder = |max_message_len| {
+                UpdateBuilder::new(enable_mp_bgp, max_message_len)
+                    .set_next_hop(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)).into())
+                    .set_origin(Origin::Igp)
+                    .path_attribute(path::Value {
+                        flags: path::Flags::OPTIONAL_TRANSITIVE_EXTENDED,
+                        data: path::Data::Unsupported(200, vec![0; 4090].into()),
+                    })
+                    .add_ipv4_routes([Cidr4::new(Ipv4Addr::new(10, 0, 0, 0), 8)])
+            };
+            // The route would otherwise be silently dropped
+            let Err(crate::Error::MessageTooSmall(needed)) = builder(None).build() else {
+                panic!("expected MessageTooSmall");
+            };
+            assert!(needed > MAX_MESSAGE_LEN);
+            let updates = builder(Some(needed)).build_announcements().unwrap();
+            // The overhead is estimated conservatively
+            assert!(updates.last().unwrap().encoded_len() + 19 <= needed);
+            assert!(matches!(
+                builder(Some(needed - 1)).build_announcements(),
+                Err(crate::Error::MessageTooSmall(n)) if n == needed
+            ));
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(50))]
+
+        #[test]
+        fn test_random_routes_fit_max_message_len(
+            enable_mp_bgp in any::<bool>(),
+            max_message_len in prop::sample::select(vec![
+                None,
+                Some(MAX_EXTENDED_MESSAGE_LEN),
+                Some(1024),
+            ]),
+            next_hop in 0..3usize,
+            routes in prop::collection::vec(
+                any::<(Ipv4Addr, core::net::Ipv6Addr, bool, bool)>()
+                    .prop_flat_map(|(v4, v6, add_v4, add_v6)| {
+                        (Just((v4, v6, add_v4, add_v6)), 0..=32u8, 0..=128u8)
+                    }),
+                0..3000,
+            ),
+        ) {
+            let v4_hop = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+            let v6_hop = IpAddr::from("2001:db8::1".parse::<core::net::Ipv6Addr>().unwrap());
+            let v6_and_ll =
+                MpNextHop::V6AndLL("2001:db8::1".parse().unwrap(), "fe80::1".parse().unwrap());
+            let next_hop = if enable_mp_bgp {
+                [v4_hop.into(), v6_hop.into(), v6_and_ll][next_hop]
+            } else {
+                v4_hop.into()
+            };
+            let mut builder = UpdateBuilder::new(enable_mp_bgp, max_message_len)
+                .set_next_hop(next_hop)
+                .set_extended_next_hop(true)
+                .set_origin(Origin::Igp)
+                .set_as_path(AsSegmentType::AsSequence, vec![65000, 4_200_000_000]);
+            for ((v4, v6, add_v4, add_v6), v4_len, v6_len) in routes {
+                let cidr = Cidr::V4(Cidr4::new(v4, v4_len));
+                builder = if add_v4 {
+                    builder.add_route(cidr)
+                } else {
+                    builder.withdraw_route(cidr)
+                };
+                if enable_mp_bgp {
+                    let cidr = Cidr::V6(Cidr6::new(v6, v6_len));
+                    builder = if add_v6 {
+                        builder.add_route(cidr)
+                    } else {
+                        builder.withdraw_route(cidr)
+                    };
+                }
+            }
+            let max = max_message_len.unwrap_or(crate::MAX_MESSAGE_LEN);
+            for update in builder.build().unwrap() {
+                assert!(update.encoded_len() + 19 <= max);
+                let next_hops = update
+                    .path_attributes
+                    .iter()
+                    .filter(|attr| matches!(attr.data, path::Data::NextHop(_)))
+                    .count();
+                assert!(next_hops <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_end_of_rib() {
+        let mut dst = bytes::BytesMut::new();
+        UpdateBuilder::end_of_rib(Afi::Ipv4, Safi::Unicast).to_bytes(&mut dst);
+        assert_eq!(dst, crate::hex_to_bytes("0000 0000"));
+        let mut dst = bytes::BytesMut::new();
+        UpdateBuilder::end_of_rib(Afi::Ipv6, Safi::Unicast).to_bytes(&mut dst);
+        assert_eq!(dst, crate::hex_to_bytes("0000 0006 800f03 0002 01"));
+    }
+
+    #[test]
+    fn test_build_withdrawals_first() {
+        for enable_mp_bgp in [true, false] {
+            let builder = UpdateBuilder::new(enable_mp_bgp, None)
+                .set_next_hop(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)).into())
+                .set_origin(Origin::Igp)
+                .set_as_path(AsSegmentType::AsSequence, vec![65000])
+                .add_route("192.0.2.0/24".parse().unwrap())
+                .withdraw_route("198.51.100.0/24".parse().unwrap());
+            let has_reach = |update: &crate::Update| {
+                !update.nlri.is_empty()
+                    || update.path_attributes.iter().any(|attr| {
+                        matches!(&attr.data, path::Data::MpReachNlri(r) if !r.nlri.is_empty())
+                    })
+            };
+            let has_unreach = |update: &crate::Update| {
+                !update.withdrawn_routes.is_empty()
+                    || update.path_attributes.iter().any(|attr| {
+                        matches!(&attr.data, path::Data::MpUnreachNlri(u) if !u.withdrawn_routes.is_empty())
+                    })
+            };
+            let withdrawals = builder.build_withdrawals().unwrap();
+            assert!(withdrawals.iter().any(has_unreach));
+            assert!(!withdrawals.iter().any(has_reach));
+            let announcements = builder.build_announcements().unwrap();
+            assert!(announcements.iter().any(has_reach));
+            assert!(!announcements.iter().any(has_unreach));
+            // The combined build still sends withdrawals before announcements
+            let combined = builder.build().unwrap();
+            let first_reach = combined.iter().position(has_reach).unwrap();
+            let last_unreach = combined.iter().rposition(has_unreach).unwrap();
+            assert!(last_unreach < first_reach);
+        }
+    }
+
+    #[test]
+    fn test_drop_if_as_in_path() {
+        let build = |asn| {
+            UpdateBuilder::new(true, None)
+                .set_next_hop(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)).into())
+                .set_origin(Origin::Igp)
+                .set_as_path(AsSegmentType::AsSequence, vec![65000])
+                .add_route("192.0.2.0/24".parse().unwrap())
+                .withdraw_route("198.51.100.0/24".parse().unwrap())
+                .drop_if_as_in_path(asn)
+                .build()
+                .unwrap()
+        };
+        let count_routes = |updates: &[crate::Update]| {
+            let mut reach = 0;
+            let mut unreach = 0;
+            for update in updates {
+                for attr in update.path_attributes.iter() {
+                    match &attr.data {
+                        path::Data::MpReachNlri(r) => reach += r.nlri.len(),
+                        path::Data::MpUnreachNlri(u) => unreach += u.withdrawn_routes.len(),
+                        _ => {}
+                    }
+                }
+            }
+            (reach, unreach)
+        };
+        assert_eq!(count_routes(&build(65001)), (1, 1));
+        assert_eq!(count_routes(&build(65000)), (0, 1));
+    }
+
+    #[test]
+    fn test_add_routes_from_iter() {
+        let prefixes: Vec<Cidr4> = vec![
+            "192.0.2.0/24".parse().unwrap(),
+            "198.51.100.0/24".parse().unwrap(),
+        ];
+        let routes = Routes::from(prefixes.clone());
+        let from_routes = UpdateBuilder::new(true, None)
+            .add_ipv4_routes(routes.clone())
+            .withdraw_ipv6_routes(Routes::default());
+        let from_iter = UpdateBuilder::new(true, None)
+            .add_ipv4_routes(prefixes.iter())
+            .withdraw_ipv6_routes(Vec::<Cidr6>::new());
+        assert_eq!(from_routes, from_iter);
+        assert_eq!(
+            routes.into_iter().collect::<Routes>(),
+            from_iter.nlri_ipv4_routes
+        );
+    }
+
+    #[test]
+    fn test_add_routes_tagged() {
+        let tag = Communities::community(65000, 1);
+        let updates = UpdateBuilder::new(true, None)
+            .set_next_hop(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)).into())
+            .set_extended_next_hop(true)
+            .set_origin(Origin::Igp)
+            .add_ipv4_routes_tagged(
+                vec!["192.0.2.0/24".parse::<Cidr4>().unwrap()],
+                Communities(vec![tag]),
+            )
+            .add_ipv6_routes_tagged(
+                Routes::from(vec!["2001:db8::/32".parse::<Cidr6>().unwrap()]),
+                Communities(vec![tag, Communities::NO_EXPORT]),
+            )
+            .build()
+            .unwrap();
+        assert!(!updates.is_empty());
+        for update in updates {
+            let communities: Vec<_> = update
+                .path_attributes
+                .iter()
+                .filter_map(|attr| match &attr.data {
+                    path::Data::Communities(c) => Some((attr.flags, c.clone())),
+                    _ => None,
+                })
+                .collect();
+            // A single merged attribute
+            assert_eq!(
+                communities,
+                vec![(
+                    path::Flags::OPTIONAL_TRANSITIVE,
+                    Communities(vec![tag, Communities::NO_EXPORT])
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn test_med_and_local_pref() {
+        let updates = UpdateBuilder::new(true, None)
+            .set_next_hop(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)).into())
+            .set_extended_next_hop(true)
+            .set_origin(Origin::Igp)
+            .set_as_path(AsSegmentType::AsSequence, vec![65000])
+            .set_med(100)
+            .set_local_pref(200)
+            .add_route(Cidr::V4(Cidr4::new(Ipv4Addr::new(10, 0, 0, 0), 8)))
+            .add_route(Cidr::V6("2001:db8::/32".parse().unwrap()))
+            .build()
+            .unwrap();
+        assert!(!updates.is_empty());
+        for update in &updates {
+            let mut bytes = bytes::BytesMut::new();
+            update.encode_to(&mut bytes);
+            assert!(update.path_attributes.contains(&path::Value {
+                flags: path::Flags(0x80),
+                data: path::Data::MultiExitDisc(100),
+            }));
+            assert!(update.path_attributes.contains(&path::Value {
+                flags: path::Flags(0x40),
+                data: path::Data::LocalPref(200),
+            }));
+            // Flags, type, length, value
+            let med = crate::hex_to_bytes("800404 00000064");
+            let local_pref = crate::hex_to_bytes("400504 000000c8");
+            assert!(bytes.windows(med.len()).any(|w| w == med.as_ref()));
+            assert!(bytes
+                .windows(local_pref.len())
+                .any(|w| w == local_pref.as_ref()));
+        }
+    }
+
+    /// Build a single route and return the path attributes of its UPDATE
+    fn build_path_attributes(builder: UpdateBuilder) -> PathAttributes {
+        let mut updates = builder
+            .set_next_hop(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)).into())
+            .set_origin(Origin::Igp)
+            .set_as_path(AsSegmentType::AsSequence, vec![65000])
+            .add_route(Cidr::V4(Cidr4::new(Ipv4Addr::new(10, 0, 0, 0), 8)))
+            .build()
+            .unwrap();
+        let update = updates.pop().unwrap();
+        assert_eq!(update.nlri.len(), 1);
+        update.path_attributes
+    }
+
+    #[test]
+    fn test_aggregator_2byte_asn() {
+        let attrs = build_path_attributes(
+            UpdateBuilder::new(false, None)
+                .set_aggregator(64512, Ipv4Addr::new(192, 0, 2, 2))
+                .set_atomic_aggregate()
+                .set_atomic_aggregate(),
+        );
+        let mut bytes = bytes::BytesMut::new();
+        attrs.encode_to(&mut bytes);
+        // Flags, type, length, value
+        let aggregator = crate::hex_to_bytes("c00706 fc00 c0000202");
+        let atomic_aggregate = crate::hex_to_bytes("400600");
+        assert_eq!(
+            bytes
+                .windows(aggregator.len())
+                .filter(|w| *w == aggregator.as_ref())
+                .count(),
+            1
+        );
+        assert_eq!(
+            bytes
+                .windows(atomic_aggregate.len())
+                .filter(|w| *w == atomic_aggregate.as_ref())
+                .count(),
+            1
+        );
+        assert!(!attrs
+            .iter()
+            .any(|attr| matches!(attr.data, path::Data::As4Aggregator(_))));
+    }
+
+    #[test]
+    fn test_aggregator_4byte_asn() {
+        let attrs = build_path_attributes(
+            UpdateBuilder::new(false, None)
+                .set_aggregator(64512, Ipv4Addr::new(192, 0, 2, 3))
+                .set_aggregator(4_200_000_000, Ipv4Addr::new(192, 0, 2, 2)),
+        );
+        let mut bytes = bytes::BytesMut::new();
+        attrs.encode_to(&mut bytes);
+        let aggregator = crate::hex_to_bytes("c00706 5ba0 c0000202");
+        let as4_aggregator = crate::hex_to_bytes("c01208 fa56ea00 c0000202");
+        assert!(bytes
+            .windows(aggregator.len())
+            .any(|w| w == aggregator.as_ref()));
+        assert!(bytes
+            .windows(as4_aggregator.len())
+            .any(|w| w == as4_aggregator.as_ref()));
+        // The earlier aggregator is replaced
+        assert_eq!(
+            attrs
+                .iter()
+                .filter(|attr| matches!(attr.data, path::Data::Aggregator(_)))
+                .count(),
+            1
+        );
+        assert!(!attrs
+            .iter()
+            .any(|attr| attr.data == path::Data::AtomicAggregate));
+    }
+
+    #[test]
+    fn test_aggregator_four_octet_asn() {
+        let attrs = build_path_attributes(
+            UpdateBuilder::new(false, None)
+                .set_four_octet_asn(true)
+                .set_aggregator(4_200_000_000, Ipv4Addr::new(192, 0, 2, 2)),
+        );
+        let mut bytes = bytes::BytesMut::new();
+        attrs.encode_to(&mut bytes);
+        let aggregator = crate::hex_to_bytes("c00708 fa56ea00 c0000202");
+        assert!(bytes
+            .windows(aggregator.len())
+            .any(|w| w == aggregator.as_ref()));
+        // Not needed between four-octet speakers
+        assert!(!attrs
+            .iter()
+            .any(|attr| matches!(attr.data, path::Data::As4Aggregator(_))));
+    }
+
+    #[test]
+    fn test_filter_prefix_len() {
+        let builder = UpdateBuilder::new(true, None)
+            .add_route("10.0.0.0/8".parse().unwrap())
+            .add_route("192.0.2.0/24".parse().unwrap())
+            .add_route("198.51.100.128/25".parse().unwrap())
+            .add_route("2001:db8::/32".parse().unwrap())
+            .add_route("2001:db8:1::/48".parse().unwrap())
+            .withdraw_route("203.0.113.0/24".parse().unwrap())
+            .withdraw_route("203.0.113.0/28".parse().unwrap())
+            .withdraw_route("2001:db8:2::/64".parse().unwrap())
+            .filter_prefix_len(8, 24, 16, 48);
+        let prefix_lens = |routes: &Routes| routes.iter().map(|r| r.prefix_len).collect::<Vec<_>>();
+        assert_eq!(prefix_lens(&builder.nlri_ipv4_routes), vec![8, 24]);
+        assert_eq!(prefix_lens(&builder.withdrawn_ipv4_routes), vec![24]);
+        assert_eq!(prefix_lens(&builder.nlri_ipv6_routes), vec![32, 48]);
+        assert!(builder.withdrawn_ipv6_routes.is_empty());
+    }
+
+    #[test]
+    fn test_next_hop_afi() {
+        let builder = |extended_next_hop| {
+            UpdateBuilder::new(true, None)
+                .set_next_hop(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)).into())
+                .set_extended_next_hop(extended_next_hop)
+                .set_origin(Origin::Igp)
+                .add_route(Cidr::V6("2001:db8::/32".parse().unwrap()))
+        };
+        assert!(matches!(
+            builder(false).build(),
+            Err(crate::Error::NextHopAfi(Afi::Ipv6))
+        ));
+        // Withdrawals do not need a next hop
+        assert!(builder(false).build_withdrawals().is_ok());
+        let updates = builder(true).build().unwrap();
+        let reach = updates
+            .iter()
+            .flat_map(|update| update.path_attributes.iter())
+            .find_map(|pa| match &pa.data {
+                path::Data::MpReachNlri(reach) if reach.afi == Afi::Ipv6 => Some(reach),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            reach.nlri,
+            MpNlri::Routes(Routes::from(vec!["2001:db8::/32"
+                .parse::<Cidr6>()
+                .unwrap()]))
+        );
+        assert_eq!(
+            reach.next_hop,
+            MpNextHop::Single(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)))
+        );
+        // An IPv6 next hop cannot be used for IPv4 routes either
+        let result = UpdateBuilder::new(true, None)
+            .set_next_hop(
+                IpAddr::from("2001:db8::1".parse::<core::net::Ipv6Addr>().unwrap()).into(),
+            )
+            .add_route(Cidr::V4(Cidr4::new(Ipv4Addr::new(10, 0, 0, 0), 8)))
+            .build();
+        assert!(matches!(result, Err(crate::Error::NextHopAfi(Afi::Ipv4))));
+    }
+
+    #[test]
+    fn test_four_octet_asn() {
+        let as_path = |four_octet_asn| {
+            let updates = UpdateBuilder::new(false, None)
+                .set_four_octet_asn(four_octet_asn)
+                .set_next_hop(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)).into())
+                .set_origin(Origin::Igp)
+                .set_as_path(AsSegmentType::AsSequence, vec![65000])
+                .add_route(Cidr::V4(Cidr4::new(Ipv4Addr::new(10, 0, 0, 0), 8)))
+                .build()
+                .unwrap();
+            let mut dst = bytes::BytesMut::new();
+            updates
+                .iter()
+                .flat_map(|update| update.path_attributes.iter())
+                .find(|pa| matches!(pa.data, path::Data::AsPath(_)))
+                .unwrap()
+                .encode_to(&mut dst);
+            dst.freeze()
+        };
+        assert_eq!(as_path(false), crate::hex_to_bytes("40 02 04 02 01 fde8"));
+        assert_eq!(
+            as_path(true),
+            crate::hex_to_bytes("40 02 06 02 01 0000fde8")
+        );
+    }
+
+    #[test]
+    fn test_four_octet_asn_not_negotiated() {
+        let updates = UpdateBuilder::new(false, None)
+            .set_four_octet_asn(false)
+            .set_next_hop(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)).into())
+            .set_origin(Origin::Igp)
+            .set_as_path(AsSegmentType::AsSequence, vec![4_200_000_000])
+            .add_route(Cidr::V4(Cidr4::new(Ipv4Addr::new(10, 0, 0, 0), 8)))
+            .build()
+            .unwrap();
+        let mut dst = bytes::BytesMut::new();
+        updates[0].path_attributes.encode_to(&mut dst);
+        // AS_TRANS in AS_PATH and the real ASN in AS4_PATH
+        let as_path = crate::hex_to_bytes("40 02 04 02 01 5ba0");
+        let as4_path = crate::hex_to_bytes("c0 11 06 02 01 fa56ea00");
+        assert!(dst.windows(as_path.len()).any(|w| w == as_path.as_ref()));
+        assert!(dst.windows(as4_path.len()).any(|w| w == as4_path.as_ref()));
+        // A peer without four-octet ASNs can decode it
+        let mut src = dst.freeze();
+        let attrs = PathAttributes::from_bytes_as4(&mut src, false).unwrap();
+        assert_eq!(
+            attrs.merged_as_path(),
+            Some(AsPath(vec![AsSegment {
+                type_: AsSegmentType::AsSequence,
+                asns: vec![4_200_000_000],
+                as4: true,
+            }]))
+        );
+    }
+}